@@ -0,0 +1,9 @@
+-- Add migration script here
+create table email_change_requests(
+  change_token text not null,
+  subscriber_id uuid not null
+    references subscriptions (id),
+  new_email text not null,
+  requested_at timestamptz not null,
+  primary key (change_token)
+);
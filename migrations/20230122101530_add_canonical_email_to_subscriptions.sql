@@ -0,0 +1,43 @@
+-- Add migration script here
+begin;
+  alter table subscriptions add column canonical_email text null;
+
+  -- Mirrors `SubscriberEmail::canonical`, except for punycode conversion of
+  -- IDN domains which Postgres cannot do.
+  update subscriptions
+  set canonical_email = lower(email);
+
+  update subscriptions
+  set canonical_email =
+    replace(split_part(split_part(canonical_email, '@', 1), '+', 1), '.', '')
+    || '@gmail.com'
+  where split_part(canonical_email, '@', 2) in ('gmail.com', 'googlemail.com');
+
+  -- Keep one row per canonical address: a confirmed one if there is any,
+  -- otherwise the oldest.
+  create temporary table duplicate_subscriptions on commit drop as
+  select id from (
+    select
+      id,
+      row_number() over (
+        partition by canonical_email
+        order by status = 'confirmed' desc, subscribed_at asc
+      ) as position
+    from subscriptions
+  ) as ranked
+  where position > 1;
+
+  delete from subscription_tokens
+  where subscriber_id in (select id from duplicate_subscriptions);
+
+  delete from email_change_requests
+  where subscriber_id in (select id from duplicate_subscriptions);
+
+  delete from subscriptions
+  where id in (select id from duplicate_subscriptions);
+
+  alter table subscriptions alter column canonical_email set not null;
+  alter table subscriptions add constraint subscriptions_canonical_email_key unique (canonical_email);
+  alter table subscriptions drop constraint subscriptions_email_key;
+
+commit;
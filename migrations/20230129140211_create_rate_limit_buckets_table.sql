@@ -0,0 +1,7 @@
+-- Add migration script here
+create table rate_limit_buckets(
+  key text not null,
+  tokens double precision not null,
+  updated_at timestamptz not null,
+  primary key (key)
+);
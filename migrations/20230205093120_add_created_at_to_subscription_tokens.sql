@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table subscription_tokens
+  add column created_at timestamptz not null default now();
@@ -0,0 +1,2 @@
+-- Add migration script here
+alter table subscriptions add column reminder_sent_at timestamptz null;
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- The password is `everythinghastostartsomewhere`, change it after deploying.
+insert into users (user_id, username, password_hash)
+values (
+  'ddf8994f-d522-4659-8d02-c1d479057be6',
+  'admin',
+  '$argon2id$v=19$m=15000,t=2,p=1$y6ZFUaBJI9+STkY3/VWefw$7zQnsevSpkKMGTMTfONzIJA5okzlWO0mdau1GQTD5Zo'
+);
@@ -0,0 +1,28 @@
+-- Add migration script here
+create table sequences(
+  id uuid primary key,
+  name text not null,
+  active boolean not null default true,
+  created_at timestamptz not null default now()
+);
+
+create table sequence_steps(
+  sequence_id uuid not null references sequences (id) on delete cascade,
+  position integer not null,
+  -- Counted from enrollment, not from the previous step.
+  delay_hours integer not null,
+  subject text not null,
+  html_content text not null,
+  text_content text not null,
+  primary key (sequence_id, position)
+);
+
+create table sequence_enrollments(
+  sequence_id uuid not null references sequences (id) on delete cascade,
+  subscriber_id uuid not null references subscriptions (id),
+  enrolled_at timestamptz not null default now(),
+  last_position integer not null default 0,
+  completed_at timestamptz null,
+  stopped_at timestamptz null,
+  primary key (sequence_id, subscriber_id)
+);
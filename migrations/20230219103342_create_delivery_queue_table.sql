@@ -0,0 +1,11 @@
+-- Add migration script here
+create table delivery_queue(
+  id uuid primary key,
+  subscriber_id uuid not null references subscriptions (id),
+  subject text not null,
+  html_content text not null,
+  text_content text not null,
+  n_retries smallint not null default 0,
+  execute_after timestamptz not null default now(),
+  enqueued_at timestamptz not null default now()
+);
@@ -0,0 +1,22 @@
+-- Add migration script here
+alter table subscriptions add column attributes jsonb not null default '{}';
+
+create table subscriber_fields(
+  name text primary key,
+  field_type text not null,
+  required boolean not null default false,
+  signup boolean not null default false,
+  max_length integer null,
+  min double precision null,
+  max double precision null,
+  choices text[] null,
+  created_at timestamptz not null default now()
+);
+
+create table subscriber_tags(
+  subscriber_id uuid not null references subscriptions (id) on delete cascade,
+  tag text not null,
+  created_at timestamptz not null default now(),
+  primary key (subscriber_id, tag)
+);
+create index subscriber_tags_tag_idx on subscriber_tags (tag);
@@ -0,0 +1,18 @@
+-- Add migration script here
+create table newsletter_issues(
+  newsletter_issue_id uuid primary key,
+  title text not null,
+  text_content text not null,
+  html_content text not null,
+  created_at timestamptz not null default now(),
+  -- Set once the issue has gone out; segments look at the latest ones.
+  published_at timestamptz null
+);
+
+create table issue_opens(
+  newsletter_issue_id uuid not null references newsletter_issues (newsletter_issue_id) on delete cascade,
+  subscriber_id uuid not null references subscriptions (id) on delete cascade,
+  first_opened_at timestamptz not null default now(),
+  primary key (newsletter_issue_id, subscriber_id)
+);
+create index issue_opens_subscriber_id_idx on issue_opens (subscriber_id);
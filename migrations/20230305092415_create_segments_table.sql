@@ -0,0 +1,9 @@
+-- Add migration script here
+create table segments(
+  id uuid primary key,
+  name text not null unique,
+  -- Parsed again whenever the segment is used, as subscriber fields may change.
+  filter text not null,
+  created_at timestamptz not null default now(),
+  updated_at timestamptz not null default now()
+);
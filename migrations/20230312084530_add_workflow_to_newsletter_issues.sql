@@ -0,0 +1,16 @@
+-- Add migration script here
+alter table newsletter_issues
+  add column status text not null default 'draft',
+  -- Bumped on every edit and transition, so stale writes can be refused.
+  add column version integer not null default 1,
+  add column author_id uuid null references users (user_id),
+  add column approved_by uuid null references users (user_id),
+  add column segment_id uuid null references segments (id),
+  add column publish_at timestamptz null,
+  add column updated_at timestamptz not null default now();
+update newsletter_issues set status = 'sent' where published_at is not null;
+create index newsletter_issues_due_idx on newsletter_issues (publish_at) where status = 'scheduled';
+
+alter table delivery_queue
+  add column newsletter_issue_id uuid null references newsletter_issues (newsletter_issue_id);
+create index delivery_queue_newsletter_issue_id_idx on delivery_queue (newsletter_issue_id);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- The source of the html and text content when an issue is written in Markdown.
+alter table newsletter_issues add column markdown_content text null;
@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Admins' edits of the email templates; templates without a row use the
+-- defaults shipped in templates/emails.
+create table email_templates(
+  name text not null primary key,
+  -- Null for layouts and partials.
+  subject text null,
+  html_body text not null,
+  text_body text not null,
+  updated_at timestamptz not null default now()
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Authenticates the unsubscribe and preferences links in a subscriber's emails.
+-- `gen_random_uuid` is only built in from Postgres 13, before that it comes
+-- with pgcrypto.
+create extension if not exists pgcrypto;
+alter table subscriptions
+  add column manage_token text not null default replace(gen_random_uuid()::text, '-', '');
+create unique index subscriptions_manage_token_idx on subscriptions (manage_token);
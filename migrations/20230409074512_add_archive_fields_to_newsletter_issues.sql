@@ -0,0 +1,13 @@
+-- Add migration script here
+-- Issues get a slug in the public archive when they are published, unless
+-- they are private.
+alter table newsletter_issues
+  add column slug text null,
+  add column private boolean not null default false;
+update newsletter_issues
+  set slug = coalesce(nullif(trim(both '-' from lower(regexp_replace(title, '[^a-zA-Z0-9]+', '-', 'g'))), ''), 'issue')
+    || '-' || left(newsletter_issue_id::text, 8)
+  where published_at is not null;
+create unique index newsletter_issues_slug_idx on newsletter_issues (slug);
+create index newsletter_issues_published_at_idx on newsletter_issues (published_at)
+  where published_at is not null and not private;
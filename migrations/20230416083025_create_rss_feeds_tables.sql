@@ -0,0 +1,32 @@
+-- Add migration script here
+-- External feeds whose new items become issues.
+create table rss_feeds(
+  id uuid primary key,
+  url text not null unique,
+  -- 'draft' leaves issues for review, 'send' schedules them straight away.
+  mode text not null,
+  -- Bundles new items into one issue a week instead of one issue each.
+  digest boolean not null default false,
+  title_template text not null,
+  body_template text not null,
+  -- Null until the first poll, which only records the items already there.
+  last_checked_at timestamptz null,
+  last_error text null,
+  last_digest_at timestamptz not null default now(),
+  created_at timestamptz not null default now()
+);
+
+create table rss_feed_items(
+  feed_id uuid not null references rss_feeds (id) on delete cascade,
+  guid text not null,
+  title text not null,
+  link text not null,
+  content text not null,
+  published_at timestamptz null,
+  seen_at timestamptz not null default now(),
+  -- Null while the item waits for the next digest.
+  handled_at timestamptz null,
+  newsletter_issue_id uuid null references newsletter_issues (newsletter_issue_id) on delete set null,
+  primary key (feed_id, guid)
+);
+create index rss_feed_items_pending_idx on rss_feed_items (feed_id) where handled_at is null;
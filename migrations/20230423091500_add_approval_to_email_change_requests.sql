@@ -0,0 +1,7 @@
+-- Changes now need the old address's approval too, which pending requests
+-- have no way to get.
+delete from email_change_requests;
+alter table email_change_requests
+  add column approval_token text not null unique,
+  add column confirmed_at timestamptz,
+  add column approved_at timestamptz;
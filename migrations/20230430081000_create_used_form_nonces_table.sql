@@ -0,0 +1,7 @@
+-- Nonces of accepted subscribe forms, so a solved form cannot be replayed.
+create table used_form_nonces(
+  nonce text not null,
+  expires_at timestamptz not null,
+  primary key (nonce)
+);
+create index used_form_nonces_expires_at on used_form_nonces (expires_at);
@@ -0,0 +1,8 @@
+-- The seeded admin's password is in the repository. Unless it was changed,
+-- lock the account with a value that is not a password hash at all, so no
+-- password can verify against it; the first admin now comes from the
+-- `initial_admin` setting.
+update users
+set password_hash = 'locked'
+where user_id = 'ddf8994f-d522-4659-8d02-c1d479057be6'
+  and password_hash = '$argon2id$v=19$m=15000,t=2,p=1$y6ZFUaBJI9+STkY3/VWefw$7zQnsevSpkKMGTMTfONzIJA5okzlWO0mdau1GQTD5Zo';
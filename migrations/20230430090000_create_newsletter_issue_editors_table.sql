@@ -0,0 +1,7 @@
+-- Everyone who changed an issue's content, so none of them can approve it.
+create table newsletter_issue_editors(
+  newsletter_issue_id uuid not null
+    references newsletter_issues (newsletter_issue_id) on delete cascade,
+  user_id uuid not null references users (user_id),
+  primary key (newsletter_issue_id, user_id)
+);
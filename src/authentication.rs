@@ -0,0 +1,193 @@
+use crate::routes::error_chain_fmt;
+use crate::telemetry::spawn_blocking_with_tracing;
+use actix_web::{dev::ServiceRequest, HttpMessage};
+use actix_web_httpauth::extractors::{
+    basic::{BasicAuth, Config},
+    AuthenticationError,
+};
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Stored instead of a password hash for accounts nobody may log in to.
+const LOCKED_PASSWORD_HASH: &str = "locked";
+
+#[derive(thiserror::Error)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+/// The admin making the request, available to handlers behind
+/// `admin_basic_auth` as `web::ReqData<UserId>`.
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(pub Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Validator for `HttpAuthentication::basic` guarding the admin routes.
+pub async fn admin_basic_auth(
+    request: ServiceRequest,
+    credentials: BasicAuth,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let config = request.app_data::<Config>().cloned().unwrap_or_default();
+    let challenge = || AuthenticationError::from(config.clone()).into();
+    let pool = match request.app_data::<actix_web::web::Data<PgPool>>() {
+        Some(pool) => pool.clone(),
+        None => return Err((challenge(), request)),
+    };
+    let credentials = Credentials {
+        username: credentials.user_id().to_string(),
+        password: Secret::new(credentials.password().unwrap_or_default().to_string()),
+    };
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            request.extensions_mut().insert(UserId(user_id));
+            Ok(request)
+        }
+        Err(AuthError::InvalidCredentials(_)) => Err((challenge(), request)),
+        Err(AuthError::UnexpectedError(e)) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to validate credentials.");
+            Err((actix_web::error::ErrorInternalServerError(e), request))
+        }
+    }
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    // Verify against a dummy hash when the user does not exist, so that the
+    // response time does not tell which usernames are valid.
+    let mut expected_password_hash = Secret::new(
+        "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+            .to_string(),
+    );
+    // Locked accounts take the same path as unknown usernames.
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool)
+            .await?
+            .filter(|(_, hash)| hash.expose_secret() != LOCKED_PASSWORD_HASH)
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+
+    user_id
+        .ok_or_else(|| anyhow::anyhow!("Unknown username."))
+        .map_err(AuthError::InvalidCredentials)
+}
+
+/// Creates an admin with `credentials` unless a user of that name exists, so
+/// that a fresh deployment has someone to log in as. An existing user is left
+/// alone, in case they have changed their password since, unless their
+/// account is locked, in which case it gets the configured password.
+#[tracing::instrument(name = "Create initial admin", skip(credentials, pool), fields(username = %credentials.username))]
+pub async fn create_initial_admin(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(credentials.password))
+            .await
+            .context("Failed to spawn blocking task.")??;
+    sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (username) DO UPDATE
+        SET password_hash = EXCLUDED.password_hash
+        WHERE users.password_hash = $4
+        "#,
+        Uuid::new_v4(),
+        credentials.username,
+        password_hash.expose_secret(),
+        LOCKED_PASSWORD_HASH,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the initial admin.")?;
+    Ok(())
+}
+
+fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).unwrap(),
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)?
+    .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .context("Failed to parse hash in PHC string format.")?;
+
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .context("Invalid password.")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, Secret<String>)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT user_id, password_hash
+        FROM users
+        WHERE username = $1
+        "#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve stored credentials.")?
+    .map(|row| (row.user_id, Secret::new(row.password_hash)));
+    Ok(row)
+}
@@ -0,0 +1,352 @@
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+const SALT_LENGTH: usize = 16;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum BotCheckError {
+    #[error("The honeypot field was filled in.")]
+    HoneypotFilled,
+    #[error("The form nonce is missing.")]
+    MissingNonce,
+    #[error("The form nonce is malformed or its signature does not match.")]
+    InvalidNonce,
+    #[error("The form was submitted {0} seconds after it was issued.")]
+    SubmittedTooFast(i64),
+    #[error("The form nonce expired {0} seconds ago.")]
+    NonceExpired(i64),
+    #[error("The proof of work is missing.")]
+    MissingProofOfWork,
+    #[error("The proof of work does not solve the challenge.")]
+    InvalidProofOfWork,
+}
+
+/// What a client needs to submit the subscribe form.
+#[derive(serde::Serialize)]
+pub struct Challenge {
+    pub nonce: String,
+    /// Leading zero bits required of `sha256("{nonce}:{proof_of_work}")`,
+    /// zero when no proof of work is needed.
+    pub proof_of_work_difficulty: u8,
+}
+
+/// The anti-bot fields of a form submission.
+pub struct Submission<'a> {
+    pub honeypot: Option<&'a str>,
+    pub nonce: Option<&'a str>,
+    pub proof_of_work: Option<u64>,
+}
+
+/// Tells scripted submissions apart from people filling in the form.
+///
+/// A hidden honeypot field is always checked. When `require_nonce` is set the
+/// form must also carry a nonce from `issue`, signed with the server secret,
+/// that is at least `min_fill_seconds` old, and solve the proof of work the
+/// nonce asks for. Each nonce is good for one accepted submission, see
+/// `spend_nonce`.
+pub struct BotProtection {
+    secret: Secret<String>,
+    require_nonce: bool,
+    min_fill_seconds: i64,
+    max_form_age_seconds: i64,
+    proof_of_work_difficulty: u8,
+}
+
+impl BotProtection {
+    pub fn new(
+        secret: Secret<String>,
+        require_nonce: bool,
+        min_fill_seconds: i64,
+        max_form_age_seconds: i64,
+        proof_of_work_difficulty: u8,
+    ) -> Self {
+        Self {
+            secret,
+            require_nonce,
+            min_fill_seconds,
+            max_form_age_seconds,
+            proof_of_work_difficulty,
+        }
+    }
+
+    pub fn issue(&self) -> Challenge {
+        self.issue_at(chrono::Utc::now().timestamp())
+    }
+
+    pub fn check(&self, submission: &Submission) -> Result<(), BotCheckError> {
+        self.check_at(submission, chrono::Utc::now().timestamp())
+    }
+
+    /// Records the nonce of a submission that passed `check` and was
+    /// accepted. Returns `false` if it had been spent already, i.e. a solved
+    /// form is being replayed.
+    #[tracing::instrument(name = "Spending a form nonce", skip_all)]
+    pub async fn spend_nonce(
+        &self,
+        pool: &PgPool,
+        nonce: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        let nonce = match nonce {
+            Some(nonce) if self.require_nonce => nonce,
+            _ => return Ok(true),
+        };
+        // Past its maximum age the nonce is rejected anyway, so it need not
+        // be kept any longer.
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO used_form_nonces (nonce, expires_at)
+            VALUES ($1, now() + make_interval(secs => $2))
+            ON CONFLICT (nonce) DO NOTHING
+            "#,
+            nonce,
+            self.max_form_age_seconds as f64,
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    fn issue_at(&self, now: i64) -> Challenge {
+        let mut rng = rand::thread_rng();
+        let salt: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(SALT_LENGTH)
+            .collect();
+        // The difficulty is signed along with the rest, so changing the
+        // setting does not invalidate forms that are already open.
+        let payload = format!("{}.{}.{}", now, self.proof_of_work_difficulty, salt);
+        let signature = hex::encode(self.mac(&payload).finalize().into_bytes());
+        Challenge {
+            nonce: format!("{}.{}", payload, signature),
+            proof_of_work_difficulty: self.proof_of_work_difficulty,
+        }
+    }
+
+    fn check_at(&self, submission: &Submission, now: i64) -> Result<(), BotCheckError> {
+        // Browsers leave the field empty as it is hidden from people, while
+        // naive bots fill in every input they find.
+        if matches!(submission.honeypot, Some(h) if !h.trim().is_empty()) {
+            return Err(BotCheckError::HoneypotFilled);
+        }
+        if !self.require_nonce {
+            return Ok(());
+        }
+
+        let nonce = submission.nonce.ok_or(BotCheckError::MissingNonce)?;
+        let (issued_at, difficulty) = self.verify_nonce(nonce)?;
+        let age = now - issued_at;
+        if age < self.min_fill_seconds {
+            return Err(BotCheckError::SubmittedTooFast(age));
+        }
+        if age > self.max_form_age_seconds {
+            return Err(BotCheckError::NonceExpired(age - self.max_form_age_seconds));
+        }
+        if difficulty > 0 {
+            let proof_of_work = submission
+                .proof_of_work
+                .ok_or(BotCheckError::MissingProofOfWork)?;
+            if leading_zero_bits(nonce, proof_of_work) < u32::from(difficulty) {
+                return Err(BotCheckError::InvalidProofOfWork);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns when the nonce was issued and the difficulty it asks for.
+    fn verify_nonce(&self, nonce: &str) -> Result<(i64, u8), BotCheckError> {
+        let (payload, signature) = nonce.rsplit_once('.').ok_or(BotCheckError::InvalidNonce)?;
+        let signature = hex::decode(signature).map_err(|_| BotCheckError::InvalidNonce)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| BotCheckError::InvalidNonce)?;
+
+        let mut parts = payload.splitn(3, '.');
+        let issued_at = parts.next().and_then(|p| p.parse().ok());
+        let difficulty = parts.next().and_then(|p| p.parse().ok());
+        match (issued_at, difficulty) {
+            (Some(issued_at), Some(difficulty)) => Ok((issued_at, difficulty)),
+            _ => Err(BotCheckError::InvalidNonce),
+        }
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length.");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// Deletes spent nonces that have expired since.
+#[tracing::instrument(name = "Deleting expired form nonces", skip_all)]
+pub async fn delete_expired_nonces(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM used_form_nonces WHERE expires_at < now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+fn leading_zero_bits(nonce: &str, proof_of_work: u64) -> u32 {
+    let hash = Sha256::digest(format!("{}:{}", nonce, proof_of_work).as_bytes());
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use crate::bot_protection::{leading_zero_bits, BotCheckError, BotProtection, Submission};
+
+    const NOW: i64 = 1_675_000_000;
+
+    fn protection(proof_of_work_difficulty: u8) -> BotProtection {
+        BotProtection::new(
+            Secret::new("secret".into()),
+            true,
+            3,
+            3600,
+            proof_of_work_difficulty,
+        )
+    }
+
+    fn submission(nonce: &str) -> Submission<'_> {
+        Submission {
+            honeypot: None,
+            nonce: Some(nonce),
+            proof_of_work: None,
+        }
+    }
+
+    #[test]
+    fn a_filled_in_honeypot_is_rejected_even_without_nonces() {
+        let protection = BotProtection::new(Secret::new("secret".into()), false, 3, 3600, 0);
+        let submission = Submission {
+            honeypot: Some("https://spam.example.com"),
+            nonce: None,
+            proof_of_work: None,
+        };
+        assert_eq!(
+            protection.check_at(&submission, NOW),
+            Err(BotCheckError::HoneypotFilled)
+        );
+    }
+
+    #[test]
+    fn nonces_are_not_required_unless_configured() {
+        let protection = BotProtection::new(Secret::new("secret".into()), false, 3, 3600, 0);
+        let submission = Submission {
+            honeypot: Some(""),
+            nonce: None,
+            proof_of_work: None,
+        };
+        assert!(protection.check_at(&submission, NOW).is_ok());
+    }
+
+    #[test]
+    fn a_nonce_submitted_in_time_is_accepted() {
+        let protection = protection(0);
+        let challenge = protection.issue_at(NOW);
+        assert!(protection
+            .check_at(&submission(&challenge.nonce), NOW + 10)
+            .is_ok());
+    }
+
+    #[test]
+    fn a_missing_nonce_is_rejected() {
+        let submission = Submission {
+            honeypot: None,
+            nonce: None,
+            proof_of_work: None,
+        };
+        assert_eq!(
+            protection(0).check_at(&submission, NOW),
+            Err(BotCheckError::MissingNonce)
+        );
+    }
+
+    #[test]
+    fn a_form_submitted_too_fast_is_rejected() {
+        let protection = protection(0);
+        let challenge = protection.issue_at(NOW);
+        assert_eq!(
+            protection.check_at(&submission(&challenge.nonce), NOW + 1),
+            Err(BotCheckError::SubmittedTooFast(1))
+        );
+    }
+
+    #[test]
+    fn an_expired_nonce_is_rejected() {
+        let protection = protection(0);
+        let challenge = protection.issue_at(NOW);
+        assert_eq!(
+            protection.check_at(&submission(&challenge.nonce), NOW + 3700),
+            Err(BotCheckError::NonceExpired(100))
+        );
+    }
+
+    #[test]
+    fn a_tampered_or_foreign_nonce_is_rejected() {
+        let protection = protection(0);
+        let challenge = protection.issue_at(NOW);
+        let backdated = challenge
+            .nonce
+            .replacen(&NOW.to_string(), &(NOW - 60).to_string(), 1);
+        let foreign = BotProtection::new(Secret::new("other".into()), true, 3, 3600, 0)
+            .issue_at(NOW)
+            .nonce;
+        for nonce in [backdated.as_str(), foreign.as_str(), "garbage"] {
+            assert_eq!(
+                protection.check_at(&submission(nonce), NOW + 10),
+                Err(BotCheckError::InvalidNonce)
+            );
+        }
+    }
+
+    #[test]
+    fn a_solved_proof_of_work_is_accepted() {
+        let protection = protection(8);
+        let challenge = protection.issue_at(NOW);
+        let proof_of_work = (0..)
+            .find(|n| leading_zero_bits(&challenge.nonce, *n) >= 8)
+            .unwrap();
+        let submission = Submission {
+            honeypot: None,
+            nonce: Some(&challenge.nonce),
+            proof_of_work: Some(proof_of_work),
+        };
+        assert!(protection.check_at(&submission, NOW + 10).is_ok());
+    }
+
+    #[test]
+    fn a_missing_or_wrong_proof_of_work_is_rejected() {
+        let protection = protection(8);
+        let challenge = protection.issue_at(NOW);
+        assert_eq!(
+            protection.check_at(&submission(&challenge.nonce), NOW + 10),
+            Err(BotCheckError::MissingProofOfWork)
+        );
+
+        let wrong = (0..)
+            .find(|n| leading_zero_bits(&challenge.nonce, *n) < 8)
+            .unwrap();
+        let submission = Submission {
+            honeypot: None,
+            nonce: Some(&challenge.nonce),
+            proof_of_work: Some(wrong),
+        };
+        assert_eq!(
+            protection.check_at(&submission, NOW + 10),
+            Err(BotCheckError::InvalidProofOfWork)
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::domain::EmailPolicy;
+
+/// What `backfill_canonical_emails` did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BackfillReport {
+    pub updated: u64,
+    /// Rows whose canonical address already belongs to another subscriber.
+    pub conflicts: u64,
+    /// Rows whose stored address no longer parses.
+    pub invalid: u64,
+}
+
+/// Brings every stored canonical address in line with `email_policy`, e.g.
+/// rows written before IDN domains were converted to punycode or before a
+/// canonicalization rule was enabled. Runs on startup and only writes the rows
+/// whose canonical address changed.
+///
+/// Subscribers that would end up sharing an address are left as they are and
+/// reported, for an admin to merge: deleting either could lose a
+/// confirmation, an enrollment or a queued delivery.
+#[tracing::instrument(name = "Backfilling canonical emails", skip_all)]
+pub async fn backfill_canonical_emails(
+    pool: &PgPool,
+    email_policy: &EmailPolicy,
+) -> Result<BackfillReport, anyhow::Error> {
+    let subscriptions = sqlx::query!(r#"SELECT id, email, canonical_email FROM subscriptions"#)
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch the subscriber emails.")?;
+    let mut report = BackfillReport::default();
+    for subscription in subscriptions {
+        let canonical_email = match email_policy.parse(subscription.email) {
+            Ok(email) => email.canonical().to_string(),
+            Err(e) => {
+                tracing::warn!(subscriber_id = %subscription.id, error.message = %e, "Skipping an invalid stored email.");
+                report.invalid += 1;
+                continue;
+            }
+        };
+        if canonical_email == subscription.canonical_email {
+            continue;
+        }
+        let updated = sqlx::query!(
+            r#"UPDATE subscriptions SET canonical_email = $2 WHERE id = $1"#,
+            subscription.id,
+            canonical_email
+        )
+        .execute(pool)
+        .await;
+        match updated {
+            Ok(_) => report.updated += 1,
+            Err(e) if e.as_database_error().and_then(|e| e.code()).as_deref() == Some("23505") => {
+                tracing::warn!(
+                    subscriber_id = %subscription.id,
+                    "Another subscriber already has this canonical email, leaving it for an admin to merge."
+                );
+                report.conflicts += 1;
+            }
+            Err(e) => return Err(e).context("Failed to update a canonical email."),
+        }
+    }
+    tracing::info!(
+        updated = report.updated,
+        conflicts = report.conflicts,
+        invalid = report.invalid,
+        "Backfilled canonical emails."
+    );
+    Ok(report)
+}
@@ -1,15 +1,49 @@
-use crate::domain::SubscriberEmail;
+use crate::authentication::Credentials;
+use crate::bot_protection::BotProtection;
+use crate::domain::{
+    CanonicalizationRule, EmailPolicy, EmailValidationError, NamePolicy, SubscriberEmail,
+};
+use crate::email_client::EmailClient;
+use crate::email_templates::{EmailTemplates, ListDetails};
+use crate::merge_tags::Personalizer;
+use crate::pages::{Pages, RedirectAllowlist};
+use crate::rate_limit::{SubscriptionRateLimits, TokenBucket};
+use crate::rss_to_email::HttpFeedFetcher;
+use actix_web::http::header;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::postgres::PgSslMode;
 use sqlx::ConnectOptions;
+use std::collections::HashMap;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub email_validation: EmailValidationSettings,
+    pub name_validation: NameValidationSettings,
+    pub domain_check: DomainCheckSettings,
+    pub bot_protection: BotProtectionSettings,
+    pub pages: PagesSettings,
+    pub email_templates: EmailTemplatesSettings,
+    pub merge_tags: MergeTagsSettings,
+    pub cors: CorsSettings,
+    pub pending_subscriptions: PendingSubscriptionsSettings,
+    pub delivery_queue: DeliveryQueueSettings,
+    pub sequences: SequencesSettings,
+    pub issues: IssuesSettings,
+    pub rss_to_email: RssToEmailSettings,
+    /// Admin created on startup unless a user of that name exists, set
+    /// through `APP_INITIAL_ADMIN__USERNAME` and `APP_INITIAL_ADMIN__PASSWORD`.
+    pub initial_admin: Option<InitialAdminSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct InitialAdminSettings {
+    pub username: String,
+    pub password: Secret<String>,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -20,12 +54,156 @@ pub struct EmailClientSettings {
     pub timeout_milliseconds: u64,
 }
 
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailValidationSettings {
+    pub role_addresses: Vec<String>,
+    pub disposable_domains_path: Option<String>,
+    /// Changing these does not update the canonical form of stored addresses.
+    #[serde(default)]
+    pub canonicalization_rules: Vec<CanonicalizationRule>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct NameValidationSettings {
+    pub forbidden_characters: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DomainCheckSettings {
+    pub enabled: bool,
+    pub cache_ttl_seconds: u64,
+    pub cache_capacity: usize,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct BotProtectionSettings {
+    /// Not in `base.yaml`: production reads it from
+    /// `APP_BOT_PROTECTION__FORM_SECRET` and refuses to start without it.
+    pub form_secret: Secret<String>,
+    pub require_form_nonce: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_seconds: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_form_age_seconds: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub proof_of_work_difficulty: u8,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PagesSettings {
+    pub templates_path: String,
+    pub theme: HashMap<String, String>,
+    pub allowed_redirect_origins: Vec<String>,
+}
+
+/// Where the default email templates are and what they say about the list.
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailTemplatesSettings {
+    pub templates_path: String,
+    pub list: ListDetails,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct MergeTagsSettings {
+    /// What merge tags, e.g. `subscriber.attributes.company`, show for
+    /// subscribers without a value, unless the tag sets its own default.
+    pub fallbacks: HashMap<String, String>,
+}
+
+/// When to remind subscribers who did not confirm and when to give up on them.
+#[derive(serde::Deserialize, Clone)]
+pub struct PendingSubscriptionsSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub reminder_delay_hours: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retention_days: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub check_interval_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DeliveryQueueSettings {
+    /// How long the worker waits before polling an empty queue again.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub idle_interval_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_retries: i32,
+    /// The wait before the first retry, doubled for every retry after it.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_backoff_seconds: i64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SequencesSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub check_interval_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct IssuesSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub check_interval_seconds: u64,
+    /// Internal addresses that test sends of an issue go to.
+    pub seed_addresses: Vec<String>,
+    /// How many issues a page of the public archive lists.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub archive_page_size: i64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RssToEmailSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub check_interval_seconds: u64,
+    /// How often feeds that bundle their items send a digest.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub digest_interval_days: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub fetch_timeout_milliseconds: u64,
+    /// Feeds with a larger body fail to fetch.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_feed_bytes: usize,
+    /// Lets feeds be fetched from private, loopback and link-local
+    /// addresses, e.g. when testing against a local server.
+    #[serde(default)]
+    pub allow_private_addresses: bool,
+}
+
+/// Sites allowed to call the subscription API from the browser and to embed
+/// the signup widget.
+#[derive(serde::Deserialize, Clone)]
+pub struct CorsSettings {
+    pub allowed_origins: Vec<String>,
+}
+
 #[derive(serde::Deserialize, Clone)]
 pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub confirmation_link_ttl_hours: i64,
+    /// Confirm on the first `GET` of the link, as link scanners will too.
+    pub one_click_confirmation: bool,
+    pub rate_limits: RateLimitsSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitsSettings {
+    pub per_ip: RateLimitSettings,
+    pub per_email: RateLimitSettings,
+    /// Addresses of the proxies in front of the app, whose `X-Forwarded-For`
+    /// is trusted.
+    #[serde(default)]
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub capacity: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refill_per_hour: u32,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -63,6 +241,18 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         )
         .build()?;
 
+    // Without this check a missing secret is only reported as a missing
+    // `form_secret` field, which does not say how to provide it.
+    let form_secret = settings
+        .get_string("bot_protection.form_secret")
+        .unwrap_or_default();
+    if form_secret.is_empty() {
+        return Err(config::ConfigError::Message(
+            "The form secret is not set, provide it through APP_BOT_PROTECTION__FORM_SECRET."
+                .into(),
+        ));
+    }
+
     settings.try_deserialize::<Settings>()
 }
 
@@ -118,11 +308,198 @@ impl DatabaseSettings {
 }
 
 impl EmailClientSettings {
-    pub fn sender(&self) -> Result<SubscriberEmail, String> {
+    pub fn sender(&self) -> Result<SubscriberEmail, EmailValidationError> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
 
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
+
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email address.");
+        let timeout = self.timeout();
+        EmailClient::new(
+            self.base_url,
+            sender_email,
+            self.authorization_token,
+            timeout,
+        )
+    }
+}
+
+impl DeliveryQueueSettings {
+    pub fn idle_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.idle_interval_seconds)
+    }
+
+    pub fn retry_backoff(&self, n_retries: i16) -> chrono::Duration {
+        chrono::Duration::seconds(self.retry_backoff_seconds << n_retries.clamp(0, 16))
+    }
+}
+
+impl SequencesSettings {
+    pub fn check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.check_interval_seconds)
+    }
+}
+
+impl IssuesSettings {
+    pub fn check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.check_interval_seconds)
+    }
+
+    pub fn seed_addresses(&self) -> Result<Vec<SubscriberEmail>, EmailValidationError> {
+        self.seed_addresses
+            .iter()
+            .map(|address| SubscriberEmail::parse(address.clone()))
+            .collect()
+    }
+}
+
+impl RssToEmailSettings {
+    pub fn check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.check_interval_seconds)
+    }
+
+    pub fn digest_interval(&self) -> chrono::Duration {
+        chrono::Duration::days(self.digest_interval_days)
+    }
+
+    pub fn fetch_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.fetch_timeout_milliseconds)
+    }
+
+    pub fn fetcher(&self) -> HttpFeedFetcher {
+        HttpFeedFetcher::new(
+            self.fetch_timeout(),
+            self.max_feed_bytes,
+            self.allow_private_addresses,
+        )
+    }
+}
+
+impl PendingSubscriptionsSettings {
+    pub fn reminder_delay(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.reminder_delay_hours)
+    }
+
+    pub fn retention(&self) -> chrono::Duration {
+        chrono::Duration::days(self.retention_days)
+    }
+
+    pub fn check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.check_interval_seconds)
+    }
+}
+
+impl EmailValidationSettings {
+    pub fn policy(&self) -> Result<EmailPolicy, std::io::Error> {
+        let disposable_domains = match &self.disposable_domains_path {
+            Some(path) => EmailPolicy::parse_domain_list(&std::fs::read_to_string(path)?),
+            None => vec![],
+        };
+        Ok(
+            EmailPolicy::new(self.role_addresses.clone(), disposable_domains)
+                .with_canonicalization_rules(self.canonicalization_rules.clone()),
+        )
+    }
+}
+
+impl NameValidationSettings {
+    pub fn policy(&self) -> NamePolicy {
+        NamePolicy::new(&self.forbidden_characters)
+    }
+}
+
+impl DomainCheckSettings {
+    pub fn cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_ttl_seconds)
+    }
+}
+
+impl InitialAdminSettings {
+    pub fn credentials(&self) -> Credentials {
+        Credentials {
+            username: self.username.clone(),
+            password: self.password.clone(),
+        }
+    }
+}
+
+impl BotProtectionSettings {
+    pub fn protection(&self) -> BotProtection {
+        BotProtection::new(
+            self.form_secret.clone(),
+            self.require_form_nonce,
+            self.min_fill_seconds,
+            self.max_form_age_seconds,
+            self.proof_of_work_difficulty,
+        )
+    }
+}
+
+impl ApplicationSettings {
+    pub fn confirmation_link_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.confirmation_link_ttl_hours)
+    }
+}
+
+impl PagesSettings {
+    pub fn pages(&self) -> Result<Pages, tera::Error> {
+        Pages::load(&self.templates_path, self.theme.clone())
+    }
+
+    pub fn redirect_allowlist(&self) -> RedirectAllowlist {
+        RedirectAllowlist::new(&self.allowed_redirect_origins)
+    }
+}
+
+impl EmailTemplatesSettings {
+    pub fn templates(&self) -> Result<EmailTemplates, anyhow::Error> {
+        EmailTemplates::load(&self.templates_path, self.list.clone())
+    }
+}
+
+impl MergeTagsSettings {
+    pub fn personalizer(&self, base_url: &str) -> Personalizer {
+        Personalizer::new(base_url, self.fallbacks.clone())
+    }
+}
+
+impl CorsSettings {
+    pub fn cors(&self) -> actix_cors::Cors {
+        self.allowed_origins.iter().fold(
+            actix_cors::Cors::default()
+                .allowed_methods(["GET", "POST"])
+                .allowed_headers([header::ACCEPT, header::CONTENT_TYPE])
+                .expose_headers([header::RETRY_AFTER])
+                .max_age(3600),
+            |cors, origin| cors.allowed_origin(origin),
+        )
+    }
+
+    /// `Content-Security-Policy` keeping the widget from being framed by
+    /// sites that are not allowed.
+    pub fn frame_ancestors(&self) -> String {
+        let mut sources = vec!["'self'"];
+        sources.extend(self.allowed_origins.iter().map(String::as_str));
+        format!("frame-ancestors {}", sources.join(" "))
+    }
+}
+
+impl RateLimitsSettings {
+    pub fn subscription_rate_limits(&self) -> SubscriptionRateLimits {
+        SubscriptionRateLimits {
+            per_ip: self.per_ip.bucket(),
+            per_email: self.per_email.bucket(),
+            trusted_proxies: self.trusted_proxies.clone(),
+        }
+    }
+}
+
+impl RateLimitSettings {
+    pub fn bucket(&self) -> TokenBucket {
+        TokenBucket::new(self.capacity, self.refill_per_hour)
+    }
 }
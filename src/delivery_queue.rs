@@ -0,0 +1,260 @@
+use crate::{
+    configuration::{DeliveryQueueSettings, Settings},
+    domain::SubscriberEmail,
+    email_client::EmailClient,
+    email_rendering::{clipping_warning, EmailRenderer},
+    email_templates::{EmailTemplates, Recipient, RenderedEmail},
+    merge_tags::{Format, Personalizer, Subscriber},
+    startup::get_connection_pool,
+};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// An email waiting to be sent to one subscriber.
+pub struct Delivery<'a> {
+    pub subscriber_id: Uuid,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+}
+
+/// Queues `delivery`, to be sent once `tx` commits.
+#[tracing::instrument(skip_all, fields(subscriber_id = %delivery.subscriber_id))]
+pub async fn enqueue_delivery(
+    tx: &mut Transaction<'_, Postgres>,
+    delivery: &Delivery<'_>,
+) -> Result<Uuid, sqlx::Error> {
+    let delivery_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO delivery_queue (id, subscriber_id, subject, html_content, text_content)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        delivery_id,
+        delivery.subscriber_id,
+        delivery.subject,
+        delivery.html_content,
+        delivery.text_content
+    )
+    .execute(tx)
+    .await?;
+    Ok(delivery_id)
+}
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+    let email_client = configuration.email_client.client();
+    let personalizer = configuration
+        .merge_tags
+        .personalizer(&configuration.application.base_url);
+    let renderer = EmailRenderer::new(&configuration.application.base_url)?;
+    let email_templates = configuration.email_templates.templates()?;
+    worker_loop(
+        connection_pool,
+        email_client,
+        personalizer,
+        renderer,
+        email_templates,
+        configuration.delivery_queue,
+    )
+    .await
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    email_client: EmailClient,
+    personalizer: Personalizer,
+    renderer: EmailRenderer,
+    email_templates: EmailTemplates,
+    settings: DeliveryQueueSettings,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(
+            &pool,
+            &email_client,
+            &personalizer,
+            &renderer,
+            &email_templates,
+            &settings,
+        )
+        .await
+        {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(settings.idle_interval()).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// Sends the oldest due delivery, with its merge tags filled in for the
+/// subscriber and its HTML body rendered for email clients. Issues also get
+/// an open tracking pixel, and are sent in the `issue` template so they carry
+/// the layout's footer.
+///
+/// Deliveries to subscribers who are no longer confirmed are dropped, and a
+/// failed send is retried with exponential backoff up to `max_retries` times.
+#[tracing::instrument(
+    skip_all,
+    fields(delivery_id = tracing::field::Empty, subscriber_id = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    personalizer: &Personalizer,
+    renderer: &EmailRenderer,
+    email_templates: &EmailTemplates,
+    settings: &DeliveryQueueSettings,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let (mut tx, task) = match dequeue_task(pool).await? {
+        Some(task) => task,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    let span = tracing::Span::current();
+    span.record("delivery_id", tracing::field::display(task.id));
+    span.record("subscriber_id", tracing::field::display(task.subscriber_id));
+
+    if task.status != "confirmed" {
+        tracing::info!("Dropping a delivery to a subscriber who is no longer confirmed.");
+        delete_task(&mut tx, task.id).await?;
+        tx.commit().await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+
+    let subscriber = Subscriber {
+        name: task.name,
+        email: task.email.clone(),
+        attributes: task.attributes,
+        manage_token: task.manage_token,
+    };
+    let mut content = RenderedEmail {
+        subject: personalizer.personalize(&task.subject, &subscriber, Format::Text),
+        html: renderer.render(&personalizer.personalize(
+            &task.html_content,
+            &subscriber,
+            Format::Html,
+        )),
+        text: personalizer.personalize(&task.text_content, &subscriber, Format::Text),
+    };
+    if let Some(newsletter_issue_id) = task.newsletter_issue_id {
+        content.html =
+            renderer.track_opens(&content.html, newsletter_issue_id, &subscriber.manage_token);
+        let recipient = Recipient {
+            name: subscriber.name.clone(),
+            email: subscriber.email.clone(),
+        };
+        let unsubscribe_url = personalizer.unsubscribe_url(&subscriber);
+        content = email_templates
+            .render_issue(pool, &recipient, unsubscribe_url.as_deref(), content)
+            .await?;
+    }
+    if let Some(warning) = clipping_warning(&content.html) {
+        tracing::warn!("{}", warning);
+    }
+    let sent = match SubscriberEmail::parse(task.email) {
+        Ok(email) => email_client
+            .send_email(email, &content.subject, &content.html, &content.text)
+            .await
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(anyhow::Error::from(e).context("Skipping an invalid stored email.")),
+    };
+    match sent {
+        Ok(()) => delete_task(&mut tx, task.id).await?,
+        Err(e) if i32::from(task.n_retries) < settings.max_retries => {
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to deliver an email, will retry."
+            );
+            let backoff = settings.retry_backoff(task.n_retries);
+            postpone_task(&mut tx, task.id, backoff).await?;
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to deliver an email, giving up."
+            );
+            delete_task(&mut tx, task.id).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+struct Task {
+    id: Uuid,
+    subscriber_id: Uuid,
+    email: String,
+    name: String,
+    attributes: serde_json::Value,
+    manage_token: String,
+    status: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    n_retries: i16,
+    newsletter_issue_id: Option<Uuid>,
+}
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(pool: &PgPool) -> Result<Option<(PgTransaction, Task)>, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let task = sqlx::query_as!(
+        Task,
+        r#"
+        SELECT q.id, q.subscriber_id, s.email, s.name, s.attributes, s.manage_token,
+            s.status, q.subject, q.html_content, q.text_content, q.n_retries,
+            q.newsletter_issue_id
+        FROM delivery_queue q
+        JOIN subscriptions s ON s.id = q.subscriber_id
+        WHERE q.execute_after <= now()
+        ORDER BY q.execute_after
+        FOR UPDATE OF q
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    Ok(task.map(|task| (tx, task)))
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(tx: &mut PgTransaction, id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!(r#"DELETE FROM delivery_queue WHERE id = $1"#, id)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn postpone_task(
+    tx: &mut PgTransaction,
+    id: Uuid,
+    backoff: chrono::Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE delivery_queue
+        SET n_retries = n_retries + 1, execute_after = $2
+        WHERE id = $1
+        "#,
+        id,
+        chrono::Utc::now() + backoff
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
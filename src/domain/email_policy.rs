@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+
+use super::{CanonicalizationRule, EmailValidationError, SubscriberEmail};
+
+/// Rules on top of the syntax check that decide which addresses may subscribe,
+/// and which addresses count as the same one.
+#[derive(Debug, Default)]
+pub struct EmailPolicy {
+    role_addresses: HashSet<String>,
+    disposable_domains: HashSet<String>,
+    canonicalization_rules: Vec<CanonicalizationRule>,
+}
+
+impl EmailPolicy {
+    pub fn new(role_addresses: Vec<String>, disposable_domains: Vec<String>) -> Self {
+        Self {
+            role_addresses: role_addresses
+                .into_iter()
+                .map(|r| r.to_lowercase())
+                .collect(),
+            disposable_domains: disposable_domains
+                .into_iter()
+                .map(|d| d.to_lowercase())
+                .collect(),
+            canonicalization_rules: vec![],
+        }
+    }
+
+    pub fn with_canonicalization_rules(mut self, rules: Vec<CanonicalizationRule>) -> Self {
+        self.canonicalization_rules = rules;
+        self
+    }
+
+    /// Parses an address typed by a reader into the canonical form used to
+    /// tell subscribers apart.
+    pub fn parse(&self, s: String) -> Result<SubscriberEmail, EmailValidationError> {
+        SubscriberEmail::parse_with_rules(s, &self.canonicalization_rules)
+    }
+
+    /// Parses a blocklist file with one domain per line. Blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn parse_domain_list(contents: &str) -> Vec<String> {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect()
+    }
+
+    pub fn check(&self, email: &SubscriberEmail) -> Result<(), EmailValidationError> {
+        let (local_part, domain) = email
+            .canonical()
+            .rsplit_once('@')
+            .ok_or(EmailValidationError::MissingAtSign)?;
+
+        let mailbox = local_part.split('+').next().unwrap_or(local_part);
+        if self.role_addresses.contains(mailbox) {
+            return Err(EmailValidationError::RoleAddress(mailbox.to_string()));
+        }
+
+        // Subdomains of a blocked domain are blocked as well.
+        let mut candidate = domain;
+        loop {
+            if self.disposable_domains.contains(candidate) {
+                return Err(EmailValidationError::DisposableDomain(
+                    candidate.to_string(),
+                ));
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{EmailPolicy, EmailValidationError, SubscriberEmail};
+
+    fn policy() -> EmailPolicy {
+        EmailPolicy::new(
+            vec!["postmaster".into(), "abuse".into()],
+            vec!["mailinator.com".into()],
+        )
+    }
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn personal_address_is_accepted() {
+        assert!(policy().check(&email("ursula@example.com")).is_ok());
+    }
+
+    #[test]
+    fn role_address_is_rejected_regardless_of_case_and_tag() {
+        for address in ["postmaster@example.com", "Abuse+spam@example.com"] {
+            assert!(matches!(
+                policy().check(&email(address)),
+                Err(EmailValidationError::RoleAddress(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn disposable_domain_and_its_subdomains_are_rejected() {
+        for address in ["ursula@mailinator.com", "ursula@eu.Mailinator.com"] {
+            assert_eq!(
+                policy().check(&email(address)).unwrap_err(),
+                EmailValidationError::DisposableDomain("mailinator.com".into())
+            );
+        }
+    }
+
+    #[test]
+    fn default_policy_accepts_everything() {
+        assert!(EmailPolicy::default()
+            .check(&email("postmaster@mailinator.com"))
+            .is_ok());
+    }
+
+    #[test]
+    fn domain_list_skips_comments_and_blank_lines() {
+        let contents = "# disposable\nmailinator.com\n\n  yopmail.com  \n";
+        assert_eq!(
+            EmailPolicy::parse_domain_list(contents),
+            vec!["mailinator.com", "yopmail.com"]
+        );
+    }
+}
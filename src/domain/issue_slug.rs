@@ -0,0 +1,75 @@
+use unicode_normalization::UnicodeNormalization;
+
+const MAX_LENGTH: usize = 60;
+
+/// The part of an archived issue's URL made from its title, e.g.
+/// `whats-new-in-march`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IssueSlug(String);
+
+impl IssueSlug {
+    /// Keeps the ASCII letters and digits of the title, accents removed, with
+    /// dashes in between words.
+    pub fn from_title(title: &str) -> Self {
+        let mut slug = String::new();
+        for c in title.nfkd() {
+            if c.is_ascii_alphanumeric() {
+                slug.push(c.to_ascii_lowercase());
+            } else if !c.is_alphanumeric() && !slug.is_empty() && !slug.ends_with('-') {
+                // Combining accents are neither, so they are just dropped.
+                if !unicode_normalization::char::is_combining_mark(c) {
+                    slug.push('-');
+                }
+            }
+            if slug.len() >= MAX_LENGTH {
+                break;
+            }
+        }
+        let slug = slug.trim_end_matches('-');
+        if slug.is_empty() {
+            Self("issue".into())
+        } else {
+            Self(slug.into())
+        }
+    }
+
+    /// Tells apart issues with the same title, e.g. `weekly-digest-2`.
+    pub fn with_number(&self, number: u32) -> Self {
+        Self(format!("{}-{}", self.0, number))
+    }
+}
+
+impl AsRef<str> for IssueSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::IssueSlug;
+
+    #[test]
+    fn slugs_keep_the_words_of_the_title() {
+        for (title, slug) in [
+            ("What's new in March?", "what-s-new-in-march"),
+            ("  Café & Crème brûlée  ", "cafe-creme-brulee"),
+            ("Issue #12: the end", "issue-12-the-end"),
+            ("日本語", "issue"),
+            ("", "issue"),
+        ] {
+            assert_eq!(IssueSlug::from_title(title).as_ref(), slug, "{}", title);
+        }
+    }
+
+    #[test]
+    fn long_titles_are_cut_short() {
+        let slug = IssueSlug::from_title(&"word ".repeat(30));
+        assert!(slug.as_ref().len() <= 60);
+        assert!(!slug.as_ref().ends_with('-'));
+        assert_eq!(
+            IssueSlug::from_title("Digest").with_number(2).as_ref(),
+            "digest-2"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+/// Where a newsletter issue is in its way from draft to inboxes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueStatus {
+    Draft,
+    InReview,
+    Approved,
+    Scheduled,
+    Sending,
+    Sent,
+}
+
+/// What an editor can do to an issue. `Sending` and `Sent` are only reached
+/// through the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueAction {
+    Submit,
+    Approve,
+    Reject,
+    Schedule,
+    Unschedule,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("An issue that is {} cannot be {}.", .from.as_str().replace('_', " "), .action.past_tense())]
+pub struct InvalidTransition {
+    pub from: IssueStatus,
+    pub action: IssueAction,
+}
+
+impl IssueStatus {
+    pub fn parse(s: &str) -> Result<IssueStatus, String> {
+        match s {
+            "draft" => Ok(Self::Draft),
+            "in_review" => Ok(Self::InReview),
+            "approved" => Ok(Self::Approved),
+            "scheduled" => Ok(Self::Scheduled),
+            "sending" => Ok(Self::Sending),
+            "sent" => Ok(Self::Sent),
+            other => Err(format!("{} is not a valid issue status.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Draft => "draft",
+            Self::InReview => "in_review",
+            Self::Approved => "approved",
+            Self::Scheduled => "scheduled",
+            Self::Sending => "sending",
+            Self::Sent => "sent",
+        }
+    }
+
+    /// Only drafts can be edited, so what was reviewed is what gets sent.
+    pub fn is_editable(&self) -> bool {
+        *self == Self::Draft
+    }
+
+    pub fn apply(self, action: IssueAction) -> Result<IssueStatus, InvalidTransition> {
+        match (self, action) {
+            (Self::Draft, IssueAction::Submit) => Ok(Self::InReview),
+            (Self::InReview, IssueAction::Approve) => Ok(Self::Approved),
+            (Self::InReview | Self::Approved, IssueAction::Reject) => Ok(Self::Draft),
+            (Self::Approved, IssueAction::Schedule) => Ok(Self::Scheduled),
+            (Self::Scheduled, IssueAction::Unschedule) => Ok(Self::Approved),
+            (from, action) => Err(InvalidTransition { from, action }),
+        }
+    }
+}
+
+impl IssueAction {
+    pub fn parse(s: &str) -> Option<IssueAction> {
+        match s {
+            "submit" => Some(Self::Submit),
+            "approve" => Some(Self::Approve),
+            "reject" => Some(Self::Reject),
+            "schedule" => Some(Self::Schedule),
+            "unschedule" => Some(Self::Unschedule),
+            _ => None,
+        }
+    }
+
+    fn past_tense(&self) -> &'static str {
+        match self {
+            Self::Submit => "submitted for review",
+            Self::Approve => "approved",
+            Self::Reject => "sent back to draft",
+            Self::Schedule => "scheduled",
+            Self::Unschedule => "unscheduled",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{IssueAction, IssueStatus};
+
+    #[test]
+    fn an_issue_goes_from_draft_to_scheduled_through_review() {
+        let status = IssueStatus::Draft
+            .apply(IssueAction::Submit)
+            .and_then(|s| s.apply(IssueAction::Approve))
+            .and_then(|s| s.apply(IssueAction::Schedule));
+        assert_eq!(status, Ok(IssueStatus::Scheduled));
+    }
+
+    #[test]
+    fn review_cannot_be_skipped() {
+        for action in [IssueAction::Approve, IssueAction::Schedule] {
+            assert!(IssueStatus::Draft.apply(action).is_err(), "{:?}", action);
+        }
+    }
+
+    #[test]
+    fn issues_being_sent_cannot_be_changed() {
+        for status in [IssueStatus::Sending, IssueStatus::Sent] {
+            for action in [
+                IssueAction::Submit,
+                IssueAction::Approve,
+                IssueAction::Reject,
+                IssueAction::Schedule,
+                IssueAction::Unschedule,
+            ] {
+                assert!(status.apply(action).is_err());
+            }
+            assert!(!status.is_editable());
+        }
+    }
+
+    #[test]
+    fn errors_describe_the_refused_transition() {
+        let error = IssueStatus::Scheduled
+            .apply(IssueAction::Submit)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "An issue that is scheduled cannot be submitted for review."
+        );
+    }
+
+    #[test]
+    fn statuses_round_trip_through_their_names() {
+        for status in [
+            IssueStatus::Draft,
+            IssueStatus::InReview,
+            IssueStatus::Approved,
+            IssueStatus::Scheduled,
+            IssueStatus::Sending,
+            IssueStatus::Sent,
+        ] {
+            assert_eq!(IssueStatus::parse(status.as_str()), Ok(status));
+        }
+    }
+}
@@ -1,9 +1,23 @@
+mod email_policy;
+mod issue_slug;
+mod issue_status;
 mod new_subscriber;
+mod segment_filter;
+mod subscriber_attributes;
 mod subscriber_email;
 mod subscriber_name;
 mod subscription_token;
+mod tag;
 
+pub use email_policy::EmailPolicy;
+pub use issue_slug::IssueSlug;
+pub use issue_status::{InvalidTransition, IssueAction, IssueStatus};
 pub use new_subscriber::NewSubscriber;
-pub use subscriber_email::SubscriberEmail;
-pub use subscriber_name::SubscriberName;
+pub use segment_filter::{Clause, Comparison, Predicate, SegmentFilter, SegmentFilterError};
+pub use subscriber_attributes::{
+    AttributeError, AttributeSchema, Attributes, FieldDefinition, FieldDefinitionError, FieldType,
+};
+pub use subscriber_email::{CanonicalizationRule, EmailValidationError, SubscriberEmail};
+pub use subscriber_name::{NamePolicy, NameValidationError, SubscriberName};
 pub use subscription_token::SubscriptionToken;
+pub use tag::{InvalidTag, Tag};
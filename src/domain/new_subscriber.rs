@@ -1,7 +1,9 @@
+use super::Attributes;
 use super::SubscriberEmail;
 use super::SubscriberName;
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub attributes: Attributes,
 }
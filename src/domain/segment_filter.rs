@@ -0,0 +1,529 @@
+use serde_json::Value;
+
+use crate::domain::{AttributeSchema, FieldType, Tag};
+
+const STATUSES: [&str; 4] = [
+    "pending_confirmation",
+    "confirmed",
+    "unsubscribed",
+    "suppressed",
+];
+const NUMBER_WORDS: [&str; 10] = [
+    "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+];
+/// Keeps `subscribed in last N days` and `of last N issues` within sane bounds.
+const MAX_COUNT: i32 = 36500;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SegmentFilterError {
+    #[error("The filter is empty.")]
+    Empty,
+    #[error("In \"{clause}\": {reason}.")]
+    Invalid { clause: String, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Status(String),
+    Tag(Tag),
+    Attribute {
+        name: String,
+        comparison: Comparison,
+        value: Value,
+    },
+    /// `subscribed in last N days`
+    SubscribedWithinDays(i32),
+    /// `subscribed more than N days ago`
+    SubscribedBeforeDays(i32),
+    /// `opened at least N of last M issues`
+    OpenedAtLeast {
+        count: i32,
+        of_last: i32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clause {
+    pub negated: bool,
+    pub predicate: Predicate,
+}
+
+/// A parsed and type-checked segment filter: clauses separated by commas or
+/// `and`, all of which must hold. For example
+/// `confirmed, tag=beta, subscribed in last 90 days, opened at least one of last 5 issues`.
+///
+/// Any clause can be negated with a leading `not`; `status`, `tag` and
+/// attribute equality can also use `!=`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentFilter {
+    clauses: Vec<Clause>,
+}
+
+impl SegmentFilter {
+    pub fn parse(s: &str, schema: &AttributeSchema) -> Result<SegmentFilter, SegmentFilterError> {
+        let tokens = tokenize(s)?;
+        let mut clauses = Vec::new();
+        let mut start = 0;
+        for end in 0..=tokens.len() {
+            let at_separator = match tokens.get(end) {
+                None => true,
+                Some(token) => token.kind == TokenKind::Comma || token.is_word("and"),
+            };
+            if at_separator {
+                let clause_tokens = &tokens[start..end];
+                if clause_tokens.is_empty() {
+                    if end < tokens.len() || !clauses.is_empty() {
+                        return Err(invalid(s, "a condition is missing"));
+                    }
+                } else {
+                    let text = &s[clause_tokens[0].start..clause_tokens.last().unwrap().end];
+                    clauses.push(ClauseParser::new(text, clause_tokens, schema).parse()?);
+                }
+                start = end + 1;
+            }
+        }
+        if clauses.is_empty() {
+            return Err(SegmentFilterError::Empty);
+        }
+        Ok(Self { clauses })
+    }
+
+    pub fn clauses(&self) -> &[Clause] {
+        &self.clauses
+    }
+}
+
+fn invalid(clause: &str, reason: &str) -> SegmentFilterError {
+    SegmentFilterError::Invalid {
+        clause: clause.trim().to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Quoted(String),
+    Operator {
+        comparison: Comparison,
+        negated: bool,
+    },
+    Comma,
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+impl Token {
+    fn is_word(&self, word: &str) -> bool {
+        matches!(&self.kind, TokenKind::Word(w) if w.eq_ignore_ascii_case(word))
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, SegmentFilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            ',' => TokenKind::Comma,
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => value.push(c),
+                        None => return Err(invalid(&s[start..], "the quote is never closed")),
+                    }
+                }
+                TokenKind::Quoted(value)
+            }
+            '=' | '!' | '<' | '>' => {
+                let or_equal = chars.next_if(|(_, c)| *c == '=').is_some();
+                let (comparison, negated) = match (c, or_equal) {
+                    ('=', false) => (Comparison::Equal, false),
+                    ('!', true) => (Comparison::Equal, true),
+                    ('<', false) => (Comparison::Less, false),
+                    ('<', true) => (Comparison::LessOrEqual, false),
+                    ('>', false) => (Comparison::Greater, false),
+                    ('>', true) => (Comparison::GreaterOrEqual, false),
+                    _ => return Err(invalid(&s[start..], "`!` must be followed by `=`")),
+                };
+                TokenKind::Operator {
+                    comparison,
+                    negated,
+                }
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+                    word.push(c);
+                }
+                TokenKind::Word(word)
+            }
+        };
+        let end = chars.peek().map(|(i, _)| *i).unwrap_or(s.len());
+        tokens.push(Token { kind, start, end });
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, ',' | '"' | '=' | '!' | '<' | '>')
+}
+
+struct ClauseParser<'a> {
+    text: &'a str,
+    tokens: &'a [Token],
+    position: usize,
+    schema: &'a AttributeSchema,
+}
+
+impl<'a> ClauseParser<'a> {
+    fn new(text: &'a str, tokens: &'a [Token], schema: &'a AttributeSchema) -> Self {
+        Self {
+            text,
+            tokens,
+            position: 0,
+            schema,
+        }
+    }
+
+    fn parse(mut self) -> Result<Clause, SegmentFilterError> {
+        let mut negated = self.accept("not");
+        let keyword = self.word()?.to_lowercase();
+        let predicate = match keyword.as_str() {
+            status if STATUSES.contains(&status) => Predicate::Status(keyword),
+            "status" => {
+                negated ^= self.equality()?;
+                let status = self.word()?.to_lowercase();
+                if !STATUSES.contains(&status.as_str()) {
+                    return Err(self.error(&format!(
+                        "the status must be one of {}",
+                        STATUSES.join(", ")
+                    )));
+                }
+                Predicate::Status(status)
+            }
+            "tag" => {
+                negated ^= self.equality()?;
+                let tag = self.value()?;
+                Predicate::Tag(Tag::parse(&tag).map_err(|e| self.error(&e.to_string()))?)
+            }
+            "subscribed" => {
+                if self.accept("in") {
+                    self.accept("the");
+                    self.expect("last")?;
+                    let days = self.count()?;
+                    self.expect_either("day", "days")?;
+                    Predicate::SubscribedWithinDays(days)
+                } else {
+                    self.expect("more")?;
+                    self.expect("than")?;
+                    let days = self.count()?;
+                    self.expect_either("day", "days")?;
+                    self.expect("ago")?;
+                    Predicate::SubscribedBeforeDays(days)
+                }
+            }
+            "opened" => {
+                self.expect("at")?;
+                self.expect("least")?;
+                let count = self.count()?;
+                self.expect("of")?;
+                self.accept("the");
+                self.expect("last")?;
+                let of_last = self.count()?;
+                self.expect_either("issue", "issues")?;
+                if count > of_last {
+                    return Err(self.error("cannot open more issues than there are"));
+                }
+                Predicate::OpenedAtLeast { count, of_last }
+            }
+            _ => match keyword.strip_prefix("attribute.") {
+                Some(name) => self.attribute(name, &mut negated)?,
+                None => return Err(self.error("unknown condition")),
+            },
+        };
+        if self.position < self.tokens.len() {
+            return Err(self.error("unexpected text at the end"));
+        }
+        Ok(Clause { negated, predicate })
+    }
+
+    fn attribute(
+        &mut self,
+        name: &str,
+        negated: &mut bool,
+    ) -> Result<Predicate, SegmentFilterError> {
+        let field = self
+            .schema
+            .field(name)
+            .ok_or_else(|| self.error(&format!("{} is not a known subscriber field", name)))?;
+        let (comparison, not_equal) = match self.next() {
+            Some(TokenKind::Operator {
+                comparison,
+                negated,
+            }) => (*comparison, *negated),
+            _ => return Err(self.error("expected a comparison")),
+        };
+        *negated ^= not_equal;
+        if comparison != Comparison::Equal && !matches!(field.field_type, FieldType::Number { .. })
+        {
+            return Err(self.error(&format!("{} is not a number field", name)));
+        }
+        let value = self.value()?;
+        let value = field
+            .coerce(&Value::String(value))
+            .map_err(|e| self.error(e.to_string().trim_end_matches('.')))?
+            .ok_or_else(|| self.error("expected a value"))?;
+        Ok(Predicate::Attribute {
+            name: name.to_string(),
+            comparison,
+            value,
+        })
+    }
+
+    fn next(&mut self) -> Option<&'a TokenKind> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(&token.kind)
+    }
+
+    fn accept(&mut self, word: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(token) if token.is_word(word) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), SegmentFilterError> {
+        if self.accept(word) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", word)))
+        }
+    }
+
+    fn expect_either(&mut self, singular: &str, plural: &str) -> Result<(), SegmentFilterError> {
+        if self.accept(singular) || self.accept(plural) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", plural)))
+        }
+    }
+
+    fn word(&mut self) -> Result<&'a str, SegmentFilterError> {
+        match self.next() {
+            Some(TokenKind::Word(word)) => Ok(word),
+            _ => Err(self.error("expected a word")),
+        }
+    }
+
+    fn value(&mut self) -> Result<String, SegmentFilterError> {
+        match self.next() {
+            Some(TokenKind::Word(value)) | Some(TokenKind::Quoted(value)) => Ok(value.clone()),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Parses `=` or `!=`, returning whether it was negated.
+    fn equality(&mut self) -> Result<bool, SegmentFilterError> {
+        match self.next() {
+            Some(TokenKind::Operator {
+                comparison: Comparison::Equal,
+                negated,
+            }) => Ok(*negated),
+            _ => Err(self.error("expected `=` or `!=`")),
+        }
+    }
+
+    /// A positive whole number, in digits or spelled out up to ten.
+    fn count(&mut self) -> Result<i32, SegmentFilterError> {
+        let word = self.word()?.to_lowercase();
+        let count = match NUMBER_WORDS.iter().position(|w| *w == word) {
+            Some(i) => Some(i as i32 + 1),
+            None => word.parse::<i32>().ok(),
+        };
+        match count {
+            Some(count) if (1..=MAX_COUNT).contains(&count) => Ok(count),
+            _ => Err(self.error(&format!("expected a number from 1 to {}", MAX_COUNT))),
+        }
+    }
+
+    fn error(&self, reason: &str) -> SegmentFilterError {
+        invalid(self.text, reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::domain::{
+        AttributeSchema, Comparison, FieldDefinition, FieldType, Predicate, SegmentFilter,
+        SegmentFilterError, Tag,
+    };
+
+    fn schema() -> AttributeSchema {
+        let field = |name: &str, field_type| FieldDefinition {
+            name: name.into(),
+            field_type,
+            required: false,
+            signup: false,
+        };
+        AttributeSchema::new(vec![
+            field(
+                "plan",
+                FieldType::Choice {
+                    choices: vec!["free".into(), "pro".into()],
+                },
+            ),
+            field(
+                "seats",
+                FieldType::Number {
+                    min: None,
+                    max: None,
+                },
+            ),
+            field("company", FieldType::Text { max_length: None }),
+        ])
+    }
+
+    fn parse(s: &str) -> Result<Vec<(bool, Predicate)>, SegmentFilterError> {
+        let filter = SegmentFilter::parse(s, &schema())?;
+        Ok(filter
+            .clauses()
+            .iter()
+            .map(|c| (c.negated, c.predicate.clone()))
+            .collect())
+    }
+
+    fn reason(s: &str) -> String {
+        match parse(s) {
+            Err(SegmentFilterError::Invalid { reason, .. }) => reason,
+            other => panic!("{:?} was not rejected: {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn the_example_from_the_docs_is_understood() {
+        let clauses = parse(
+            "confirmed, tag=beta, subscribed in last 90 days, opened at least one of last 5 issues",
+        )
+        .unwrap();
+        assert_eq!(
+            clauses,
+            vec![
+                (false, Predicate::Status("confirmed".into())),
+                (false, Predicate::Tag(Tag::parse("beta").unwrap())),
+                (false, Predicate::SubscribedWithinDays(90)),
+                (
+                    false,
+                    Predicate::OpenedAtLeast {
+                        count: 1,
+                        of_last: 5
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn clauses_can_be_joined_with_and_and_negated() {
+        let clauses = parse("not tag = vip AND status != unsubscribed and tag!=beta").unwrap();
+        assert_eq!(
+            clauses
+                .iter()
+                .map(|(negated, _)| *negated)
+                .collect::<Vec<_>>(),
+            vec![true, true, true]
+        );
+    }
+
+    #[test]
+    fn attribute_values_are_checked_against_the_field_type() {
+        let clauses = parse(r#"attribute.seats >= 10, attribute.company = "Acme, Inc""#).unwrap();
+        assert_eq!(
+            clauses,
+            vec![
+                (
+                    false,
+                    Predicate::Attribute {
+                        name: "seats".into(),
+                        comparison: Comparison::GreaterOrEqual,
+                        value: json!(10)
+                    }
+                ),
+                (
+                    false,
+                    Predicate::Attribute {
+                        name: "company".into(),
+                        comparison: Comparison::Equal,
+                        value: json!("Acme, Inc")
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            reason("attribute.plan = gold"),
+            "plan must be one of free, pro"
+        );
+        assert_eq!(
+            reason("attribute.plan > free"),
+            "plan is not a number field"
+        );
+        assert_eq!(reason("attribute.seats = many"), "seats must be a number");
+        assert_eq!(
+            reason("attribute.colour = blue"),
+            "colour is not a known subscriber field"
+        );
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        assert_eq!(parse("  "), Err(SegmentFilterError::Empty));
+        assert_eq!(reason("confirmed,, tag=beta"), "a condition is missing");
+        assert_eq!(reason("confirmed,"), "a condition is missing");
+        assert_eq!(reason("status = sleeping").split(' ').next(), Some("the"));
+        assert_eq!(
+            reason("opened at least 6 of last 5 issues"),
+            "cannot open more issues than there are"
+        );
+        assert_eq!(
+            reason("subscribed in last 0 days"),
+            "expected a number from 1 to 36500"
+        );
+        assert_eq!(reason("subscribed in last 90"), "expected `days`");
+        assert_eq!(reason("confirmed please"), "unexpected text at the end");
+        assert_eq!(reason("vip"), "unknown condition");
+        assert_eq!(reason(r#"tag = "beta"#), "the quote is never closed");
+    }
+
+    #[test]
+    fn errors_name_the_offending_clause() {
+        assert_eq!(
+            parse("confirmed, tag = not a tag"),
+            Err(SegmentFilterError::Invalid {
+                clause: "tag = not a tag".into(),
+                reason: "unexpected text at the end".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+/// Names the signup form already uses, which custom fields cannot take.
+const RESERVED_NAMES: [&str; 6] = [
+    "email",
+    "name",
+    "website",
+    "form_nonce",
+    "proof_of_work",
+    "redirect_to",
+];
+const MAX_NAME_LENGTH: usize = 64;
+/// Longest value of a text field without a `max_length` of its own, so the
+/// signup form cannot be used to store arbitrary amounts of text.
+const DEFAULT_MAX_TEXT_LENGTH: usize = 1000;
+
+/// Custom attributes of a subscriber, as stored in `subscriptions.attributes`.
+pub type Attributes = Map<String, Value>;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum AttributeError {
+    #[error("{0} is required.")]
+    Missing(String),
+    #[error("{0} is not a known subscriber field.")]
+    Unknown(String),
+    #[error("{name} {reason}.")]
+    Invalid { name: String, reason: String },
+}
+
+impl AttributeError {
+    /// The field the error is about.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Missing(name) | Self::Unknown(name) | Self::Invalid { name, .. } => name,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum FieldDefinitionError {
+    #[error(
+        "Field names are 1 to 64 lowercase letters, digits or underscores, starting with a letter."
+    )]
+    InvalidName,
+    #[error("{0} is used by the signup form and cannot be a field name.")]
+    ReservedName(String),
+    #[error("A choice field needs at least one choice.")]
+    NoChoices,
+    #[error("The minimum is greater than the maximum.")]
+    EmptyRange,
+    #[error("The maximum length must be at least 1.")]
+    InvalidMaxLength,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldType {
+    Text {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_length: Option<i32>,
+    },
+    Number {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<f64>,
+    },
+    Boolean,
+    Choice {
+        choices: Vec<String>,
+    },
+}
+
+/// An admin-defined subscriber field.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct FieldDefinition {
+    pub name: String,
+    #[serde(flatten)]
+    pub field_type: FieldType,
+    /// Whether the signup form must fill it in. Only applies to `signup` fields.
+    #[serde(default)]
+    pub required: bool,
+    /// Whether the signup form may set it.
+    #[serde(default)]
+    pub signup: bool,
+}
+
+impl FieldDefinition {
+    pub fn validate(&self) -> Result<(), FieldDefinitionError> {
+        let mut chars = self.name.chars();
+        let valid_name = matches!(chars.next(), Some(c) if c.is_ascii_lowercase())
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            && self.name.len() <= MAX_NAME_LENGTH;
+        if !valid_name {
+            return Err(FieldDefinitionError::InvalidName);
+        }
+        if RESERVED_NAMES.contains(&self.name.as_str()) {
+            return Err(FieldDefinitionError::ReservedName(self.name.clone()));
+        }
+        match &self.field_type {
+            FieldType::Choice { choices } if choices.is_empty() => {
+                Err(FieldDefinitionError::NoChoices)
+            }
+            FieldType::Number {
+                min: Some(min),
+                max: Some(max),
+            } if min > max => Err(FieldDefinitionError::EmptyRange),
+            FieldType::Text {
+                max_length: Some(max_length),
+            } if *max_length <= 0 => Err(FieldDefinitionError::InvalidMaxLength),
+            _ => Ok(()),
+        }
+    }
+
+    /// Converts `value` to the field's type, accepting the strings HTML forms
+    /// send. Returns `None` for empty values.
+    pub fn coerce(&self, value: &Value) -> Result<Option<Value>, AttributeError> {
+        let invalid = |reason: &str| AttributeError::Invalid {
+            name: self.name.clone(),
+            reason: reason.to_string(),
+        };
+        let value = match value {
+            Value::Null => return Ok(None),
+            Value::String(s) if s.trim().is_empty() => return Ok(None),
+            Value::String(s) => Value::String(s.trim().to_string()),
+            value => value.clone(),
+        };
+        match &self.field_type {
+            FieldType::Text { max_length } => {
+                let s = value.as_str().ok_or_else(|| invalid("must be text"))?;
+                // Definitions saved before non-positive lengths were rejected
+                // fall back to the default too.
+                let max_length = max_length
+                    .and_then(|max_length| usize::try_from(max_length).ok())
+                    .filter(|max_length| *max_length > 0)
+                    .unwrap_or(DEFAULT_MAX_TEXT_LENGTH);
+                if s.chars().count() > max_length {
+                    return Err(invalid(&format!(
+                        "must be at most {} characters long",
+                        max_length
+                    )));
+                }
+                Ok(Some(value))
+            }
+            FieldType::Number { min, max } => {
+                let n = match &value {
+                    Value::Number(n) => n.as_f64(),
+                    Value::String(s) => s.parse::<f64>().ok().filter(|n| n.is_finite()),
+                    _ => None,
+                }
+                .ok_or_else(|| invalid("must be a number"))?;
+                if matches!(min, Some(min) if n < *min) {
+                    return Err(invalid(&format!("must be at least {}", min.unwrap())));
+                }
+                if matches!(max, Some(max) if n > *max) {
+                    return Err(invalid(&format!("must be at most {}", max.unwrap())));
+                }
+                // Whole numbers are stored as integers, so `12` matches `12`
+                // rather than `12.0` when the attributes are rendered.
+                if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+                    return Ok(Some(Value::from(n as i64)));
+                }
+                Ok(Some(Value::from(n)))
+            }
+            FieldType::Boolean => match &value {
+                Value::Bool(b) => Ok(Some(Value::Bool(*b))),
+                Value::String(s) => match s.to_lowercase().as_str() {
+                    "true" | "on" | "yes" | "1" => Ok(Some(Value::Bool(true))),
+                    "false" | "off" | "no" | "0" => Ok(Some(Value::Bool(false))),
+                    _ => Err(invalid("must be true or false")),
+                },
+                _ => Err(invalid("must be true or false")),
+            },
+            FieldType::Choice { choices } => {
+                let s = value.as_str().ok_or_else(|| invalid("must be text"))?;
+                if choices.iter().any(|choice| choice == s) {
+                    Ok(Some(value))
+                } else {
+                    Err(invalid(&format!("must be one of {}", choices.join(", "))))
+                }
+            }
+        }
+    }
+}
+
+/// The subscriber fields defined by admins.
+#[derive(Debug, Default)]
+pub struct AttributeSchema {
+    fields: Vec<FieldDefinition>,
+}
+
+impl AttributeSchema {
+    pub fn new(fields: Vec<FieldDefinition>) -> Self {
+        Self { fields }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FieldDefinition> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    /// Picks the fields the signup form may set out of everything it sent.
+    /// Other inputs are ignored, as forms carry buttons and the like.
+    pub fn parse_signup(
+        &self,
+        submitted: &HashMap<String, Value>,
+    ) -> Result<Attributes, AttributeError> {
+        let mut attributes = Attributes::new();
+        for field in self.fields.iter().filter(|field| field.signup) {
+            match submitted.get(&field.name).map(|v| field.coerce(v)) {
+                Some(Ok(Some(value))) => {
+                    attributes.insert(field.name.clone(), value);
+                }
+                Some(Err(e)) => return Err(e),
+                Some(Ok(None)) | None if field.required => {
+                    return Err(AttributeError::Missing(field.name.clone()))
+                }
+                Some(Ok(None)) | None => {}
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// Validates changes made by an admin. Any field may be set, and a null
+    /// value removes the attribute.
+    pub fn parse_update(&self, changes: &Attributes) -> Result<Attributes, AttributeError> {
+        let mut parsed = Attributes::new();
+        for (name, value) in changes {
+            let field = self
+                .field(name)
+                .ok_or_else(|| AttributeError::Unknown(name.clone()))?;
+            parsed.insert(name.clone(), field.coerce(value)?.unwrap_or(Value::Null));
+        }
+        Ok(parsed)
+    }
+
+    /// Converts a filter value from a query string to the field's type.
+    pub fn parse_filter(&self, name: &str, value: &str) -> Result<Value, AttributeError> {
+        let field = self
+            .field(name)
+            .ok_or_else(|| AttributeError::Unknown(name.to_string()))?;
+        field
+            .coerce(&Value::String(value.to_string()))?
+            .ok_or_else(|| AttributeError::Missing(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::{json, Value};
+
+    use crate::domain::{
+        AttributeError, AttributeSchema, FieldDefinition, FieldDefinitionError, FieldType,
+    };
+
+    fn field(name: &str, field_type: FieldType, required: bool) -> FieldDefinition {
+        FieldDefinition {
+            name: name.into(),
+            field_type,
+            required,
+            signup: true,
+        }
+    }
+
+    fn schema() -> AttributeSchema {
+        AttributeSchema::new(vec![
+            field(
+                "company",
+                FieldType::Text {
+                    max_length: Some(10),
+                },
+                false,
+            ),
+            field(
+                "seats",
+                FieldType::Number {
+                    min: Some(1.0),
+                    max: None,
+                },
+                false,
+            ),
+            field("beta", FieldType::Boolean, false),
+            field(
+                "plan",
+                FieldType::Choice {
+                    choices: vec!["free".into(), "pro".into()],
+                },
+                true,
+            ),
+            FieldDefinition {
+                signup: false,
+                ..field("source", FieldType::Text { max_length: None }, false)
+            },
+        ])
+    }
+
+    fn form(pairs: &[(&str, &str)]) -> HashMap<String, Value> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn form_strings_are_converted_to_the_field_types() {
+        let attributes = schema()
+            .parse_signup(&form(&[
+                ("company", " Acme "),
+                ("seats", "12"),
+                ("beta", "on"),
+                ("plan", "pro"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            Value::Object(attributes),
+            json!({"company": "Acme", "seats": 12, "beta": true, "plan": "pro"})
+        );
+    }
+
+    #[test]
+    fn fields_not_open_to_signup_and_unknown_inputs_are_ignored() {
+        let attributes = schema()
+            .parse_signup(&form(&[
+                ("plan", "free"),
+                ("source", "spoofed"),
+                ("submit", "Subscribe"),
+            ]))
+            .unwrap();
+        assert_eq!(Value::Object(attributes), json!({"plan": "free"}));
+    }
+
+    #[test]
+    fn required_fields_must_be_filled_in() {
+        assert_eq!(
+            schema().parse_signup(&form(&[("plan", " ")])),
+            Err(AttributeError::Missing("plan".into()))
+        );
+    }
+
+    #[test]
+    fn values_breaking_the_rules_are_rejected() {
+        for (name, value) in [
+            ("company", "Much too long"),
+            ("seats", "0"),
+            ("seats", "many"),
+            ("beta", "maybe"),
+            ("plan", "enterprise"),
+        ] {
+            let result = schema().parse_signup(&form(&[("plan", "free"), (name, value)]));
+            let error = result.expect_err(value);
+            assert_eq!(error.name(), name);
+        }
+    }
+
+    #[test]
+    fn admins_can_set_any_field_and_remove_attributes_with_null() {
+        let changes = json!({"source": "import", "company": null});
+        let parsed = schema().parse_update(changes.as_object().unwrap()).unwrap();
+        assert_eq!(
+            Value::Object(parsed),
+            json!({"source": "import", "company": null})
+        );
+        let unknown = json!({"favourite_colour": "blue"});
+        assert_eq!(
+            schema().parse_update(unknown.as_object().unwrap()),
+            Err(AttributeError::Unknown("favourite_colour".into()))
+        );
+    }
+
+    #[test]
+    fn field_names_must_be_identifiers_and_not_reserved() {
+        let valid = field("signup_source", FieldType::Boolean, false);
+        assert!(valid.validate().is_ok());
+        for name in ["", "Company", "2fa", "has space", "email", "redirect_to"] {
+            let field = field(name, FieldType::Boolean, false);
+            assert!(field.validate().is_err(), "{}", name);
+        }
+        let no_choices = field("plan", FieldType::Choice { choices: vec![] }, false);
+        assert!(no_choices.validate().is_err());
+    }
+
+    #[test]
+    fn a_max_length_must_be_positive() {
+        for max_length in [0, -1, i32::MIN] {
+            let field = field(
+                "company",
+                FieldType::Text {
+                    max_length: Some(max_length),
+                },
+                false,
+            );
+            assert_eq!(
+                field.validate(),
+                Err(FieldDefinitionError::InvalidMaxLength)
+            );
+        }
+    }
+
+    #[test]
+    fn text_without_a_max_length_is_capped_by_default() {
+        let schema = AttributeSchema::new(vec![
+            field("bio", FieldType::Text { max_length: None }, false),
+            // Saved before non-positive lengths were rejected.
+            field(
+                "motto",
+                FieldType::Text {
+                    max_length: Some(-1),
+                },
+                false,
+            ),
+        ]);
+        for name in ["bio", "motto"] {
+            let at_limit = "a".repeat(1000);
+            assert!(schema.parse_signup(&form(&[(name, &at_limit)])).is_ok());
+            let too_long = "a".repeat(1001);
+            let error = schema
+                .parse_signup(&form(&[(name, &too_long)]))
+                .unwrap_err();
+            assert_eq!(error.name(), name);
+        }
+    }
+}
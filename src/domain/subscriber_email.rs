@@ -1,27 +1,199 @@
-use validator::validate_email;
+/// RFC 5321 limits, in octets.
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_DOMAIN_LENGTH: usize = 255;
+const MAX_ADDRESS_LENGTH: usize = 254;
 
-#[derive(Debug)]
-pub struct SubscriberEmail(String);
+#[derive(Debug, Clone)]
+pub struct SubscriberEmail {
+    email: String,
+    canonical: String,
+}
+
+/// How a mailbox provider folds addresses that reach the same mailbox, e.g.
+/// Gmail ignoring dots and `+tag` suffixes in the local part.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct CanonicalizationRule {
+    /// The provider's domains, in ASCII. Addresses at any of them fold into
+    /// the first.
+    pub domains: Vec<String>,
+    pub enabled: bool,
+    pub ignore_dots: bool,
+    pub ignore_tags: bool,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum EmailValidationError {
+    #[error("The email address is empty.")]
+    Empty,
+    #[error("The email address is missing an @ sign.")]
+    MissingAtSign,
+    #[error("The part of the email address before the @ sign is empty.")]
+    MissingLocalPart,
+    #[error("The part of the email address after the @ sign is empty.")]
+    MissingDomain,
+    #[error("The part of the email address before the @ sign is longer than 64 bytes.")]
+    LocalPartTooLong,
+    #[error("The domain of the email address is longer than 255 bytes.")]
+    DomainTooLong,
+    #[error("The email address is longer than 254 bytes.")]
+    AddressTooLong,
+    #[error("The part of the email address before the @ sign contains invalid characters.")]
+    InvalidLocalPart,
+    #[error("{0} is not a valid email domain.")]
+    InvalidDomain(String),
+    #[error("Role addresses such as {0}@ cannot subscribe, please use a personal address.")]
+    RoleAddress(String),
+    #[error("Disposable email addresses from {0} are not accepted.")]
+    DisposableDomain(String),
+    #[error("{}", undeliverable_domain_message(.domain, .suggestion))]
+    UndeliverableDomain {
+        domain: String,
+        suggestion: Option<String>,
+    },
+}
+
+fn undeliverable_domain_message(domain: &str, suggestion: &Option<String>) -> String {
+    match suggestion {
+        Some(suggestion) => format!(
+            "{} does not accept email. Did you mean {}?",
+            domain, suggestion
+        ),
+        None => format!("{} does not accept email.", domain),
+    }
+}
 
 impl SubscriberEmail {
-    pub fn parse(s: String) -> Result<SubscriberEmail, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
-        } else {
-            Err(format!("{} is not a valid subscriber email.", s))
+    /// Parses `s` without folding any provider's aliases, e.g. addresses
+    /// read back from the database to send to.
+    pub fn parse(s: String) -> Result<SubscriberEmail, EmailValidationError> {
+        Self::parse_with_rules(s, &[])
+    }
+
+    /// Checks the syntax of `s`, accepting internationalized domains (IDN)
+    /// and UTF-8 local parts (EAI, RFC 6531), and folds it with the enabled
+    /// `rules` into its canonical form.
+    pub fn parse_with_rules(
+        s: String,
+        rules: &[CanonicalizationRule],
+    ) -> Result<SubscriberEmail, EmailValidationError> {
+        if s.is_empty() {
+            return Err(EmailValidationError::Empty);
         }
+        let (local_part, domain) = s
+            .rsplit_once('@')
+            .ok_or(EmailValidationError::MissingAtSign)?;
+        if local_part.is_empty() {
+            return Err(EmailValidationError::MissingLocalPart);
+        }
+        if domain.is_empty() {
+            return Err(EmailValidationError::MissingDomain);
+        }
+        if local_part.len() > MAX_LOCAL_PART_LENGTH {
+            return Err(EmailValidationError::LocalPartTooLong);
+        }
+        if !is_valid_local_part(local_part) {
+            return Err(EmailValidationError::InvalidLocalPart);
+        }
+        let ascii_domain = to_ascii_domain(domain)?;
+        if local_part.len() + 1 + ascii_domain.len() > MAX_ADDRESS_LENGTH {
+            return Err(EmailValidationError::AddressTooLong);
+        }
+
+        let canonical = canonicalize(local_part, ascii_domain, rules);
+        Ok(Self {
+            email: s,
+            canonical,
+        })
+    }
+
+    /// The form used to decide whether two addresses reach the same mailbox.
+    /// Uniqueness and lookups use it, while `as_ref` keeps the address as typed.
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+
+    /// The domain of the canonical form, in its ASCII (punycode) form.
+    pub fn domain(&self) -> &str {
+        self.canonical
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or_default()
     }
 }
 
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.email
     }
 }
 
+/// A dot-atom made of RFC 5322 `atext`, extended with any non-ASCII
+/// character as allowed by RFC 6532.
+fn is_valid_local_part(local_part: &str) -> bool {
+    let is_atext = |c: char| {
+        c.is_ascii_alphanumeric()
+            || "!#$%&'*+-/=?^_`{|}~".contains(c)
+            || (!c.is_ascii() && !c.is_control() && !c.is_whitespace())
+    };
+    local_part
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// Converts the domain to its ASCII (punycode) form and checks it is a
+/// hostname with at least two labels.
+fn to_ascii_domain(domain: &str) -> Result<String, EmailValidationError> {
+    if domain.len() > MAX_DOMAIN_LENGTH {
+        return Err(EmailValidationError::DomainTooLong);
+    }
+    let invalid_domain = || EmailValidationError::InvalidDomain(domain.to_string());
+    let ascii_domain = idna::Config::default()
+        .use_std3_ascii_rules(true)
+        .check_hyphens(true)
+        .to_ascii(domain)
+        .map_err(|_| invalid_domain())?;
+    if ascii_domain.len() > MAX_DOMAIN_LENGTH {
+        return Err(EmailValidationError::DomainTooLong);
+    }
+    let labels: Vec<&str> = ascii_domain.split('.').collect();
+    if labels.len() < 2 || labels.iter().any(|l| l.is_empty() || l.len() > 63) {
+        return Err(invalid_domain());
+    }
+    Ok(ascii_domain)
+}
+
+/// Lowercases the local part and applies the first enabled rule for the
+/// domain.
+///
+/// RFC 5321 allows case-sensitive local parts, but no mailbox provider we
+/// care about makes use of it.
+fn canonicalize(local_part: &str, mut domain: String, rules: &[CanonicalizationRule]) -> String {
+    let mut local_part = local_part.to_lowercase();
+
+    let rule = rules
+        .iter()
+        .find(|rule| rule.enabled && rule.domains.iter().any(|d| d.eq_ignore_ascii_case(&domain)));
+    if let Some(rule) = rule {
+        if rule.ignore_tags {
+            // `+tag@` is a mailbox of its own rather than a tagged one, and
+            // would otherwise collide with every other such address.
+            match local_part.split_once('+') {
+                Some((address, _tag)) if !address.is_empty() => local_part = address.to_string(),
+                _ => {}
+            }
+        }
+        if rule.ignore_dots {
+            local_part.retain(|c| c != '.');
+        }
+        domain = rule.domains[0].to_lowercase();
+    }
+
+    format!("{}@{}", local_part, domain)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberEmail;
+    use crate::domain::{CanonicalizationRule, EmailValidationError, SubscriberEmail};
     use fake::faker::internet::en::SafeEmail;
     use fake::Fake;
 
@@ -38,23 +210,166 @@ mod tests {
     #[test]
     fn empty_string_is_rejected() {
         let email = "".to_string();
-        assert!(SubscriberEmail::parse(email).is_err());
+        assert_eq!(
+            SubscriberEmail::parse(email).unwrap_err(),
+            EmailValidationError::Empty
+        );
     }
 
     #[test]
     fn email_missing_at_symbol_is_rejected() {
         let email = "ursuladomain.com".to_string();
-        assert!(SubscriberEmail::parse(email).is_err());
+        assert_eq!(
+            SubscriberEmail::parse(email).unwrap_err(),
+            EmailValidationError::MissingAtSign
+        );
     }
 
     #[test]
     fn email_missing_subject_is_rejected() {
         let email = "@domain.com".to_string();
-        assert!(SubscriberEmail::parse(email).is_err());
+        assert_eq!(
+            SubscriberEmail::parse(email).unwrap_err(),
+            EmailValidationError::MissingLocalPart
+        );
+    }
+
+    #[test]
+    fn email_with_a_local_part_longer_than_64_octets_is_rejected() {
+        let email = format!("{}@domain.com", "a".repeat(65));
+        assert_eq!(
+            SubscriberEmail::parse(email).unwrap_err(),
+            EmailValidationError::LocalPartTooLong
+        );
+    }
+
+    #[test]
+    fn length_limits_count_bytes_rather_than_characters() {
+        let email = format!("{}@domain.com", "é".repeat(33));
+        let error = SubscriberEmail::parse(email).unwrap_err();
+        assert_eq!(error, EmailValidationError::LocalPartTooLong);
+        assert!(error.to_string().contains("64 bytes"));
+    }
+
+    #[test]
+    fn email_longer_than_254_octets_is_rejected() {
+        let domain = vec!["a".repeat(63); 3].join(".") + ".com";
+        let email = format!("{}@{}", "a".repeat(64), domain);
+        assert_eq!(
+            SubscriberEmail::parse(email).unwrap_err(),
+            EmailValidationError::AddressTooLong
+        );
+    }
+
+    #[test]
+    fn email_with_consecutive_dots_in_the_local_part_is_rejected() {
+        let email = "ursula..le.guin@domain.com".to_string();
+        assert_eq!(
+            SubscriberEmail::parse(email).unwrap_err(),
+            EmailValidationError::InvalidLocalPart
+        );
+    }
+
+    #[test]
+    fn email_with_an_invalid_domain_is_rejected() {
+        for domain in ["domain", "domain..com", "-domain.com", "do_main.com"] {
+            let email = format!("ursula@{}", domain);
+            assert_eq!(
+                SubscriberEmail::parse(email).unwrap_err(),
+                EmailValidationError::InvalidDomain(domain.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn internationalized_email_is_accepted() {
+        let email = "用户@例子.广告".to_string();
+        assert!(SubscriberEmail::parse(email).is_ok());
     }
 
     #[quickcheck_macros::quickcheck]
     fn valid_emails_are_parsed_successfully(valid_email: ValidEmailFixture) -> bool {
         SubscriberEmail::parse(valid_email.0).is_ok()
     }
+
+    #[test]
+    fn email_is_kept_as_typed() {
+        let email = SubscriberEmail::parse("Alice@Example.com".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "Alice@Example.com");
+    }
+
+    #[test]
+    fn canonical_form_is_lowercased() {
+        let email = SubscriberEmail::parse("Alice@Example.com".to_string()).unwrap();
+        assert_eq!(email.canonical(), "alice@example.com");
+    }
+
+    #[test]
+    fn canonical_form_converts_idn_domains_to_punycode() {
+        let email = SubscriberEmail::parse("ursula@bücher.de".to_string()).unwrap();
+        assert_eq!(email.canonical(), "ursula@xn--bcher-kva.de");
+        let email = SubscriberEmail::parse("ursula@xn--bcher-kva.de".to_string()).unwrap();
+        assert_eq!(email.canonical(), "ursula@xn--bcher-kva.de");
+    }
+
+    fn gmail(enabled: bool) -> CanonicalizationRule {
+        CanonicalizationRule {
+            domains: vec!["gmail.com".into(), "googlemail.com".into()],
+            enabled,
+            ignore_dots: true,
+            ignore_tags: true,
+        }
+    }
+
+    #[test]
+    fn canonical_form_folds_dots_and_tags_for_domains_with_a_rule() {
+        let email = SubscriberEmail::parse_with_rules(
+            "Ursula.Le.Guin+news@googlemail.com".to_string(),
+            &[gmail(true)],
+        )
+        .unwrap();
+        assert_eq!(email.canonical(), "ursulaleguin@gmail.com");
+    }
+
+    #[test]
+    fn canonical_form_keeps_dots_and_tags_for_other_domains() {
+        let email = SubscriberEmail::parse_with_rules(
+            "ursula.le.guin+news@example.com".to_string(),
+            &[gmail(true)],
+        )
+        .unwrap();
+        assert_eq!(email.canonical(), "ursula.le.guin+news@example.com");
+    }
+
+    #[test]
+    fn disabled_rules_are_not_applied() {
+        let email = SubscriberEmail::parse_with_rules(
+            "Ursula.Le.Guin+news@googlemail.com".to_string(),
+            &[gmail(false)],
+        )
+        .unwrap();
+        assert_eq!(email.canonical(), "ursula.le.guin+news@googlemail.com");
+    }
+
+    #[test]
+    fn rules_can_fold_only_tags() {
+        let rule = CanonicalizationRule {
+            domains: vec!["fastmail.com".into()],
+            enabled: true,
+            ignore_dots: false,
+            ignore_tags: true,
+        };
+        let email =
+            SubscriberEmail::parse_with_rules("ursula.le+news@fastmail.com".to_string(), &[rule])
+                .unwrap();
+        assert_eq!(email.canonical(), "ursula.le@fastmail.com");
+    }
+
+    #[test]
+    fn local_parts_starting_with_a_tag_are_kept_whole() {
+        let email =
+            SubscriberEmail::parse_with_rules("+news@gmail.com".to_string(), &[gmail(true)])
+                .unwrap();
+        assert_eq!(email.canonical(), "+news@gmail.com");
+    }
 }
@@ -1,20 +1,92 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::MixedScript;
 use unicode_segmentation::UnicodeSegmentation;
 
+const MAX_LENGTH: usize = 256;
+
+/// Characters that change the display order of text without being visible.
+const BIDI_CONTROLS: [char; 12] = [
+    '\u{061C}', '\u{200E}', '\u{200F}', '\u{202A}', '\u{202B}', '\u{202C}', '\u{202D}', '\u{202E}',
+    '\u{2066}', '\u{2067}', '\u{2068}', '\u{2069}',
+];
+
+/// Invisible characters with no use in a name. Zero-width (non-)joiners are
+/// kept as some scripts need them.
+const INVISIBLE_CHARACTERS: [char; 2] = ['\u{200B}', '\u{FEFF}'];
+
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum NameValidationError {
+    #[error("The name is empty.")]
+    Empty,
+    #[error("The name is longer than 256 graphemes.")]
+    TooLong,
+    #[error("The name contains the forbidden character {0}.")]
+    ForbiddenCharacter(char),
+    #[error("The word {0} mixes letters from different alphabets.")]
+    MixedScript(String),
+    #[error("The name contains stylized characters such as {0}, please use plain letters.")]
+    Confusable(char),
+}
+
+/// Configurable part of name validation.
+#[derive(Debug)]
+pub struct NamePolicy {
+    forbidden_characters: Vec<char>,
+}
+
+impl NamePolicy {
+    pub fn new(forbidden_characters: &str) -> Self {
+        Self {
+            forbidden_characters: forbidden_characters.chars().collect(),
+        }
+    }
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self::new("/()\"<>\\{}")
+    }
+}
+
 impl SubscriberName {
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace = s.trim().is_empty();
-        let is_too_long = s.graphemes(true).count() > 256;
-        let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contains_forbidden_characters = s.chars().any(|g| forbidden_characters.contains(&g));
-
-        if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name.", s))
-        } else {
-            Ok(Self(s))
+    pub fn parse(s: String) -> Result<SubscriberName, NameValidationError> {
+        Self::parse_with_policy(s, &NamePolicy::default())
+    }
+
+    /// Normalizes the name to NFC, drops control, bidi-override and invisible
+    /// characters and collapses runs of whitespace before validating it.
+    pub fn parse_with_policy(
+        s: String,
+        policy: &NamePolicy,
+    ) -> Result<SubscriberName, NameValidationError> {
+        let name = normalize(&s);
+
+        if name.is_empty() {
+            return Err(NameValidationError::Empty);
+        }
+        if name.graphemes(true).count() > MAX_LENGTH {
+            return Err(NameValidationError::TooLong);
         }
+        if let Some(c) = name
+            .chars()
+            .find(|c| policy.forbidden_characters.contains(c))
+        {
+            return Err(NameValidationError::ForbiddenCharacter(c));
+        }
+        // Mixing scripts inside a word is how look-alike names such as
+        // "Pаypal" (with a Cyrillic "а") are built. Words written in a single
+        // script are fine, even when they happen to resemble Latin ones.
+        if let Some(word) = name.split(' ').find(|word| !word.is_single_script()) {
+            return Err(NameValidationError::MixedScript(word.to_string()));
+        }
+        if let Some(c) = name.chars().find(|c| is_stylized(*c)) {
+            return Err(NameValidationError::Confusable(c));
+        }
+
+        Ok(Self(name))
     }
 }
 
@@ -24,9 +96,26 @@ impl AsRef<str> for SubscriberName {
     }
 }
 
+fn normalize(s: &str) -> String {
+    // Whitespace controls such as tabs and newlines are collapsed below.
+    let is_dropped = |c: &char| {
+        (c.is_control() && !c.is_whitespace())
+            || BIDI_CONTROLS.contains(c)
+            || INVISIBLE_CHARACTERS.contains(c)
+    };
+    let visible: String = s.nfc().filter(|c| !is_dropped(c)).collect();
+    visible.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Compatibility variants of ordinary letters and digits, e.g. mathematical
+/// bold, fullwidth or circled forms, which spammers use to dodge filters.
+fn is_stylized(c: char) -> bool {
+    c.is_alphanumeric() && c.nfkc().ne(std::iter::once(c))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
+    use crate::domain::{NamePolicy, NameValidationError, SubscriberName};
 
     #[test]
     fn a_256_grapheme_long_name_is_valid() {
@@ -40,6 +129,16 @@ mod tests {
         assert!(SubscriberName::parse(name).is_err());
     }
 
+    #[test]
+    fn length_is_counted_in_graphemes_not_code_points() {
+        let flag = "\u{1F1F5}\u{1F1F1}";
+        assert!(SubscriberName::parse(flag.repeat(256)).is_ok());
+        assert_eq!(
+            SubscriberName::parse(flag.repeat(257)).unwrap_err(),
+            NameValidationError::TooLong
+        );
+    }
+
     #[test]
     fn whitespace_only_names_are_rejected() {
         let name = " ".to_string();
@@ -65,4 +164,78 @@ mod tests {
         let name = "Philip K. Dick".to_string();
         assert!(SubscriberName::parse(name).is_ok());
     }
+
+    #[test]
+    fn forbidden_characters_are_configurable() {
+        let policy = NamePolicy::new("#");
+        assert_eq!(
+            SubscriberName::parse_with_policy("Ursula #1".to_string(), &policy).unwrap_err(),
+            NameValidationError::ForbiddenCharacter('#')
+        );
+        assert!(SubscriberName::parse_with_policy("Ursula (K.)".to_string(), &policy).is_ok());
+    }
+
+    #[test]
+    fn name_is_normalized_to_nfc() {
+        let name = SubscriberName::parse("Jose\u{0301}".to_string()).unwrap();
+        assert_eq!(name.as_ref(), "Jos\u{00E9}");
+    }
+
+    #[test]
+    fn control_bidi_and_invisible_characters_are_stripped() {
+        let name = SubscriberName::parse("Ur\u{202E}su\u{200B}la\u{0007}".to_string()).unwrap();
+        assert_eq!(name.as_ref(), "Ursula");
+    }
+
+    #[test]
+    fn whitespace_is_collapsed_and_trimmed() {
+        let name = SubscriberName::parse("  Ursula \t\n K.   Le Guin ".to_string()).unwrap();
+        assert_eq!(name.as_ref(), "Ursula K. Le Guin");
+    }
+
+    #[test]
+    fn a_name_made_only_of_invisible_characters_is_rejected() {
+        let name = "\u{200B}\u{202E}".to_string();
+        assert_eq!(
+            SubscriberName::parse(name).unwrap_err(),
+            NameValidationError::Empty
+        );
+    }
+
+    #[test]
+    fn words_mixing_scripts_are_rejected() {
+        let name = "P\u{0430}ypal Support".to_string();
+        assert_eq!(
+            SubscriberName::parse(name).unwrap_err(),
+            NameValidationError::MixedScript("P\u{0430}ypal".to_string())
+        );
+    }
+
+    #[test]
+    fn cyrillic_names_resembling_latin_words_are_accepted() {
+        for name in ["Вера", "Сара", "Ася", "Тор", "Вера Сара"] {
+            assert!(SubscriberName::parse(name.to_string()).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn names_in_a_single_non_latin_script_are_accepted() {
+        for name in [
+            "Анна Каренина",
+            "山田 太郎",
+            "やまだ たろう",
+            "Ursula Ле Гуин",
+        ] {
+            assert!(SubscriberName::parse(name.to_string()).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn stylized_letters_are_rejected() {
+        let name = "\u{1D40F}\u{1D41A}\u{1D432}".to_string();
+        assert_eq!(
+            SubscriberName::parse(name).unwrap_err(),
+            NameValidationError::Confusable('\u{1D40F}')
+        );
+    }
 }
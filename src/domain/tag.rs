@@ -0,0 +1,51 @@
+const MAX_LENGTH: usize = 64;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Tags are 1 to 64 letters, digits, dashes or underscores.")]
+pub struct InvalidTag;
+
+/// A label attached to subscribers, stored lowercased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag(String);
+
+impl Tag {
+    pub fn parse(s: &str) -> Result<Tag, InvalidTag> {
+        let tag = s.trim().to_lowercase();
+        let valid = !tag.is_empty()
+            && tag.len() <= MAX_LENGTH
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(Self(tag))
+        } else {
+            Err(InvalidTag)
+        }
+    }
+}
+
+impl AsRef<str> for Tag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::Tag;
+
+    #[test]
+    fn tags_are_trimmed_and_lowercased() {
+        assert_eq!(
+            Tag::parse(" Beta-Testers ").unwrap().as_ref(),
+            "beta-testers"
+        );
+    }
+
+    #[test]
+    fn empty_long_or_punctuated_tags_are_rejected() {
+        for tag in ["", "  ", "a b", "vip!", &"a".repeat(65)] {
+            assert!(Tag::parse(tag).is_err(), "{}", tag);
+        }
+    }
+}
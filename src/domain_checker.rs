@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+
+use crate::domain::{EmailValidationError, SubscriberEmail};
+
+/// Domains we suggest when an address looks like a typo of one of them.
+const COMMON_DOMAINS: [&str; 10] = [
+    "aol.com",
+    "gmail.com",
+    "gmx.com",
+    "hotmail.com",
+    "icloud.com",
+    "live.com",
+    "outlook.com",
+    "proton.me",
+    "yahoo.com",
+    "yandex.com",
+];
+
+/// Maximum edit distance for a domain to be considered a typo.
+const MAX_TYPO_DISTANCE: usize = 2;
+
+#[async_trait::async_trait]
+pub trait DomainResolver: Send + Sync {
+    /// Whether the domain can receive email, i.e. it has MX records or,
+    /// failing that, an A/AAAA record (RFC 5321, section 5.1).
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error>;
+}
+
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    pub fn from_system_conf() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainResolver for DnsResolver {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        // The trailing dot keeps the resolver from trying search domains.
+        let fqdn = format!("{}.", domain);
+        match self.resolver.mx_lookup(fqdn.as_str()).await {
+            // A single "." exchange is a null MX (RFC 7505): no mail accepted.
+            Ok(mx) => return Ok(mx.iter().any(|record| !record.exchange().is_root())),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+        match self.resolver.lookup_ip(fqdn.as_str()).await {
+            Ok(ips) => Ok(ips.iter().next().is_some()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Rejects addresses whose domain cannot receive email, caching lookups for
+/// `cache_ttl`. Domains are chosen by whoever fills in the form, so the cache
+/// holds at most `cache_capacity` of them.
+pub struct DomainChecker {
+    resolver: Box<dyn DomainResolver>,
+    cache_ttl: Duration,
+    cache_capacity: usize,
+    cache: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl DomainChecker {
+    pub fn new(
+        resolver: Box<dyn DomainResolver>,
+        cache_ttl: Duration,
+        cache_capacity: usize,
+    ) -> Self {
+        Self {
+            resolver,
+            cache_ttl,
+            cache_capacity,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolver failures (timeouts, SERVFAIL, ...) let the address through:
+    /// a flaky DNS server should not stop people from subscribing.
+    #[tracing::instrument(name = "Checking email domain deliverability", skip(self, email))]
+    pub async fn check(&self, email: &SubscriberEmail) -> Result<(), EmailValidationError> {
+        let domain = email.domain();
+        let accepts_mail = match self.cached(domain) {
+            Some(accepts_mail) => accepts_mail,
+            None => match self.resolver.accepts_mail(domain).await {
+                Ok(accepts_mail) => {
+                    self.remember(domain, accepts_mail);
+                    accepts_mail
+                }
+                Err(e) => {
+                    tracing::warn!(error.cause_chain = ?e, "Failed to resolve email domain.");
+                    return Ok(());
+                }
+            },
+        };
+
+        if accepts_mail {
+            Ok(())
+        } else {
+            Err(EmailValidationError::UndeliverableDomain {
+                domain: domain.to_string(),
+                suggestion: suggest_domain(domain).map(String::from),
+            })
+        }
+    }
+
+    fn cached(&self, domain: &str) -> Option<bool> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(domain) {
+            Some((accepts_mail, resolved_at)) if resolved_at.elapsed() < self.cache_ttl => {
+                Some(*accepts_mail)
+            }
+            Some(_) => {
+                cache.remove(domain);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches a lookup, making room by dropping expired lookups or, failing
+    /// that, the oldest one.
+    fn remember(&self, domain: &str, accepts_mail: bool) {
+        if self.cache_capacity == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.cache_capacity && !cache.contains_key(domain) {
+            cache.retain(|_, (_, resolved_at)| resolved_at.elapsed() < self.cache_ttl);
+            if cache.len() >= self.cache_capacity {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, (_, resolved_at))| *resolved_at)
+                    .map(|(domain, _)| domain.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        cache.insert(domain.to_string(), (accepts_mail, Instant::now()));
+    }
+}
+
+/// Returns the closest common domain if `domain` is within a couple of
+/// typos of it, e.g. `gmail.com` for `gmial.com`.
+pub fn suggest_domain(domain: &str) -> Option<&'static str> {
+    COMMON_DOMAINS
+        .iter()
+        .map(|candidate| (candidate, edit_distance(domain, candidate)))
+        .filter(|(_, distance)| *distance > 0 && *distance <= MAX_TYPO_DISTANCE)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(candidate, _)| *candidate)
+}
+
+/// Optimal string alignment distance: Levenshtein plus transpositions of
+/// adjacent characters, which are the most common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::domain::{EmailValidationError, SubscriberEmail};
+    use crate::domain_checker::{suggest_domain, DomainChecker, DomainResolver};
+
+    /// Accepts only `example.com` and counts how many lookups it served.
+    struct FakeResolver {
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl DomainResolver for FakeResolver {
+        async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            match domain {
+                "example.com" => Ok(true),
+                "broken.com" => Err(anyhow::anyhow!("SERVFAIL")),
+                _ => Ok(false),
+            }
+        }
+    }
+
+    fn fake_checker(cache_ttl: Duration) -> (DomainChecker, Arc<AtomicUsize>) {
+        fake_checker_holding(cache_ttl, 100)
+    }
+
+    fn fake_checker_holding(
+        cache_ttl: Duration,
+        cache_capacity: usize,
+    ) -> (DomainChecker, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = FakeResolver {
+            lookups: lookups.clone(),
+        };
+        let checker = DomainChecker::new(Box::new(resolver), cache_ttl, cache_capacity);
+        (checker, lookups)
+    }
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn deliverable_domain_is_accepted() {
+        let (checker, _) = fake_checker(Duration::from_secs(60));
+        assert!(checker.check(&email("ursula@example.com")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn undeliverable_domain_is_rejected_with_a_suggestion() {
+        let (checker, _) = fake_checker(Duration::from_secs(60));
+        assert_eq!(
+            checker.check(&email("ursula@gmial.com")).await.unwrap_err(),
+            EmailValidationError::UndeliverableDomain {
+                domain: "gmial.com".into(),
+                suggestion: Some("gmail.com".into()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn resolver_failures_let_the_address_through() {
+        let (checker, _) = fake_checker(Duration::from_secs(60));
+        assert!(checker.check(&email("ursula@broken.com")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached_until_the_ttl_expires() {
+        let (checker, lookups) = fake_checker(Duration::from_secs(60));
+        checker.check(&email("ursula@example.com")).await.unwrap();
+        checker.check(&email("le.guin@Example.com")).await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        let (checker, lookups) = fake_checker(Duration::ZERO);
+        checker.check(&email("ursula@example.com")).await.unwrap();
+        checker.check(&email("ursula@example.com")).await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn the_cache_drops_the_oldest_lookup_when_full() {
+        let (checker, lookups) = fake_checker_holding(Duration::from_secs(60), 2);
+        for domain in ["example.com", "a.com", "b.com", "c.com"] {
+            checker
+                .check(&email(&format!("ursula@{}", domain)))
+                .await
+                .ok();
+        }
+        assert_eq!(checker.cache.lock().unwrap().len(), 2);
+
+        checker.check(&email("ursula@c.com")).await.ok();
+        assert_eq!(lookups.load(Ordering::SeqCst), 4);
+        checker.check(&email("ursula@example.com")).await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn common_misspellings_get_a_suggestion() {
+        assert_eq!(suggest_domain("gmial.com"), Some("gmail.com"));
+        assert_eq!(suggest_domain("hotmal.com"), Some("hotmail.com"));
+        assert_eq!(suggest_domain("yaho.com"), Some("yahoo.com"));
+    }
+
+    #[test]
+    fn unrelated_and_exact_domains_get_no_suggestion() {
+        assert_eq!(suggest_domain("gmail.com"), None);
+        assert_eq!(suggest_domain("example.com"), None);
+    }
+}
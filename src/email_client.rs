@@ -24,6 +24,10 @@ impl EmailClient {
         }
     }
 
+    pub fn sender(&self) -> &SubscriberEmail {
+        &self.sender
+    }
+
     pub async fn send_email(
         &self,
         recipient: SubscriberEmail,
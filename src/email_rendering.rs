@@ -0,0 +1,522 @@
+use std::borrow::Cow;
+
+use ammonia::{Builder, UrlRelative};
+use reqwest::Url;
+use uuid::Uuid;
+
+use crate::email_templates::escape_html;
+
+/// Gmail hides everything past this many bytes of HTML behind a link.
+pub const GMAIL_CLIPPING_LIMIT: usize = 102 * 1024;
+
+/// Turns the HTML body of an issue into what is sent: `<style>` rules are
+/// inlined, anything outside the allowlist is stripped and relative URLs are
+/// made absolute.
+pub struct EmailRenderer {
+    sanitizer: Builder<'static>,
+    base_url: Url,
+}
+
+impl EmailRenderer {
+    pub fn new(base_url: &str) -> Result<Self, ammonia::url::ParseError> {
+        // Relative URLs resolve against the base as a directory.
+        let base_url = Url::parse(&format!("{}/", base_url.trim_end_matches('/')))?;
+        let mut sanitizer = Builder::default();
+        sanitizer
+            .add_generic_attributes(&[
+                "style", "align", "valign", "width", "height", "bgcolor", "dir",
+            ])
+            .add_tag_attributes("table", &["border", "cellpadding", "cellspacing"])
+            .add_clean_content_tags(&["title"])
+            .url_relative(UrlRelative::RewriteWithBase(base_url.clone()))
+            .attribute_filter(|_, attribute, value| match attribute {
+                "style" => safe_style(value),
+                _ => Some(value.into()),
+            });
+        Ok(Self {
+            sanitizer,
+            base_url,
+        })
+    }
+
+    pub fn render(&self, html: &str) -> String {
+        self.sanitizer.clean(&inline_css(html)).to_string()
+    }
+
+    /// Appends an invisible image to a rendered issue, so that the email
+    /// client loading it records the subscriber's first open.
+    pub fn track_opens(&self, html: &str, newsletter_issue_id: Uuid, manage_token: &str) -> String {
+        let mut url = self
+            .base_url
+            .join(&format!("issues/{}/open", newsletter_issue_id))
+            .expect("An issue path is a valid relative URL.");
+        url.query_pairs_mut()
+            .append_pair("manage_token", manage_token);
+        format!(
+            "{}<img src=\"{}\" width=\"1\" height=\"1\" alt=\"\">",
+            html,
+            escape_html(url.as_str())
+        )
+    }
+}
+
+/// Explains why Gmail would clip `html`, if it would.
+pub fn clipping_warning(html: &str) -> Option<String> {
+    (html.len() > GMAIL_CLIPPING_LIMIT).then(|| {
+        format!(
+            "The HTML body is {}KB, Gmail clips messages over {}KB.",
+            html.len() / 1024,
+            GMAIL_CLIPPING_LIMIT / 1024
+        )
+    })
+}
+
+/// Drops declarations that could load or run something, which clients
+/// disagree on how to handle.
+fn safe_style(style: &str) -> Option<Cow<'_, str>> {
+    let declarations: Vec<_> = style
+        .split(';')
+        .map(str::trim)
+        .filter(|declaration| {
+            let declaration = declaration.to_ascii_lowercase();
+            !declaration.is_empty()
+                && !["url(", "expression(", "javascript:", "behavior:", "@import"]
+                    .iter()
+                    .any(|unsafe_part| declaration.contains(unsafe_part))
+        })
+        .collect();
+    if declarations.is_empty() {
+        None
+    } else {
+        Some(declarations.join("; ").into())
+    }
+}
+
+/// An element selector made of a type, classes and an id, e.g. `p.lead`.
+/// Combinators, attribute selectors and pseudo-classes are not supported.
+#[derive(Debug, PartialEq, Eq)]
+struct Selector {
+    tag: Option<String>,
+    classes: Vec<String>,
+    id: Option<String>,
+}
+
+impl Selector {
+    fn parse(selector: &str) -> Option<Self> {
+        let mut parsed = Self {
+            tag: None,
+            classes: Vec::new(),
+            id: None,
+        };
+        // CSS identifiers may use any non-ASCII character, e.g. `.café`.
+        let is_name_char =
+            |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || !c.is_ascii();
+        let mut rest = selector.trim();
+        if rest.is_empty() {
+            return None;
+        }
+        let tag_end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        if tag_end > 0 {
+            parsed.tag = Some(rest[..tag_end].to_ascii_lowercase());
+        } else if let Some(after_star) = rest.strip_prefix('*') {
+            rest = after_star;
+        }
+        rest = &rest[tag_end..];
+        while let Some(kind) = rest.chars().next() {
+            let name_start = kind.len_utf8();
+            let name_end = rest[name_start..]
+                .find(|c: char| !is_name_char(c))
+                .map_or(rest.len(), |end| end + name_start);
+            let name = &rest[name_start..name_end];
+            match kind {
+                _ if name.is_empty() => return None,
+                '.' => parsed.classes.push(name.to_string()),
+                '#' if parsed.id.is_none() => parsed.id = Some(name.to_string()),
+                _ => return None,
+            }
+            rest = &rest[name_end..];
+        }
+        Some(parsed)
+    }
+
+    fn specificity(&self) -> (usize, usize, usize) {
+        (
+            usize::from(self.id.is_some()),
+            self.classes.len(),
+            usize::from(self.tag.is_some()),
+        )
+    }
+
+    fn matches(&self, tag: &str, classes: &[&str], id: Option<&str>) -> bool {
+        self.tag.iter().all(|t| t == tag)
+            && self.id.iter().all(|i| Some(i.as_str()) == id)
+            && self.classes.iter().all(|c| classes.contains(&c.as_str()))
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    selector: Selector,
+    declarations: String,
+}
+
+/// Reads the style rules `inline_css` can apply, skipping at-rules and
+/// selectors it does not support.
+fn parse_css(css: &str) -> Vec<Rule> {
+    let mut css = css.to_string();
+    while let Some(start) = css.find("/*") {
+        let end = css[start + 2..]
+            .find("*/")
+            .map_or(css.len(), |end| start + end + 4);
+        css.replace_range(start..end, " ");
+    }
+    let mut rules = Vec::new();
+    let mut rest = css.as_str();
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with('@') {
+            // `@import ...;` or a block such as `@media { ... }`.
+            let statement_end = rest.find(';');
+            let block_start = rest.find('{');
+            match (statement_end, block_start) {
+                (Some(end), Some(start)) if end < start => rest = &rest[end + 1..],
+                (_, Some(start)) => rest = &rest[skip_block(rest, start)..],
+                (Some(end), None) => rest = &rest[end + 1..],
+                (None, None) => break,
+            }
+            continue;
+        }
+        let (selectors, body) = match rest.split_once('{') {
+            Some(split) => split,
+            None => break,
+        };
+        let (declarations, after) = body.split_once('}').unwrap_or((body, ""));
+        let declarations = declarations
+            .split(';')
+            .map(str::trim)
+            .filter(|d| d.contains(':'))
+            .collect::<Vec<_>>()
+            .join("; ");
+        for selector in selectors.split(',') {
+            if let Some(selector) = Selector::parse(selector) {
+                rules.push(Rule {
+                    selector,
+                    declarations: declarations.clone(),
+                });
+            }
+        }
+        rest = after;
+    }
+    rules
+}
+
+/// Where the block opening at `start` ends, counting nested blocks.
+fn skip_block(css: &str, start: usize) -> usize {
+    let mut depth = 0;
+    for (i, c) in css[start..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return start + i + 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    css.len()
+}
+
+/// Moves the rules of `<style>` blocks into the `style` attribute of the
+/// elements they select, since many clients drop style sheets. Rules apply in
+/// order of specificity then position, and before the element's own style.
+fn inline_css(html: &str) -> String {
+    let (html, css) = take_style_blocks(html);
+    let rules = parse_css(&css);
+    if rules.is_empty() {
+        return html;
+    }
+    let mut rules: Vec<_> = rules.into_iter().enumerate().collect();
+    rules.sort_by_key(|(position, rule)| (rule.selector.specificity(), *position));
+
+    let mut out = String::with_capacity(html.len() * 2);
+    let mut rest = html.as_str();
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |end| end + 3);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        match StartTag::parse(rest) {
+            Some(tag) => {
+                let classes: Vec<_> = tag
+                    .attribute("class")
+                    .map(|c| c.split_whitespace().collect())
+                    .unwrap_or_default();
+                let id = tag.attribute("id");
+                let mut style: Vec<_> = rules
+                    .iter()
+                    .filter(|(_, rule)| rule.selector.matches(&tag.name, &classes, id))
+                    .map(|(_, rule)| escape_html(&rule.declarations))
+                    .collect();
+                if style.is_empty() {
+                    out.push_str(&rest[..tag.len]);
+                } else {
+                    style.extend(tag.attribute("style").map(str::to_string));
+                    out.push_str(&tag.with_style(&style.join("; ")));
+                }
+                rest = &rest[tag.len..];
+            }
+            None => {
+                out.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Removes the `<style>` blocks from `html`, returning what is left and
+/// their CSS.
+fn take_style_blocks(html: &str) -> (String, String) {
+    // ASCII lowercasing keeps byte offsets the same.
+    let lowercase = html.to_ascii_lowercase();
+    let mut out = String::with_capacity(html.len());
+    let mut css = String::new();
+    let mut position = 0;
+    while let Some(start) = lowercase[position..].find("<style").map(|s| s + position) {
+        let content_start = match lowercase[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let content_end = lowercase[content_start..]
+            .find("</style")
+            .map_or(html.len(), |end| content_start + end);
+        let block_end = lowercase[content_end..]
+            .find('>')
+            .map_or(html.len(), |end| content_end + end + 1);
+        out.push_str(&html[position..start]);
+        css.push_str(&html[content_start..content_end]);
+        css.push('\n');
+        position = block_end;
+    }
+    out.push_str(&html[position..]);
+    (out, css)
+}
+
+/// A start tag as written, with attribute values left escaped.
+struct StartTag {
+    name: String,
+    attributes: Vec<(String, Option<String>)>,
+    self_closing: bool,
+    /// How many bytes of the source the tag spans.
+    len: usize,
+}
+
+impl StartTag {
+    /// Parses the tag `source` starts with, if it is a start tag.
+    fn parse(source: &str) -> Option<Self> {
+        let bytes = source.as_bytes();
+        if bytes.len() < 2 || !bytes[1].is_ascii_alphabetic() {
+            return None;
+        }
+        let mut i = 1;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"/>".contains(&bytes[i]) {
+            i += 1;
+        }
+        let name = source[1..i].to_ascii_lowercase();
+        let mut attributes = Vec::new();
+        let mut self_closing = false;
+        loop {
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            match bytes.get(i)? {
+                b'>' => break,
+                b'/' => {
+                    self_closing = true;
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            self_closing = false;
+            let name_start = i;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"/>=".contains(&bytes[i])
+            {
+                i += 1;
+            }
+            let attribute = source[name_start..i].to_ascii_lowercase();
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if bytes.get(i) != Some(&b'=') {
+                attributes.push((attribute, None));
+                continue;
+            }
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let value = match bytes.get(i)? {
+                quote @ (b'"' | b'\'') => {
+                    let end = i + 1 + source[i + 1..].find(char::from(*quote))?;
+                    let value = &source[i + 1..end];
+                    i = end + 1;
+                    value
+                }
+                _ => {
+                    let start = i;
+                    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                        i += 1;
+                    }
+                    &source[start..i]
+                }
+            };
+            attributes.push((attribute, Some(value.to_string())));
+        }
+        Some(Self {
+            name,
+            attributes,
+            self_closing,
+            len: i + 1,
+        })
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Writes the tag back out with `style` as its style attribute.
+    fn with_style(&self, style: &str) -> String {
+        let mut tag = format!("<{}", self.name);
+        for (attribute, value) in &self.attributes {
+            match value {
+                _ if attribute == "style" => {}
+                Some(value) => tag.push_str(&format!(
+                    " {}=\"{}\"",
+                    attribute,
+                    value.replace('"', "&quot;")
+                )),
+                None => tag.push_str(&format!(" {}", attribute)),
+            }
+        }
+        tag.push_str(&format!(" style=\"{}\"", style.replace('"', "&quot;")));
+        tag.push_str(if self.self_closing { " />" } else { ">" });
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::email_rendering::{clipping_warning, inline_css, EmailRenderer};
+
+    fn renderer() -> EmailRenderer {
+        EmailRenderer::new("https://example.com/newsletter").unwrap()
+    }
+
+    #[test]
+    fn style_rules_are_inlined_by_specificity_then_order() {
+        let html = inline_css(
+            "<style>\n\
+             p.lead { color: red; font-weight: bold }\n\
+             /* a comment */ p { color: blue; margin: 0 }\n\
+             #intro { color: green }\n\
+             </style>\
+             <p class=\"lead big\" id=intro style=\"margin: 4px\">Hi</p><p>there</p>",
+        );
+        assert_eq!(
+            html,
+            "<p class=\"lead big\" id=\"intro\" \
+             style=\"color: blue; margin: 0; color: red; font-weight: bold; color: green; margin: 4px\">\
+             Hi</p><p style=\"color: blue; margin: 0\">there</p>"
+        );
+    }
+
+    #[test]
+    fn selectors_with_non_ascii_names_are_inlined() {
+        let html = inline_css(
+            "<style>.café { color: red } p.naïve#über { margin: 0 } p§ { color: blue }</style>\
+             <p class=\"café naïve\" id=\"über\">Hi</p><p class=\"cafe\">there</p>",
+        );
+        assert_eq!(
+            html,
+            "<p class=\"café naïve\" id=\"über\" style=\"color: red; margin: 0\">Hi</p>\
+             <p class=\"cafe\">there</p>"
+        );
+    }
+
+    #[test]
+    fn unsupported_selectors_and_at_rules_are_skipped() {
+        let html = inline_css(
+            "<STYLE type=\"text/css\">\
+             @import url(\"x.css\");\
+             @media (max-width: 600px) { p { color: red } }\
+             div p, a:hover, td[align] { color: red }\
+             h1, * { font-family: \"Helvetica\" }\
+             </STYLE><h1>Title</h1><br/>",
+        );
+        assert_eq!(
+            html,
+            "<h1 style=\"font-family: &quot;Helvetica&quot;; font-family: &quot;Helvetica&quot;\">\
+             Title</h1><br style=\"font-family: &quot;Helvetica&quot;\" />"
+        );
+    }
+
+    #[test]
+    fn disallowed_tags_and_attributes_are_stripped() {
+        let html = renderer().render(
+            "<html><head><title>Issue</title></head><body>\
+             <script>alert(1)</script>\
+             <p onclick=\"alert(1)\" style=\"color: red; background: url(https://evil.com/x.png)\">Hi</p>\
+             <a href=\"javascript:alert(1)\">click</a><iframe src=\"https://evil.com\"></iframe>\
+             </body></html>",
+        );
+        assert_eq!(
+            html,
+            "<p style=\"color: red\">Hi</p><a rel=\"noopener noreferrer\">click</a>"
+        );
+    }
+
+    #[test]
+    fn relative_urls_are_made_absolute() {
+        let html = renderer().render(
+            "<a href=\"archive/1\">Issue #1</a><a href=\"/about\">About</a>\
+             <img src=\"logo.png\" alt=\"\"><a href=\"https://other.com/\">Other</a>",
+        );
+        assert_eq!(
+            html,
+            "<a href=\"https://example.com/newsletter/archive/1\" rel=\"noopener noreferrer\">Issue #1</a>\
+             <a href=\"https://example.com/about\" rel=\"noopener noreferrer\">About</a>\
+             <img src=\"https://example.com/newsletter/logo.png\" alt=\"\">\
+             <a href=\"https://other.com/\" rel=\"noopener noreferrer\">Other</a>"
+        );
+    }
+
+    #[test]
+    fn the_open_tracking_pixel_is_appended() {
+        let issue_id = Uuid::nil();
+        let html = renderer().track_opens("<p>Hi</p>", issue_id, "a&b");
+        assert_eq!(
+            html,
+            "<p>Hi</p><img src=\"https://example.com/newsletter/issues/\
+             00000000-0000-0000-0000-000000000000/open?manage_token=a%26b\" \
+             width=\"1\" height=\"1\" alt=\"\">"
+        );
+    }
+
+    #[test]
+    fn bodies_over_gmails_limit_are_flagged() {
+        assert!(clipping_warning(&"a".repeat(100 * 1024)).is_none());
+        assert_eq!(
+            clipping_warning(&"a".repeat(110 * 1024)).unwrap(),
+            "The HTML body is 110KB, Gmail clips messages over 102KB."
+        );
+    }
+}
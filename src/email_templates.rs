@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use sqlx::{PgExecutor, PgPool};
+use tera::Tera;
+
+/// The emails the app sends on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailKind {
+    Confirmation,
+    AlreadySubscribed,
+    ConfirmationReminder,
+    EmailChangeConfirmation,
+    EmailChangeNotice,
+}
+
+impl EmailKind {
+    pub const ALL: [Self; 5] = [
+        Self::Confirmation,
+        Self::AlreadySubscribed,
+        Self::ConfirmationReminder,
+        Self::EmailChangeConfirmation,
+        Self::EmailChangeNotice,
+    ];
+
+    /// The name of the email's template.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Confirmation => "confirmation",
+            Self::AlreadySubscribed => "already_subscribed",
+            Self::ConfirmationReminder => "confirmation_reminder",
+            Self::EmailChangeConfirmation => "email_change_confirmation",
+            Self::EmailChangeNotice => "email_change_notice",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// What the template gets on top of `subscriber` and `list`.
+    ///
+    /// Only emails to confirmed subscribers get `unsubscribe_url`: the others
+    /// go to addresses that have not opted in (yet), which have nothing to
+    /// unsubscribe from.
+    pub fn variables(&self) -> &'static [&'static str] {
+        match self {
+            Self::Confirmation | Self::ConfirmationReminder | Self::EmailChangeConfirmation => {
+                &["confirmation_link"]
+            }
+            Self::EmailChangeNotice => &["approval_link", "unsubscribe_url"],
+            Self::AlreadySubscribed => &["unsubscribe_url"],
+        }
+    }
+
+    fn sample(&self) -> Email {
+        let confirmation_link =
+            "https://example.com/subscriptions/confirm?subscription_token=sample".to_string();
+        let unsubscribe_url = SAMPLE_UNSUBSCRIBE_URL.to_string();
+        match self {
+            Self::Confirmation => Email::Confirmation { confirmation_link },
+            Self::AlreadySubscribed => Email::AlreadySubscribed { unsubscribe_url },
+            Self::ConfirmationReminder => Email::ConfirmationReminder { confirmation_link },
+            Self::EmailChangeConfirmation => Email::EmailChangeConfirmation { confirmation_link },
+            Self::EmailChangeNotice => Email::EmailChangeNotice {
+                approval_link:
+                    "https://example.com/subscriptions/change_email/approve?approval_token=sample"
+                        .into(),
+                unsubscribe_url,
+            },
+        }
+    }
+}
+
+const SAMPLE_UNSUBSCRIBE_URL: &str = "https://example.com/subscriptions/unsubscribe?token=sample";
+
+/// The template issues are sent in, with their body as `content`.
+const ISSUE_TEMPLATE: &str = "issue";
+
+/// An email to render, with the values of its variables.
+#[derive(Debug, Clone)]
+pub enum Email {
+    Confirmation {
+        confirmation_link: String,
+    },
+    AlreadySubscribed {
+        unsubscribe_url: String,
+    },
+    ConfirmationReminder {
+        confirmation_link: String,
+    },
+    EmailChangeConfirmation {
+        confirmation_link: String,
+    },
+    /// Goes to the old address, which has to approve the change.
+    EmailChangeNotice {
+        approval_link: String,
+        unsubscribe_url: String,
+    },
+}
+
+impl Email {
+    pub fn kind(&self) -> EmailKind {
+        match self {
+            Self::Confirmation { .. } => EmailKind::Confirmation,
+            Self::AlreadySubscribed { .. } => EmailKind::AlreadySubscribed,
+            Self::ConfirmationReminder { .. } => EmailKind::ConfirmationReminder,
+            Self::EmailChangeConfirmation { .. } => EmailKind::EmailChangeConfirmation,
+            Self::EmailChangeNotice { .. } => EmailKind::EmailChangeNotice,
+        }
+    }
+
+    fn insert_variables(&self, context: &mut tera::Context) {
+        match self {
+            Self::Confirmation { confirmation_link }
+            | Self::ConfirmationReminder { confirmation_link }
+            | Self::EmailChangeConfirmation { confirmation_link } => {
+                context.insert("confirmation_link", confirmation_link)
+            }
+            Self::EmailChangeNotice {
+                approval_link,
+                unsubscribe_url,
+            } => {
+                context.insert("approval_link", approval_link);
+                context.insert("unsubscribe_url", unsubscribe_url);
+            }
+            Self::AlreadySubscribed { unsubscribe_url } => {
+                context.insert("unsubscribe_url", unsubscribe_url)
+            }
+        }
+    }
+}
+
+/// Who an email goes to, available to templates as `subscriber`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Recipient {
+    pub name: String,
+    pub email: String,
+}
+
+/// What every email says about the newsletter, available to templates as
+/// `list`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ListDetails {
+    pub name: String,
+    pub physical_address: String,
+}
+
+/// The source of a template. Emails have a subject, layouts and partials do
+/// not.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TemplateSource {
+    pub subject: Option<String>,
+    pub html_body: String,
+    pub text_body: String,
+}
+
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct InvalidTemplate(String);
+
+impl From<tera::Error> for InvalidTemplate {
+    /// Tera puts the useful part, e.g. which variable is missing, in the
+    /// sources of its errors.
+    fn from(e: tera::Error) -> Self {
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        Self(message)
+    }
+}
+
+/// Tera templates for the emails the app sends. Every template has an HTML
+/// and a text body, so emails can extend the `layout` and include partials
+/// in both. Admins' edits are stored in the database on top of the defaults
+/// read from files.
+pub struct EmailTemplates {
+    defaults: BTreeMap<String, TemplateSource>,
+    list: ListDetails,
+    compiled: Mutex<Option<Compiled>>,
+}
+
+/// The templates last compiled, with the overrides they were compiled from.
+struct Compiled {
+    overrides: BTreeMap<String, TemplateSource>,
+    tera: Arc<Tera>,
+}
+
+impl EmailTemplates {
+    /// Reads `NAME.html` and `NAME.txt` for every template in
+    /// `templates_path`, plus `NAME.subject` for emails.
+    pub fn load(templates_path: &str, list: ListDetails) -> Result<Self, anyhow::Error> {
+        let mut defaults = BTreeMap::new();
+        let entries = std::fs::read_dir(templates_path).with_context(|| {
+            format!("Failed to read the email templates in {}.", templates_path)
+        })?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("html") {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let subject = match std::fs::read_to_string(path.with_extension("subject")) {
+                Ok(subject) => Some(subject.trim().to_string()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).context("Failed to read an email subject template."),
+            };
+            defaults.insert(
+                name,
+                TemplateSource {
+                    subject,
+                    html_body: read_template(&path)?,
+                    text_body: read_template(&path.with_extension("txt"))?,
+                },
+            );
+        }
+        let templates = Self {
+            defaults,
+            list,
+            compiled: Mutex::new(None),
+        };
+        templates
+            .check(&templates.defaults)
+            .context("The default email templates are invalid.")?;
+        Ok(templates)
+    }
+
+    pub fn list(&self) -> &ListDetails {
+        &self.list
+    }
+
+    pub fn defaults(&self) -> &BTreeMap<String, TemplateSource> {
+        &self.defaults
+    }
+
+    /// The templates admins have edited or added.
+    pub async fn overrides(
+        &self,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<BTreeMap<String, TemplateSource>, anyhow::Error> {
+        let rows =
+            sqlx::query!(r#"SELECT name, subject, html_body, text_body FROM email_templates"#)
+                .fetch_all(executor)
+                .await
+                .context("Failed to fetch the email templates.")?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let source = TemplateSource {
+                    subject: row.subject,
+                    html_body: row.html_body,
+                    text_body: row.text_body,
+                };
+                (row.name, source)
+            })
+            .collect())
+    }
+
+    /// The defaults with `overrides` on top.
+    pub fn merged(
+        &self,
+        overrides: &BTreeMap<String, TemplateSource>,
+    ) -> BTreeMap<String, TemplateSource> {
+        let mut templates = self.defaults.clone();
+        templates.extend(overrides.clone());
+        templates
+    }
+
+    /// Checks that `templates` compile and that every email, and the issue
+    /// template, renders with sample values, so a missing variable or partial
+    /// shows up when a template is saved rather than when an email is sent.
+    pub fn check(
+        &self,
+        templates: &BTreeMap<String, TemplateSource>,
+    ) -> Result<(), InvalidTemplate> {
+        let tera = compile(templates)?;
+        let recipient = Recipient {
+            name: "Ursula Le Guin".into(),
+            email: "ursula_le_guin@example.com".into(),
+        };
+        for kind in EmailKind::ALL {
+            if templates
+                .get(kind.name())
+                .and_then(|template| template.subject.as_ref())
+                .is_none()
+            {
+                return Err(InvalidTemplate(format!(
+                    "The {} email needs a template with a subject.",
+                    kind.name()
+                )));
+            }
+            self.render_with(&tera, &recipient, &kind.sample())?;
+        }
+        let issue = RenderedEmail {
+            subject: "Issue #1".into(),
+            html: "<p>Hello</p>".into(),
+            text: "Hello".into(),
+        };
+        self.render_issue_with(&tera, &recipient, Some(SAMPLE_UNSUBSCRIBE_URL), issue)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(email = email.kind().name()))]
+    pub async fn render(
+        &self,
+        pool: &PgPool,
+        recipient: &Recipient,
+        email: &Email,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        let tera = self.compiled(pool).await?;
+        self.render_with(&tera, recipient, email)
+            .with_context(|| format!("Failed to render the {} email.", email.kind().name()))
+    }
+
+    /// Puts an issue, already personalised and rendered, in the `issue`
+    /// template. It gets the issue's bodies as `content`, and
+    /// `unsubscribe_url` when the recipient has one, on top of `subscriber`
+    /// and `list`.
+    #[tracing::instrument(skip_all)]
+    pub async fn render_issue(
+        &self,
+        pool: &PgPool,
+        recipient: &Recipient,
+        unsubscribe_url: Option<&str>,
+        issue: RenderedEmail,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        let tera = self.compiled(pool).await?;
+        self.render_issue_with(&tera, recipient, unsubscribe_url, issue)
+            .context("Failed to render the issue template.")
+    }
+
+    /// The merged templates, compiled. The overrides are the cache key, so
+    /// an admin saving a template invalidates the cache of every process
+    /// sending emails, not only the one serving the admin.
+    async fn compiled(&self, pool: &PgPool) -> Result<Arc<Tera>, anyhow::Error> {
+        let overrides = self.overrides(pool).await?;
+        let mut compiled = self.compiled.lock().unwrap();
+        if let Some(compiled) = compiled.as_ref() {
+            if compiled.overrides == overrides {
+                return Ok(compiled.tera.clone());
+            }
+        }
+        let tera = Arc::new(
+            compile(&self.merged(&overrides)).context("Failed to compile the email templates.")?,
+        );
+        *compiled = Some(Compiled {
+            overrides,
+            tera: tera.clone(),
+        });
+        Ok(tera)
+    }
+
+    fn render_with(
+        &self,
+        tera: &Tera,
+        recipient: &Recipient,
+        email: &Email,
+    ) -> Result<RenderedEmail, tera::Error> {
+        let mut context = tera::Context::new();
+        context.insert("subscriber", recipient);
+        context.insert("list", &self.list);
+        email.insert_variables(&mut context);
+        let name = email.kind().name();
+        let subject = tera.render(&format!("{}.subject", name), &context)?;
+        Ok(RenderedEmail {
+            subject: subject.split_whitespace().collect::<Vec<_>>().join(" "),
+            html: tera.render(&format!("{}.html", name), &context)?,
+            text: tera.render(&format!("{}.txt", name), &context)?,
+        })
+    }
+
+    fn render_issue_with(
+        &self,
+        tera: &Tera,
+        recipient: &Recipient,
+        unsubscribe_url: Option<&str>,
+        issue: RenderedEmail,
+    ) -> Result<RenderedEmail, tera::Error> {
+        let mut context = tera::Context::new();
+        context.insert("subscriber", recipient);
+        context.insert("list", &self.list);
+        if let Some(unsubscribe_url) = unsubscribe_url {
+            context.insert("unsubscribe_url", unsubscribe_url);
+        }
+        context.insert("content", &issue.html);
+        let html = tera.render(&format!("{}.html", ISSUE_TEMPLATE), &context)?;
+        context.insert("content", &issue.text);
+        let text = tera.render(&format!("{}.txt", ISSUE_TEMPLATE), &context)?;
+        Ok(RenderedEmail {
+            subject: issue.subject,
+            html,
+            text,
+        })
+    }
+}
+
+fn read_template(path: &Path) -> Result<String, anyhow::Error> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the email template {}.", path.display()))
+}
+
+/// Registers every template as `NAME.html`, `NAME.txt` and, for emails,
+/// `NAME.subject`. Only the HTML bodies are escaped.
+fn compile(templates: &BTreeMap<String, TemplateSource>) -> Result<Tera, tera::Error> {
+    let mut tera = Tera::default();
+    tera.autoescape_on(vec![".html"]);
+    tera.set_escape_fn(escape_html);
+    let mut sources = Vec::new();
+    for (name, template) in templates {
+        sources.push((format!("{}.html", name), template.html_body.as_str()));
+        sources.push((format!("{}.txt", name), template.text_body.as_str()));
+        if let Some(subject) = &template.subject {
+            sources.push((format!("{}.subject", name), subject.as_str()));
+        }
+    }
+    tera.add_raw_templates(sources)?;
+    Ok(tera)
+}
+
+/// Tera's own escaping, except for `/`, which does not need it and would
+/// garble every link.
+pub(crate) fn escape_html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#x27;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_templates::{
+        Email, EmailKind, EmailTemplates, ListDetails, Recipient, TemplateSource,
+    };
+    use tera::Tera;
+
+    fn templates() -> EmailTemplates {
+        EmailTemplates::load(
+            "templates/emails",
+            ListDetails {
+                name: "Dispatch".into(),
+                physical_address: "1 Main Street, Springfield".into(),
+            },
+        )
+        .unwrap()
+    }
+
+    fn recipient() -> Recipient {
+        Recipient {
+            name: "Ursula <Le Guin>".into(),
+            email: "ursula_le_guin@example.com".into(),
+        }
+    }
+
+    fn with_confirmation(html_body: &str, text_body: &str) -> TemplateSource {
+        TemplateSource {
+            subject: Some("Welcome to {{ list.name }}".into()),
+            html_body: html_body.into(),
+            text_body: text_body.into(),
+        }
+    }
+
+    #[test]
+    fn the_default_templates_render_every_email() {
+        let templates = templates();
+        let tera = super::compile(templates.defaults()).unwrap();
+        for kind in EmailKind::ALL {
+            let email = templates
+                .render_with(&tera, &recipient(), &kind.sample())
+                .unwrap();
+            assert!(!email.subject.is_empty(), "{:?}", kind);
+            assert!(email.html.contains("1 Main Street"), "{:?}", kind);
+            assert!(email.text.contains("1 Main Street"), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn variables_are_escaped_in_html_bodies_only() {
+        let templates = templates();
+        let mut sources = templates.defaults().clone();
+        sources.insert(
+            "confirmation".into(),
+            with_confirmation(
+                "<p>{{ subscriber.name }}</p><a href=\"{{ confirmation_link }}\">",
+                "{{ subscriber.name }}",
+            ),
+        );
+        let tera = super::compile(&sources).unwrap();
+        let email = templates
+            .render_with(
+                &tera,
+                &recipient(),
+                &Email::Confirmation {
+                    confirmation_link: "https://example.com/confirm?a=1&b=2".into(),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(email.subject, "Welcome to Dispatch");
+        assert_eq!(
+            email.html,
+            "<p>Ursula &lt;Le Guin&gt;</p><a href=\"https://example.com/confirm?a=1&amp;b=2\">"
+        );
+        assert_eq!(email.text, "Ursula <Le Guin>");
+    }
+
+    #[test]
+    fn templates_using_variables_their_email_does_not_have_are_rejected() {
+        let templates = templates();
+        let mut sources = templates.defaults().clone();
+        sources.insert(
+            "email_change_notice".into(),
+            with_confirmation("{{ confirmation_link }}", "Hello"),
+        );
+
+        let error = templates.check(&sources).unwrap_err();
+        assert!(error.to_string().contains("confirmation_link"), "{}", error);
+    }
+
+    #[test]
+    fn partials_and_layouts_are_checked_through_the_emails_using_them() {
+        let templates = templates();
+        let mut sources = templates.defaults().clone();
+        templates.check(&sources).unwrap();
+
+        sources.insert(
+            "footer".into(),
+            TemplateSource {
+                subject: None,
+                html_body: "{{ list.nmae }}".into(),
+                text_body: "".into(),
+            },
+        );
+        assert!(templates.check(&sources).is_err());
+
+        sources.remove("footer");
+        let error = templates.check(&sources).unwrap_err();
+        assert!(error.to_string().contains("footer"), "{}", error);
+    }
+
+    #[test]
+    fn emails_without_a_subject_are_rejected() {
+        let templates = templates();
+        let mut sources = templates.defaults().clone();
+        sources.get_mut("already_subscribed").unwrap().subject = None;
+
+        assert!(templates.check(&sources).is_err());
+    }
+
+    #[test]
+    fn unsubscribe_blocks_are_skipped_when_there_is_no_link() {
+        let mut tera = Tera::default();
+        tera.add_raw_template(
+            "footer.txt",
+            "{% if unsubscribe_url is defined %}{{ unsubscribe_url }}{% endif %}",
+        )
+        .unwrap();
+
+        let rendered = tera.render("footer.txt", &tera::Context::new()).unwrap();
+
+        assert_eq!(rendered, "");
+    }
+}
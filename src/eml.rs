@@ -0,0 +1,167 @@
+use chrono::{DateTime, Utc};
+
+/// An email with a text and an HTML body, written out as a MIME message that
+/// mail clients can open.
+pub struct Eml<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub html: &'a str,
+    pub text: &'a str,
+}
+
+impl Eml<'_> {
+    pub fn render(&self, date: DateTime<Utc>, boundary: &str) -> String {
+        let mut message = String::new();
+        for (name, value) in [
+            ("From", self.from.to_string()),
+            ("To", self.to.to_string()),
+            ("Subject", encode_header(self.subject)),
+            ("Date", date.to_rfc2822()),
+            ("MIME-Version", "1.0".to_string()),
+            (
+                "Content-Type",
+                format!("multipart/alternative; boundary=\"{}\"", boundary),
+            ),
+        ] {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        for (content_type, body) in [("text/plain", self.text), ("text/html", self.html)] {
+            message.push_str(&format!(
+                "\r\n--{}\r\n\
+                 Content-Type: {}; charset=utf-8\r\n\
+                 Content-Transfer-Encoding: quoted-printable\r\n\r\n{}",
+                boundary,
+                content_type,
+                quoted_printable(body)
+            ));
+        }
+        message.push_str(&format!("\r\n--{}--\r\n", boundary));
+        message
+    }
+}
+
+/// Uses RFC 2047 encoded words for headers that are not plain ASCII.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) && !value.contains("=?") {
+        return value.to_string();
+    }
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let encoded = match c {
+            ' ' => "_".to_string(),
+            c if c.is_ascii_alphanumeric() || "!*+-/".contains(c) => c.to_string(),
+            c => c
+                .to_string()
+                .bytes()
+                .map(|b| format!("={:02X}", b))
+                .collect(),
+        };
+        // Encoded words are at most 75 characters, `=?UTF-8?Q?` and `?=`
+        // included.
+        if word.len() + encoded.len() > 63 {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+    words
+        .iter()
+        .map(|word| format!("=?UTF-8?Q?{}?=", word))
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+/// Encodes `body` as quoted-printable, with CRLF line endings and lines of
+/// at most 76 characters.
+fn quoted_printable(body: &str) -> String {
+    let mut out = String::with_capacity(body.len() * 11 / 10);
+    for (i, line) in body.split('\n').enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let mut width = 0;
+        let bytes = line.as_bytes();
+        for (j, &byte) in bytes.iter().enumerate() {
+            let is_last = j + 1 == bytes.len();
+            let encoded = match byte {
+                b' ' | b'\t' if !is_last => char::from(byte).to_string(),
+                b'!'..=b'~' if byte != b'=' => char::from(byte).to_string(),
+                _ => format!("={:02X}", byte),
+            };
+            // Leaves room for the `=` of a soft line break.
+            let limit = if is_last { 76 } else { 75 };
+            if width + encoded.len() > limit {
+                out.push_str("=\r\n");
+                width = 0;
+            }
+            out.push_str(&encoded);
+            width += encoded.len();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::eml::{encode_header, quoted_printable, Eml};
+
+    #[test]
+    fn messages_have_a_text_and_an_html_part() {
+        let eml = Eml {
+            from: "newsletter@example.com",
+            to: "ursula@example.com",
+            subject: "Issue #1",
+            html: "<p>Hi</p>",
+            text: "Hi\n",
+        }
+        .render(
+            chrono::Utc.with_ymd_and_hms(2023, 4, 9, 10, 0, 0).unwrap(),
+            "b",
+        );
+        assert_eq!(
+            eml,
+            "From: newsletter@example.com\r\n\
+             To: ursula@example.com\r\n\
+             Subject: Issue #1\r\n\
+             Date: Sun, 9 Apr 2023 10:00:00 +0000\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+             \r\n--b\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+             Hi\r\n\
+             \r\n--b\r\n\
+             Content-Type: text/html; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+             <p>Hi</p>\r\n\
+             --b--\r\n"
+        );
+    }
+
+    #[test]
+    fn non_ascii_subjects_are_encoded() {
+        assert_eq!(
+            encode_header("Plain [TEST] subject"),
+            "Plain [TEST] subject"
+        );
+        assert_eq!(encode_header("Café = ok"), "=?UTF-8?Q?Caf=C3=A9_=3D_ok?=");
+        let long = encode_header(&"é".repeat(20));
+        assert!(long.split("\r\n ").all(|word| word.len() <= 75), "{}", long);
+    }
+
+    #[test]
+    fn bodies_are_quoted_printable() {
+        assert_eq!(
+            quoted_printable("a=b café \nend \t"),
+            "a=3Db caf=C3=A9=20\r\nend =09"
+        );
+        let long = quoted_printable(&"x".repeat(200));
+        assert!(long.split("\r\n").all(|line| line.len() <= 76), "{}", long);
+        assert_eq!(long.replace("=\r\n", ""), "x".repeat(200));
+    }
+}
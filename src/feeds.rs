@@ -0,0 +1,181 @@
+use atom_syndication::{Content, Entry, Feed, Link, Text};
+use chrono::{DateTime, TimeZone, Utc};
+use rss::{Channel, Guid, Item};
+use uuid::Uuid;
+
+/// What a feed is about.
+pub struct FeedDetails<'a> {
+    pub title: &'a str,
+    /// Where the issues can be read on the web.
+    pub archive_url: &'a str,
+    /// Where the feed itself is served.
+    pub feed_url: &'a str,
+}
+
+/// An archived issue, newest first in feeds.
+pub struct FeedEntry {
+    pub id: Uuid,
+    pub title: String,
+    pub url: String,
+    /// The full, sanitised HTML body.
+    pub html: String,
+    pub published_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl FeedEntry {
+    /// Stays the same when the issue's title or slug do not, so readers do
+    /// not show it as new again.
+    fn guid(&self) -> String {
+        format!("urn:uuid:{}", self.id)
+    }
+}
+
+pub fn rss(details: &FeedDetails, entries: &[FeedEntry]) -> String {
+    let channel = Channel {
+        title: details.title.to_string(),
+        link: details.archive_url.to_string(),
+        description: format!("The latest issues of {}.", details.title),
+        last_build_date: last_updated(entries).map(|updated| updated.to_rfc2822()),
+        items: entries
+            .iter()
+            .map(|entry| Item {
+                title: Some(entry.title.clone()),
+                link: Some(entry.url.clone()),
+                description: Some(entry.html.clone()),
+                guid: Some(Guid {
+                    value: entry.guid(),
+                    permalink: false,
+                }),
+                pub_date: Some(entry.published_at.to_rfc2822()),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    channel.to_string()
+}
+
+pub fn atom(details: &FeedDetails, entries: &[FeedEntry]) -> String {
+    let link = |href: &str, rel: &str| Link {
+        href: href.to_string(),
+        rel: rel.to_string(),
+        ..Default::default()
+    };
+    let feed = Feed {
+        title: Text::plain(details.title),
+        id: details.feed_url.to_string(),
+        // Feeds must have a date, even without entries.
+        updated: last_updated(entries)
+            .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap())
+            .into(),
+        links: vec![
+            link(details.archive_url, "alternate"),
+            link(details.feed_url, "self"),
+        ],
+        entries: entries
+            .iter()
+            .map(|entry| Entry {
+                title: Text::plain(entry.title.as_str()),
+                id: entry.guid(),
+                updated: entry.updated_at.into(),
+                published: Some(entry.published_at.into()),
+                links: vec![link(&entry.url, "alternate")],
+                content: Some(Content {
+                    value: Some(entry.html.clone()),
+                    content_type: Some("html".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    feed.to_string()
+}
+
+fn last_updated(entries: &[FeedEntry]) -> Option<DateTime<Utc>> {
+    entries.iter().map(|entry| entry.updated_at).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    use crate::feeds::{atom, rss, FeedDetails, FeedEntry};
+
+    fn details() -> FeedDetails<'static> {
+        FeedDetails {
+            title: "Dispatch",
+            archive_url: "https://example.com/archive",
+            feed_url: "https://example.com/feed",
+        }
+    }
+
+    fn entry() -> FeedEntry {
+        let published_at = chrono::Utc.with_ymd_and_hms(2023, 4, 9, 10, 0, 0).unwrap();
+        FeedEntry {
+            id: Uuid::parse_str("6f1c1c9e-7c59-4c3a-9a53-0a3e2ef6f6b1").unwrap(),
+            title: "Issue #1 & more".into(),
+            url: "https://example.com/archive/issue-1".into(),
+            html: "<p>Hello</p>".into(),
+            published_at,
+            updated_at: published_at + chrono::Duration::minutes(5),
+        }
+    }
+
+    #[test]
+    fn rss_items_carry_the_html_a_guid_and_dates() {
+        let xml = rss(&details(), &[entry()]);
+        let channel = xml.parse::<::rss::Channel>().unwrap();
+        assert_eq!(channel.title, "Dispatch");
+        assert_eq!(
+            channel.last_build_date.as_deref(),
+            Some("Sun, 9 Apr 2023 10:05:00 +0000")
+        );
+        let item = &channel.items[0];
+        assert_eq!(item.title.as_deref(), Some("Issue #1 & more"));
+        assert_eq!(item.description.as_deref(), Some("<p>Hello</p>"));
+        assert_eq!(
+            item.pub_date.as_deref(),
+            Some("Sun, 9 Apr 2023 10:00:00 +0000")
+        );
+        let guid = item.guid.as_ref().unwrap();
+        assert_eq!(guid.value, "urn:uuid:6f1c1c9e-7c59-4c3a-9a53-0a3e2ef6f6b1");
+        assert!(!guid.permalink);
+    }
+
+    #[test]
+    fn atom_entries_carry_the_html_an_id_and_dates() {
+        let xml = atom(&details(), &[entry()]);
+        let feed = xml.parse::<atom_syndication::Feed>().unwrap();
+        assert_eq!(feed.id, "https://example.com/feed");
+        assert_eq!(feed.updated.to_rfc3339(), "2023-04-09T10:05:00+00:00");
+        let parsed = &feed.entries[0];
+        assert_eq!(parsed.title.value, "Issue #1 & more");
+        assert_eq!(parsed.id, "urn:uuid:6f1c1c9e-7c59-4c3a-9a53-0a3e2ef6f6b1");
+        assert_eq!(
+            parsed.published.unwrap().to_rfc3339(),
+            "2023-04-09T10:00:00+00:00"
+        );
+        assert_eq!(parsed.links[0].href, "https://example.com/archive/issue-1");
+        let content = parsed.content.as_ref().unwrap();
+        assert_eq!(content.value.as_deref(), Some("<p>Hello</p>"));
+        assert_eq!(content.content_type.as_deref(), Some("html"));
+    }
+
+    #[test]
+    fn empty_feeds_are_still_valid() {
+        assert!(rss(&details(), &[])
+            .parse::<::rss::Channel>()
+            .unwrap()
+            .items
+            .is_empty());
+        assert!(atom(&details(), &[])
+            .parse::<atom_syndication::Feed>()
+            .unwrap()
+            .entries
+            .is_empty());
+    }
+}
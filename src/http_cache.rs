@@ -0,0 +1,142 @@
+use actix_web::{
+    http::header::{self, CacheControl, CacheDirective},
+    HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+/// Responds with `body`, or with `304 Not Modified` if the client's copy is
+/// still current according to `If-None-Match` or, failing that,
+/// `If-Modified-Since`.
+///
+/// The `ETag` is a hash of the body, so it changes with anything that goes
+/// into it, e.g. the theme, not just with `last_modified`.
+pub fn conditional_response(
+    request: &HttpRequest,
+    content_type: &str,
+    body: String,
+    last_modified: Option<DateTime<Utc>>,
+) -> HttpResponse {
+    let etag = format!(
+        "\"{}\"",
+        &hex::encode(Sha256::digest(body.as_bytes()))[..32]
+    );
+    let not_modified = match request.headers().get(header::IF_NONE_MATCH) {
+        Some(if_none_match) => if_none_match
+            .to_str()
+            .map(|tags| etag_matches(tags, &etag))
+            .unwrap_or(false),
+        None => match (
+            request.headers().get(header::IF_MODIFIED_SINCE),
+            last_modified,
+        ) {
+            (Some(since), Some(last_modified)) => since
+                .to_str()
+                .ok()
+                .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+                .map(|since| last_modified.timestamp() <= since.timestamp())
+                .unwrap_or(false),
+            _ => false,
+        },
+    };
+
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((header::ETAG, etag))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::NoCache,
+        ]));
+    if let Some(last_modified) = last_modified {
+        response.insert_header((
+            header::LAST_MODIFIED,
+            last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ));
+    }
+    if not_modified {
+        response.finish()
+    } else {
+        response
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .body(body)
+    }
+}
+
+/// Whether `etag` is among the comma-separated `tags` of `If-None-Match`,
+/// using the weak comparison GETs call for.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test::TestRequest};
+    use chrono::TimeZone;
+
+    use crate::http_cache::conditional_response;
+
+    fn respond(request: TestRequest) -> actix_web::HttpResponse {
+        let last_modified = chrono::Utc.with_ymd_and_hms(2023, 4, 9, 10, 0, 0).unwrap();
+        conditional_response(
+            &request.to_http_request(),
+            "text/html; charset=utf-8",
+            "<p>Hi</p>".into(),
+            Some(last_modified),
+        )
+    }
+
+    fn etag() -> String {
+        respond(TestRequest::default())
+            .headers()
+            .get(header::ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn responses_carry_validators() {
+        let response = respond(TestRequest::default());
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(
+            response.headers().get(header::LAST_MODIFIED).unwrap(),
+            "Sun, 09 Apr 2023 10:00:00 GMT"
+        );
+        assert!(etag().starts_with('"'));
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        for if_none_match in [etag(), format!("\"other\", W/{}", etag()), "*".into()] {
+            let request =
+                TestRequest::default().insert_header((header::IF_NONE_MATCH, if_none_match));
+            assert_eq!(respond(request).status().as_u16(), 304);
+        }
+        let request = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .insert_header((header::IF_MODIFIED_SINCE, "Sun, 09 Apr 2023 10:00:00 GMT"));
+        assert_eq!(respond(request).status().as_u16(), 200);
+    }
+
+    #[test]
+    fn if_modified_since_is_compared_to_the_last_modification() {
+        for (since, status) in [
+            ("Sun, 09 Apr 2023 10:00:00 GMT", 304),
+            ("Mon, 10 Apr 2023 10:00:00 GMT", 304),
+            ("Sun, 09 Apr 2023 09:59:59 GMT", 200),
+            ("not a date", 200),
+        ] {
+            let request = TestRequest::default().insert_header((header::IF_MODIFIED_SINCE, since));
+            assert_eq!(respond(request).status().as_u16(), status, "{}", since);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{
+    configuration::{IssuesSettings, Settings},
+    domain::{IssueSlug, SegmentFilter},
+    merge_tags::{Format, Personalizer, Subscriber},
+    routes::load_attribute_schema,
+    segments::push_filter,
+    startup::get_connection_pool,
+};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Key of the advisory lock held by whichever replica is running a pass.
+const SCHEDULER_LOCK_ID: i64 = 0x6973_7375_6573;
+
+pub async fn run_scheduler_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    scheduler_loop(pool, configuration.issues).await
+}
+
+async fn scheduler_loop(pool: PgPool, settings: IssuesSettings) -> Result<(), anyhow::Error> {
+    loop {
+        if let Err(e) = publish_due_issues(&pool).await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to publish scheduled issues."
+            );
+        }
+        tokio::time::sleep(settings.check_interval()).await;
+    }
+}
+
+/// What one pass of the issue scheduler did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PublishReport {
+    /// Another replica was running a pass, so this one did nothing.
+    pub lock_held_elsewhere: bool,
+    pub issues_started: u64,
+    pub deliveries_queued: u64,
+    /// Issues sent back to `approved` because their segment no longer parses.
+    pub issues_failed: u64,
+    pub issues_sent: u64,
+}
+
+/// Queues a delivery per recipient of every scheduled issue whose
+/// `publish_at` has passed, moving it to `sending`, and marks issues whose
+/// deliveries have all gone out as `sent`.
+///
+/// The pass runs in one transaction holding an advisory lock, so replicas
+/// never queue an issue twice and a crash queues nothing.
+#[tracing::instrument(skip_all)]
+pub async fn publish_due_issues(pool: &PgPool) -> Result<PublishReport, anyhow::Error> {
+    let mut report = PublishReport::default();
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    let locked = sqlx::query!(
+        r#"SELECT pg_try_advisory_xact_lock($1) AS "locked!""#,
+        SCHEDULER_LOCK_ID
+    )
+    .fetch_one(&mut tx)
+    .await
+    .context("Failed to try the scheduler lock.")?
+    .locked;
+    if !locked {
+        report.lock_held_elsewhere = true;
+        return Ok(report);
+    }
+
+    let due = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, title, html_content, text_content, segment_id
+        FROM newsletter_issues
+        WHERE status = 'scheduled' AND publish_at <= now()
+        ORDER BY publish_at
+        FOR UPDATE
+        "#
+    )
+    .fetch_all(&mut tx)
+    .await
+    .context("Failed to fetch the due issues.")?;
+    for issue in due {
+        let filter = match issue.segment_id {
+            None => None,
+            Some(segment_id) => match segment_filter(pool, segment_id).await {
+                Ok(filter) => Some(filter),
+                Err(e) => {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        newsletter_issue_id = %issue.newsletter_issue_id,
+                        "The issue's segment is no longer valid, sending it back to approved."
+                    );
+                    set_status(&mut tx, issue.newsletter_issue_id, "approved").await?;
+                    report.issues_failed += 1;
+                    continue;
+                }
+            },
+        };
+        let slug = unique_slug(&mut tx, &issue.title).await?;
+        // `gen_random_uuid` needs pgcrypto on Postgres 12, which the
+        // manage token migration enables.
+        let mut builder = QueryBuilder::new(
+            "INSERT INTO delivery_queue \
+            (id, subscriber_id, subject, html_content, text_content, newsletter_issue_id) \
+            SELECT gen_random_uuid(), s.id, ",
+        );
+        builder
+            .push_bind(issue.title)
+            .push(", ")
+            .push_bind(issue.html_content)
+            .push(", ")
+            .push_bind(issue.text_content)
+            .push(", ")
+            .push_bind(issue.newsletter_issue_id)
+            .push(" FROM subscriptions s WHERE s.status = 'confirmed' AND ");
+        match &filter {
+            Some(filter) => push_filter(&mut builder, filter),
+            None => {
+                builder.push("TRUE");
+            }
+        }
+        report.deliveries_queued += builder
+            .build()
+            .execute(&mut tx)
+            .await
+            .context("Failed to queue the issue deliveries.")?
+            .rows_affected();
+        sqlx::query!(
+            r#"
+            UPDATE newsletter_issues
+            SET status = 'sending', published_at = now(), slug = $2,
+                version = version + 1, updated_at = now()
+            WHERE newsletter_issue_id = $1
+            "#,
+            issue.newsletter_issue_id,
+            slug.as_ref()
+        )
+        .execute(&mut tx)
+        .await
+        .context("Failed to mark the issue as sending.")?;
+        report.issues_started += 1;
+    }
+
+    report.issues_sent = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues i
+        SET status = 'sent', version = version + 1, updated_at = now()
+        WHERE status = 'sending' AND NOT EXISTS (
+            SELECT 1 FROM delivery_queue q
+            WHERE q.newsletter_issue_id = i.newsletter_issue_id
+        )
+        "#
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to mark issues as sent.")?
+    .rows_affected();
+    tx.commit().await.context("Failed to commit transaction.")?;
+    tracing::info!(
+        issues_started = report.issues_started,
+        deliveries_queued = report.deliveries_queued,
+        issues_failed = report.issues_failed,
+        issues_sent = report.issues_sent,
+        "Published scheduled issues."
+    );
+    Ok(report)
+}
+
+/// The title's slug, numbered if an archived issue already has it.
+async fn unique_slug(
+    tx: &mut Transaction<'_, Postgres>,
+    title: &str,
+) -> Result<IssueSlug, anyhow::Error> {
+    // Leaves out merge tags, e.g. the reader's name.
+    let title = Personalizer::new("", HashMap::new()).personalize(
+        title,
+        &Subscriber::anonymous(),
+        Format::Text,
+    );
+    let slug = IssueSlug::from_title(&title);
+    let taken: Vec<String> = sqlx::query_scalar!(
+        r#"
+        SELECT slug AS "slug!" FROM newsletter_issues
+        WHERE slug = $1 OR slug LIKE $1 || '-%'
+        "#,
+        slug.as_ref()
+    )
+    .fetch_all(tx)
+    .await
+    .context("Failed to look up the slugs in use.")?;
+    if !taken.iter().any(|t| t == slug.as_ref()) {
+        return Ok(slug);
+    }
+    Ok((2..)
+        .map(|number| slug.with_number(number))
+        .find(|numbered| !taken.iter().any(|t| t == numbered.as_ref()))
+        .expect("There are fewer taken slugs than numbers."))
+}
+
+async fn segment_filter(pool: &PgPool, segment_id: Uuid) -> Result<SegmentFilter, anyhow::Error> {
+    let segment = sqlx::query!(r#"SELECT filter FROM segments WHERE id = $1"#, segment_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to fetch the issue's segment.")?;
+    let schema = load_attribute_schema(pool).await?;
+    Ok(SegmentFilter::parse(&segment.filter, &schema)?)
+}
+
+async fn set_status(
+    tx: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    status: &str,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, version = version + 1, updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        status
+    )
+    .execute(tx)
+    .await
+    .context("Failed to update the issue status.")?;
+    Ok(())
+}
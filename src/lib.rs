@@ -1,7 +1,26 @@
 //! src/lib.rs
+pub mod authentication;
+pub mod bot_protection;
+pub mod canonical_emails;
 pub mod configuration;
+pub mod delivery_queue;
 pub mod domain;
+pub mod domain_checker;
 pub mod email_client;
+pub mod email_rendering;
+pub mod email_templates;
+pub mod eml;
+pub mod feeds;
+pub mod http_cache;
+pub mod issue_scheduler;
+pub mod markdown;
+pub mod merge_tags;
+pub mod pages;
+pub mod pending_subscriptions_worker;
+pub mod rate_limit;
 pub mod routes;
+pub mod rss_to_email;
+pub mod segments;
+pub mod sequences;
 pub mod startup;
 pub mod telemetry;
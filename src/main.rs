@@ -1,17 +1,66 @@
+use std::fmt::{Debug, Display};
+use tokio::task::JoinError;
 use zero2prod::{
     configuration::get_configuration,
+    delivery_queue, issue_scheduler, pending_subscriptions_worker, rss_to_email, sequences,
     startup::Application,
     telemetry::{get_subscriber, init_subscriber},
 };
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> anyhow::Result<()> {
     let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
     init_subscriber(subscriber);
 
     let configuration = get_configuration().expect("Failed to read configuration.");
     let application = Application::build(configuration.clone()).await?;
-    application.run_until_stopped().await?;
+    let application_task = tokio::spawn(application.run_until_stopped());
+    let pending_subscriptions_task = tokio::spawn(
+        pending_subscriptions_worker::run_worker_until_stopped(configuration.clone()),
+    );
+    let sequences_task = tokio::spawn(sequences::run_scheduler_until_stopped(
+        configuration.clone(),
+    ));
+    let issues_task = tokio::spawn(issue_scheduler::run_scheduler_until_stopped(
+        configuration.clone(),
+    ));
+    let rss_to_email_task = tokio::spawn(rss_to_email::run_worker_until_stopped(
+        configuration.clone(),
+    ));
+    let delivery_task = tokio::spawn(delivery_queue::run_worker_until_stopped(configuration));
+
+    tokio::select! {
+        o = application_task => report_exit("API", o),
+        o = pending_subscriptions_task => report_exit("Pending subscriptions worker", o),
+        o = sequences_task => report_exit("Sequence scheduler", o),
+        o = issues_task => report_exit("Issue scheduler", o),
+        o = rss_to_email_task => report_exit("RSS-to-email worker", o),
+        o = delivery_task => report_exit("Delivery queue worker", o),
+    };
 
     Ok(())
 }
+
+fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+    match outcome {
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name)
+        }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} failed",
+                task_name
+            )
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{}' task failed to complete",
+                task_name
+            )
+        }
+    }
+}
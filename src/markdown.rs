@@ -0,0 +1,400 @@
+use pulldown_cmark::{html, CowStr, Event, HeadingLevel, Options, Parser, Tag};
+
+/// The two bodies of an email written in Markdown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedMarkdown {
+    pub html: String,
+    pub text: String,
+}
+
+/// Renders CommonMark into an HTML body and a plain-text body.
+///
+/// Raw HTML in the source is escaped rather than passed through, and links or
+/// images with a scheme other than http, https or mailto lose their target.
+pub fn render(markdown: &str) -> RenderedMarkdown {
+    RenderedMarkdown {
+        html: render_html(markdown),
+        text: render_text(markdown),
+    }
+}
+
+fn render_html(markdown: &str) -> String {
+    let events = Parser::new_ext(markdown, Options::empty()).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Event::Start(Tag::Link(kind, safe_url(url), title))
+        }
+        Event::End(Tag::Link(kind, url, title)) => {
+            Event::End(Tag::Link(kind, safe_url(url), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Event::Start(Tag::Image(kind, safe_url(url), title))
+        }
+        Event::End(Tag::Image(kind, url, title)) => {
+            Event::End(Tag::Image(kind, safe_url(url), title))
+        }
+        event => event,
+    });
+    let mut out = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut out, events);
+    restore_merge_tags(&out)
+}
+
+/// Undoes the percent-encoding of merge tags used as link targets, e.g.
+/// `[Unsubscribe](<{{ unsubscribe_url }}>)`.
+fn restore_merge_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("%7B%7B") {
+        let tag = &rest[start + 6..];
+        match tag.find("%7D%7D") {
+            Some(end) if !tag[..end].contains(['"', '<', '>']) => {
+                out.push_str(&rest[..start]);
+                out.push_str("{{");
+                out.push_str(&tag[..end].replace("%20", " "));
+                out.push_str("}}");
+                rest = &tag[end + 6..];
+            }
+            _ => {
+                out.push_str(&rest[..start + 6]);
+                rest = tag;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    let has_unsafe_scheme = match url.split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => !matches!(
+            scheme.to_ascii_lowercase().as_str(),
+            "http" | "https" | "mailto"
+        ),
+        _ => false,
+    };
+    if has_unsafe_scheme {
+        CowStr::Borrowed("")
+    } else {
+        url
+    }
+}
+
+fn render_text(markdown: &str) -> String {
+    let mut writer = TextWriter {
+        at_line_start: true,
+        ..Default::default()
+    };
+    for event in Parser::new_ext(markdown, Options::empty()) {
+        writer.event(event);
+    }
+    let mut text = writer.out.trim_end().to_string();
+    text.push('\n');
+    text
+}
+
+/// Lays Markdown out as plain text the way people write it in emails:
+/// underlined headings, `-` and `1.` lists, `>` quotes, indented code, and
+/// link targets in parentheses after the link text.
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+    /// What each line in the current containers starts with.
+    indents: Vec<String>,
+    /// Replaces the indent at the given depth on the next line, e.g. a list
+    /// item's bullet.
+    marker: Option<(usize, String)>,
+    /// The next number of each open list, `None` for bullet lists.
+    lists: Vec<Option<u64>>,
+    /// Where the text of each open heading, link or image starts in `out`.
+    spans: Vec<usize>,
+    at_line_start: bool,
+    needs_blank_line: bool,
+}
+
+impl TextWriter {
+    fn event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) | Event::Code(text) | Event::Html(text) => self.push(&text),
+            Event::SoftBreak | Event::HardBreak => self.end_line(),
+            Event::Rule => {
+                self.start_block();
+                self.push("----");
+                self.end_block();
+            }
+            Event::TaskListMarker(checked) => self.push(if checked { "[x] " } else { "[ ] " }),
+            Event::FootnoteReference(_) => {}
+        }
+    }
+
+    fn start(&mut self, tag: Tag<'_>) {
+        match tag {
+            Tag::Paragraph | Tag::Heading(..) => {
+                self.start_block();
+                // Writes the line prefix, so the span starts at the text.
+                self.push("");
+                self.spans.push(self.out.len());
+            }
+            Tag::BlockQuote => {
+                self.start_block();
+                self.indents.push("> ".into());
+            }
+            Tag::CodeBlock(_) => {
+                self.start_block();
+                self.indents.push("    ".into());
+            }
+            Tag::List(first) => {
+                if self.marker.is_none() && !self.at_line_start && !self.lists.is_empty() {
+                    // A list nested in a tight list item.
+                    self.end_line();
+                } else {
+                    self.start_block();
+                }
+                self.lists.push(first);
+            }
+            Tag::Item => {
+                if !self.at_line_start {
+                    self.end_line();
+                }
+                self.needs_blank_line = false;
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.indents.push(" ".repeat(marker.len()));
+                self.marker = Some((self.indents.len() - 1, marker));
+            }
+            Tag::Strong => self.push("*"),
+            Tag::Emphasis => self.push("_"),
+            Tag::Link(..) => {
+                self.push("");
+                self.spans.push(self.out.len());
+            }
+            Tag::Image(..) => {
+                self.push("[");
+                self.spans.push(self.out.len());
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: Tag<'_>) {
+        match tag {
+            Tag::Paragraph => {
+                self.spans.pop();
+                self.end_block();
+            }
+            Tag::Heading(level, ..) => {
+                let start = self.spans.pop().unwrap_or(self.out.len());
+                let width = self.out[start..].chars().count();
+                match level {
+                    HeadingLevel::H1 | HeadingLevel::H2 => {
+                        let underline = if level == HeadingLevel::H1 { "=" } else { "-" };
+                        self.end_line();
+                        self.push(&underline.repeat(width));
+                    }
+                    _ => {
+                        let hashes = "#".repeat(level as usize);
+                        self.out.insert_str(start, &format!("{} ", hashes));
+                    }
+                }
+                self.end_block();
+            }
+            Tag::BlockQuote | Tag::CodeBlock(_) => {
+                self.indents.pop();
+                self.end_block();
+            }
+            Tag::List(_) => {
+                self.lists.pop();
+                self.end_block();
+            }
+            Tag::Item => {
+                if self.marker.is_some() {
+                    // An empty item still gets its bullet.
+                    self.push("");
+                }
+                self.indents.pop();
+                if !self.at_line_start {
+                    self.end_line();
+                }
+            }
+            Tag::Strong => self.push("*"),
+            Tag::Emphasis => self.push("_"),
+            Tag::Link(_, url, _) => {
+                let start = self.spans.pop().unwrap_or(self.out.len());
+                let text = &self.out[start..];
+                let shows_url = text == url.as_ref() || Some(text) == url.strip_prefix("mailto:");
+                if !shows_url && !safe_url(url.clone()).is_empty() {
+                    self.push(&format!(" ({})", url));
+                }
+            }
+            Tag::Image(_, url, _) => {
+                let start = self.spans.pop().unwrap_or(self.out.len());
+                if self.out.len() == start {
+                    self.push("image");
+                }
+                self.push("]");
+                if !safe_url(url.clone()).is_empty() {
+                    self.push(&format!(" ({})", url));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes `text`, starting every line with the current prefix.
+    fn push(&mut self, text: &str) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.end_line();
+                if line.is_empty() {
+                    continue;
+                }
+            }
+            if self.at_line_start {
+                let prefix = self.line_prefix();
+                self.out.push_str(&prefix);
+                self.at_line_start = false;
+            }
+            self.out.push_str(line);
+        }
+    }
+
+    fn line_prefix(&mut self) -> String {
+        let marker = self.marker.take();
+        self.indents
+            .iter()
+            .enumerate()
+            .map(|(depth, indent)| match &marker {
+                Some((marker_depth, marker)) if *marker_depth == depth => marker.as_str(),
+                _ => indent.as_str(),
+            })
+            .collect()
+    }
+
+    fn end_line(&mut self) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+        self.at_line_start = true;
+    }
+
+    fn start_block(&mut self) {
+        if self.out.is_empty() {
+            return;
+        }
+        if !self.at_line_start {
+            self.end_line();
+        }
+        if self.needs_blank_line {
+            let prefix = self.indents.concat();
+            self.out.push_str(prefix.trim_end());
+            self.out.push('\n');
+        }
+        self.needs_blank_line = false;
+    }
+
+    fn end_block(&mut self) {
+        if !self.at_line_start {
+            self.end_line();
+        }
+        self.needs_blank_line = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::render;
+
+    #[test]
+    fn headings_lists_links_and_images_are_rendered_to_html() {
+        let html = render(
+            "# Issue #1\n\n\
+             Read [the post](https://example.com/post).\n\n\
+             - one\n- two\n\n\
+             ![A cat](https://example.com/cat.png)\n",
+        )
+        .html;
+        assert_eq!(
+            html,
+            "<h1>Issue #1</h1>\n\
+             <p>Read <a href=\"https://example.com/post\">the post</a>.</p>\n\
+             <ul>\n<li>one</li>\n<li>two</li>\n</ul>\n\
+             <p><img src=\"https://example.com/cat.png\" alt=\"A cat\" /></p>\n"
+        );
+    }
+
+    #[test]
+    fn raw_html_and_script_links_do_not_reach_the_email() {
+        let html = render(
+            "<script>alert(1)</script>\n\n\
+             [click](javascript:alert(1)) [mail](mailto:a@example.com)\n",
+        )
+        .html;
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(html.contains("&lt;script&gt;"), "{}", html);
+        assert!(html.contains("<a href=\"\">click</a>"), "{}", html);
+        assert!(
+            html.contains("<a href=\"mailto:a@example.com\">"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn the_text_version_reads_like_a_plain_text_email() {
+        let text = render(
+            "# Issue #1\n\n\
+             Hello **there**, read [the post](https://example.com/post)\n\
+             or <https://example.com>.\n\n\
+             ## Links\n\n\
+             1. First\n2. Second\n   - nested\n\n\
+             > Quoted\n> text\n\n\
+             ```\nlet x = 1;\n```\n\n\
+             ![A cat](https://example.com/cat.png)\n",
+        )
+        .text;
+        assert_eq!(
+            text,
+            "Issue #1\n\
+             ========\n\
+             \n\
+             Hello *there*, read the post (https://example.com/post)\n\
+             or https://example.com.\n\
+             \n\
+             Links\n\
+             -----\n\
+             \n\
+             1. First\n\
+             2. Second\n   \
+             - nested\n\
+             \n\
+             > Quoted\n\
+             > text\n\
+             \n    \
+             let x = 1;\n\
+             \n\
+             [A cat] (https://example.com/cat.png)\n"
+        );
+    }
+
+    #[test]
+    fn merge_tags_can_be_used_as_link_targets() {
+        let html = render("[Unsubscribe](<{{ unsubscribe_url }}>) [a](%7B%7Bb)\n").html;
+        assert_eq!(
+            html,
+            "<p><a href=\"{{ unsubscribe_url }}\">Unsubscribe</a> <a href=\"%7B%7Bb\">a</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn loose_list_items_keep_their_paragraphs_together() {
+        let text = render("- one\n\n  more\n- two\n\n### Done\n").text;
+        assert_eq!(text, "- one\n\n  more\n- two\n\n### Done\n");
+    }
+}
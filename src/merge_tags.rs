@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use crate::domain::AttributeSchema;
+use crate::email_templates::escape_html;
+
+/// What a merge tag stands for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Name,
+    /// The first word of the name.
+    FirstName,
+    Email,
+    /// One of the admin-defined subscriber fields.
+    Attribute(String),
+    UnsubscribeUrl,
+    PreferencesUrl,
+}
+
+impl Field {
+    fn parse(path: &str) -> Option<Self> {
+        match path {
+            "subscriber.name" => Some(Self::Name),
+            "subscriber.first_name" => Some(Self::FirstName),
+            "subscriber.email" => Some(Self::Email),
+            "unsubscribe_url" => Some(Self::UnsubscribeUrl),
+            "preferences_url" => Some(Self::PreferencesUrl),
+            _ => path
+                .strip_prefix("subscriber.attributes.")
+                .filter(|name| !name.is_empty())
+                .map(|name| Self::Attribute(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tag {
+    /// As written, e.g. `subscriber.first_name`, to look up the configured
+    /// fallback.
+    path: String,
+    field: Field,
+    fallback: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Tag(Tag),
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum MergeTagError {
+    #[error("A merge tag is missing its closing `}}}}`.")]
+    Unclosed,
+    #[error("There is no merge tag called `{0}`.")]
+    UnknownTag(String),
+    #[error("`{0}` is not a valid fallback, write it as `default(value=\"...\")`.")]
+    InvalidFallback(String),
+    #[error("There is no subscriber field called `{0}`.")]
+    UnknownAttribute(String),
+}
+
+/// Content with merge tags such as `{{ subscriber.first_name }}` or
+/// `{{ subscriber.attributes.company | default(value="your company") }}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeTemplate(Vec<Part>);
+
+impl MergeTemplate {
+    pub fn parse(source: &str) -> Result<Self, MergeTagError> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or(MergeTagError::Unclosed)?;
+            parts.push(Part::Tag(parse_tag(&after[..end])?));
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self(parts))
+    }
+
+    /// Fails on tags for subscriber fields that are not defined.
+    pub fn check_attributes(&self, schema: &AttributeSchema) -> Result<(), MergeTagError> {
+        for part in &self.0 {
+            if let Part::Tag(Tag {
+                field: Field::Attribute(name),
+                ..
+            }) = part
+            {
+                if schema.field(name).is_none() {
+                    return Err(MergeTagError::UnknownAttribute(name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_tag(inner: &str) -> Result<Tag, MergeTagError> {
+    // Tags in HTML may have had their quotes escaped, e.g. by the Markdown
+    // renderer.
+    let inner = inner
+        .replace("&quot;", "\"")
+        .replace("&#34;", "\"")
+        .replace("&amp;", "&");
+    let (path, filter) = match inner.split_once('|') {
+        Some((path, filter)) => (path.trim(), Some(filter.trim())),
+        None => (inner.trim(), None),
+    };
+    let field = Field::parse(path).ok_or_else(|| MergeTagError::UnknownTag(path.to_string()))?;
+    let fallback = filter
+        .map(|filter| {
+            parse_default(filter).ok_or_else(|| MergeTagError::InvalidFallback(filter.to_string()))
+        })
+        .transpose()?;
+    Ok(Tag {
+        path: path.to_string(),
+        field,
+        fallback,
+    })
+}
+
+/// Parses `default(value="...")`.
+fn parse_default(filter: &str) -> Option<String> {
+    let arguments = filter
+        .strip_prefix("default")?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .trim();
+    let value = arguments
+        .strip_prefix("value")?
+        .trim_start()
+        .strip_prefix('=')?
+        .trim();
+    let value = value.strip_prefix('"')?.strip_suffix('"')?;
+    Some(value.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Values are HTML-escaped.
+    Html,
+    Text,
+}
+
+/// The subscriber an email is personalised for.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub name: String,
+    pub email: String,
+    pub attributes: serde_json::Value,
+    /// Authenticates the subscriber's unsubscribe and preferences links.
+    pub manage_token: String,
+}
+
+impl Subscriber {
+    /// A reader of the web archive, who only gets fallbacks and no links.
+    pub fn anonymous() -> Self {
+        Self {
+            name: String::new(),
+            email: String::new(),
+            attributes: serde_json::json!({}),
+            manage_token: String::new(),
+        }
+    }
+}
+
+/// Where the subscriber with `manage_token` unsubscribes.
+pub fn unsubscribe_link(base_url: &str, manage_token: &str) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        base_url, manage_token
+    )
+}
+
+/// Fills in merge tags for one subscriber at a time.
+#[derive(Debug, Clone)]
+pub struct Personalizer {
+    base_url: String,
+    /// Used for fields a subscriber does not have, keyed by tag, unless the
+    /// tag has a fallback of its own.
+    fallbacks: HashMap<String, String>,
+}
+
+impl Personalizer {
+    pub fn new(base_url: &str, fallbacks: HashMap<String, String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            fallbacks,
+        }
+    }
+
+    pub fn render(
+        &self,
+        template: &MergeTemplate,
+        subscriber: &Subscriber,
+        format: Format,
+    ) -> String {
+        let mut output = String::new();
+        for part in &template.0 {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Tag(tag) => {
+                    let value = self
+                        .value(&tag.field, subscriber)
+                        .or_else(|| tag.fallback.clone())
+                        .or_else(|| self.fallbacks.get(&tag.path).cloned())
+                        .unwrap_or_default();
+                    match format {
+                        Format::Html => output.push_str(&escape_html(&value)),
+                        Format::Text => output.push_str(&value),
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    /// The subscriber's unsubscribe link, if they have a manage token.
+    pub fn unsubscribe_url(&self, subscriber: &Subscriber) -> Option<String> {
+        self.value(&Field::UnsubscribeUrl, subscriber)
+    }
+
+    /// Parses and renders `content` the way it is sent: content with invalid
+    /// merge tags is left as written rather than not sent at all.
+    pub fn personalize(&self, content: &str, subscriber: &Subscriber, format: Format) -> String {
+        match MergeTemplate::parse(content) {
+            Ok(template) => self.render(&template, subscriber, format),
+            Err(e) => {
+                tracing::warn!(error.message = %e, "Leaving content with invalid merge tags as is.");
+                content.to_string()
+            }
+        }
+    }
+
+    fn value(&self, field: &Field, subscriber: &Subscriber) -> Option<String> {
+        let value = match field {
+            Field::Name => subscriber.name.clone(),
+            Field::FirstName => subscriber.name.split_whitespace().next()?.to_string(),
+            Field::Email => subscriber.email.clone(),
+            Field::Attribute(name) => match subscriber.attributes.get(name)? {
+                serde_json::Value::String(value) => value.clone(),
+                serde_json::Value::Null => return None,
+                value => value.to_string(),
+            },
+            Field::UnsubscribeUrl | Field::PreferencesUrl if subscriber.manage_token.is_empty() => {
+                return None
+            }
+            Field::UnsubscribeUrl => unsubscribe_link(&self.base_url, &subscriber.manage_token),
+            Field::PreferencesUrl => format!(
+                "{}/subscriptions/preferences?token={}",
+                self.base_url, subscriber.manage_token
+            ),
+        };
+        Some(value).filter(|value| !value.trim().is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::domain::{AttributeSchema, FieldDefinition, FieldType};
+    use crate::merge_tags::{Format, MergeTagError, MergeTemplate, Personalizer, Subscriber};
+
+    fn subscriber() -> Subscriber {
+        Subscriber {
+            name: "Ursula <Le Guin>".into(),
+            email: "ursula_le_guin@example.com".into(),
+            attributes: serde_json::json!({"company": "Earthsea & Co", "seats": 3}),
+            manage_token: "token".into(),
+        }
+    }
+
+    fn personalizer() -> Personalizer {
+        Personalizer::new(
+            "https://example.com/",
+            HashMap::from([(
+                "subscriber.attributes.city".to_string(),
+                "your city".to_string(),
+            )]),
+        )
+    }
+
+    fn render(source: &str, format: Format) -> String {
+        let template = MergeTemplate::parse(source).unwrap();
+        personalizer().render(&template, &subscriber(), format)
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_only() {
+        let source = "Hi {{ subscriber.first_name }}, {{subscriber.attributes.company}}!";
+
+        assert_eq!(
+            render(source, Format::Html),
+            "Hi Ursula, Earthsea &amp; Co!"
+        );
+        assert_eq!(render(source, Format::Text), "Hi Ursula, Earthsea & Co!");
+    }
+
+    #[test]
+    fn links_are_made_for_each_subscriber() {
+        assert_eq!(
+            render("{{ unsubscribe_url }} {{ preferences_url }}", Format::Text),
+            "https://example.com/subscriptions/unsubscribe?token=token \
+             https://example.com/subscriptions/preferences?token=token"
+        );
+    }
+
+    #[test]
+    fn anonymous_readers_get_fallbacks_and_no_links() {
+        let template = MergeTemplate::parse(
+            "Hi {{ subscriber.first_name | default(value=\"reader\") }}[{{ unsubscribe_url }}]",
+        )
+        .unwrap();
+        assert_eq!(
+            personalizer().render(&template, &Subscriber::anonymous(), Format::Html),
+            "Hi reader[]"
+        );
+    }
+
+    #[test]
+    fn missing_fields_use_the_tag_fallback_then_the_configured_one() {
+        assert_eq!(
+            render(
+                "{{ subscriber.attributes.plan | default(value=\"free\") }}, \
+                 {{ subscriber.attributes.city }}, [{{ subscriber.attributes.team }}], \
+                 {{ subscriber.attributes.seats | default(value=\"none\") }}",
+                Format::Text
+            ),
+            "free, your city, [], 3"
+        );
+    }
+
+    #[test]
+    fn fallbacks_escaped_by_the_markdown_renderer_still_parse() {
+        let html = render(
+            "{{ subscriber.attributes.plan | default(value=&quot;R&amp;D&quot;) }}",
+            Format::Html,
+        );
+        assert_eq!(html, "R&amp;D");
+    }
+
+    #[test]
+    fn malformed_and_unknown_tags_are_rejected() {
+        for (source, expected) in [
+            ("Hi {{ subscriber.name", MergeTagError::Unclosed),
+            (
+                "{{ subscriber.age }}",
+                MergeTagError::UnknownTag("subscriber.age".into()),
+            ),
+            (
+                "{{ subscriber.name | upper }}",
+                MergeTagError::InvalidFallback("upper".into()),
+            ),
+        ] {
+            assert_eq!(MergeTemplate::parse(source), Err(expected), "{}", source);
+        }
+    }
+
+    #[test]
+    fn tags_for_undefined_subscriber_fields_are_rejected() {
+        let schema = AttributeSchema::new(vec![FieldDefinition {
+            name: "company".into(),
+            field_type: FieldType::Text { max_length: None },
+            required: false,
+            signup: false,
+        }]);
+
+        let known = MergeTemplate::parse("{{ subscriber.attributes.company }}").unwrap();
+        let unknown = MergeTemplate::parse("{{ subscriber.attributes.compnay }}").unwrap();
+
+        assert_eq!(known.check_attributes(&schema), Ok(()));
+        assert_eq!(
+            unknown.check_attributes(&schema),
+            Err(MergeTagError::UnknownAttribute("compnay".into()))
+        );
+    }
+}
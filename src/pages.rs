@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use actix_web::{http::header::ContentType, http::StatusCode, HttpResponse};
+use reqwest::Url;
+use tera::Tera;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    CheckInbox,
+    /// Asks the reader to press a button, expects `subscription_token`.
+    ConfirmSubscription,
+    Confirmed,
+    /// Asks the reader to press a button, expects `step` (`confirm` from the
+    /// new address or `approve` from the old one) and `token`.
+    ConfirmEmailChange,
+    EmailChanged,
+    /// One of the two addresses has yet to follow its link.
+    EmailChangePending,
+    LinkExpired,
+    InvalidLink,
+    /// Asks the reader to press a button, expects `token`.
+    Unsubscribe,
+    Unsubscribed,
+    /// Lets the reader change their name, expects `token`, `name` and `email`.
+    Preferences,
+    /// The signup form embedded on other sites by `widget.js`.
+    Widget,
+    /// A page of published issues, expects `issues` and `canonical_url`.
+    Archive,
+    /// A published issue, expects `title`, `published_at`, `published_on`,
+    /// `content`, `canonical_url` and `archive_url`.
+    ArchiveIssue,
+    NotFound,
+}
+
+impl Page {
+    fn template(&self) -> &'static str {
+        match self {
+            Self::CheckInbox => "check_inbox.html",
+            Self::ConfirmSubscription => "confirm_subscription.html",
+            Self::Confirmed => "confirmed.html",
+            Self::ConfirmEmailChange => "confirm_email_change.html",
+            Self::EmailChanged => "email_changed.html",
+            Self::EmailChangePending => "email_change_pending.html",
+            Self::LinkExpired => "link_expired.html",
+            Self::InvalidLink => "invalid_link.html",
+            Self::Unsubscribe => "unsubscribe.html",
+            Self::Unsubscribed => "unsubscribed.html",
+            Self::Preferences => "preferences.html",
+            Self::Widget => "widget.html",
+            Self::Archive => "archive.html",
+            Self::ArchiveIssue => "archive_issue.html",
+            Self::NotFound => "not_found.html",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::CheckInbox
+            | Self::ConfirmSubscription
+            | Self::Confirmed
+            | Self::ConfirmEmailChange
+            | Self::EmailChanged
+            | Self::EmailChangePending
+            | Self::Unsubscribe
+            | Self::Unsubscribed
+            | Self::Preferences
+            | Self::Widget
+            | Self::Archive
+            | Self::ArchiveIssue => StatusCode::OK,
+            Self::LinkExpired => StatusCode::GONE,
+            Self::InvalidLink => StatusCode::UNAUTHORIZED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+/// HTML pages shown to readers. A theme is a directory of templates plus the
+/// variables they get as `theme`, so both the markup and the branding can be
+/// replaced without rebuilding.
+pub struct Pages {
+    tera: Tera,
+    theme: HashMap<String, String>,
+}
+
+impl Pages {
+    pub fn load(templates_path: &str, theme: HashMap<String, String>) -> Result<Self, tera::Error> {
+        let tera = Tera::new(&format!(
+            "{}/**/*.html",
+            templates_path.trim_end_matches('/')
+        ))?;
+        Ok(Self { tera, theme })
+    }
+
+    pub fn render(&self, page: Page) -> HttpResponse {
+        self.render_with(page, tera::Context::new())
+    }
+
+    /// Renders `page` with page-specific variables on top of the theme.
+    pub fn render_with(&self, page: Page, context: tera::Context) -> HttpResponse {
+        match self.render_to_string(page, context) {
+            Ok(body) => HttpResponse::build(page.status())
+                .insert_header(ContentType::html())
+                .body(body),
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, "Failed to render {:?} page.", page);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    /// Renders just the body, for responses that need it first, e.g. to
+    /// compute an `ETag`.
+    pub fn render_to_string(
+        &self,
+        page: Page,
+        mut context: tera::Context,
+    ) -> Result<String, tera::Error> {
+        context.insert("theme", &self.theme);
+        self.tera.render(page.template(), &context)
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Redirecting to {0} is not allowed.")]
+pub struct RedirectNotAllowed(pub String);
+
+/// Origins that forms may ask us to send readers back to after submitting.
+#[derive(Debug, Default)]
+pub struct RedirectAllowlist {
+    origins: Vec<String>,
+}
+
+impl RedirectAllowlist {
+    /// Entries that are not valid URLs are dropped.
+    pub fn new(origins: &[String]) -> Self {
+        Self {
+            origins: origins
+                .iter()
+                .filter_map(|origin| Url::parse(origin).ok())
+                .map(|url| url.origin().ascii_serialization())
+                .collect(),
+        }
+    }
+
+    pub fn check(&self, redirect_to: &str) -> Result<Url, RedirectNotAllowed> {
+        let not_allowed = || RedirectNotAllowed(redirect_to.to_string());
+        let url = Url::parse(redirect_to).map_err(|_| not_allowed())?;
+        let origin = url.origin();
+        // Opaque origins, e.g. of `data:` or `javascript:` URLs, are never allowed.
+        if origin.is_tuple() && self.origins.contains(&origin.ascii_serialization()) {
+            Ok(url)
+        } else {
+            Err(not_allowed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::pages::{Page, Pages, RedirectAllowlist};
+
+    fn allowlist() -> RedirectAllowlist {
+        RedirectAllowlist::new(&["https://example.com/".into(), "not a url".into()])
+    }
+
+    #[test]
+    fn redirects_to_an_allowed_origin_are_accepted() {
+        let url = allowlist()
+            .check("https://example.com/thanks?from=newsletter")
+            .unwrap();
+        assert_eq!(url.as_str(), "https://example.com/thanks?from=newsletter");
+    }
+
+    #[test]
+    fn redirects_to_other_origins_are_rejected() {
+        for redirect_to in [
+            "https://evil.com/thanks",
+            "http://example.com/thanks",
+            "https://example.com:8443/thanks",
+            "https://example.com.evil.com/",
+            "//evil.com/thanks",
+            "/thanks",
+            "javascript:alert(1)",
+        ] {
+            assert!(allowlist().check(redirect_to).is_err(), "{}", redirect_to);
+        }
+    }
+
+    #[test]
+    fn every_page_renders_with_the_default_templates() {
+        let theme = HashMap::from([("site_name".to_string(), "Dispatch".to_string())]);
+        let pages = Pages::load("templates/pages", theme).unwrap();
+        for page in [
+            Page::CheckInbox,
+            Page::ConfirmSubscription,
+            Page::Confirmed,
+            Page::ConfirmEmailChange,
+            Page::EmailChanged,
+            Page::EmailChangePending,
+            Page::LinkExpired,
+            Page::InvalidLink,
+            Page::Unsubscribe,
+            Page::Unsubscribed,
+            Page::Preferences,
+            Page::Widget,
+            Page::Archive,
+            Page::ArchiveIssue,
+            Page::NotFound,
+        ] {
+            let mut context = tera::Context::new();
+            context.insert("subscription_token", "token");
+            context.insert("token", "token");
+            context.insert("step", "approve");
+            context.insert("name", "Ursula");
+            context.insert("email", "ursula@example.com");
+            context.insert("issues", &Vec::<String>::new());
+            context.insert("canonical_url", "https://example.com/archive");
+            context.insert("title", "Issue #1");
+            context.insert("published_at", "2023-04-09T10:00:00Z");
+            context.insert("published_on", "9 April 2023");
+            context.insert("archive_url", "https://example.com/archive");
+            context.insert("content", "<p>Hi</p>");
+            let response = pages.render_with(page, context);
+            assert_eq!(response.status(), page.status());
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use crate::{
+    bot_protection,
+    configuration::{PendingSubscriptionsSettings, Settings},
+    domain::{SubscriberEmail, SubscriptionToken},
+    email_client::EmailClient,
+    email_templates::{Email, EmailTemplates, Recipient},
+    rate_limit::SubscriptionRateLimits,
+    routes::confirmation_link,
+    startup::get_connection_pool,
+};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// What one pass over the pending subscriptions did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PendingSubscriptionsReport {
+    pub reminders_sent: u64,
+    pub reminders_failed: u64,
+    pub subscriptions_deleted: u64,
+    pub tokens_deleted: u64,
+}
+
+impl std::ops::AddAssign for PendingSubscriptionsReport {
+    fn add_assign(&mut self, other: Self) {
+        self.reminders_sent += other.reminders_sent;
+        self.reminders_failed += other.reminders_failed;
+        self.subscriptions_deleted += other.subscriptions_deleted;
+        self.tokens_deleted += other.tokens_deleted;
+    }
+}
+
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+    let email_client = configuration.email_client.client();
+    let email_templates = configuration.email_templates.templates()?;
+    worker_loop(
+        connection_pool,
+        email_client,
+        email_templates,
+        configuration.application.base_url,
+        configuration.pending_subscriptions,
+        configuration
+            .application
+            .rate_limits
+            .subscription_rate_limits(),
+    )
+    .await
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    email_client: EmailClient,
+    email_templates: EmailTemplates,
+    base_url: String,
+    settings: PendingSubscriptionsSettings,
+    rate_limits: SubscriptionRateLimits,
+) -> Result<(), anyhow::Error> {
+    // Counters since the worker started, next to the per-pass counts.
+    let mut totals = PendingSubscriptionsReport::default();
+    loop {
+        // A failed pass is retried on the next tick rather than stopping the worker.
+        match process_pending_subscriptions(
+            &pool,
+            &email_client,
+            &email_templates,
+            &base_url,
+            &settings,
+        )
+        .await
+        {
+            Ok(report) => {
+                totals += report;
+                tracing::info!(
+                    reminders_sent_total = totals.reminders_sent,
+                    reminders_failed_total = totals.reminders_failed,
+                    deleted_subscriptions_total = totals.subscriptions_deleted,
+                    deleted_tokens_total = totals.tokens_deleted,
+                    "Pending subscription counters."
+                );
+            }
+            Err(e) => tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to process pending subscriptions."
+            ),
+        }
+        // Signups leave a bucket per address and a spent form nonce behind, so
+        // this is the place to clear them out too.
+        match rate_limits.delete_idle_buckets(&pool).await {
+            Ok(deleted) => tracing::info!(
+                buckets_deleted = deleted,
+                "Deleted idle rate limit buckets."
+            ),
+            Err(e) => tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to delete idle rate limit buckets."
+            ),
+        }
+        match bot_protection::delete_expired_nonces(&pool).await {
+            Ok(deleted) => tracing::info!(nonces_deleted = deleted, "Deleted expired form nonces."),
+            Err(e) => tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to delete expired form nonces."
+            ),
+        }
+        tokio::time::sleep(settings.check_interval()).await;
+    }
+}
+
+/// Reminds subscribers who have not confirmed after the reminder delay, then
+/// deletes the ones still unconfirmed after the retention period. What it did
+/// is recorded on the span, logged as a single event and returned.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        deleted_subscriptions = tracing::field::Empty,
+        deleted_tokens = tracing::field::Empty
+    )
+)]
+pub async fn process_pending_subscriptions(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    settings: &PendingSubscriptionsSettings,
+) -> Result<PendingSubscriptionsReport, anyhow::Error> {
+    let now = Utc::now();
+    let mut report = PendingSubscriptionsReport::default();
+    while let Some(sent) = try_send_reminder(
+        pool,
+        email_client,
+        email_templates,
+        base_url,
+        now - settings.reminder_delay(),
+        now - settings.retention(),
+    )
+    .await?
+    {
+        if sent {
+            report.reminders_sent += 1;
+        } else {
+            report.reminders_failed += 1;
+        }
+    }
+    let (subscriptions_deleted, tokens_deleted) =
+        delete_expired_pending_subscriptions(pool, now - settings.retention()).await?;
+    report.subscriptions_deleted = subscriptions_deleted;
+    report.tokens_deleted = tokens_deleted;
+    let span = tracing::Span::current();
+    span.record("deleted_subscriptions", subscriptions_deleted);
+    span.record("deleted_tokens", tokens_deleted);
+
+    tracing::info!(
+        reminders_sent = report.reminders_sent,
+        reminders_failed = report.reminders_failed,
+        subscriptions_deleted = report.subscriptions_deleted,
+        tokens_deleted = report.tokens_deleted,
+        "Processed pending subscriptions."
+    );
+    Ok(report)
+}
+
+/// Sends at most one reminder. Returns `None` once nobody is due one,
+/// otherwise whether the email went out.
+///
+/// Every subscriber gets a single attempt: the row is marked even when
+/// sending fails, so an address the email API refuses cannot hold up the rest.
+#[tracing::instrument(
+    skip_all,
+    fields(subscriber_id = tracing::field::Empty)
+)]
+async fn try_send_reminder(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    subscribed_before: chrono::DateTime<Utc>,
+    retained_after: chrono::DateTime<Utc>,
+) -> Result<Option<bool>, anyhow::Error> {
+    let (mut tx, subscriber_id, email, name) =
+        match dequeue_reminder(pool, subscribed_before, retained_after).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+    tracing::Span::current().record("subscriber_id", tracing::field::display(subscriber_id));
+
+    let subscription_token = SubscriptionToken::generate();
+    store_token(&mut tx, subscriber_id, subscription_token.as_ref()).await?;
+    let sent = match SubscriberEmail::parse(email) {
+        Ok(email) => {
+            let recipient = Recipient {
+                name,
+                email: email.as_ref().to_string(),
+            };
+            let confirmation_link = confirmation_link(base_url, subscription_token.as_ref());
+            match send_reminder_email(
+                email_client,
+                email_templates,
+                pool,
+                email,
+                &recipient,
+                confirmation_link,
+            )
+            .await
+            {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        "Failed to send a confirmation reminder."
+                    );
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Skipping a confirmation reminder to an invalid stored email."
+            );
+            false
+        }
+    };
+    mark_reminder_sent(&mut tx, subscriber_id).await?;
+    tx.commit().await?;
+    Ok(Some(sent))
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_reminder(
+    pool: &PgPool,
+    subscribed_before: chrono::DateTime<Utc>,
+    retained_after: chrono::DateTime<Utc>,
+) -> Result<Option<(PgTransaction, Uuid, String, String)>, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let r = sqlx::query!(
+        r#"
+        SELECT id, email, name
+        FROM subscriptions
+        WHERE status = 'pending_confirmation'
+            AND reminder_sent_at IS NULL
+            AND subscribed_at < $1
+            AND subscribed_at >= $2
+        ORDER BY subscribed_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+        subscribed_before,
+        retained_after
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    Ok(r.map(|r| (tx, r.id, r.email, r.name)))
+}
+
+async fn store_token(
+    tx: &mut PgTransaction,
+    subscriber_id: Uuid,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"insert into subscription_tokens (subscription_token, subscriber_id) values ($1, $2)"#,
+        subscription_token,
+        subscriber_id
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+async fn mark_reminder_sent(
+    tx: &mut PgTransaction,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET reminder_sent_at = now() WHERE id = $1"#,
+        subscriber_id
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+async fn send_reminder_email(
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    pool: &PgPool,
+    email: SubscriberEmail,
+    recipient: &Recipient,
+    confirmation_link: String,
+) -> Result<(), anyhow::Error> {
+    let rendered = email_templates
+        .render(
+            pool,
+            recipient,
+            &Email::ConfirmationReminder { confirmation_link },
+        )
+        .await?;
+    email_client
+        .send_email(email, &rendered.subject, &rendered.html, &rendered.text)
+        .await?;
+    Ok(())
+}
+
+/// Deletes subscriptions that were never confirmed and everything pointing at
+/// them. Returns how many subscriptions and tokens went.
+#[tracing::instrument(skip(pool))]
+async fn delete_expired_pending_subscriptions(
+    pool: &PgPool,
+    subscribed_before: chrono::DateTime<Utc>,
+) -> Result<(u64, u64), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let tokens_deleted = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id IN (
+            SELECT id FROM subscriptions
+            WHERE status = 'pending_confirmation' AND subscribed_at < $1
+        )
+        "#,
+        subscribed_before
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        r#"
+        DELETE FROM email_change_requests
+        WHERE subscriber_id IN (
+            SELECT id FROM subscriptions
+            WHERE status = 'pending_confirmation' AND subscribed_at < $1
+        )
+        "#,
+        subscribed_before
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sequence_enrollments
+        WHERE subscriber_id IN (
+            SELECT id FROM subscriptions
+            WHERE status = 'pending_confirmation' AND subscribed_at < $1
+        )
+        "#,
+        subscribed_before
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM delivery_queue
+        WHERE subscriber_id IN (
+            SELECT id FROM subscriptions
+            WHERE status = 'pending_confirmation' AND subscribed_at < $1
+        )
+        "#,
+        subscribed_before
+    )
+    .execute(&mut tx)
+    .await?;
+    let subscriptions_deleted = sqlx::query!(
+        r#"
+        DELETE FROM subscriptions
+        WHERE status = 'pending_confirmation' AND subscribed_at < $1
+        "#,
+        subscribed_before
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok((subscriptions_deleted, tokens_deleted))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pending_subscriptions_worker::PendingSubscriptionsReport;
+
+    #[test]
+    fn counters_add_up_the_passes() {
+        let mut totals = PendingSubscriptionsReport::default();
+        for deleted in [2, 0, 3] {
+            totals += PendingSubscriptionsReport {
+                reminders_sent: 1,
+                reminders_failed: 0,
+                subscriptions_deleted: deleted,
+                tokens_deleted: deleted * 2,
+            };
+        }
+        assert_eq!(
+            totals,
+            PendingSubscriptionsReport {
+                reminders_sent: 3,
+                reminders_failed: 0,
+                subscriptions_deleted: 5,
+                tokens_deleted: 10,
+            }
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use actix_web::HttpRequest;
+use sqlx::PgPool;
+
+use crate::domain::SubscriberEmail;
+
+/// Longest `Retry-After` we report, so a misconfigured refill rate of zero
+/// does not tell clients to come back in a thousand years.
+const MAX_RETRY_AFTER_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+/// A token bucket whose state lives in Postgres, so every replica shares it.
+/// Each request takes one token; tokens come back at a steady rate up to
+/// `capacity`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_hour: u32) -> Self {
+        Self {
+            capacity: capacity.into(),
+            refill_per_second: f64::from(refill_per_hour) / 3600.0,
+        }
+    }
+
+    /// Refills a bucket holding `tokens` after `elapsed_seconds` and takes a
+    /// token out of it if there is one. Returns what is left in the bucket.
+    fn take(&self, tokens: f64, elapsed_seconds: f64) -> (f64, Decision) {
+        let tokens = (tokens + elapsed_seconds * self.refill_per_second).min(self.capacity);
+        if tokens >= 1.0 {
+            (tokens - 1.0, Decision::Allowed)
+        } else {
+            let retry_after = ((1.0 - tokens) / self.refill_per_second)
+                .ceil()
+                .min(MAX_RETRY_AFTER_SECONDS);
+            (
+                tokens,
+                Decision::Limited {
+                    retry_after: Duration::from_secs_f64(retry_after),
+                },
+            )
+        }
+    }
+
+    /// How long an untouched bucket takes to fill up, after which it is no
+    /// different from a missing one. Buckets that never refill are kept for
+    /// the longest `Retry-After` we report.
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(
+            (self.capacity / self.refill_per_second).min(MAX_RETRY_AFTER_SECONDS),
+        )
+    }
+
+    #[tracing::instrument(name = "Taking a token from a rate limit bucket", skip(self, pool))]
+    pub async fn acquire(&self, pool: &PgPool, key: &str) -> Result<Decision, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            r#"
+            insert into rate_limit_buckets (key, tokens, updated_at)
+            values ($1, $2, now())
+            on conflict (key) do nothing
+            "#,
+            key,
+            self.capacity
+        )
+        .execute(&mut tx)
+        .await?;
+        let bucket = sqlx::query!(
+            r#"
+            SELECT tokens, extract(epoch from now() - updated_at)::float8 as "elapsed_seconds!"
+            FROM rate_limit_buckets
+            WHERE key = $1
+            FOR UPDATE
+            "#,
+            key
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        let (tokens, decision) = self.take(bucket.tokens, bucket.elapsed_seconds);
+
+        sqlx::query!(
+            r#"UPDATE rate_limit_buckets SET tokens = $2, updated_at = now() WHERE key = $1"#,
+            key,
+            tokens
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(decision)
+    }
+}
+
+/// Limits applied to the endpoints that send email to an arbitrary address:
+/// subscribing and changing a subscriber's email.
+#[derive(Debug, Clone)]
+pub struct SubscriptionRateLimits {
+    pub per_ip: TokenBucket,
+    pub per_email: TokenBucket,
+    /// Proxies whose `X-Forwarded-For` we believe. Anyone else can put
+    /// whatever they like in the header.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl SubscriptionRateLimits {
+    /// Rate limit key for the client address: the peer's address, or, when
+    /// the peer is a trusted proxy, the last address it forwarded for that is
+    /// not a trusted proxy itself.
+    pub fn client_ip_key(&self, request: &HttpRequest) -> String {
+        let mut ip = match request.peer_addr() {
+            Some(peer) => peer.ip(),
+            None => return "ip:unknown".into(),
+        };
+        let forwarded_for = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in forwarded_for.into_iter().rev() {
+            if !self.trusted_proxies.contains(&ip) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => ip = hop,
+                Err(_) => break,
+            }
+        }
+        format!("ip:{}", ip)
+    }
+
+    /// Deletes buckets that have filled up again since their last use.
+    #[tracing::instrument(name = "Deleting idle rate limit buckets", skip_all)]
+    pub async fn delete_idle_buckets(&self, pool: &PgPool) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM rate_limit_buckets
+            WHERE (key LIKE 'ip:%' AND updated_at < now() - make_interval(secs => $1))
+                OR (key LIKE 'email:%' AND updated_at < now() - make_interval(secs => $2))
+            "#,
+            self.per_ip.refill_time().as_secs_f64(),
+            self.per_email.refill_time().as_secs_f64(),
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+pub fn email_key(email: &SubscriberEmail) -> String {
+    format!("email:{}", email.canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::test::TestRequest;
+
+    use crate::rate_limit::{Decision, SubscriptionRateLimits, TokenBucket};
+
+    #[test]
+    fn a_token_is_taken_when_available() {
+        let bucket = TokenBucket::new(3, 3600);
+        assert_eq!(bucket.take(3.0, 0.0), (2.0, Decision::Allowed));
+    }
+
+    #[test]
+    fn an_empty_bucket_reports_when_the_next_token_arrives() {
+        let bucket = TokenBucket::new(3, 60);
+        assert_eq!(
+            bucket.take(0.0, 0.0),
+            (
+                0.0,
+                Decision::Limited {
+                    retry_after: Duration::from_secs(60)
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time_up_to_the_capacity() {
+        let bucket = TokenBucket::new(3, 3600);
+        assert_eq!(bucket.take(0.0, 1.0), (0.0, Decision::Allowed));
+        assert_eq!(bucket.take(0.0, 3600.0), (2.0, Decision::Allowed));
+    }
+
+    #[test]
+    fn buckets_refill_in_capacity_over_rate_capped_at_a_day() {
+        assert_eq!(
+            TokenBucket::new(3, 2).refill_time(),
+            Duration::from_secs(5400)
+        );
+        assert_eq!(
+            TokenBucket::new(1, 0).refill_time(),
+            Duration::from_secs(24 * 60 * 60)
+        );
+    }
+
+    fn limits_behind(trusted_proxies: &[&str]) -> SubscriptionRateLimits {
+        SubscriptionRateLimits {
+            per_ip: TokenBucket::new(1, 1),
+            per_email: TokenBucket::new(1, 1),
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_unless_the_peer_is_a_trusted_proxy() {
+        let request = TestRequest::default()
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1"))
+            .to_http_request();
+        assert_eq!(limits_behind(&[]).client_ip_key(&request), "ip:203.0.113.7");
+        assert_eq!(
+            limits_behind(&["10.0.0.1"]).client_ip_key(&request),
+            "ip:203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn the_client_is_the_last_hop_a_trusted_proxy_forwarded_for() {
+        let request = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "192.0.2.9, 198.51.100.1, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(
+            limits_behind(&["10.0.0.1", "10.0.0.2"]).client_ip_key(&request),
+            "ip:198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn a_bucket_that_never_refills_caps_retry_after() {
+        let bucket = TokenBucket::new(1, 0);
+        assert_eq!(
+            bucket.take(0.0, 10.0),
+            (
+                0.0,
+                Decision::Limited {
+                    retry_after: Duration::from_secs(24 * 60 * 60)
+                }
+            )
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{
+    authentication::UserId,
+    email_templates::{EmailKind, EmailTemplates, InvalidTemplate, TemplateSource},
+    routes::error_chain_fmt,
+};
+
+#[derive(serde::Serialize)]
+pub struct EmailTemplate {
+    name: String,
+    #[serde(flatten)]
+    source: TemplateSource,
+    /// What an email's template gets on top of `subscriber` and `list`.
+    variables: &'static [&'static str],
+    /// Whether the template was edited or added rather than the default.
+    customised: bool,
+}
+
+impl EmailTemplate {
+    fn new(name: String, source: TemplateSource, customised: bool) -> Self {
+        let variables = EmailKind::parse(&name).map_or(&[][..], |kind| kind.variables());
+        Self {
+            name,
+            source,
+            variables,
+            customised,
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum EmailTemplateError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    InvalidTemplate(#[from] InvalidTemplate),
+    #[error("There is no such template.")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for EmailTemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for EmailTemplateError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidTemplate(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Listing email templates",
+    skip(pool, email_templates),
+    fields(user_id = %*user_id)
+)]
+pub async fn list_email_templates(
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, EmailTemplateError> {
+    let overrides = email_templates.overrides(pool.get_ref()).await?;
+    let templates: Vec<_> = email_templates
+        .merged(&overrides)
+        .into_iter()
+        .map(|(name, source)| {
+            let customised = overrides.contains_key(&name);
+            EmailTemplate::new(name, source, customised)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(templates))
+}
+
+#[tracing::instrument(
+    name = "Fetching an email template",
+    skip(pool, email_templates),
+    fields(user_id = %*user_id)
+)]
+pub async fn email_template(
+    name: web::Path<String>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, EmailTemplateError> {
+    let name = name.into_inner();
+    let overrides = email_templates.overrides(pool.get_ref()).await?;
+    let customised = overrides.contains_key(&name);
+    let source = email_templates
+        .merged(&overrides)
+        .remove(&name)
+        .ok_or(EmailTemplateError::NotFound)?;
+    Ok(HttpResponse::Ok().json(EmailTemplate::new(name, source, customised)))
+}
+
+/// Stores an edited template, or a new partial, once every email still
+/// renders with it.
+#[tracing::instrument(
+    name = "Saving an email template",
+    skip(body, pool, email_templates),
+    fields(user_id = %*user_id)
+)]
+pub async fn save_email_template(
+    name: web::Path<String>,
+    body: web::Json<TemplateSource>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, EmailTemplateError> {
+    let name = name.into_inner();
+    let source = body.into_inner();
+    validate(&name, &source)?;
+    let mut tx = lock_templates(&pool).await?;
+    let mut overrides = email_templates.overrides(&mut tx).await?;
+    overrides.insert(name.clone(), source.clone());
+    email_templates.check(&email_templates.merged(&overrides))?;
+    sqlx::query!(
+        r#"
+        INSERT INTO email_templates (name, subject, html_body, text_body)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (name) DO UPDATE
+        SET subject = EXCLUDED.subject, html_body = EXCLUDED.html_body,
+            text_body = EXCLUDED.text_body, updated_at = now()
+        "#,
+        name,
+        source.subject,
+        source.html_body,
+        source.text_body
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to save the email template.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+    Ok(HttpResponse::Ok().json(EmailTemplate::new(name, source, true)))
+}
+
+/// Goes back to the default template, or removes a partial that has none.
+#[tracing::instrument(
+    name = "Resetting an email template",
+    skip(pool, email_templates),
+    fields(user_id = %*user_id)
+)]
+pub async fn delete_email_template(
+    name: web::Path<String>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, EmailTemplateError> {
+    let mut tx = lock_templates(&pool).await?;
+    let mut overrides = email_templates.overrides(&mut tx).await?;
+    if overrides.remove(name.as_str()).is_none() {
+        return Err(EmailTemplateError::NotFound);
+    }
+    email_templates.check(&email_templates.merged(&overrides))?;
+    sqlx::query!(r#"DELETE FROM email_templates WHERE name = $1"#, *name)
+        .execute(&mut tx)
+        .await
+        .context("Failed to delete the email template.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn validate(name: &str, source: &TemplateSource) -> Result<(), EmailTemplateError> {
+    let valid_name = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid_name {
+        return Err(EmailTemplateError::ValidationError(
+            "Template names are made of lowercase letters, digits and underscores.".into(),
+        ));
+    }
+    match (EmailKind::parse(name), &source.subject) {
+        (Some(_), Some(subject)) if !subject.trim().is_empty() => Ok(()),
+        (Some(_), _) => Err(EmailTemplateError::ValidationError(
+            "Emails need a subject.".into(),
+        )),
+        (None, Some(_)) => Err(EmailTemplateError::ValidationError(
+            "Only emails have a subject, layouts and partials do not.".into(),
+        )),
+        (None, None) => Ok(()),
+    }
+}
+
+/// Edits are checked against the other templates, so they are made one at a
+/// time.
+async fn lock_templates(pool: &PgPool) -> Result<Transaction<'static, Postgres>, anyhow::Error> {
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    sqlx::query!("LOCK TABLE email_templates IN EXCLUSIVE MODE")
+        .execute(&mut tx)
+        .await
+        .context("Failed to lock the email templates.")?;
+    Ok(tx)
+}
@@ -0,0 +1,689 @@
+use actix_web::{http::header::ContentType, web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    authentication::UserId,
+    domain::{InvalidTransition, IssueAction, IssueStatus},
+    email_client::EmailClient,
+    email_rendering::{clipping_warning, EmailRenderer},
+    email_templates::{EmailTemplates, Recipient, RenderedEmail},
+    eml::Eml,
+    markdown,
+    merge_tags::{Format, MergeTemplate, Personalizer, Subscriber},
+    routes::{error_chain_fmt, load_attribute_schema},
+    startup::SeedAddresses,
+};
+
+/// An issue is written either in Markdown, from which both bodies are
+/// rendered, or as a text and an HTML body.
+#[derive(serde::Deserialize)]
+pub struct IssueData {
+    title: String,
+    markdown_content: Option<String>,
+    text_content: Option<String>,
+    html_content: Option<String>,
+    /// Sends the issue to the segment's confirmed subscribers only.
+    segment_id: Option<Uuid>,
+    /// Keeps the issue out of the public archive once it is sent.
+    #[serde(default)]
+    private: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct IssueUpdateData {
+    #[serde(flatten)]
+    issue: IssueData,
+    /// The version the edit was made on.
+    version: i32,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TransitionData {
+    version: i32,
+    /// Required to schedule an issue.
+    publish_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MarkdownPreviewData {
+    markdown_content: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct MarkdownPreview {
+    text_content: String,
+    html_content: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct IssuePreviewQuery {
+    /// Who to personalise the issue for, a made-up subscriber if missing.
+    subscriber_id: Option<Uuid>,
+    /// Returns just that body, or the whole message, instead of JSON.
+    format: Option<PreviewFormat>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    Html,
+    Text,
+    Eml,
+}
+
+#[derive(serde::Serialize)]
+pub struct TestSend {
+    sent_to: Vec<String>,
+}
+
+/// An issue as a subscriber would get it.
+#[derive(serde::Serialize)]
+pub struct IssuePreview {
+    subject: String,
+    text_content: String,
+    html_content: String,
+    /// Problems recipients would see, e.g. the message being clipped.
+    warnings: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Issue {
+    id: Uuid,
+    title: String,
+    markdown_content: Option<String>,
+    text_content: String,
+    html_content: String,
+    segment_id: Option<Uuid>,
+    private: bool,
+    /// Where the issue is in the archive, set when it is published.
+    slug: Option<String>,
+    status: String,
+    version: i32,
+    author_id: Option<Uuid>,
+    approved_by: Option<Uuid>,
+    publish_at: Option<DateTime<Utc>>,
+    published_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(thiserror::Error)]
+pub enum IssueError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no such issue.")]
+    NotFound,
+    #[error("The issue was changed since you loaded it, reload it and try again.")]
+    VersionConflict,
+    #[error("Only drafts can be edited.")]
+    NotEditable,
+    #[error(transparent)]
+    InvalidTransition(#[from] InvalidTransition),
+    #[error("Issues must be approved by someone who did not write or edit them.")]
+    SelfApproval,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for IssueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for IssueError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::VersionConflict | Self::NotEditable | Self::InvalidTransition(_) => {
+                reqwest::StatusCode::CONFLICT
+            }
+            Self::SelfApproval => reqwest::StatusCode::FORBIDDEN,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The bodies an issue is stored with.
+struct IssueContent {
+    markdown: Option<String>,
+    text: String,
+    html: String,
+}
+
+impl IssueData {
+    async fn validate(&self, pool: &PgPool) -> Result<IssueContent, IssueError> {
+        if self.title.trim().is_empty() {
+            return Err(IssueError::ValidationError("The title is empty.".into()));
+        }
+        let content =
+            match (
+                &self.markdown_content,
+                &self.text_content,
+                &self.html_content,
+            ) {
+                (Some(markdown), None, None) if !markdown.trim().is_empty() => {
+                    let rendered = markdown::render(markdown);
+                    IssueContent {
+                        markdown: Some(markdown.clone()),
+                        text: rendered.text,
+                        html: rendered.html,
+                    }
+                }
+                (None, Some(text), Some(html))
+                    if !text.trim().is_empty() && !html.trim().is_empty() =>
+                {
+                    IssueContent {
+                        markdown: None,
+                        text: text.clone(),
+                        html: html.clone(),
+                    }
+                }
+                _ => return Err(IssueError::ValidationError(
+                    "Either the Markdown content or both the text and the HTML content are needed."
+                        .into(),
+                )),
+            };
+        check_merge_tags(pool, &[&self.title, &content.text, &content.html]).await?;
+        if let Some(segment_id) = self.segment_id {
+            let exists = sqlx::query!(r#"SELECT id FROM segments WHERE id = $1"#, segment_id)
+                .fetch_optional(pool)
+                .await
+                .context("Failed to look up the segment.")?
+                .is_some();
+            if !exists {
+                return Err(IssueError::ValidationError(
+                    "There is no such segment.".into(),
+                ));
+            }
+        }
+        Ok(content)
+    }
+}
+
+/// Rejects malformed merge tags and tags for undefined subscriber fields,
+/// which would otherwise only show up once the issue is sent.
+async fn check_merge_tags(pool: &PgPool, contents: &[&str]) -> Result<(), IssueError> {
+    let templates = contents
+        .iter()
+        .map(|content| MergeTemplate::parse(content))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| IssueError::ValidationError(e.to_string()))?;
+    let schema = load_attribute_schema(pool).await?;
+    for template in templates {
+        template
+            .check_attributes(&schema)
+            .map_err(|e| IssueError::ValidationError(e.to_string()))?;
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Listing issues", skip(pool), fields(user_id = %*user_id))]
+pub async fn list_issues(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let issues = sqlx::query_as!(
+        Issue,
+        r#"
+        SELECT newsletter_issue_id AS id, title, markdown_content, text_content,
+            html_content, segment_id, private, slug,
+            status, version, author_id, approved_by, publish_at, published_at,
+            created_at, updated_at
+        FROM newsletter_issues
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list issues.")?;
+    Ok(HttpResponse::Ok().json(issues))
+}
+
+#[tracing::instrument(name = "Fetching an issue", skip(pool), fields(user_id = %*user_id))]
+pub async fn issue(
+    issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let issue = get_issue(&pool, *issue_id)
+        .await?
+        .ok_or(IssueError::NotFound)?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+#[tracing::instrument(
+    name = "Creating an issue",
+    skip(body, pool),
+    fields(user_id = %*user_id, issue_title = %body.title)
+)]
+pub async fn create_issue(
+    body: web::Json<IssueData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let content = body.validate(&pool).await?;
+    let issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (newsletter_issue_id, title, markdown_content,
+            text_content, html_content, segment_id, private, author_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        issue_id,
+        body.title,
+        content.markdown,
+        content.text,
+        content.html,
+        body.segment_id,
+        body.private,
+        user_id.0
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to insert the issue.")?;
+    let issue = get_issue(&pool, issue_id)
+        .await?
+        .context("The new issue is gone.")?;
+    Ok(HttpResponse::Created().json(issue))
+}
+
+/// Replaces the content of a draft, provided nobody changed it since
+/// `version`.
+#[tracing::instrument(
+    name = "Updating an issue",
+    skip(body, pool),
+    fields(user_id = %*user_id, issue_title = %body.issue.title)
+)]
+pub async fn update_issue(
+    issue_id: web::Path<Uuid>,
+    body: web::Json<IssueUpdateData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let content = body.issue.validate(&pool).await?;
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    let current = lock_issue(&mut tx, *issue_id, body.version).await?;
+    if !current.status.is_editable() {
+        return Err(IssueError::NotEditable);
+    }
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET title = $2, markdown_content = $3, text_content = $4, html_content = $5,
+            segment_id = $6, private = $7, version = version + 1, updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        *issue_id,
+        body.issue.title,
+        content.markdown,
+        content.text,
+        content.html,
+        body.issue.segment_id,
+        body.issue.private
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to update the issue.")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issue_editors (newsletter_issue_id, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        *issue_id,
+        user_id.0
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to record the issue editor.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+
+    let issue = get_issue(&pool, *issue_id)
+        .await?
+        .ok_or(IssueError::NotFound)?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+/// Renders Markdown the way it would be sent, without saving anything.
+#[tracing::instrument(name = "Previewing Markdown", skip(body), fields(user_id = %*user_id))]
+pub async fn preview_markdown(
+    body: web::Json<MarkdownPreviewData>,
+    user_id: web::ReqData<UserId>,
+) -> HttpResponse {
+    let rendered = markdown::render(&body.markdown_content);
+    HttpResponse::Ok().json(MarkdownPreview {
+        text_content: rendered.text,
+        html_content: rendered.html,
+    })
+}
+
+/// Renders an issue the way one of the subscribers would get it.
+#[tracing::instrument(
+    name = "Previewing an issue",
+    skip(query, pool, email_client, personalizer, email_renderer, email_templates),
+    fields(user_id = %*user_id)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn preview_issue(
+    issue_id: web::Path<Uuid>,
+    query: web::Query<IssuePreviewQuery>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    personalizer: web::Data<Personalizer>,
+    email_renderer: web::Data<EmailRenderer>,
+    email_templates: web::Data<EmailTemplates>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let issue = get_issue(&pool, *issue_id)
+        .await?
+        .ok_or(IssueError::NotFound)?;
+    let subscriber = match query.subscriber_id {
+        Some(subscriber_id) => get_subscriber(&pool, subscriber_id)
+            .await?
+            .ok_or_else(|| IssueError::ValidationError("There is no such subscriber.".into()))?,
+        None => sample_subscriber("jane.doe@example.com"),
+    };
+    let preview = render_issue(
+        &pool,
+        &issue,
+        &subscriber,
+        &personalizer,
+        &email_renderer,
+        &email_templates,
+    )
+    .await?;
+    let response = match query.format {
+        None => HttpResponse::Ok().json(preview),
+        Some(PreviewFormat::Html) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(preview.html_content),
+        Some(PreviewFormat::Text) => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(preview.text_content),
+        Some(PreviewFormat::Eml) => {
+            let eml = Eml {
+                from: email_client.sender().as_ref(),
+                to: &subscriber.email,
+                subject: &preview.subject,
+                html: &preview.html_content,
+                text: &preview.text_content,
+            };
+            HttpResponse::Ok()
+                .content_type("message/rfc822")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}.eml\"", issue.id),
+                ))
+                .body(eml.render(Utc::now(), &Uuid::new_v4().simple().to_string()))
+        }
+    };
+    Ok(response)
+}
+
+/// Sends the issue as subscribers would get it to the seed addresses, with a
+/// `[TEST]` subject and without recording any delivery.
+#[tracing::instrument(
+    name = "Sending a test issue",
+    skip(
+        pool,
+        email_client,
+        personalizer,
+        email_renderer,
+        email_templates,
+        seed_addresses
+    ),
+    fields(user_id = %*user_id)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn send_test_issue(
+    issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    personalizer: web::Data<Personalizer>,
+    email_renderer: web::Data<EmailRenderer>,
+    email_templates: web::Data<EmailTemplates>,
+    seed_addresses: web::Data<SeedAddresses>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let issue = get_issue(&pool, *issue_id)
+        .await?
+        .ok_or(IssueError::NotFound)?;
+    if seed_addresses.0.is_empty() {
+        return Err(IssueError::ValidationError(
+            "There are no seed addresses to send tests to.".into(),
+        ));
+    }
+    for address in &seed_addresses.0 {
+        let subscriber = sample_subscriber(address.as_ref());
+        let preview = render_issue(
+            &pool,
+            &issue,
+            &subscriber,
+            &personalizer,
+            &email_renderer,
+            &email_templates,
+        )
+        .await?;
+        email_client
+            .send_email(
+                address.clone(),
+                &format!("[TEST] {}", preview.subject),
+                &preview.html_content,
+                &preview.text_content,
+            )
+            .await
+            .with_context(|| format!("Failed to send a test email to {}.", address.as_ref()))?;
+    }
+    Ok(HttpResponse::Ok().json(TestSend {
+        sent_to: seed_addresses
+            .0
+            .iter()
+            .map(|address| address.as_ref().to_string())
+            .collect(),
+    }))
+}
+
+/// Stands in for a subscriber when there is none to preview the issue as.
+fn sample_subscriber(email: &str) -> Subscriber {
+    Subscriber {
+        name: "Jane Doe".into(),
+        email: email.into(),
+        attributes: serde_json::json!({}),
+        manage_token: "preview".into(),
+    }
+}
+
+/// Renders the issue the way `subscriber` would get it, in the `issue`
+/// template but without the open tracking pixel.
+async fn render_issue(
+    pool: &PgPool,
+    issue: &Issue,
+    subscriber: &Subscriber,
+    personalizer: &Personalizer,
+    email_renderer: &EmailRenderer,
+    email_templates: &EmailTemplates,
+) -> Result<IssuePreview, IssueError> {
+    let content = RenderedEmail {
+        subject: personalizer.personalize(&issue.title, subscriber, Format::Text),
+        html: email_renderer.render(&personalizer.personalize(
+            &issue.html_content,
+            subscriber,
+            Format::Html,
+        )),
+        text: personalizer.personalize(&issue.text_content, subscriber, Format::Text),
+    };
+    let recipient = Recipient {
+        name: subscriber.name.clone(),
+        email: subscriber.email.clone(),
+    };
+    let unsubscribe_url = personalizer.unsubscribe_url(subscriber);
+    let rendered = email_templates
+        .render_issue(pool, &recipient, unsubscribe_url.as_deref(), content)
+        .await?;
+    Ok(IssuePreview {
+        subject: rendered.subject,
+        text_content: rendered.text,
+        warnings: clipping_warning(&rendered.html).into_iter().collect(),
+        html_content: rendered.html,
+    })
+}
+
+/// Moves an issue through its workflow: `submit`, `approve`, `reject`,
+/// `schedule` or `unschedule`.
+#[tracing::instrument(name = "Changing an issue's status", skip(body, pool), fields(user_id = %*user_id))]
+pub async fn transition_issue(
+    path: web::Path<(Uuid, String)>,
+    body: web::Json<TransitionData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let (issue_id, action) = path.into_inner();
+    let action = IssueAction::parse(&action).ok_or(IssueError::NotFound)?;
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    let current = lock_issue(&mut tx, issue_id, body.version).await?;
+    let status = current.status.apply(action)?;
+    let (approved_by, publish_at) = match action {
+        IssueAction::Approve => {
+            if current.author_id == Some(user_id.0)
+                || edited_issue(&mut tx, issue_id, user_id.0).await?
+            {
+                return Err(IssueError::SelfApproval);
+            }
+            (Some(user_id.0), None)
+        }
+        IssueAction::Schedule => match body.publish_at {
+            Some(publish_at) => (current.approved_by, Some(publish_at)),
+            None => {
+                return Err(IssueError::ValidationError(
+                    "A publication time is needed to schedule an issue.".into(),
+                ))
+            }
+        },
+        IssueAction::Unschedule => (current.approved_by, None),
+        IssueAction::Submit | IssueAction::Reject => (None, None),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, approved_by = $3, publish_at = $4, version = version + 1,
+            updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id,
+        status.as_str(),
+        approved_by,
+        publish_at
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to update the issue status.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+
+    let issue = get_issue(&pool, issue_id)
+        .await?
+        .ok_or(IssueError::NotFound)?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+/// Whether `user_id` ever changed the content of the issue.
+async fn edited_issue(
+    tx: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    let editor = sqlx::query!(
+        r#"
+        SELECT user_id FROM newsletter_issue_editors
+        WHERE newsletter_issue_id = $1 AND user_id = $2
+        "#,
+        issue_id,
+        user_id
+    )
+    .fetch_optional(tx)
+    .await
+    .context("Failed to look up the issue editors.")?;
+    Ok(editor.is_some())
+}
+
+struct LockedIssue {
+    status: IssueStatus,
+    author_id: Option<Uuid>,
+    approved_by: Option<Uuid>,
+}
+
+/// Locks the issue for the rest of `tx`, failing if it moved past `version`.
+async fn lock_issue(
+    tx: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+    version: i32,
+) -> Result<LockedIssue, IssueError> {
+    let issue = sqlx::query!(
+        r#"
+        SELECT status, version, author_id, approved_by
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        FOR UPDATE
+        "#,
+        issue_id
+    )
+    .fetch_optional(tx)
+    .await
+    .context("Failed to lock the issue.")?
+    .ok_or(IssueError::NotFound)?;
+    if issue.version != version {
+        return Err(IssueError::VersionConflict);
+    }
+    Ok(LockedIssue {
+        status: IssueStatus::parse(&issue.status).map_err(anyhow::Error::msg)?,
+        author_id: issue.author_id,
+        approved_by: issue.approved_by,
+    })
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<Option<Issue>, anyhow::Error> {
+    sqlx::query_as!(
+        Issue,
+        r#"
+        SELECT newsletter_issue_id AS id, title, markdown_content, text_content,
+            html_content, segment_id, private, slug,
+            status, version, author_id, approved_by, publish_at, published_at,
+            created_at, updated_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the issue.")
+}
+
+async fn get_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, anyhow::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT name, email, attributes, manage_token
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the subscriber.")
+}
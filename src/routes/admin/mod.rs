@@ -0,0 +1,15 @@
+mod email_templates;
+mod issues;
+mod rss_feeds;
+mod segments;
+mod sequences;
+mod subscriber_fields;
+mod subscribers;
+
+pub use email_templates::*;
+pub use issues::*;
+pub use rss_feeds::*;
+pub use segments::*;
+pub use sequences::*;
+pub use subscriber_fields::*;
+pub use subscribers::*;
@@ -0,0 +1,176 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    authentication::UserId,
+    routes::error_chain_fmt,
+    rss_to_email::{
+        render_issue, sample_items, FeedMode, DEFAULT_BODY_TEMPLATE, DEFAULT_DIGEST_BODY_TEMPLATE,
+        DEFAULT_DIGEST_TITLE_TEMPLATE, DEFAULT_TITLE_TEMPLATE,
+    },
+};
+
+/// A feed to turn into issues. Templates are Tera templates of the issue's
+/// title and Markdown body, with defaults that suit the `digest` setting.
+/// Bodies should use `item.text`: Markdown escapes the HTML of `item.content`.
+#[derive(serde::Deserialize)]
+pub struct RssFeedData {
+    url: String,
+    mode: FeedMode,
+    /// Bundles the items of a week into one issue.
+    #[serde(default)]
+    digest: bool,
+    title_template: Option<String>,
+    body_template: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RssFeed {
+    id: Uuid,
+    url: String,
+    mode: String,
+    digest: bool,
+    title_template: String,
+    body_template: String,
+    last_checked_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    last_digest_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(thiserror::Error)]
+pub enum RssFeedError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("This feed is already registered.")]
+    UrlTaken,
+    #[error("There is no such feed.")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for RssFeedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for RssFeedError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::UrlTaken => reqwest::StatusCode::CONFLICT,
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl RssFeedData {
+    /// Checks the URL and renders the templates with made-up items, returning
+    /// the templates to store.
+    fn validate(&self) -> Result<(String, String), RssFeedError> {
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => {
+                return Err(RssFeedError::ValidationError(
+                    "The feed URL is not an http(s) URL.".into(),
+                ))
+            }
+        }
+        let (default_title, default_body) = if self.digest {
+            (DEFAULT_DIGEST_TITLE_TEMPLATE, DEFAULT_DIGEST_BODY_TEMPLATE)
+        } else {
+            (DEFAULT_TITLE_TEMPLATE, DEFAULT_BODY_TEMPLATE)
+        };
+        let title_template = self.title_template.as_deref().unwrap_or(default_title);
+        let body_template = self.body_template.as_deref().unwrap_or(default_body);
+        let items = sample_items();
+        let items = if self.digest { &items[..] } else { &items[..1] };
+        render_issue(title_template, body_template, items).map_err(|e| {
+            RssFeedError::ValidationError(format!("The templates do not render: {:#}", e))
+        })?;
+        Ok((title_template.to_string(), body_template.to_string()))
+    }
+}
+
+#[tracing::instrument(name = "Listing RSS feeds", skip(pool), fields(user_id = %*user_id))]
+pub async fn list_rss_feeds(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, RssFeedError> {
+    let feeds = sqlx::query_as!(
+        RssFeed,
+        r#"
+        SELECT id, url, mode, digest, title_template, body_template, last_checked_at,
+            last_error, last_digest_at, created_at
+        FROM rss_feeds
+        ORDER BY created_at
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list feeds.")?;
+    Ok(HttpResponse::Ok().json(feeds))
+}
+
+#[tracing::instrument(
+    name = "Registering an RSS feed",
+    skip(body, pool),
+    fields(user_id = %*user_id, feed_url = %body.url)
+)]
+pub async fn create_rss_feed(
+    body: web::Json<RssFeedData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, RssFeedError> {
+    let (title_template, body_template) = body.validate()?;
+    let feed = sqlx::query_as!(
+        RssFeed,
+        r#"
+        INSERT INTO rss_feeds (id, url, mode, digest, title_template, body_template)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, url, mode, digest, title_template, body_template, last_checked_at,
+            last_error, last_digest_at, created_at
+        "#,
+        Uuid::new_v4(),
+        body.url,
+        body.mode.as_str(),
+        body.digest,
+        title_template,
+        body_template
+    )
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(
+        |e| match e.as_database_error().and_then(|e| e.code()).as_deref() {
+            Some("23505") => RssFeedError::UrlTaken,
+            _ => RssFeedError::UnexpectedError(
+                anyhow::Error::new(e).context("Failed to insert the feed."),
+            ),
+        },
+    )?;
+    Ok(HttpResponse::Created().json(feed))
+}
+
+/// Stops polling the feed. Issues already made from it are kept.
+#[tracing::instrument(name = "Deleting an RSS feed", skip(pool), fields(user_id = %*user_id))]
+pub async fn delete_rss_feed(
+    feed_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, RssFeedError> {
+    let deleted = sqlx::query!(r#"DELETE FROM rss_feeds WHERE id = $1"#, *feed_id)
+        .execute(pool.get_ref())
+        .await
+        .context("Failed to delete the feed.")?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(RssFeedError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,254 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    authentication::UserId,
+    domain::{SegmentFilter, SegmentFilterError},
+    routes::{error_chain_fmt, load_attribute_schema},
+    segments::{count_members, sample_members, SegmentMember},
+};
+
+const SAMPLE_SIZE: i64 = 10;
+
+#[derive(serde::Deserialize)]
+pub struct SegmentData {
+    name: String,
+    filter: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreviewData {
+    filter: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct Segment {
+    id: Uuid,
+    name: String,
+    filter: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SegmentPreview {
+    count: i64,
+    sample: Vec<SegmentMember>,
+}
+
+#[derive(thiserror::Error)]
+pub enum SegmentError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    InvalidFilter(#[from] SegmentFilterError),
+    #[error("There is already a segment with this name.")]
+    NameTaken,
+    #[error("Issues are addressed to this segment.")]
+    InUse,
+    #[error("There is no such segment.")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SegmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SegmentError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidFilter(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::NameTaken | Self::InUse => reqwest::StatusCode::CONFLICT,
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl SegmentData {
+    /// Checks the name and that the filter parses against the current
+    /// subscriber fields.
+    async fn validate(&self, pool: &PgPool) -> Result<(), SegmentError> {
+        if self.name.trim().is_empty() {
+            return Err(SegmentError::ValidationError(
+                "The segment name is empty.".into(),
+            ));
+        }
+        parse_filter(pool, &self.filter).await?;
+        Ok(())
+    }
+}
+
+#[tracing::instrument(name = "Listing segments", skip(pool), fields(user_id = %*user_id))]
+pub async fn list_segments(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    let segments = sqlx::query_as!(
+        Segment,
+        r#"SELECT id, name, filter, created_at, updated_at FROM segments ORDER BY name"#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list segments.")?;
+    Ok(HttpResponse::Ok().json(segments))
+}
+
+#[tracing::instrument(name = "Fetching a segment", skip(pool), fields(user_id = %*user_id))]
+pub async fn segment(
+    segment_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    let segment = get_segment(&pool, *segment_id)
+        .await?
+        .ok_or(SegmentError::NotFound)?;
+    Ok(HttpResponse::Ok().json(segment))
+}
+
+#[tracing::instrument(
+    name = "Creating a segment",
+    skip(body, pool),
+    fields(user_id = %*user_id, segment_name = %body.name)
+)]
+pub async fn create_segment(
+    body: web::Json<SegmentData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    body.validate(&pool).await?;
+    let segment_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO segments (id, name, filter) VALUES ($1, $2, $3)"#,
+        segment_id,
+        body.name.trim(),
+        body.filter
+    )
+    .execute(pool.get_ref())
+    .await
+    .map_err(constraint_violation_or_unexpected)?;
+    let segment = get_segment(&pool, segment_id)
+        .await?
+        .context("The new segment is gone.")?;
+    Ok(HttpResponse::Created().json(segment))
+}
+
+#[tracing::instrument(
+    name = "Updating a segment",
+    skip(body, pool),
+    fields(user_id = %*user_id, segment_name = %body.name)
+)]
+pub async fn update_segment(
+    segment_id: web::Path<Uuid>,
+    body: web::Json<SegmentData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    body.validate(&pool).await?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE segments SET name = $2, filter = $3, updated_at = now()
+        WHERE id = $1
+        "#,
+        *segment_id,
+        body.name.trim(),
+        body.filter
+    )
+    .execute(pool.get_ref())
+    .await
+    .map_err(constraint_violation_or_unexpected)?
+    .rows_affected();
+    if updated == 0 {
+        return Err(SegmentError::NotFound);
+    }
+    let segment = get_segment(&pool, *segment_id)
+        .await?
+        .ok_or(SegmentError::NotFound)?;
+    Ok(HttpResponse::Ok().json(segment))
+}
+
+#[tracing::instrument(name = "Deleting a segment", skip(pool), fields(user_id = %*user_id))]
+pub async fn delete_segment(
+    segment_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    let deleted = sqlx::query!(r#"DELETE FROM segments WHERE id = $1"#, *segment_id)
+        .execute(pool.get_ref())
+        .await
+        .map_err(constraint_violation_or_unexpected)?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(SegmentError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Shows who an unsaved filter would match.
+#[tracing::instrument(name = "Previewing a filter", skip(body, pool), fields(user_id = %*user_id))]
+pub async fn preview_filter(
+    body: web::Json<PreviewData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    let filter = parse_filter(&pool, &body.filter).await?;
+    Ok(HttpResponse::Ok().json(preview(&pool, &filter).await?))
+}
+
+/// Shows who a saved segment currently matches.
+#[tracing::instrument(name = "Previewing a segment", skip(pool), fields(user_id = %*user_id))]
+pub async fn preview_segment(
+    segment_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SegmentError> {
+    let segment = get_segment(&pool, *segment_id)
+        .await?
+        .ok_or(SegmentError::NotFound)?;
+    let filter = parse_filter(&pool, &segment.filter).await?;
+    Ok(HttpResponse::Ok().json(preview(&pool, &filter).await?))
+}
+
+async fn preview(pool: &PgPool, filter: &SegmentFilter) -> Result<SegmentPreview, anyhow::Error> {
+    let count = count_members(pool, filter)
+        .await
+        .context("Failed to count the segment members.")?;
+    let sample = sample_members(pool, filter, SAMPLE_SIZE)
+        .await
+        .context("Failed to sample the segment members.")?;
+    Ok(SegmentPreview { count, sample })
+}
+
+async fn parse_filter(pool: &PgPool, filter: &str) -> Result<SegmentFilter, SegmentError> {
+    let schema = load_attribute_schema(pool).await?;
+    Ok(SegmentFilter::parse(filter, &schema)?)
+}
+
+fn constraint_violation_or_unexpected(e: sqlx::Error) -> SegmentError {
+    match e.as_database_error().and_then(|e| e.code()).as_deref() {
+        Some("23505") => SegmentError::NameTaken,
+        Some("23503") => SegmentError::InUse,
+        _ => SegmentError::UnexpectedError(
+            anyhow::Error::new(e).context("Failed to write the segment."),
+        ),
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_segment(pool: &PgPool, segment_id: Uuid) -> Result<Option<Segment>, anyhow::Error> {
+    sqlx::query_as!(
+        Segment,
+        r#"SELECT id, name, filter, created_at, updated_at FROM segments WHERE id = $1"#,
+        segment_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the segment.")
+}
@@ -0,0 +1,270 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{authentication::UserId, merge_tags::MergeTemplate, routes::error_chain_fmt};
+
+#[derive(serde::Deserialize)]
+pub struct SequenceData {
+    name: String,
+    #[serde(default = "default_active")]
+    active: bool,
+    steps: Vec<StepData>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct StepData {
+    /// Hours after enrollment at which the step is sent.
+    delay_hours: i32,
+    subject: String,
+    html_content: String,
+    text_content: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct Sequence {
+    id: Uuid,
+    name: String,
+    active: bool,
+    steps: Vec<StepData>,
+}
+
+impl SequenceData {
+    fn validate(&self) -> Result<(), SequenceError> {
+        if self.name.trim().is_empty() {
+            return Err(SequenceError::ValidationError(
+                "The sequence name is empty.".into(),
+            ));
+        }
+        if self.steps.is_empty() {
+            return Err(SequenceError::ValidationError(
+                "A sequence needs at least one step.".into(),
+            ));
+        }
+        let mut previous_delay = 0;
+        for (i, step) in self.steps.iter().enumerate() {
+            if step.delay_hours < previous_delay {
+                return Err(SequenceError::ValidationError(format!(
+                    "Step {} is sent before the step preceding it.",
+                    i + 1
+                )));
+            }
+            if step.subject.trim().is_empty() {
+                return Err(SequenceError::ValidationError(format!(
+                    "Step {} has an empty subject.",
+                    i + 1
+                )));
+            }
+            for content in [&step.subject, &step.html_content, &step.text_content] {
+                MergeTemplate::parse(content).map_err(|e| {
+                    SequenceError::ValidationError(format!("Step {}: {}", i + 1, e))
+                })?;
+            }
+            previous_delay = step.delay_hours;
+        }
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum SequenceError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no such sequence.")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SequenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SequenceError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Listing sequences", skip(pool), fields(user_id = %*user_id))]
+pub async fn list_sequences(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SequenceError> {
+    let ids = sqlx::query!(r#"SELECT id FROM sequences ORDER BY created_at"#)
+        .fetch_all(pool.get_ref())
+        .await
+        .context("Failed to list sequences.")?;
+    let mut sequences = Vec::with_capacity(ids.len());
+    for r in ids {
+        if let Some(sequence) = get_sequence(&pool, r.id).await? {
+            sequences.push(sequence);
+        }
+    }
+    Ok(HttpResponse::Ok().json(sequences))
+}
+
+#[tracing::instrument(name = "Fetching a sequence", skip(pool), fields(user_id = %*user_id))]
+pub async fn sequence(
+    sequence_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SequenceError> {
+    let sequence = get_sequence(&pool, *sequence_id)
+        .await?
+        .ok_or(SequenceError::NotFound)?;
+    Ok(HttpResponse::Ok().json(sequence))
+}
+
+#[tracing::instrument(
+    name = "Creating a sequence",
+    skip(body, pool),
+    fields(user_id = %*user_id, sequence_name = %body.name)
+)]
+pub async fn create_sequence(
+    body: web::Json<SequenceData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SequenceError> {
+    body.validate()?;
+    let sequence_id = Uuid::new_v4();
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    sqlx::query!(
+        r#"INSERT INTO sequences (id, name, active) VALUES ($1, $2, $3)"#,
+        sequence_id,
+        body.name,
+        body.active
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to insert the sequence.")?;
+    insert_steps(&mut tx, sequence_id, &body.steps).await?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+
+    let sequence = get_sequence(&pool, sequence_id)
+        .await?
+        .context("The new sequence is gone.")?;
+    Ok(HttpResponse::Created().json(sequence))
+}
+
+/// Replaces the name, state and steps of a sequence. Subscribers already
+/// enrolled carry on from the position they reached.
+#[tracing::instrument(
+    name = "Updating a sequence",
+    skip(body, pool),
+    fields(user_id = %*user_id, sequence_name = %body.name)
+)]
+pub async fn update_sequence(
+    sequence_id: web::Path<Uuid>,
+    body: web::Json<SequenceData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SequenceError> {
+    body.validate()?;
+    let sequence_id = *sequence_id;
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    let updated = sqlx::query!(
+        r#"UPDATE sequences SET name = $2, active = $3 WHERE id = $1"#,
+        sequence_id,
+        body.name,
+        body.active
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to update the sequence.")?
+    .rows_affected();
+    if updated == 0 {
+        return Err(SequenceError::NotFound);
+    }
+    sqlx::query!(
+        r#"DELETE FROM sequence_steps WHERE sequence_id = $1"#,
+        sequence_id
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to delete the old steps.")?;
+    insert_steps(&mut tx, sequence_id, &body.steps).await?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+
+    let sequence = get_sequence(&pool, sequence_id)
+        .await?
+        .ok_or(SequenceError::NotFound)?;
+    Ok(HttpResponse::Ok().json(sequence))
+}
+
+async fn insert_steps(
+    tx: &mut Transaction<'_, Postgres>,
+    sequence_id: Uuid,
+    steps: &[StepData],
+) -> Result<(), anyhow::Error> {
+    for (position, step) in (1..).zip(steps) {
+        sqlx::query!(
+            r#"
+            INSERT INTO sequence_steps
+                (sequence_id, position, delay_hours, subject, html_content, text_content)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            sequence_id,
+            position,
+            step.delay_hours,
+            step.subject,
+            step.html_content,
+            step.text_content
+        )
+        .execute(&mut *tx)
+        .await
+        .context("Failed to insert a sequence step.")?;
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_sequence(pool: &PgPool, sequence_id: Uuid) -> Result<Option<Sequence>, anyhow::Error> {
+    let sequence = sqlx::query!(
+        r#"SELECT id, name, active FROM sequences WHERE id = $1"#,
+        sequence_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the sequence.")?;
+    let sequence = match sequence {
+        Some(sequence) => sequence,
+        None => return Ok(None),
+    };
+    let steps = sqlx::query_as!(
+        StepData,
+        r#"
+        SELECT delay_hours, subject, html_content, text_content
+        FROM sequence_steps
+        WHERE sequence_id = $1
+        ORDER BY position
+        "#,
+        sequence_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the sequence steps.")?;
+    Ok(Some(Sequence {
+        id: sequence.id,
+        name: sequence.name,
+        active: sequence.active,
+        steps,
+    }))
+}
@@ -0,0 +1,169 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::{
+    authentication::UserId,
+    domain::{AttributeSchema, FieldDefinition, FieldDefinitionError, FieldType},
+    routes::error_chain_fmt,
+};
+
+#[derive(thiserror::Error)]
+pub enum SubscriberFieldError {
+    #[error(transparent)]
+    ValidationError(#[from] FieldDefinitionError),
+    #[error("There is no such subscriber field.")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscriberFieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscriberFieldError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Listing subscriber fields",
+    skip(pool),
+    fields(user_id = %*user_id)
+)]
+pub async fn list_subscriber_fields(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberFieldError> {
+    let fields = get_field_definitions(&pool).await?;
+    Ok(HttpResponse::Ok().json(fields))
+}
+
+/// Creates the field, or replaces the definition of the field with the same
+/// name. Values already stored are left as they are.
+#[tracing::instrument(
+    name = "Saving a subscriber field",
+    skip(body, pool),
+    fields(user_id = %*user_id, field_name = %body.name)
+)]
+pub async fn save_subscriber_field(
+    body: web::Json<FieldDefinition>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberFieldError> {
+    body.validate()?;
+    let (max_length, min, max, choices) = match &body.field_type {
+        FieldType::Text { max_length } => (*max_length, None, None, None),
+        FieldType::Number { min, max } => (None, *min, *max, None),
+        FieldType::Boolean => (None, None, None, None),
+        FieldType::Choice { choices } => (None, None, None, Some(choices.as_slice())),
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriber_fields
+            (name, field_type, required, signup, max_length, min, max, choices)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (name) DO UPDATE
+        SET field_type = EXCLUDED.field_type, required = EXCLUDED.required,
+            signup = EXCLUDED.signup, max_length = EXCLUDED.max_length,
+            min = EXCLUDED.min, max = EXCLUDED.max, choices = EXCLUDED.choices
+        "#,
+        body.name,
+        field_type_name(&body.field_type),
+        body.required,
+        body.signup,
+        max_length,
+        min,
+        max,
+        choices as Option<&[String]>
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to save the subscriber field.")?;
+    Ok(HttpResponse::Ok().json(body.into_inner()))
+}
+
+/// Removes the field from the schema. Values already stored are kept, but
+/// can no longer be set or filtered on.
+#[tracing::instrument(
+    name = "Deleting a subscriber field",
+    skip(pool),
+    fields(user_id = %*user_id)
+)]
+pub async fn delete_subscriber_field(
+    name: web::Path<String>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberFieldError> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM subscriber_fields WHERE name = $1"#,
+        name.as_str()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to delete the subscriber field.")?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(SubscriberFieldError::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn field_type_name(field_type: &FieldType) -> &'static str {
+    match field_type {
+        FieldType::Text { .. } => "text",
+        FieldType::Number { .. } => "number",
+        FieldType::Boolean => "boolean",
+        FieldType::Choice { .. } => "choice",
+    }
+}
+
+#[tracing::instrument(name = "Loading the subscriber field definitions", skip(pool))]
+async fn get_field_definitions(pool: &PgPool) -> Result<Vec<FieldDefinition>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT name, field_type, required, signup, max_length, min, max, choices
+        FROM subscriber_fields
+        ORDER BY created_at, name
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the subscriber fields.")?;
+    rows.into_iter()
+        .map(|row| {
+            let field_type = match row.field_type.as_str() {
+                "text" => FieldType::Text {
+                    max_length: row.max_length,
+                },
+                "number" => FieldType::Number {
+                    min: row.min,
+                    max: row.max,
+                },
+                "boolean" => FieldType::Boolean,
+                "choice" => FieldType::Choice {
+                    choices: row.choices.unwrap_or_default(),
+                },
+                other => anyhow::bail!("Unknown subscriber field type {:?}.", other),
+            };
+            Ok(FieldDefinition {
+                name: row.name,
+                field_type,
+                required: row.required,
+                signup: row.signup,
+            })
+        })
+        .collect()
+}
+
+pub async fn load_attribute_schema(pool: &PgPool) -> Result<AttributeSchema, anyhow::Error> {
+    Ok(AttributeSchema::new(get_field_definitions(pool).await?))
+}
@@ -0,0 +1,245 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    authentication::UserId,
+    domain::{AttributeError, Attributes, InvalidTag, Tag},
+    routes::{error_chain_fmt, load_attribute_schema},
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(serde::Serialize)]
+pub struct Subscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    attributes: serde_json::Value,
+    tags: Vec<String>,
+}
+
+/// Filters for the subscriber listing, all of which must match:
+/// `status=confirmed`, `tag=beta` (repeatable), `attribute.plan=pro`
+/// (repeatable), plus `limit` and `offset` for paging.
+#[derive(Debug, Default)]
+struct SubscriberFilter {
+    status: Option<String>,
+    tags: Vec<String>,
+    attributes: Attributes,
+    limit: i64,
+    offset: i64,
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscriberAdminError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    InvalidAttribute(#[from] AttributeError),
+    #[error(transparent)]
+    InvalidTag(#[from] InvalidTag),
+    #[error("There is no such subscriber.")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscriberAdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscriberAdminError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidAttribute(_) | Self::InvalidTag(_) => {
+                reqwest::StatusCode::BAD_REQUEST
+            }
+            Self::NotFound => reqwest::StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Listing subscribers",
+    skip(query, pool),
+    fields(user_id = %*user_id)
+)]
+pub async fn list_subscribers(
+    query: web::Query<Vec<(String, String)>>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let filter = parse_filter(&pool, query.into_inner()).await?;
+    let subscribers = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT s.id, s.email, s.name, s.status, s.subscribed_at, s.attributes,
+            COALESCE(
+                array_agg(t.tag ORDER BY t.tag) FILTER (WHERE t.tag IS NOT NULL),
+                '{}'
+            ) AS "tags!"
+        FROM subscriptions s
+        LEFT JOIN subscriber_tags t ON t.subscriber_id = s.id
+        WHERE ($1::text IS NULL OR s.status = $1)
+            AND s.attributes @> $2
+            AND (
+                SELECT count(*) FROM subscriber_tags st
+                WHERE st.subscriber_id = s.id AND st.tag = ANY($3)
+            ) = cardinality($3::text[])
+        GROUP BY s.id
+        ORDER BY s.subscribed_at, s.id
+        LIMIT $4 OFFSET $5
+        "#,
+        filter.status,
+        serde_json::Value::Object(filter.attributes),
+        &filter.tags,
+        filter.limit,
+        filter.offset
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to list subscribers.")?;
+    Ok(HttpResponse::Ok().json(subscribers))
+}
+
+async fn parse_filter(
+    pool: &PgPool,
+    query: Vec<(String, String)>,
+) -> Result<SubscriberFilter, SubscriberAdminError> {
+    let schema = load_attribute_schema(pool).await?;
+    let invalid = |key: &str| SubscriberAdminError::ValidationError(format!("Invalid {}.", key));
+    let mut filter = SubscriberFilter {
+        limit: DEFAULT_PAGE_SIZE,
+        ..Default::default()
+    };
+    for (key, value) in query {
+        match key.as_str() {
+            "status" => filter.status = Some(value),
+            "tag" => filter.tags.push(Tag::parse(&value)?.as_ref().to_string()),
+            "limit" => {
+                filter.limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit| (1..=MAX_PAGE_SIZE).contains(limit))
+                    .ok_or_else(|| invalid("limit"))?
+            }
+            "offset" => {
+                filter.offset = value
+                    .parse()
+                    .ok()
+                    .filter(|offset| *offset >= 0)
+                    .ok_or_else(|| invalid("offset"))?
+            }
+            key => match key.strip_prefix("attribute.") {
+                Some(name) => {
+                    let value = schema.parse_filter(name, &value)?;
+                    filter.attributes.insert(name.to_string(), value);
+                }
+                None => {
+                    return Err(SubscriberAdminError::ValidationError(format!(
+                        "Unknown filter {}.",
+                        key
+                    )))
+                }
+            },
+        }
+    }
+    Ok(filter)
+}
+
+/// Merges the given attributes into the subscriber's, removing the ones set
+/// to null.
+#[tracing::instrument(
+    name = "Updating subscriber attributes",
+    skip(body, pool),
+    fields(user_id = %*user_id)
+)]
+pub async fn update_subscriber_attributes(
+    subscriber_id: web::Path<Uuid>,
+    body: web::Json<Attributes>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let schema = load_attribute_schema(&pool).await?;
+    let changes = schema.parse_update(&body)?;
+    let (set, removed): (Attributes, Attributes) =
+        changes.into_iter().partition(|(_, value)| !value.is_null());
+    let removed: Vec<String> = removed.into_iter().map(|(name, _)| name).collect();
+    let updated = sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET attributes = (attributes || $2) - $3::text[]
+        WHERE id = $1
+        RETURNING attributes
+        "#,
+        *subscriber_id,
+        serde_json::Value::Object(set),
+        &removed
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to update subscriber attributes.")?
+    .ok_or(SubscriberAdminError::NotFound)?;
+    Ok(HttpResponse::Ok().json(updated.attributes))
+}
+
+#[tracing::instrument(name = "Tagging a subscriber", skip(pool), fields(user_id = %*user_id))]
+pub async fn add_subscriber_tag(
+    path: web::Path<(Uuid, String)>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let (subscriber_id, tag) = path.into_inner();
+    let tag = Tag::parse(&tag)?;
+    let exists = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look up the subscriber.")?
+    .is_some();
+    if !exists {
+        return Err(SubscriberAdminError::NotFound);
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriber_tags (subscriber_id, tag) VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        subscriber_id,
+        tag.as_ref()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to tag the subscriber.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(name = "Untagging a subscriber", skip(pool), fields(user_id = %*user_id))]
+pub async fn remove_subscriber_tag(
+    path: web::Path<(Uuid, String)>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberAdminError> {
+    let (subscriber_id, tag) = path.into_inner();
+    let tag = Tag::parse(&tag)?;
+    sqlx::query!(
+        r#"DELETE FROM subscriber_tags WHERE subscriber_id = $1 AND tag = $2"#,
+        subscriber_id,
+        tag.as_ref()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to untag the subscriber.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,210 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::{
+    email_rendering::EmailRenderer,
+    http_cache::conditional_response,
+    merge_tags::{Format, Personalizer, Subscriber},
+    pages::{Page, Pages},
+    startup::{ApplicationBaseUrl, ArchivePageSize},
+};
+
+#[derive(serde::Deserialize)]
+pub struct ArchiveParameters {
+    /// 1-based, the newest issues come first.
+    page: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct ArchiveEntry {
+    title: String,
+    url: String,
+    published_at: String,
+    published_on: String,
+}
+
+/// Lists the issues that were sent and are not private, newest first.
+#[tracing::instrument(
+    name = "Listing archived issues",
+    skip(request, parameters, pool, pages, personalizer, base_url, page_size)
+)]
+pub async fn archive(
+    request: HttpRequest,
+    parameters: web::Query<ArchiveParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    personalizer: web::Data<Personalizer>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    page_size: web::Data<ArchivePageSize>,
+) -> HttpResponse {
+    let page = parameters.page.unwrap_or(1);
+    let offset = match page.checked_sub(1).and_then(|n| n.checked_mul(page_size.0)) {
+        Some(offset) if offset >= 0 => offset,
+        _ => return pages.render(Page::NotFound),
+    };
+    let summary = match archive_summary(&pool).await {
+        Ok(summary) => summary,
+        Err(e) => return internal_error(e),
+    };
+    // An empty archive still has a first page.
+    if page > 1 && offset >= summary.count {
+        return pages.render(Page::NotFound);
+    }
+    let issues = match sqlx::query!(
+        r#"
+        SELECT title, slug AS "slug!", published_at AS "published_at!"
+        FROM newsletter_issues
+        WHERE published_at IS NOT NULL AND NOT private
+        ORDER BY published_at DESC, newsletter_issue_id
+        LIMIT $1 OFFSET $2
+        "#,
+        page_size.0,
+        offset
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    {
+        Ok(issues) => issues,
+        Err(e) => return internal_error(e.into()),
+    };
+
+    let archive_url = format!("{}/archive", base_url.0);
+    let page_url = |page: i64| match page {
+        1 => archive_url.clone(),
+        page => format!("{}?page={}", archive_url, page),
+    };
+    let entries: Vec<_> = issues
+        .into_iter()
+        .map(|issue| ArchiveEntry {
+            title: personalizer.personalize(&issue.title, &Subscriber::anonymous(), Format::Text),
+            url: format!("{}/{}", archive_url, issue.slug),
+            published_at: issue.published_at.to_rfc3339(),
+            published_on: published_on(issue.published_at),
+        })
+        .collect();
+    let mut context = tera::Context::new();
+    context.insert("issues", &entries);
+    context.insert("canonical_url", &page_url(page));
+    if page > 1 {
+        context.insert("newer_url", &page_url(page - 1));
+    }
+    if offset + page_size.0 < summary.count {
+        context.insert("older_url", &page_url(page + 1));
+    }
+    respond(
+        &request,
+        &pages,
+        Page::Archive,
+        context,
+        summary.last_modified,
+    )
+}
+
+/// Shows an issue the way it was sent, without what is only meant for
+/// subscribers, e.g. their name or unsubscribe link.
+#[tracing::instrument(
+    name = "Showing an archived issue",
+    skip(request, pool, pages, personalizer, email_renderer, base_url)
+)]
+pub async fn archived_issue(
+    request: HttpRequest,
+    slug: web::Path<String>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    personalizer: web::Data<Personalizer>,
+    email_renderer: web::Data<EmailRenderer>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    let issue = match sqlx::query!(
+        r#"
+        SELECT title, html_content, published_at AS "published_at!", updated_at
+        FROM newsletter_issues
+        WHERE slug = $1 AND published_at IS NOT NULL AND NOT private
+        "#,
+        slug.as_str()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    {
+        Ok(Some(issue)) => issue,
+        Ok(None) => return pages.render(Page::NotFound),
+        Err(e) => return internal_error(e.into()),
+    };
+
+    let archive_url = format!("{}/archive", base_url.0);
+    let mut context = tera::Context::new();
+    context.insert(
+        "title",
+        &personalizer.personalize(&issue.title, &Subscriber::anonymous(), Format::Text),
+    );
+    context.insert(
+        "content",
+        &public_html(&personalizer, &email_renderer, &issue.html_content),
+    );
+    context.insert("published_at", &issue.published_at.to_rfc3339());
+    context.insert("published_on", &published_on(issue.published_at));
+    context.insert("canonical_url", &format!("{}/{}", archive_url, slug));
+    context.insert("archive_url", &archive_url);
+    respond(
+        &request,
+        &pages,
+        Page::ArchiveIssue,
+        context,
+        Some(issue.updated_at),
+    )
+}
+
+/// Fills in merge tags for nobody in particular and sanitises the result like
+/// outgoing emails, since it is served from our own origin.
+pub(crate) fn public_html(
+    personalizer: &Personalizer,
+    email_renderer: &EmailRenderer,
+    html: &str,
+) -> String {
+    email_renderer.render(&personalizer.personalize(html, &Subscriber::anonymous(), Format::Html))
+}
+
+pub(crate) struct ArchiveSummary {
+    pub count: i64,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+pub(crate) async fn archive_summary(pool: &PgPool) -> Result<ArchiveSummary, anyhow::Error> {
+    sqlx::query_as!(
+        ArchiveSummary,
+        r#"
+        SELECT count(*) AS "count!", max(updated_at) AS last_modified
+        FROM newsletter_issues
+        WHERE published_at IS NOT NULL AND NOT private
+        "#
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to count the archived issues.")
+}
+
+fn published_on(published_at: DateTime<Utc>) -> String {
+    published_at.format("%-d %B %Y").to_string()
+}
+
+fn respond(
+    request: &HttpRequest,
+    pages: &Pages,
+    page: Page,
+    context: tera::Context,
+    last_modified: Option<DateTime<Utc>>,
+) -> HttpResponse {
+    match pages.render_to_string(page, context) {
+        Ok(body) => conditional_response(request, "text/html; charset=utf-8", body, last_modified),
+        Err(e) => {
+            internal_error(anyhow::Error::from(e).context(format!("Failed to render {:?}.", page)))
+        }
+    }
+}
+
+fn internal_error(e: anyhow::Error) -> HttpResponse {
+    tracing::error!(error.cause_chain = ?e, "Failed to show the archive.");
+    HttpResponse::InternalServerError().finish()
+}
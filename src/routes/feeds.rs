@@ -0,0 +1,188 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::{
+    email_rendering::EmailRenderer,
+    email_templates::EmailTemplates,
+    feeds::{self, FeedDetails, FeedEntry},
+    http_cache::conditional_response,
+    merge_tags::{Format, Personalizer, Subscriber},
+    routes::{archive_summary, public_html},
+    startup::{ApplicationBaseUrl, ArchivePageSize},
+};
+
+#[derive(Clone, Copy, Debug)]
+enum FeedFormat {
+    Rss,
+    Atom,
+}
+
+impl FeedFormat {
+    fn path(&self) -> &'static str {
+        match self {
+            Self::Rss => "/feed.rss",
+            Self::Atom => "/feed.atom",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Rss => "application/rss+xml; charset=utf-8",
+            Self::Atom => "application/atom+xml; charset=utf-8",
+        }
+    }
+}
+
+/// The latest page of the archive as RSS 2.0.
+#[tracing::instrument(
+    name = "Serving the RSS feed",
+    skip(
+        request,
+        pool,
+        personalizer,
+        email_renderer,
+        email_templates,
+        base_url,
+        page_size
+    )
+)]
+pub async fn rss_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    personalizer: web::Data<Personalizer>,
+    email_renderer: web::Data<EmailRenderer>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    page_size: web::Data<ArchivePageSize>,
+) -> HttpResponse {
+    feed(
+        FeedFormat::Rss,
+        &request,
+        &pool,
+        &personalizer,
+        &email_renderer,
+        &email_templates,
+        &base_url,
+        &page_size,
+    )
+    .await
+}
+
+/// The latest page of the archive as Atom.
+#[tracing::instrument(
+    name = "Serving the Atom feed",
+    skip(
+        request,
+        pool,
+        personalizer,
+        email_renderer,
+        email_templates,
+        base_url,
+        page_size
+    )
+)]
+pub async fn atom_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    personalizer: web::Data<Personalizer>,
+    email_renderer: web::Data<EmailRenderer>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    page_size: web::Data<ArchivePageSize>,
+) -> HttpResponse {
+    feed(
+        FeedFormat::Atom,
+        &request,
+        &pool,
+        &personalizer,
+        &email_renderer,
+        &email_templates,
+        &base_url,
+        &page_size,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn feed(
+    format: FeedFormat,
+    request: &HttpRequest,
+    pool: &PgPool,
+    personalizer: &Personalizer,
+    email_renderer: &EmailRenderer,
+    email_templates: &EmailTemplates,
+    base_url: &ApplicationBaseUrl,
+    page_size: &ArchivePageSize,
+) -> HttpResponse {
+    let archive_url = format!("{}/archive", base_url.0);
+    let feed_url = format!("{}{}", base_url.0, format.path());
+    let details = FeedDetails {
+        title: &email_templates.list().name,
+        archive_url: &archive_url,
+        feed_url: &feed_url,
+    };
+    let entries = match feed_entries(
+        pool,
+        personalizer,
+        email_renderer,
+        &archive_url,
+        page_size.0,
+    )
+    .await
+    {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to build the {:?} feed.", format);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let body = match format {
+        FeedFormat::Rss => feeds::rss(&details, &entries),
+        FeedFormat::Atom => feeds::atom(&details, &entries),
+    };
+    // The newest `updated_at` of the whole archive, since an issue dropping
+    // off the end changes the feed too.
+    let last_modified = match archive_summary(pool).await {
+        Ok(summary) => summary.last_modified,
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to build the {:?} feed.", format);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    conditional_response(request, format.content_type(), body, last_modified)
+}
+
+async fn feed_entries(
+    pool: &PgPool,
+    personalizer: &Personalizer,
+    email_renderer: &EmailRenderer,
+    archive_url: &str,
+    limit: i64,
+) -> Result<Vec<FeedEntry>, anyhow::Error> {
+    let issues = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id AS id, title, slug AS "slug!", html_content,
+            published_at AS "published_at!", updated_at
+        FROM newsletter_issues
+        WHERE published_at IS NOT NULL AND NOT private
+        ORDER BY published_at DESC, newsletter_issue_id
+        LIMIT $1
+        "#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the latest issues.")?;
+    Ok(issues
+        .into_iter()
+        .map(|issue| FeedEntry {
+            id: issue.id,
+            title: personalizer.personalize(&issue.title, &Subscriber::anonymous(), Format::Text),
+            url: format!("{}/{}", archive_url, issue.slug),
+            html: public_html(personalizer, email_renderer, &issue.html_content),
+            published_at: issue.published_at,
+            updated_at: issue.updated_at,
+        })
+        .collect())
+}
@@ -0,0 +1,46 @@
+use actix_web::{http::header, web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A transparent 1x1 GIF.
+const PIXEL: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(serde::Deserialize)]
+pub struct OpenParameters {
+    manage_token: String,
+}
+
+/// The tracking pixel of an issue: records the first time a subscriber's
+/// email client loaded it, for `opened` segment filters. The image is served
+/// whatever happens, so it never shows up broken.
+#[tracing::instrument(name = "Recording an issue open", skip(parameters, pool))]
+pub async fn record_issue_open(
+    issue_id: web::Path<Uuid>,
+    parameters: web::Query<OpenParameters>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let recorded = sqlx::query!(
+        r#"
+        INSERT INTO issue_opens (newsletter_issue_id, subscriber_id)
+        SELECT i.newsletter_issue_id, s.id
+        FROM newsletter_issues i, subscriptions s
+        WHERE i.newsletter_issue_id = $1 AND s.manage_token = $2
+        ON CONFLICT DO NOTHING
+        "#,
+        issue_id.into_inner(),
+        parameters.manage_token
+    )
+    .execute(pool.get_ref())
+    .await;
+    if let Err(e) = recorded {
+        tracing::error!(error.cause_chain = ?e, "Failed to record an issue open.");
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(PIXEL.as_slice())
+}
@@ -1,8 +1,24 @@
 //! src/routes/mod.rs
+mod admin;
+mod archive;
+mod feeds;
 mod health_check;
+mod issue_opens;
 mod subscriptions;
+mod subscriptions_challenge;
+mod subscriptions_change_email;
 mod subscriptions_confirm;
+mod subscriptions_manage;
+mod widget;
 
+pub use admin::*;
+pub use archive::*;
+pub use feeds::*;
 pub use health_check::*;
+pub use issue_opens::*;
 pub use subscriptions::*;
+pub use subscriptions_challenge::*;
+pub use subscriptions_change_email::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_manage::*;
+pub use widget::*;
@@ -1,79 +1,262 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
 use actix_web::{
-    web::{self, Form},
-    HttpResponse, ResponseError,
+    http::header::{self, ContentType},
+    web::{self, Form, Json},
+    Either, HttpRequest, HttpResponse, ResponseError,
 };
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use serde_aux::field_attributes::deserialize_option_number_from_string;
 use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionToken},
+    bot_protection::{BotProtection, Submission},
+    domain::{
+        AttributeError, AttributeSchema, EmailPolicy, EmailValidationError, NamePolicy,
+        NameValidationError, NewSubscriber, SubscriberName, SubscriptionToken,
+    },
+    domain_checker::DomainChecker,
     email_client::EmailClient,
-    startup::ApplicationBaseUrl,
+    email_templates::{Email, EmailTemplates, Recipient},
+    merge_tags::unsubscribe_link,
+    pages::{Page, Pages, RedirectAllowlist, RedirectNotAllowed},
+    rate_limit::{email_key, Decision, SubscriptionRateLimits, TokenBucket},
+    routes::load_attribute_schema,
+    startup::{ApplicationBaseUrl, ConfirmationLinkTtl},
 };
 
+/// Accepted both urlencoded and as JSON. Missing fields are read as empty,
+/// so they are reported like any other invalid field.
 #[derive(serde::Deserialize)]
 pub struct FormData {
+    #[serde(default)]
     email: String,
+    #[serde(default)]
     name: String,
+    /// Honeypot, hidden from people by the form's CSS.
+    website: Option<String>,
+    form_nonce: Option<String>,
+    // Flattening reads every value as a string from urlencoded bodies.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    proof_of_work: Option<u64>,
+    /// Where to send the reader once subscribed, for forms hosted elsewhere.
+    redirect_to: Option<String>,
+    /// Everything else, from which the subscriber fields open to signup are
+    /// picked.
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+impl FormData {
+    fn bot_submission(&self) -> Submission<'_> {
+        Submission {
+            honeypot: self.website.as_deref(),
+            nonce: self.form_nonce.as_deref(),
+            proof_of_work: self.proof_of_work,
+        }
+    }
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+    fn parse(
+        self,
+        email_policy: &EmailPolicy,
+        name_policy: &NamePolicy,
+        schema: &AttributeSchema,
+    ) -> Result<NewSubscriber, SubscribeError> {
+        let name = SubscriberName::parse_with_policy(self.name, name_policy)?;
+        let email = email_policy.parse(self.email)?;
+        let attributes = schema.parse_signup(&self.extra)?;
+        Ok(NewSubscriber {
+            email,
+            name,
+            attributes,
+        })
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+pub async fn subscribe(
+    request: HttpRequest,
+    body: Either<Json<FormData>, Form<FormData>>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    email_policy: web::Data<EmailPolicy>,
+    name_policy: web::Data<NamePolicy>,
+    domain_checker: web::Data<Option<DomainChecker>>,
+    rate_limits: web::Data<SubscriptionRateLimits>,
+    bot_protection: web::Data<BotProtection>,
+    pages: web::Data<Pages>,
+    redirect_allowlist: web::Data<RedirectAllowlist>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+) -> Result<HttpResponse, SubscribeResponseError> {
+    let format = ResponseFormat::negotiate(&request, matches!(body, Either::Left(_)));
+    let form = match body {
+        Either::Left(json) => json.0,
+        Either::Right(form) => form.0,
+    };
+    let redirect_to = form
+        .redirect_to
+        .as_deref()
+        .map(|redirect_to| redirect_allowlist.check(redirect_to))
+        .transpose()
+        .map_err(|e| SubscribeResponseError {
+            error: e.into(),
+            format,
+        })?;
+    add_subscriber(
+        &request,
+        form,
+        &pool,
+        &email_client,
+        &email_templates,
+        &base_url,
+        &email_policy,
+        &name_policy,
+        domain_checker.as_ref().as_ref(),
+        &rate_limits,
+        &bot_protection,
+        &confirmation_link_ttl,
+    )
+    .await
+    .map_err(|error| SubscribeResponseError { error, format })?;
+
+    Ok(match (redirect_to, format) {
+        (Some(redirect_to), _) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, redirect_to.as_str()))
+            .finish(),
+        (None, ResponseFormat::Html) => pages.render(Page::CheckInbox),
+        (None, _) => HttpResponse::Ok().finish(),
+    })
+}
+
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(
+        request,
+        form,
+        pool,
+        email_client,
+        email_templates,
+        base_url,
+        email_policy,
+        name_policy,
+        domain_checker,
+        rate_limits,
+        bot_protection,
+        confirmation_link_ttl
+    ),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
     )
 )]
-pub async fn subscribe(
-    form: Form<FormData>,
-    pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-    base_url: web::Data<ApplicationBaseUrl>,
-) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber = form.0.try_into()?;
+#[allow(clippy::too_many_arguments)]
+async fn add_subscriber(
+    request: &HttpRequest,
+    form: FormData,
+    pool: &PgPool,
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    base_url: &ApplicationBaseUrl,
+    email_policy: &EmailPolicy,
+    name_policy: &NamePolicy,
+    domain_checker: Option<&DomainChecker>,
+    rate_limits: &SubscriptionRateLimits,
+    bot_protection: &BotProtection,
+    confirmation_link_ttl: &ConfirmationLinkTtl,
+) -> Result<(), SubscribeError> {
+    enforce_rate_limit(
+        pool,
+        &rate_limits.per_ip,
+        &rate_limits.client_ip_key(request),
+    )
+    .await?;
+    if let Err(reason) = bot_protection.check(&form.bot_submission()) {
+        // Pretend it worked, so bots do not learn what gave them away.
+        tracing::warn!(%reason, "Rejected a suspected bot submission.");
+        return Ok(());
+    }
+    let form_nonce = form.form_nonce.clone();
+    let schema = load_attribute_schema(pool).await?;
+    let new_subscriber = form.parse(email_policy, name_policy, &schema)?;
+    enforce_rate_limit(
+        pool,
+        &rate_limits.per_email,
+        &email_key(&new_subscriber.email),
+    )
+    .await?;
+    email_policy.check(&new_subscriber.email)?;
+    if let Some(domain_checker) = domain_checker {
+        domain_checker.check(&new_subscriber.email).await?;
+    }
+    // Spent only now, so someone fixing a typo can resubmit the same form.
+    if !bot_protection
+        .spend_nonce(pool, form_nonce.as_deref())
+        .await
+        .context("Failed to record the form nonce.")?
+    {
+        tracing::warn!("Rejected a replayed form nonce.");
+        return Ok(());
+    }
     let mut tx = pool.begin().await.context("Failed to get transaction")?;
-    let pending_subscription_token =
-        check_and_get_token_pending_confirmation(&mut tx, &new_subscriber).await?;
+    let existing_subscription = get_existing_subscription(
+        &mut tx,
+        &new_subscriber,
+        Utc::now() - confirmation_link_ttl.0,
+    )
+    .await?;
 
-    let subscription_token = match pending_subscription_token {
-        Some(pending_token) => SubscriptionToken::parse(pending_token)?,
-        None => {
-            let subscriber_id = insert_subscriber(&mut tx, &new_subscriber)
-                .await
-                .context("Failed to insert new subscriber.")?;
-            let subscription_token = SubscriptionToken::generate();
-            store_token(&mut tx, subscriber_id, subscription_token.as_ref())
-                .await
-                .context("Failed to store token.")?;
-            subscription_token
-        }
+    let reply = match existing_subscription {
+        Some(existing) => reply_to_existing(&mut tx, existing, &new_subscriber).await?,
+        None => match insert_subscriber(&mut tx, &new_subscriber)
+            .await
+            .context("Failed to insert new subscriber.")?
+        {
+            Some(subscriber_id) => Reply::Confirm(issue_token(&mut tx, subscriber_id).await?),
+            // A concurrent request subscribed the same address after we
+            // looked, so answer as if it had come first.
+            None => {
+                let existing = get_existing_subscription(
+                    &mut tx,
+                    &new_subscriber,
+                    Utc::now() - confirmation_link_ttl.0,
+                )
+                .await?
+                .context("The conflicting subscription disappeared.")?;
+                reply_to_existing(&mut tx, existing, &new_subscriber).await?
+            }
+        },
     };
 
     tx.commit().await.context("Failed to commit transaction.")?;
 
-    send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        subscription_token.as_ref(),
-    )
-    .await
-    .context("Failed to send confirmation email.")?;
+    match reply {
+        Reply::Confirm(subscription_token) => send_confirmation_email(
+            email_client,
+            email_templates,
+            pool,
+            new_subscriber,
+            &base_url.0,
+            subscription_token.as_ref(),
+        )
+        .await
+        .context("Failed to send confirmation email.")?,
+        Reply::AlreadySubscribed { manage_token } => send_already_subscribed_email(
+            email_client,
+            email_templates,
+            pool,
+            new_subscriber,
+            &unsubscribe_link(&base_url.0, &manage_token),
+        )
+        .await
+        .context("Failed to send already subscribed email.")?,
+    }
 
-    Ok(HttpResponse::Ok().finish())
+    Ok(())
 }
 
 #[derive(thiserror::Error)]
@@ -81,6 +264,18 @@ pub enum SubscribeError {
     #[error("{0}")]
     ValidationError(String),
     #[error(transparent)]
+    InvalidEmail(#[from] EmailValidationError),
+    #[error(transparent)]
+    InvalidName(#[from] NameValidationError),
+    #[error(transparent)]
+    InvalidAttribute(#[from] AttributeError),
+    #[error(transparent)]
+    RedirectNotAllowed(#[from] RedirectNotAllowed),
+    #[error("Emails to this address bounce, please subscribe with another one.")]
+    Suppressed,
+    #[error("Too many requests, please try again in {} seconds.", .0.as_secs())]
+    RateLimited(Duration),
+    #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
 
@@ -99,38 +294,277 @@ impl std::fmt::Debug for SubscribeError {
 impl ResponseError for SubscribeError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
-            Self::ValidationError(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::ValidationError(_)
+            | Self::InvalidEmail(_)
+            | Self::InvalidName(_)
+            | Self::InvalidAttribute(_)
+            | Self::RedirectNotAllowed(_)
+            | Self::Suppressed => reqwest::StatusCode::BAD_REQUEST,
+            Self::RateLimited(_) => reqwest::StatusCode::TOO_MANY_REQUESTS,
             Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::RateLimited(retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.as_secs()));
+        }
+        response
+            .insert_header(ContentType::plaintext())
+            .body(self.to_string())
+    }
+}
+
+impl SubscribeError {
+    /// The form field the error is about, if any.
+    fn field(&self) -> Option<&str> {
+        match self {
+            Self::InvalidEmail(_) | Self::Suppressed => Some("email"),
+            Self::InvalidName(_) => Some("name"),
+            Self::InvalidAttribute(e) => Some(e.name()),
+            Self::RedirectNotAllowed(_) => Some("redirect_to"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ResponseFormat {
+    PlainText,
+    ProblemJson,
+    Html,
+}
+
+impl ResponseFormat {
+    /// JSON when the `Accept` header asks for it, or when the request body
+    /// was JSON and the client did not ask for anything in particular. HTML
+    /// for browsers submitting a form.
+    fn negotiate(request: &HttpRequest, sent_json: bool) -> Self {
+        let accept = request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or("*/*");
+        if accept.contains("json") || (sent_json && accept.trim() == "*/*") {
+            Self::ProblemJson
+        } else if accept.contains("text/html") {
+            Self::Html
+        } else {
+            Self::PlainText
+        }
+    }
+}
+
+/// A `SubscribeError` in the format the client negotiated.
+pub struct SubscribeResponseError {
+    error: SubscribeError,
+    format: ResponseFormat,
+}
+
+/// RFC 7807 problem details, with field-level errors as an extension member.
+#[derive(serde::Serialize)]
+struct ProblemDetails<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    errors: BTreeMap<&'a str, String>,
+}
+
+impl std::fmt::Debug for SubscribeResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl std::fmt::Display for SubscribeResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl ResponseError for SubscribeResponseError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        self.error.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = self.error.error_response();
+        if let ResponseFormat::ProblemJson = self.format {
+            let status = self.status_code();
+            let problem = ProblemDetails {
+                problem_type: "about:blank",
+                title: status.canonical_reason().unwrap_or_default(),
+                status: status.as_u16(),
+                detail: self.error.to_string(),
+                errors: self
+                    .error
+                    .field()
+                    .map(|field| (field, self.error.to_string()))
+                    .into_iter()
+                    .collect(),
+            };
+            let mut json = HttpResponse::build(status);
+            // Keep headers such as `Retry-After`.
+            for (name, value) in response.headers() {
+                if name != header::CONTENT_TYPE {
+                    json.insert_header((name.clone(), value.clone()));
+                }
+            }
+            response = json.content_type("application/problem+json").json(problem);
+        }
+        response
+    }
+}
+
+/// Takes a token from `bucket`, failing with `RateLimited` once it is empty.
+async fn enforce_rate_limit(
+    pool: &PgPool,
+    bucket: &TokenBucket,
+    key: &str,
+) -> Result<(), SubscribeError> {
+    match bucket
+        .acquire(pool, key)
+        .await
+        .context("Failed to check the rate limit.")?
+    {
+        Decision::Allowed => Ok(()),
+        Decision::Limited { retry_after } => {
+            tracing::warn!(key, "Rate limit exceeded.");
+            Err(SubscribeError::RateLimited(retry_after))
+        }
+    }
+}
+
+/// The email a subscribe request is answered with.
+enum Reply {
+    Confirm(SubscriptionToken),
+    AlreadySubscribed { manage_token: String },
+}
+
+/// Decides how to answer someone subscribing an address that is already known.
+async fn reply_to_existing(
+    tx: &mut Transaction<'_, Postgres>,
+    existing: ExistingSubscription,
+    new_subscriber: &NewSubscriber,
+) -> Result<Reply, SubscribeError> {
+    let reply = match existing.status.as_str() {
+        "pending_confirmation" => match existing.subscription_token {
+            Some(pending_token) => Reply::Confirm(SubscriptionToken::parse(pending_token)?),
+            None => Reply::Confirm(issue_token(tx, existing.subscriber_id).await?),
+        },
+        // Answer as if they were new, so the form does not reveal who is
+        // subscribed, and let the inbox owner know instead.
+        "confirmed" => Reply::AlreadySubscribed {
+            manage_token: existing.manage_token,
+        },
+        "unsubscribed" => {
+            restart_subscription(tx, existing.subscriber_id, new_subscriber)
+                .await
+                .context("Failed to restart subscription.")?;
+            Reply::Confirm(issue_token(tx, existing.subscriber_id).await?)
+        }
+        "suppressed" => return Err(SubscribeError::Suppressed),
+        status => return Err(anyhow::anyhow!("Unknown subscription status {:?}.", status).into()),
+    };
+    Ok(reply)
+}
+
+async fn issue_token(
+    tx: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<SubscriptionToken, anyhow::Error> {
+    let subscription_token = SubscriptionToken::generate();
+    store_token(tx, subscriber_id, subscription_token.as_ref())
+        .await
+        .context("Failed to store token.")?;
+    Ok(subscription_token)
+}
+
+struct ExistingSubscription {
+    subscriber_id: Uuid,
+    status: String,
+    manage_token: String,
+    /// The newest token issued after the cutoff, if any.
+    subscription_token: Option<String>,
 }
 
 #[tracing::instrument(
-    name = "Checking for an existing pending subscriber and get its token",
+    name = "Checking for an existing subscriber and get its token",
     skip(tx, new_subscriber)
 )]
-async fn check_and_get_token_pending_confirmation(
+async fn get_existing_subscription(
     tx: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Option<String>, SubscribeError> {
-    let token = sqlx::query!(
+    issued_after: DateTime<Utc>,
+) -> Result<Option<ExistingSubscription>, SubscribeError> {
+    // Locks the row, so a concurrent confirmation or unsubscription cannot
+    // change the status we act on.
+    let existing_subscription = sqlx::query!(
         r#"
-        SELECT subscription_token FROM subscription_tokens
-        join subscriptions on subscriptions.id = subscription_tokens.subscriber_id
-        WHERE subscriptions.email = $1
-        AND subscriptions.status = 'pending_confirmation'
+        SELECT subscriptions.id, subscriptions.status, subscriptions.manage_token,
+            subscription_tokens.subscription_token as "subscription_token?"
+        FROM subscriptions
+        LEFT JOIN subscription_tokens
+        ON subscription_tokens.subscriber_id = subscriptions.id
+        AND subscription_tokens.created_at > $2
+        WHERE subscriptions.canonical_email = $1
+        ORDER BY subscription_tokens.created_at DESC NULLS LAST
+        LIMIT 1
+        FOR UPDATE OF subscriptions
         "#,
-        new_subscriber.email.as_ref()
+        new_subscriber.email.canonical(),
+        issued_after
     )
     .fetch_optional(tx)
     .await
-    .context("Failed to fetch pending token.")?
-    .map(|row| row.subscription_token);
+    .context("Failed to fetch existing subscription.")?
+    .map(|row| ExistingSubscription {
+        subscriber_id: row.id,
+        status: row.status,
+        manage_token: row.manage_token,
+        subscription_token: row.subscription_token,
+    });
+
+    Ok(existing_subscription)
+}
 
-    Ok(token)
+/// Puts an unsubscribed reader back through double opt-in, as if they had
+/// just signed up. Their old sequence enrollments go too, so confirming again
+/// starts the welcome sequences from the first step.
+#[tracing::instrument(name = "Restarting an ended subscription", skip(tx, new_subscriber))]
+async fn restart_subscription(
+    tx: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_subscriber: &NewSubscriber,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = 'pending_confirmation', email = $2, name = $3, subscribed_at = now(),
+            reminder_sent_at = NULL, attributes = attributes || $4
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        new_subscriber.email.as_ref(),
+        new_subscriber.name.as_ref(),
+        serde_json::Value::Object(new_subscriber.attributes.clone())
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM sequence_enrollments WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
 }
 
-fn error_chain_fmt(
+pub fn error_chain_fmt(
     e: &impl std::error::Error,
     f: &mut std::fmt::Formatter<'_>,
 ) -> std::fmt::Result {
@@ -208,37 +642,84 @@ impl std::error::Error for StoreTokenError {
 
 #[tracing::instrument(
     name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    skip(
+        email_client,
+        email_templates,
+        pool,
+        new_subscriber,
+        base_url,
+        subscription_token
+    )
 )]
 async fn send_confirmation_email(
     email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    pool: &PgPool,
     new_subscriber: NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
-    let confirmation_link = format!(
-        "{}/subscriptions/confirm?subscription_token={}",
-        base_url, subscription_token
-    );
-    let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our newsletter!<br/>\
-             Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
-    );
+) -> Result<(), anyhow::Error> {
+    let email = Email::Confirmation {
+        confirmation_link: confirmation_link(base_url, subscription_token),
+    };
+    let rendered = email_templates
+        .render(pool, &recipient(&new_subscriber), &email)
+        .await?;
     email_client
         .send_email(
             new_subscriber.email,
-            "Welcome!",
-            plain_body.as_str(),
-            html_body.as_str(),
+            &rendered.subject,
+            &rendered.html,
+            &rendered.text,
         )
-        .await
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Send an already subscribed email to the subscriber",
+    skip(email_client, email_templates, pool, new_subscriber, unsubscribe_url)
+)]
+async fn send_already_subscribed_email(
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    pool: &PgPool,
+    new_subscriber: NewSubscriber,
+    unsubscribe_url: &str,
+) -> Result<(), anyhow::Error> {
+    let email = Email::AlreadySubscribed {
+        unsubscribe_url: unsubscribe_url.to_string(),
+    };
+    let rendered = email_templates
+        .render(pool, &recipient(&new_subscriber), &email)
+        .await?;
+    email_client
+        .send_email(
+            new_subscriber.email,
+            &rendered.subject,
+            &rendered.html,
+            &rendered.text,
+        )
+        .await?;
+    Ok(())
+}
+
+fn recipient(new_subscriber: &NewSubscriber) -> Recipient {
+    Recipient {
+        name: new_subscriber.name.as_ref().to_string(),
+        email: new_subscriber.email.as_ref().to_string(),
+    }
 }
 
+pub fn confirmation_link(base_url: &str, subscription_token: &str) -> String {
+    format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    )
+}
+
+/// Returns the new subscriber's id, or `None` when the address is already
+/// subscribed.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(new_subscriber, tx)
@@ -246,23 +727,27 @@ async fn send_confirmation_email(
 pub async fn insert_subscriber(
     tx: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let inserted = sqlx::query!(
         r#"
-        insert into subscriptions (id, email, name, subscribed_at, status)
-        values ($1, $2, $3, $4, 'pending_confirmation')
+        insert into subscriptions
+            (id, email, canonical_email, name, subscribed_at, status, attributes)
+        values ($1, $2, $3, $4, $5, 'pending_confirmation', $6)
+        on conflict (canonical_email) do nothing
+        returning id
         "#,
-        subscriber_id,
+        Uuid::new_v4(),
         new_subscriber.email.as_ref(),
+        new_subscriber.email.canonical(),
         new_subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        serde_json::Value::Object(new_subscriber.attributes.clone())
     )
-    .execute(tx)
+    .fetch_optional(tx)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
-    Ok(subscriber_id)
+    Ok(inserted.map(|r| r.id))
 }
@@ -0,0 +1,14 @@
+use actix_web::{
+    http::header::{CacheControl, CacheDirective},
+    web, HttpResponse,
+};
+
+use crate::bot_protection::BotProtection;
+
+/// Hands out a fresh form nonce, and the proof of work it asks for, to be
+/// sent back with `POST /subscriptions`.
+pub async fn subscribe_challenge(bot_protection: web::Data<BotProtection>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(bot_protection.issue())
+}
@@ -0,0 +1,605 @@
+use std::time::Duration;
+
+use actix_web::{
+    http::header::{self, ContentType},
+    web::{self, Form},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    domain::{EmailPolicy, EmailValidationError, SubscriberEmail, SubscriptionToken},
+    domain_checker::DomainChecker,
+    email_client::EmailClient,
+    email_templates::{Email, EmailTemplates, Recipient},
+    merge_tags::unsubscribe_link,
+    pages::{Page, Pages},
+    rate_limit::{email_key, Decision, SubscriptionRateLimits, TokenBucket},
+    startup::{ApplicationBaseUrl, ConfirmationLinkTtl},
+};
+
+use super::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct ChangeEmailFormData {
+    email: String,
+    new_email: String,
+}
+
+/// Starts moving a subscription to another address. The change takes effect
+/// once the new address has confirmed it and the old one has approved it, so
+/// knowing someone's address is not enough to take their subscription.
+#[tracing::instrument(
+    name = "Requesting a change of subscriber email",
+    skip(
+        request,
+        form,
+        pool,
+        email_client,
+        email_templates,
+        base_url,
+        email_policy,
+        domain_checker,
+        rate_limits
+    ),
+    fields(
+        subscriber_email = %form.email,
+        new_subscriber_email = %form.new_email
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn change_email(
+    request: HttpRequest,
+    form: Form<ChangeEmailFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    email_policy: web::Data<EmailPolicy>,
+    domain_checker: web::Data<Option<DomainChecker>>,
+    rate_limits: web::Data<SubscriptionRateLimits>,
+) -> Result<HttpResponse, ChangeEmailError> {
+    enforce_rate_limit(
+        &pool,
+        &rate_limits.per_ip,
+        &rate_limits.client_ip_key(&request),
+    )
+    .await?;
+    let current_email = email_policy.parse(form.0.email)?;
+    let new_email = email_policy.parse(form.0.new_email)?;
+    if current_email.canonical() == new_email.canonical() {
+        return Err(ChangeEmailError::ValidationError(
+            "The new email is the same as the current one.".into(),
+        ));
+    }
+    // Both addresses are sent an email.
+    for email in [&current_email, &new_email] {
+        enforce_rate_limit(&pool, &rate_limits.per_email, &email_key(email)).await?;
+    }
+    email_policy.check(&new_email)?;
+    if let Some(domain_checker) = domain_checker.as_ref() {
+        domain_checker.check(&new_email).await?;
+    }
+
+    let mut tx = pool.begin().await.context("Failed to get transaction")?;
+    let (subscriber_id, name, manage_token) =
+        match get_confirmed_subscriber(&mut tx, &current_email).await? {
+            Some(subscriber) => subscriber,
+            None => {
+                // Do not reveal whether an address is subscribed or not.
+                tracing::warn!("No confirmed subscriber found for the current email.");
+                return Ok(HttpResponse::Ok().finish());
+            }
+        };
+    if is_email_taken(&mut tx, &new_email).await? {
+        tracing::warn!("The new email already belongs to a subscriber.");
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    let change_token = SubscriptionToken::generate();
+    let approval_token = SubscriptionToken::generate();
+    store_email_change_request(
+        &mut tx,
+        subscriber_id,
+        &new_email,
+        change_token.as_ref(),
+        approval_token.as_ref(),
+    )
+    .await
+    .context("Failed to store the email change request.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+
+    let confirmation_link = format!(
+        "{}/subscriptions/change_email/confirm?change_token={}",
+        base_url.0,
+        change_token.as_ref()
+    );
+    send_email(
+        &email_client,
+        &email_templates,
+        &pool,
+        new_email,
+        &name,
+        &Email::EmailChangeConfirmation { confirmation_link },
+    )
+    .await
+    .context("Failed to send email change confirmation.")?;
+    let approval_link = format!(
+        "{}/subscriptions/change_email/approve?approval_token={}",
+        base_url.0,
+        approval_token.as_ref()
+    );
+    send_email(
+        &email_client,
+        &email_templates,
+        &pool,
+        current_email,
+        &name,
+        &Email::EmailChangeNotice {
+            approval_link,
+            unsubscribe_url: unsubscribe_link(&base_url.0, &manage_token),
+        },
+    )
+    .await
+    .context("Failed to send email change notice.")?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// What each address does to an email change request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// The new address confirms it is theirs.
+    Confirm,
+    /// The old address approves the move.
+    Approve,
+}
+
+impl Step {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Confirm => "confirm",
+            Self::Approve => "approve",
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangeEmailParameters {
+    change_token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ApproveEmailChangeParameters {
+    approval_token: String,
+}
+
+/// Link scanners fetch every link in an email, so opening the link only asks
+/// the reader to press a button that submits the token.
+#[tracing::instrument(
+    name = "Opening an email change confirmation link",
+    skip(parameters, pool, pages, confirmation_link_ttl)
+)]
+pub async fn confirm_email_change(
+    parameters: web::Query<ChangeEmailParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+) -> HttpResponse {
+    open_link(
+        &pool,
+        &pages,
+        &confirmation_link_ttl,
+        Step::Confirm,
+        &parameters.change_token,
+    )
+    .await
+}
+
+#[tracing::instrument(
+    name = "Confirming a change of subscriber email",
+    skip(form, pool, pages, confirmation_link_ttl, email_policy)
+)]
+pub async fn submit_email_change_confirmation(
+    form: Form<ChangeEmailParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+    email_policy: web::Data<EmailPolicy>,
+) -> Result<HttpResponse, ChangeEmailError> {
+    complete_step(
+        &pool,
+        &pages,
+        &confirmation_link_ttl,
+        &email_policy,
+        Step::Confirm,
+        &form.change_token,
+    )
+    .await
+}
+
+/// Like `confirm_email_change`, for the link sent to the old address.
+#[tracing::instrument(
+    name = "Opening an email change approval link",
+    skip(parameters, pool, pages, confirmation_link_ttl)
+)]
+pub async fn approve_email_change(
+    parameters: web::Query<ApproveEmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+) -> HttpResponse {
+    open_link(
+        &pool,
+        &pages,
+        &confirmation_link_ttl,
+        Step::Approve,
+        &parameters.approval_token,
+    )
+    .await
+}
+
+#[tracing::instrument(
+    name = "Approving a change of subscriber email",
+    skip(form, pool, pages, confirmation_link_ttl, email_policy)
+)]
+pub async fn submit_email_change_approval(
+    form: Form<ApproveEmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+    email_policy: web::Data<EmailPolicy>,
+) -> Result<HttpResponse, ChangeEmailError> {
+    complete_step(
+        &pool,
+        &pages,
+        &confirmation_link_ttl,
+        &email_policy,
+        Step::Approve,
+        &form.approval_token,
+    )
+    .await
+}
+
+async fn open_link(
+    pool: &PgPool,
+    pages: &Pages,
+    confirmation_link_ttl: &ConfirmationLinkTtl,
+    step: Step,
+    token: &str,
+) -> HttpResponse {
+    if let Err(response) = check_token(pool, pages, confirmation_link_ttl, step, token).await {
+        return response;
+    }
+    let mut context = tera::Context::new();
+    context.insert("step", step.as_str());
+    context.insert("token", token);
+    pages.render_with(Page::ConfirmEmailChange, context)
+}
+
+/// Records `step` and, once both addresses have done their part, moves the
+/// subscription.
+async fn complete_step(
+    pool: &PgPool,
+    pages: &Pages,
+    confirmation_link_ttl: &ConfirmationLinkTtl,
+    email_policy: &EmailPolicy,
+    step: Step,
+    token: &str,
+) -> Result<HttpResponse, ChangeEmailError> {
+    let mut tx = pool.begin().await.context("Failed to get transaction")?;
+    // Locks the request, so the two steps completing at once see each other.
+    let request = match check_token(&mut tx, pages, confirmation_link_ttl, step, token).await {
+        Ok(request) => request,
+        Err(response) => return Ok(response),
+    };
+    let other_step_done = match step {
+        Step::Confirm => request.approved_at.is_some(),
+        Step::Approve => request.confirmed_at.is_some(),
+    };
+    record_step(&mut tx, step, token)
+        .await
+        .context("Failed to record the email change step.")?;
+    if !other_step_done {
+        tx.commit().await.context("Failed to commit transaction.")?;
+        return Ok(pages.render(Page::EmailChangePending));
+    }
+
+    let new_email = email_policy
+        .parse(request.new_email)
+        .context("Stored new email is not a valid email.")?;
+    if is_email_taken(&mut tx, &new_email).await? {
+        return Err(ChangeEmailError::EmailTaken);
+    }
+    swap_subscriber_email(&mut tx, request.subscriber_id, &new_email)
+        .await
+        .context("Failed to update subscriber email.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+    Ok(pages.render(Page::EmailChanged))
+}
+
+/// Returns the request the token belongs to, or the page explaining why it
+/// cannot be used.
+async fn check_token(
+    executor: impl PgExecutor<'_>,
+    pages: &Pages,
+    confirmation_link_ttl: &ConfirmationLinkTtl,
+    step: Step,
+    token: &str,
+) -> Result<EmailChangeRequest, HttpResponse> {
+    let token =
+        SubscriptionToken::parse(token.to_string()).map_err(|_| pages.render(Page::InvalidLink))?;
+    match get_email_change_request(executor, step, token.as_ref()).await {
+        Ok(Some(request)) if request.requested_at < Utc::now() - confirmation_link_ttl.0 => {
+            Err(pages.render(Page::LinkExpired))
+        }
+        Ok(Some(request)) => Ok(request),
+        Ok(None) => Err(pages.render(Page::InvalidLink)),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to look up an email change request.");
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ChangeEmailError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    InvalidEmail(#[from] EmailValidationError),
+    #[error("The new email already belongs to a subscriber.")]
+    EmailTaken,
+    #[error("Too many requests, please try again in {} seconds.", .0.as_secs())]
+    RateLimited(Duration),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ChangeEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ChangeEmailError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidEmail(_) => reqwest::StatusCode::BAD_REQUEST,
+            Self::EmailTaken => reqwest::StatusCode::CONFLICT,
+            Self::RateLimited(_) => reqwest::StatusCode::TOO_MANY_REQUESTS,
+            Self::UnexpectedError(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::RateLimited(retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.as_secs()));
+        }
+        response
+            .insert_header(ContentType::plaintext())
+            .body(self.to_string())
+    }
+}
+
+/// Takes a token from `bucket`, failing with `RateLimited` once it is empty.
+async fn enforce_rate_limit(
+    pool: &PgPool,
+    bucket: &TokenBucket,
+    key: &str,
+) -> Result<(), ChangeEmailError> {
+    match bucket
+        .acquire(pool, key)
+        .await
+        .context("Failed to check the rate limit.")?
+    {
+        Decision::Allowed => Ok(()),
+        Decision::Limited { retry_after } => {
+            tracing::warn!(key, "Rate limit exceeded.");
+            Err(ChangeEmailError::RateLimited(retry_after))
+        }
+    }
+}
+
+/// Returns the subscriber's id, name and manage token.
+#[tracing::instrument(name = "Getting a confirmed subscriber by email", skip(tx, email))]
+async fn get_confirmed_subscriber(
+    tx: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<(Uuid, String, String)>, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT id, name, manage_token FROM subscriptions
+        WHERE canonical_email = $1 AND status = 'confirmed'
+        "#,
+        email.canonical()
+    )
+    .fetch_optional(tx)
+    .await
+    .context("Failed to fetch confirmed subscriber.")?;
+    Ok(result.map(|r| (r.id, r.name, r.manage_token)))
+}
+
+#[tracing::instrument(
+    name = "Checking whether an email is already subscribed",
+    skip(tx, email)
+)]
+async fn is_email_taken(
+    tx: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE canonical_email = $1"#,
+        email.canonical()
+    )
+    .fetch_optional(tx)
+    .await
+    .context("Failed to look up subscriber by email.")?;
+    Ok(result.is_some())
+}
+
+#[tracing::instrument(
+    name = "Storing email change request in the database",
+    skip(tx, new_email, change_token, approval_token)
+)]
+async fn store_email_change_request(
+    tx: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+    change_token: &str,
+    approval_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        insert into email_change_requests
+            (change_token, approval_token, subscriber_id, new_email, requested_at)
+        values ($1, $2, $3, $4, $5)
+        "#,
+        change_token,
+        approval_token,
+        subscriber_id,
+        new_email.as_ref(),
+        Utc::now()
+    )
+    .execute(tx)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+struct EmailChangeRequest {
+    subscriber_id: Uuid,
+    new_email: String,
+    requested_at: DateTime<Utc>,
+    confirmed_at: Option<DateTime<Utc>>,
+    approved_at: Option<DateTime<Utc>>,
+}
+
+/// Looks the request up by the token sent for `step`, locking it.
+#[tracing::instrument(
+    name = "Getting an email change request by token",
+    skip(executor, token)
+)]
+async fn get_email_change_request(
+    executor: impl PgExecutor<'_>,
+    step: Step,
+    token: &str,
+) -> Result<Option<EmailChangeRequest>, sqlx::Error> {
+    match step {
+        Step::Confirm => {
+            sqlx::query_as!(
+                EmailChangeRequest,
+                r#"
+                SELECT subscriber_id, new_email, requested_at, confirmed_at, approved_at
+                FROM email_change_requests
+                WHERE change_token = $1
+                FOR UPDATE
+                "#,
+                token
+            )
+            .fetch_optional(executor)
+            .await
+        }
+        Step::Approve => {
+            sqlx::query_as!(
+                EmailChangeRequest,
+                r#"
+                SELECT subscriber_id, new_email, requested_at, confirmed_at, approved_at
+                FROM email_change_requests
+                WHERE approval_token = $1
+                FOR UPDATE
+                "#,
+                token
+            )
+            .fetch_optional(executor)
+            .await
+        }
+    }
+}
+
+async fn record_step(
+    tx: &mut Transaction<'_, Postgres>,
+    step: Step,
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    match step {
+        Step::Confirm => {
+            sqlx::query!(
+                r#"UPDATE email_change_requests SET confirmed_at = now() WHERE change_token = $1"#,
+                token
+            )
+            .execute(tx)
+            .await?
+        }
+        Step::Approve => {
+            sqlx::query!(
+                r#"UPDATE email_change_requests SET approved_at = now() WHERE approval_token = $1"#,
+                token
+            )
+            .execute(tx)
+            .await?
+        }
+    };
+    Ok(())
+}
+
+/// Updates the address in place, so the subscriber keeps its id and with it
+/// everything that references it.
+#[tracing::instrument(name = "Swapping subscriber email", skip(tx, new_email))]
+async fn swap_subscriber_email(
+    tx: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET email = $1, canonical_email = $2 WHERE id = $3"#,
+        new_email.as_ref(),
+        new_email.canonical(),
+        subscriber_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Send an email about an email change",
+    skip(email_client, email_templates, pool, recipient, name),
+    fields(email = email.kind().name())
+)]
+async fn send_email(
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    pool: &PgPool,
+    recipient: SubscriberEmail,
+    name: &str,
+    email: &Email,
+) -> Result<(), anyhow::Error> {
+    let rendered = email_templates
+        .render(
+            pool,
+            &Recipient {
+                name: name.to_string(),
+                email: recipient.as_ref().to_string(),
+            },
+            email,
+        )
+        .await?;
+    email_client
+        .send_email(recipient, &rendered.subject, &rendered.html, &rendered.text)
+        .await?;
+    Ok(())
+}
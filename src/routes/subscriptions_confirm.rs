@@ -1,48 +1,151 @@
 use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
-use crate::domain::SubscriptionToken;
+use crate::{
+    domain::SubscriptionToken,
+    pages::{Page, Pages},
+    sequences::enroll_subscriber,
+    startup::{ConfirmationLinkTtl, OneClickConfirmation},
+};
 
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirming a pending subscriber", skip(parameters, pool))]
-pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let subscription_token =
-        match SubscriptionToken::parse(parameters.subscription_token.to_string()) {
-            Ok(token) => token,
-            Err(_) => return HttpResponse::Unauthorized().finish(),
-        };
-    let id = match get_subscriber_id_from_token(&pool, subscription_token.as_ref()).await {
+/// Link scanners fetch every link in an email, so unless one-click
+/// confirmation is switched on this only asks the reader to press a button
+/// that submits the token to `submit_confirmation`.
+#[tracing::instrument(
+    name = "Opening a confirmation link",
+    skip(parameters, pool, pages, confirmation_link_ttl, one_click_confirmation)
+)]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+    one_click_confirmation: web::Data<OneClickConfirmation>,
+) -> HttpResponse {
+    let id = match check_token(
+        &pool,
+        &pages,
+        &parameters.subscription_token,
+        &confirmation_link_ttl,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if !one_click_confirmation.0 {
+        let mut context = tera::Context::new();
+        context.insert("subscription_token", &parameters.subscription_token);
+        return pages.render_with(Page::ConfirmSubscription, context);
+    }
+    if confirm_subscriber_and_use_up_tokens(&pool, id)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    pages.render(Page::Confirmed)
+}
+
+#[tracing::instrument(
+    name = "Confirming a pending subscriber",
+    skip(form, pool, pages, confirmation_link_ttl)
+)]
+pub async fn submit_confirmation(
+    form: web::Form<Parameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    confirmation_link_ttl: web::Data<ConfirmationLinkTtl>,
+) -> HttpResponse {
+    let id = match check_token(
+        &pool,
+        &pages,
+        &form.subscription_token,
+        &confirmation_link_ttl,
+    )
+    .await
+    {
         Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+        Err(response) => return response,
     };
-    match id {
-        Some(id) => {
-            if confirm_subscriber(&pool, id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
+    if confirm_subscriber_and_use_up_tokens(&pool, id)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    pages.render(Page::Confirmed)
+}
+
+/// Returns the subscriber the token belongs to, or the response explaining
+/// why it cannot be used.
+async fn check_token(
+    pool: &PgPool,
+    pages: &Pages,
+    subscription_token: &str,
+    confirmation_link_ttl: &ConfirmationLinkTtl,
+) -> Result<Uuid, HttpResponse> {
+    let subscription_token = SubscriptionToken::parse(subscription_token.to_string())
+        .map_err(|_| pages.render(Page::InvalidLink))?;
+    match get_subscriber_id_from_token(pool, subscription_token.as_ref()).await {
+        Ok(Some((_, created_at))) if created_at < Utc::now() - confirmation_link_ttl.0 => {
+            Err(pages.render(Page::LinkExpired))
         }
-        None => HttpResponse::Unauthorized().finish(),
+        Ok(Some((id, _))) => Ok(id),
+        Ok(None) => Err(pages.render(Page::InvalidLink)),
+        Err(_) => Err(HttpResponse::InternalServerError().finish()),
     }
 }
 
-#[tracing::instrument(name = "Confirming a pending subscriber", skip(subscriber_id, pool))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+#[tracing::instrument(
+    name = "Confirming a pending subscriber and deleting its tokens",
+    skip(subscriber_id, pool)
+)]
+async fn confirm_subscriber_and_use_up_tokens(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    confirm_and_enroll(&mut tx, subscriber_id).await?;
     sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await.map_err(|error| {
+        tracing::error!("Failed to execute query: {:?}", error);
+        error
+    })
+}
+
+/// Confirms a pending subscriber and enrolls them in the welcome sequences.
+/// Anyone who is already confirmed or has unsubscribed since is left alone.
+async fn confirm_and_enroll(
+    tx: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let confirmed = sqlx::query!(
         r#"
         UPDATE subscriptions
         SET status = 'confirmed'
-        WHERE id = $1
+        WHERE id = $1 AND status = 'pending_confirmation'
         "#,
         subscriber_id
     )
-    .execute(pool)
-    .await?;
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if confirmed > 0 {
+        enroll_subscriber(tx, subscriber_id).await?;
+    }
     Ok(())
 }
 
@@ -50,13 +153,14 @@ pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<()
     name = "Getting a subscriber id from a subscription token",
     skip(pool, subscription_token)
 )]
+/// Returns the subscriber the token belongs to and when it was issued.
 pub async fn get_subscriber_id_from_token(
     pool: &PgPool,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
+) -> Result<Option<(Uuid, DateTime<Utc>)>, sqlx::Error> {
     let result = sqlx::query!(
         r#"
-        SELECT subscriber_id FROM subscription_tokens
+        SELECT subscriber_id, created_at FROM subscription_tokens
         WHERE subscription_token = $1
         "#,
         subscription_token
@@ -67,5 +171,5 @@ pub async fn get_subscriber_id_from_token(
         tracing::error!("Failed to execute query: {:?}", error);
         error
     })?;
-    Ok(result.map(|r| r.subscriber_id))
+    Ok(result.map(|r| (r.subscriber_id, r.created_at)))
 }
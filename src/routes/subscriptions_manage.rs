@@ -0,0 +1,145 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    domain::{NamePolicy, SubscriberName},
+    pages::{Page, Pages},
+};
+
+#[derive(serde::Deserialize)]
+pub struct ManageParameters {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesForm {
+    token: String,
+    name: String,
+}
+
+struct ManagedSubscriber {
+    id: Uuid,
+    name: String,
+    email: String,
+}
+
+/// Link scanners fetch every link in an email, so this only asks the reader
+/// to press a button that submits the token to `unsubscribe`.
+#[tracing::instrument(name = "Opening an unsubscribe link", skip(parameters, pool, pages))]
+pub async fn unsubscribe_form(
+    parameters: web::Query<ManageParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+) -> HttpResponse {
+    if let Err(response) = check_manage_token(&pool, &pages, &parameters.token).await {
+        return response;
+    }
+    let mut context = tera::Context::new();
+    context.insert("token", &parameters.token);
+    pages.render_with(Page::Unsubscribe, context)
+}
+
+#[tracing::instrument(name = "Unsubscribing", skip(form, pool, pages))]
+pub async fn unsubscribe(
+    form: web::Form<ManageParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+) -> HttpResponse {
+    let subscriber = match check_manage_token(&pool, &pages, &form.token).await {
+        Ok(subscriber) => subscriber,
+        Err(response) => return response,
+    };
+    let unsubscribed = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1 AND status = 'confirmed'"#,
+        subscriber.id
+    )
+    .execute(pool.get_ref())
+    .await;
+    if let Err(e) = unsubscribed {
+        tracing::error!(error.cause_chain = ?e, "Failed to unsubscribe.");
+        return HttpResponse::InternalServerError().finish();
+    }
+    pages.render(Page::Unsubscribed)
+}
+
+#[tracing::instrument(name = "Opening the preferences page", skip(parameters, pool, pages))]
+pub async fn preferences(
+    parameters: web::Query<ManageParameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+) -> HttpResponse {
+    let subscriber = match check_manage_token(&pool, &pages, &parameters.token).await {
+        Ok(subscriber) => subscriber,
+        Err(response) => return response,
+    };
+    let mut context = tera::Context::new();
+    context.insert("token", &parameters.token);
+    context.insert("name", &subscriber.name);
+    context.insert("email", &subscriber.email);
+    pages.render_with(Page::Preferences, context)
+}
+
+#[tracing::instrument(name = "Saving preferences", skip(form, pool, pages, name_policy))]
+pub async fn save_preferences(
+    form: web::Form<PreferencesForm>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<Pages>,
+    name_policy: web::Data<NamePolicy>,
+) -> HttpResponse {
+    let subscriber = match check_manage_token(&pool, &pages, &form.token).await {
+        Ok(subscriber) => subscriber,
+        Err(response) => return response,
+    };
+    let mut context = tera::Context::new();
+    context.insert("token", &form.token);
+    context.insert("email", &subscriber.email);
+    let name = match SubscriberName::parse_with_policy(form.name.clone(), &name_policy) {
+        Ok(name) => name,
+        Err(e) => {
+            context.insert("name", &form.name);
+            context.insert("error", &e.to_string());
+            let mut response = pages.render_with(Page::Preferences, context);
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+    let saved = sqlx::query!(
+        r#"UPDATE subscriptions SET name = $2 WHERE id = $1"#,
+        subscriber.id,
+        name.as_ref()
+    )
+    .execute(pool.get_ref())
+    .await;
+    if let Err(e) = saved {
+        tracing::error!(error.cause_chain = ?e, "Failed to save preferences.");
+        return HttpResponse::InternalServerError().finish();
+    }
+    context.insert("name", name.as_ref());
+    context.insert("saved", &true);
+    pages.render_with(Page::Preferences, context)
+}
+
+/// Returns the subscriber the token in their emails belongs to, or the
+/// response explaining why it cannot be used.
+async fn check_manage_token(
+    pool: &PgPool,
+    pages: &Pages,
+    token: &str,
+) -> Result<ManagedSubscriber, HttpResponse> {
+    let subscriber = sqlx::query_as!(
+        ManagedSubscriber,
+        r#"SELECT id, name, email FROM subscriptions WHERE manage_token = $1"#,
+        token
+    )
+    .fetch_optional(pool)
+    .await;
+    match subscriber {
+        Ok(Some(subscriber)) => Ok(subscriber),
+        Ok(None) => Err(pages.render(Page::InvalidLink)),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to look up the manage token.");
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use actix_web::{
+    http::header::{self, CacheControl, CacheDirective, HeaderValue},
+    web, HttpResponse,
+};
+
+use crate::pages::{Page, Pages};
+
+const WIDGET_SCRIPT: &str = include_str!("../../static/widget.js");
+
+/// `Content-Security-Policy` value restricting which sites may frame `/widget`.
+pub struct WidgetFrameAncestors(pub String);
+
+pub async fn widget(
+    pages: web::Data<Pages>,
+    frame_ancestors: web::Data<WidgetFrameAncestors>,
+) -> HttpResponse {
+    let mut response = pages.render(Page::Widget);
+    if let Ok(policy) = HeaderValue::from_str(&frame_ancestors.0) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_SECURITY_POLICY, policy);
+    }
+    response
+}
+
+pub async fn widget_script() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/javascript; charset=utf-8"))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(3600),
+        ]))
+        .body(WIDGET_SCRIPT)
+}
@@ -0,0 +1,829 @@
+use crate::{
+    configuration::{RssToEmailSettings, Settings},
+    domain::IssueStatus,
+    markdown,
+    startup::get_connection_pool,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+pub const DEFAULT_TITLE_TEMPLATE: &str = "{{ item.title }}";
+pub const DEFAULT_BODY_TEMPLATE: &str = "## [{{ item.title }}]({{ item.link }})
+
+{{ item.text }}
+";
+pub const DEFAULT_DIGEST_TITLE_TEMPLATE: &str = "{{ items | length }} new posts this week";
+pub const DEFAULT_DIGEST_BODY_TEMPLATE: &str = "{% for item in items %}\
+## [{{ item.title }}]({{ item.link }})
+
+{{ item.text }}
+
+{% endfor %}";
+
+#[async_trait::async_trait]
+pub trait FeedFetcher: Send + Sync {
+    /// The document served at `url`.
+    async fn fetch(&self, url: &str) -> Result<String, anyhow::Error>;
+}
+
+/// Redirects followed before giving up on a feed.
+const MAX_REDIRECTS: usize = 10;
+
+/// Fetches feeds over http and https. Feed URLs are chosen by admins, so
+/// unless `allow_private_addresses` is set it refuses to connect to private,
+/// loopback and link-local addresses, redirects included, rather than let the
+/// app be used to probe its own network.
+pub struct HttpFeedFetcher {
+    timeout: std::time::Duration,
+    max_feed_bytes: usize,
+    allow_private_addresses: bool,
+}
+
+impl HttpFeedFetcher {
+    pub fn new(
+        timeout: std::time::Duration,
+        max_feed_bytes: usize,
+        allow_private_addresses: bool,
+    ) -> Self {
+        Self {
+            timeout,
+            max_feed_bytes,
+            allow_private_addresses,
+        }
+    }
+
+    /// A client for `url` that connects to the address checked here, so the
+    /// host cannot resolve to a public address for the check and a private
+    /// one for the request. Redirects are followed by `fetch`, which checks
+    /// each of them the same way.
+    async fn client_for(&self, url: &Url) -> Result<reqwest::Client, anyhow::Error> {
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!(
+                "Feeds are fetched over http or https, not {}.",
+                url.scheme()
+            );
+        }
+        let client = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .redirect(Policy::none());
+        if self.allow_private_addresses {
+            return Ok(client.build()?);
+        }
+        let host = url.host_str().context("The feed URL has no host.")?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let address = tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port))
+            .await
+            .with_context(|| format!("Failed to resolve {}.", host))?
+            .find(|address| is_public(address.ip()))
+            .with_context(|| {
+                format!(
+                    "Feeds are not fetched from private addresses such as {}.",
+                    host
+                )
+            })?;
+        Ok(client.resolve(host, address).build()?)
+    }
+}
+
+#[async_trait::async_trait]
+impl FeedFetcher for HttpFeedFetcher {
+    async fn fetch(&self, url: &str) -> Result<String, anyhow::Error> {
+        let mut url = Url::parse(url).context("The feed URL is invalid.")?;
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self.client_for(&url).await?.get(url.clone()).send().await?;
+            if !response.status().is_redirection() {
+                return read_body(response.error_for_status()?, self.max_feed_bytes).await;
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .context("The feed redirected without a location.")?
+                .to_str()
+                .context("The feed redirected to an invalid location.")?;
+            url = url
+                .join(location)
+                .context("The feed redirected to an invalid location.")?;
+        }
+        anyhow::bail!("The feed redirected more than {} times.", MAX_REDIRECTS)
+    }
+}
+
+/// Reads a body of at most `max_bytes`. The length the server announces may
+/// be missing or wrong, so the body is counted as it comes in.
+async fn read_body(
+    mut response: reqwest::Response,
+    max_bytes: usize,
+) -> Result<String, anyhow::Error> {
+    let too_large = || anyhow::anyhow!("The feed is over {} bytes.", max_bytes);
+    if matches!(response.content_length(), Some(length) if length > max_bytes as u64) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Whether `ip` is reachable on the internet, as opposed to private,
+/// loopback, link-local or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || first == 0
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link-local, fe80::/10.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// What happens to the issues made from a feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedMode {
+    /// Issues are left as drafts, to go through review like any other.
+    Draft,
+    /// Issues are scheduled to go out straight away.
+    Send,
+}
+
+impl FeedMode {
+    pub fn parse(s: &str) -> Result<FeedMode, String> {
+        match s {
+            "draft" => Ok(Self::Draft),
+            "send" => Ok(Self::Send),
+            other => Err(format!("{} is not a valid feed mode.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Draft => "draft",
+            Self::Send => "send",
+        }
+    }
+}
+
+/// An item of an external feed. Templates get it with an extra `text`, its
+/// content as plain text escaped for Markdown.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FeedItem {
+    pub guid: String,
+    pub title: String,
+    /// Empty if the item has none.
+    pub link: String,
+    /// HTML, which Markdown escapes rather than renders.
+    pub content: String,
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+/// Reads an RSS 2.0 or an Atom document. Items are identified by their GUID,
+/// or failing that their link, and items with neither are dropped.
+pub fn parse_feed(body: &str) -> Result<Vec<FeedItem>, anyhow::Error> {
+    if let Ok(channel) = body.parse::<rss::Channel>() {
+        return Ok(channel
+            .items
+            .into_iter()
+            .filter_map(|item| {
+                let link = item.link.unwrap_or_default();
+                let guid = item
+                    .guid
+                    .map(|guid| guid.value)
+                    .unwrap_or_else(|| link.clone());
+                (!guid.is_empty()).then(|| FeedItem {
+                    guid,
+                    title: item.title.unwrap_or_default(),
+                    link,
+                    content: item.content.or(item.description).unwrap_or_default(),
+                    published_at: item
+                        .pub_date
+                        .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                        .map(|date| date.with_timezone(&Utc)),
+                })
+            })
+            .collect());
+    }
+    let feed = body
+        .parse::<atom_syndication::Feed>()
+        .context("The document is neither RSS nor Atom.")?;
+    Ok(feed
+        .entries
+        .into_iter()
+        .filter(|entry| !entry.id.is_empty())
+        .map(|entry| FeedItem {
+            link: entry
+                .links
+                .iter()
+                .find(|link| link.rel == "alternate")
+                .or_else(|| entry.links.first())
+                .map(|link| link.href.clone())
+                .unwrap_or_default(),
+            content: entry
+                .content
+                .and_then(|content| content.value)
+                .or(entry.summary.map(|summary| summary.value))
+                .unwrap_or_default(),
+            published_at: Some(entry.published.unwrap_or(entry.updated).with_timezone(&Utc)),
+            guid: entry.id,
+            title: entry.title.value,
+        })
+        .collect())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RenderedIssue {
+    pub title: String,
+    /// Markdown, like issues written by hand.
+    pub markdown: String,
+}
+
+/// Renders a feed's Tera templates, which get the new `items` and, for
+/// convenience, the first of them as `item`.
+pub fn render_issue(
+    title_template: &str,
+    body_template: &str,
+    items: &[FeedItem],
+) -> Result<RenderedIssue, tera::Error> {
+    let items: Vec<_> = items
+        .iter()
+        .map(|item| TemplateItem {
+            text: html_to_markdown_text(&item.content),
+            item,
+        })
+        .collect();
+    let mut context = tera::Context::new();
+    context.insert("items", &items);
+    if let Some(item) = items.first() {
+        context.insert("item", item);
+    }
+    let title = tera::Tera::one_off(title_template, &context, false)?;
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    Ok(RenderedIssue {
+        title: if title.is_empty() {
+            "Untitled".into()
+        } else {
+            title
+        },
+        markdown: tera::Tera::one_off(body_template, &context, false)?,
+    })
+}
+
+#[derive(serde::Serialize)]
+struct TemplateItem<'a> {
+    #[serde(flatten)]
+    item: &'a FeedItem,
+    text: String,
+}
+
+/// The text of an HTML fragment, with a blank line between blocks and
+/// everything Markdown would read as markup escaped.
+fn html_to_markdown_text(html: &str) -> String {
+    const BLOCKS: &[&str] = &[
+        "p",
+        "div",
+        "br",
+        "li",
+        "ul",
+        "ol",
+        "blockquote",
+        "pre",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "table",
+        "tr",
+        "hr",
+        "figure",
+    ];
+    let mut text = String::new();
+    let mut rest = html;
+    let mut skipping = None;
+    while let Some(start) = rest.find('<') {
+        if skipping.is_none() {
+            text.push_str(&rest[..start]);
+        }
+        let end = match rest[start..].find('>') {
+            Some(end) => end,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match skipping {
+            Some(ref skipped) if closing && *skipped == name => skipping = None,
+            Some(_) => {}
+            None if !closing && (name == "script" || name == "style") => skipping = Some(name),
+            None if BLOCKS.contains(&name.as_str()) => text.push_str("\n\n"),
+            None => {}
+        }
+    }
+    if skipping.is_none() {
+        text.push_str(rest);
+    }
+
+    let paragraphs: Vec<String> = decode_entities(&text)
+        .split("\n\n")
+        .map(|paragraph| {
+            let paragraph = paragraph.split_whitespace().collect::<Vec<_>>().join(" ");
+            let mut escaped = String::with_capacity(paragraph.len());
+            // Digits then `.` or `)` start an ordered list, `-`, `+` and `=`
+            // a list or a heading underline.
+            let number = paragraph.len() - paragraph.trim_start_matches(char::is_numeric).len();
+            for (i, c) in paragraph.char_indices() {
+                let starts_block = (i == 0 && "-+=".contains(c))
+                    || (i == number && number > 0 && ".)".contains(c));
+                if starts_block || "\\`*_[]<>#~|".contains(c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        })
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect();
+    paragraphs.join("\n\n")
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let c = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        });
+        match (entity, c) {
+            (Some(entity), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// What the templates of a new feed are checked against.
+pub fn sample_items() -> Vec<FeedItem> {
+    (1..=2)
+        .map(|n| FeedItem {
+            guid: format!("https://example.com/posts/{}", n),
+            title: format!("Post #{}", n),
+            link: format!("https://example.com/posts/{}", n),
+            content: "<p>Hello</p>".into(),
+            published_at: Some(Utc::now()),
+        })
+        .collect()
+}
+
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    let fetcher = configuration.rss_to_email.fetcher();
+    worker_loop(pool, fetcher, configuration.rss_to_email).await
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    fetcher: HttpFeedFetcher,
+    settings: RssToEmailSettings,
+) -> Result<(), anyhow::Error> {
+    loop {
+        if let Err(e) = poll_feeds(&pool, &fetcher, &settings).await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to poll feeds."
+            );
+        }
+        tokio::time::sleep(settings.check_interval()).await;
+    }
+}
+
+/// What one pass over the feeds did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PollReport {
+    pub feeds_checked: u64,
+    /// Feeds that could not be fetched, read or rendered; see `last_error`.
+    pub feeds_failed: u64,
+    pub new_items: u64,
+    pub issues_created: u64,
+}
+
+/// Fetches every feed and turns the items it has not seen before into
+/// issues, or keeps them for the feed's next digest.
+///
+/// The first poll of a feed only records the items already there, so
+/// registering a feed does not send its whole history.
+#[tracing::instrument(skip_all)]
+pub async fn poll_feeds(
+    pool: &PgPool,
+    fetcher: &dyn FeedFetcher,
+    settings: &RssToEmailSettings,
+) -> Result<PollReport, anyhow::Error> {
+    let mut report = PollReport::default();
+    let feeds = sqlx::query!(r#"SELECT id, url FROM rss_feeds ORDER BY created_at"#)
+        .fetch_all(pool)
+        .await
+        .context("Failed to list feeds.")?;
+    for feed in feeds {
+        report.feeds_checked += 1;
+        let outcome = match fetcher
+            .fetch(&feed.url)
+            .await
+            .and_then(|body| parse_feed(&body))
+        {
+            Ok(items) => process_feed(pool, feed.id, items, settings, &mut report).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = outcome {
+            report.feeds_failed += 1;
+            tracing::warn!(error.cause_chain = ?e, feed.url = %feed.url, "Failed to poll a feed.");
+            sqlx::query!(
+                r#"UPDATE rss_feeds SET last_error = $2 WHERE id = $1"#,
+                feed.id,
+                format!("{:#}", e)
+            )
+            .execute(pool)
+            .await
+            .context("Failed to record a feed's error.")?;
+        }
+    }
+    Ok(report)
+}
+
+async fn process_feed(
+    pool: &PgPool,
+    feed_id: Uuid,
+    items: Vec<FeedItem>,
+    settings: &RssToEmailSettings,
+    report: &mut PollReport,
+) -> Result<(), anyhow::Error> {
+    let mut tx = pool
+        .begin()
+        .await
+        .context("Failed to start a transaction.")?;
+    // Replicas polling at the same time wait here, then find the items taken.
+    let feed = match sqlx::query!(
+        r#"
+        SELECT mode, digest, title_template, body_template, last_checked_at, last_digest_at
+        FROM rss_feeds
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        feed_id
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .context("Failed to lock the feed.")?
+    {
+        Some(feed) => feed,
+        // Deleted since the pass started.
+        None => return Ok(()),
+    };
+    let mode = FeedMode::parse(&feed.mode).map_err(anyhow::Error::msg)?;
+    let first_poll = feed.last_checked_at.is_none();
+
+    // Feeds list the newest items first.
+    let mut new_items = Vec::new();
+    for item in items.into_iter().rev() {
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO rss_feed_items
+                (feed_id, guid, title, link, content, published_at, handled_at)
+            VALUES ($1, $2, $3, $4, $5, $6, CASE WHEN $7 THEN now() END)
+            ON CONFLICT DO NOTHING
+            "#,
+            feed_id,
+            item.guid,
+            item.title,
+            item.link,
+            item.content,
+            item.published_at,
+            first_poll
+        )
+        .execute(&mut tx)
+        .await
+        .context("Failed to record a feed item.")?
+        .rows_affected();
+        if inserted == 1 {
+            new_items.push(item);
+        }
+    }
+    if !first_poll {
+        report.new_items += new_items.len() as u64;
+    }
+
+    if !first_poll && !feed.digest {
+        for item in new_items {
+            let items = [item];
+            let issue_id = create_issue(
+                &mut tx,
+                mode,
+                &feed.title_template,
+                &feed.body_template,
+                &items,
+            )
+            .await?;
+            mark_handled(&mut tx, feed_id, &[items[0].guid.clone()], issue_id).await?;
+            report.issues_created += 1;
+        }
+    }
+    if feed.digest && Utc::now() - feed.last_digest_at >= settings.digest_interval() {
+        let pending = sqlx::query_as!(
+            FeedItem,
+            r#"
+            SELECT guid, title, link, content, published_at
+            FROM rss_feed_items
+            WHERE feed_id = $1 AND handled_at IS NULL
+            ORDER BY published_at NULLS LAST, seen_at
+            "#,
+            feed_id
+        )
+        .fetch_all(&mut tx)
+        .await
+        .context("Failed to fetch the items waiting for the digest.")?;
+        if !pending.is_empty() {
+            let issue_id = create_issue(
+                &mut tx,
+                mode,
+                &feed.title_template,
+                &feed.body_template,
+                &pending,
+            )
+            .await?;
+            let guids: Vec<_> = pending.into_iter().map(|item| item.guid).collect();
+            mark_handled(&mut tx, feed_id, &guids, issue_id).await?;
+            sqlx::query!(
+                r#"UPDATE rss_feeds SET last_digest_at = now() WHERE id = $1"#,
+                feed_id
+            )
+            .execute(&mut tx)
+            .await
+            .context("Failed to record the digest.")?;
+            report.issues_created += 1;
+        }
+    }
+
+    sqlx::query!(
+        r#"UPDATE rss_feeds SET last_checked_at = now(), last_error = NULL WHERE id = $1"#,
+        feed_id
+    )
+    .execute(&mut tx)
+    .await
+    .context("Failed to record the poll.")?;
+    tx.commit().await.context("Failed to commit transaction.")?;
+    Ok(())
+}
+
+async fn create_issue(
+    tx: &mut Transaction<'_, Postgres>,
+    mode: FeedMode,
+    title_template: &str,
+    body_template: &str,
+    items: &[FeedItem],
+) -> Result<Uuid, anyhow::Error> {
+    let rendered = render_issue(title_template, body_template, items)
+        .context("Failed to render the feed's templates.")?;
+    let content = markdown::render(&rendered.markdown);
+    let status = match mode {
+        FeedMode::Draft => IssueStatus::Draft,
+        FeedMode::Send => IssueStatus::Scheduled,
+    };
+    let issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (newsletter_issue_id, title, markdown_content,
+            text_content, html_content, status, publish_at)
+        VALUES ($1, $2, $3, $4, $5, $6, CASE WHEN $6 = 'scheduled' THEN now() END)
+        "#,
+        issue_id,
+        rendered.title,
+        rendered.markdown,
+        content.text,
+        content.html,
+        status.as_str()
+    )
+    .execute(tx)
+    .await
+    .context("Failed to insert the issue.")?;
+    Ok(issue_id)
+}
+
+async fn mark_handled(
+    tx: &mut Transaction<'_, Postgres>,
+    feed_id: Uuid,
+    guids: &[String],
+    issue_id: Uuid,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE rss_feed_items SET handled_at = now(), newsletter_issue_id = $3
+        WHERE feed_id = $1 AND guid = ANY($2)
+        "#,
+        feed_id,
+        guids,
+        issue_id
+    )
+    .execute(tx)
+    .await
+    .context("Failed to mark feed items as handled.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::rss_to_email::{
+        html_to_markdown_text, is_public, parse_feed, render_issue, sample_items, FeedFetcher,
+        FeedItem, HttpFeedFetcher, DEFAULT_BODY_TEMPLATE, DEFAULT_DIGEST_BODY_TEMPLATE,
+        DEFAULT_DIGEST_TITLE_TEMPLATE, DEFAULT_TITLE_TEMPLATE,
+    };
+
+    #[test]
+    fn only_addresses_reachable_on_the_internet_are_public() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn feeds_are_only_fetched_over_http_or_https() {
+        let fetcher = HttpFeedFetcher::new(std::time::Duration::from_secs(1), 1000, true);
+        for url in ["file:///etc/passwd", "ftp://example.com/feed.xml"] {
+            let error = fetcher.fetch(url).await.unwrap_err();
+            assert!(error.to_string().contains("http or https"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn rss_items_are_identified_by_guid_or_link() {
+        let items = parse_feed(
+            r#"<?xml version="1.0"?>
+            <rss version="2.0"><channel><title>Blog</title><link>https://blog.example.com</link>
+            <description>Posts</description>
+            <item><title>Second</title><link>https://blog.example.com/2</link>
+              <guid isPermaLink="false">post-2</guid>
+              <description>&lt;p&gt;Two&lt;/p&gt;</description>
+              <pubDate>Sun, 09 Apr 2023 10:00:00 GMT</pubDate></item>
+            <item><title>First</title><link>https://blog.example.com/1</link></item>
+            <item><title>Nowhere</title></item>
+            </channel></rss>"#,
+        )
+        .unwrap();
+        let guids: Vec<_> = items.iter().map(|item| item.guid.as_str()).collect();
+        assert_eq!(guids, ["post-2", "https://blog.example.com/1"]);
+        assert_eq!(items[0].content, "<p>Two</p>");
+        assert_eq!(
+            items[0].published_at.unwrap().to_rfc3339(),
+            "2023-04-09T10:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn atom_entries_are_read_too() {
+        let items = parse_feed(
+            r#"<?xml version="1.0"?>
+            <feed xmlns="http://www.w3.org/2005/Atom"><title>Blog</title>
+            <id>urn:blog</id><updated>2023-04-09T10:00:00Z</updated>
+            <entry><title>First</title><id>urn:post:1</id>
+              <updated>2023-04-09T10:00:00Z</updated>
+              <link rel="alternate" href="https://blog.example.com/1"/>
+              <content type="html">&lt;p&gt;One&lt;/p&gt;</content></entry>
+            </feed>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            items,
+            [FeedItem {
+                guid: "urn:post:1".into(),
+                title: "First".into(),
+                link: "https://blog.example.com/1".into(),
+                content: "<p>One</p>".into(),
+                published_at: items[0].published_at,
+            }]
+        );
+        assert!(items[0].published_at.is_some());
+    }
+
+    #[test]
+    fn other_documents_are_rejected() {
+        assert!(parse_feed("<html><body>Not a feed</body></html>").is_err());
+    }
+
+    #[test]
+    fn the_default_templates_render_markdown() {
+        let items = sample_items();
+        let single =
+            render_issue(DEFAULT_TITLE_TEMPLATE, DEFAULT_BODY_TEMPLATE, &items[..1]).unwrap();
+        assert_eq!(single.title, "Post #1");
+        assert_eq!(
+            single.markdown,
+            "## [Post #1](https://example.com/posts/1)\n\nHello\n"
+        );
+
+        let digest = render_issue(
+            DEFAULT_DIGEST_TITLE_TEMPLATE,
+            DEFAULT_DIGEST_BODY_TEMPLATE,
+            &items,
+        )
+        .unwrap();
+        assert_eq!(digest.title, "2 new posts this week");
+        assert!(digest.markdown.contains("## [Post #1]"));
+        assert!(digest.markdown.contains("## [Post #2]"));
+    }
+
+    #[test]
+    fn item_content_is_given_to_templates_as_markdown_text() {
+        assert_eq!(
+            html_to_markdown_text(
+                "<p>Fish &amp; chips,\n <b>*hot*</b></p><script>alert(1)</script>\
+                 <ul><li># 1</li><li>1. caf&#xE9;</li><li>- x</li></ul>"
+            ),
+            "Fish & chips, \\*hot\\*\n\n\\# 1\n\n1\\. café\n\n\\- x"
+        );
+        assert_eq!(html_to_markdown_text("a &bogus; <b"), "a &bogus;");
+    }
+
+    #[test]
+    fn invalid_templates_are_errors() {
+        assert!(render_issue("{{ item.title", DEFAULT_BODY_TEMPLATE, &sample_items()).is_err());
+        assert!(render_issue("{{ item.nope }}", DEFAULT_BODY_TEMPLATE, &sample_items()).is_err());
+    }
+}
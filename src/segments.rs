@@ -0,0 +1,163 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use crate::domain::{Comparison, Predicate, SegmentFilter};
+
+#[derive(serde::Serialize, sqlx::FromRow, Debug)]
+pub struct SegmentMember {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub attributes: serde_json::Value,
+}
+
+/// Appends `filter` as a condition on `subscriptions s`, binding every value
+/// the admin typed as a parameter.
+pub fn push_filter(builder: &mut QueryBuilder<'_, Postgres>, filter: &SegmentFilter) {
+    builder.push("TRUE");
+    for clause in filter.clauses() {
+        // COALESCE so `not` also matches subscribers the predicate says
+        // nothing about, e.g. ones without the attribute.
+        builder.push(if clause.negated {
+            " AND NOT COALESCE(("
+        } else {
+            " AND COALESCE(("
+        });
+        push_predicate(builder, &clause.predicate);
+        builder.push("), FALSE)");
+    }
+}
+
+fn push_predicate(builder: &mut QueryBuilder<'_, Postgres>, predicate: &Predicate) {
+    match predicate {
+        Predicate::Status(status) => {
+            builder.push("s.status = ").push_bind(status.clone());
+        }
+        Predicate::Tag(tag) => {
+            builder
+                .push("EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = ")
+                .push_bind(tag.as_ref().to_string())
+                .push(")");
+        }
+        Predicate::Attribute {
+            name,
+            comparison: Comparison::Equal,
+            value,
+        } => {
+            let mut attribute = serde_json::Map::new();
+            attribute.insert(name.clone(), value.clone());
+            builder
+                .push("s.attributes @> ")
+                .push_bind(serde_json::Value::Object(attribute));
+        }
+        Predicate::Attribute {
+            name,
+            comparison,
+            value,
+        } => {
+            // The type checker only allows ordering on number fields, and
+            // jsonpath comparisons are false rather than failing on a value
+            // stored before the field changed type.
+            let path = match comparison {
+                Comparison::Less => "$ ? (@ < $v)",
+                Comparison::LessOrEqual => "$ ? (@ <= $v)",
+                Comparison::Greater => "$ ? (@ > $v)",
+                Comparison::GreaterOrEqual => "$ ? (@ >= $v)",
+                Comparison::Equal => unreachable!(),
+            };
+            builder
+                .push("jsonb_path_exists(s.attributes -> ")
+                .push_bind(name.clone())
+                .push("::text, '")
+                .push(path)
+                .push("', jsonb_build_object('v', ")
+                .push_bind(value.as_f64().unwrap_or_default())
+                .push("::float8))");
+        }
+        Predicate::SubscribedWithinDays(days) => {
+            builder
+                .push("s.subscribed_at > now() - make_interval(days => ")
+                .push_bind(*days)
+                .push(")");
+        }
+        Predicate::SubscribedBeforeDays(days) => {
+            builder
+                .push("s.subscribed_at <= now() - make_interval(days => ")
+                .push_bind(*days)
+                .push(")");
+        }
+        Predicate::OpenedAtLeast { count, of_last } => {
+            builder
+                .push(
+                    "(SELECT count(*) FROM issue_opens o WHERE o.subscriber_id = s.id \
+                    AND o.newsletter_issue_id IN (\
+                    SELECT newsletter_issue_id FROM newsletter_issues \
+                    WHERE published_at IS NOT NULL ORDER BY published_at DESC LIMIT ",
+                )
+                .push_bind(i64::from(*of_last))
+                .push(")) >= ")
+                .push_bind(i64::from(*count));
+        }
+    }
+}
+
+#[tracing::instrument(name = "Counting segment members", skip(pool))]
+pub async fn count_members(pool: &PgPool, filter: &SegmentFilter) -> Result<i64, sqlx::Error> {
+    let mut builder = QueryBuilder::new("SELECT count(*) FROM subscriptions s WHERE ");
+    push_filter(&mut builder, filter);
+    let (count,): (i64,) = builder.build_query_as().fetch_one(pool).await?;
+    Ok(count)
+}
+
+/// The most recent subscribers in the segment.
+#[tracing::instrument(name = "Sampling segment members", skip(pool))]
+pub async fn sample_members(
+    pool: &PgPool,
+    filter: &SegmentFilter,
+    limit: i64,
+) -> Result<Vec<SegmentMember>, sqlx::Error> {
+    let mut builder = QueryBuilder::new(
+        "SELECT s.id, s.email, s.name, s.status, s.subscribed_at, s.attributes \
+        FROM subscriptions s WHERE ",
+    );
+    push_filter(&mut builder, filter);
+    builder
+        .push(" ORDER BY s.subscribed_at DESC, s.id LIMIT ")
+        .push_bind(limit);
+    builder.build_query_as().fetch_all(pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::QueryBuilder;
+
+    use crate::domain::{AttributeSchema, FieldDefinition, FieldType, SegmentFilter};
+    use crate::segments::push_filter;
+
+    #[test]
+    fn values_are_bound_rather_than_spliced_into_the_sql() {
+        let schema = AttributeSchema::new(vec![FieldDefinition {
+            name: "company".into(),
+            field_type: FieldType::Text { max_length: None },
+            required: false,
+            signup: false,
+        }]);
+        let filter = SegmentFilter::parse(
+            r#"not tag = beta, attribute.company = "'; DROP TABLE subscriptions; --""#,
+            &schema,
+        )
+        .unwrap();
+        let mut builder = QueryBuilder::new("SELECT s.id FROM subscriptions s WHERE ");
+
+        push_filter(&mut builder, &filter);
+
+        let sql = builder.sql();
+        assert!(!sql.contains("DROP TABLE"), "{}", sql);
+        assert!(!sql.contains("beta"), "{}", sql);
+        assert!(sql.contains("AND NOT COALESCE((EXISTS"), "{}", sql);
+        assert!(sql.contains("s.attributes @> $2"), "{}", sql);
+    }
+}
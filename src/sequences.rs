@@ -0,0 +1,175 @@
+use crate::{
+    configuration::{SequencesSettings, Settings},
+    delivery_queue::{enqueue_delivery, Delivery},
+    startup::get_connection_pool,
+};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Enrolls a newly confirmed subscriber in every active sequence.
+#[tracing::instrument(skip(tx))]
+pub async fn enroll_subscriber(
+    tx: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let enrolled = sqlx::query!(
+        r#"
+        INSERT INTO sequence_enrollments (sequence_id, subscriber_id)
+        SELECT id, $1 FROM sequences WHERE active
+        ON CONFLICT DO NOTHING
+        "#,
+        subscriber_id
+    )
+    .execute(tx)
+    .await?
+    .rows_affected();
+    Ok(enrolled)
+}
+
+pub async fn run_scheduler_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    scheduler_loop(pool, configuration.sequences).await
+}
+
+async fn scheduler_loop(pool: PgPool, settings: SequencesSettings) -> Result<(), anyhow::Error> {
+    loop {
+        if let Err(e) = schedule_due_steps(&pool).await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to schedule sequence steps."
+            );
+        }
+        tokio::time::sleep(settings.check_interval()).await;
+    }
+}
+
+/// What one pass of the sequence scheduler did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ScheduleReport {
+    pub steps_queued: u64,
+    pub enrollments_completed: u64,
+    pub enrollments_stopped: u64,
+}
+
+/// Queues every sequence step that has come due.
+#[tracing::instrument(skip_all)]
+pub async fn schedule_due_steps(pool: &PgPool) -> Result<ScheduleReport, anyhow::Error> {
+    let mut report = ScheduleReport {
+        enrollments_stopped: stop_enrollments_of_unsubscribed(pool).await?,
+        ..Default::default()
+    };
+    while try_queue_next_step(pool).await? {
+        report.steps_queued += 1;
+    }
+    report.enrollments_completed = complete_finished_enrollments(pool).await?;
+    tracing::info!(
+        steps_queued = report.steps_queued,
+        enrollments_completed = report.enrollments_completed,
+        enrollments_stopped = report.enrollments_stopped,
+        "Scheduled sequence steps."
+    );
+    Ok(report)
+}
+
+/// Queues the next due step of one enrollment, returning whether there was one.
+#[tracing::instrument(skip_all)]
+async fn try_queue_next_step(pool: &PgPool) -> Result<bool, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    // Steps are looked up by position rather than by index, so editing a
+    // sequence does not resend what an enrollment already got.
+    let step = sqlx::query!(
+        r#"
+        SELECT e.sequence_id, e.subscriber_id, st.position, st.subject,
+            st.html_content, st.text_content
+        FROM sequence_enrollments e
+        JOIN sequences sq ON sq.id = e.sequence_id
+        JOIN subscriptions s ON s.id = e.subscriber_id
+        JOIN LATERAL (
+            SELECT position, delay_hours, subject, html_content, text_content
+            FROM sequence_steps
+            WHERE sequence_id = e.sequence_id AND position > e.last_position
+            ORDER BY position
+            LIMIT 1
+        ) st ON true
+        WHERE e.completed_at IS NULL
+            AND e.stopped_at IS NULL
+            AND sq.active
+            AND s.status = 'confirmed'
+            AND e.enrolled_at + make_interval(hours => st.delay_hours) <= now()
+        FOR UPDATE OF e
+        SKIP LOCKED
+        LIMIT 1
+        "#
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let step = match step {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+
+    enqueue_delivery(
+        &mut tx,
+        &Delivery {
+            subscriber_id: step.subscriber_id,
+            subject: &step.subject,
+            html_content: &step.html_content,
+            text_content: &step.text_content,
+        },
+    )
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE sequence_enrollments
+        SET last_position = $3
+        WHERE sequence_id = $1 AND subscriber_id = $2
+        "#,
+        step.sequence_id,
+        step.subscriber_id,
+        step.position
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+#[tracing::instrument(skip_all)]
+async fn stop_enrollments_of_unsubscribed(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let stopped = sqlx::query!(
+        r#"
+        UPDATE sequence_enrollments e
+        SET stopped_at = now()
+        FROM subscriptions s
+        WHERE s.id = e.subscriber_id
+            AND s.status <> 'confirmed'
+            AND e.completed_at IS NULL
+            AND e.stopped_at IS NULL
+        "#
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(stopped)
+}
+
+#[tracing::instrument(skip_all)]
+async fn complete_finished_enrollments(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let completed = sqlx::query!(
+        r#"
+        UPDATE sequence_enrollments e
+        SET completed_at = now()
+        WHERE e.completed_at IS NULL
+            AND e.stopped_at IS NULL
+            AND NOT EXISTS (
+                SELECT 1 FROM sequence_steps st
+                WHERE st.sequence_id = e.sequence_id AND st.position > e.last_position
+            )
+        "#
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(completed)
+}
@@ -1,13 +1,38 @@
 use actix_web::{dev::Server, web, App, HttpServer};
+use actix_web_httpauth::{extractors::basic, middleware::HttpAuthentication};
 use sqlx::{postgres::PgPoolOptions, PgPool};
 use std::net::TcpListener;
 use tracing_actix_web::TracingLogger;
 
 use crate::{
+    authentication::{admin_basic_auth, create_initial_admin},
+    bot_protection::BotProtection,
+    canonical_emails::backfill_canonical_emails,
+    configuration::CorsSettings,
     configuration::DatabaseSettings,
     configuration::Settings,
+    domain::{EmailPolicy, NamePolicy, SubscriberEmail},
+    domain_checker::{DnsResolver, DomainChecker, DomainResolver},
     email_client::EmailClient,
-    routes::{confirm, health_check, subscribe},
+    email_rendering::EmailRenderer,
+    email_templates::EmailTemplates,
+    merge_tags::Personalizer,
+    pages::{Pages, RedirectAllowlist},
+    rate_limit::SubscriptionRateLimits,
+    routes::{
+        add_subscriber_tag, approve_email_change, archive, archived_issue, atom_feed, change_email,
+        confirm, confirm_email_change, create_issue, create_rss_feed, create_segment,
+        create_sequence, delete_email_template, delete_rss_feed, delete_segment,
+        delete_subscriber_field, email_template, health_check, issue, list_email_templates,
+        list_issues, list_rss_feeds, list_segments, list_sequences, list_subscriber_fields,
+        list_subscribers, preferences, preview_filter, preview_issue, preview_markdown,
+        preview_segment, record_issue_open, remove_subscriber_tag, rss_feed, save_email_template,
+        save_preferences, save_subscriber_field, segment, send_test_issue, sequence,
+        submit_confirmation, submit_email_change_approval, submit_email_change_confirmation,
+        subscribe, subscribe_challenge, transition_issue, unsubscribe, unsubscribe_form,
+        update_issue, update_segment, update_sequence, update_subscriber_attributes, widget,
+        widget_script, WidgetFrameAncestors,
+    },
 };
 
 pub struct Application {
@@ -17,29 +42,89 @@ pub struct Application {
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address.");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url.clone(),
-            sender_email,
-            configuration.email_client.authorization_token.clone(),
-            timeout,
-        );
+        Self::build_with_domain_resolver(configuration, None).await
+    }
+
+    /// Like `build`, checking email domains, if enabled, with `domain_resolver`
+    /// rather than the system's DNS.
+    pub async fn build_with_domain_resolver(
+        configuration: Settings,
+        domain_resolver: Option<Box<dyn DomainResolver>>,
+    ) -> Result<Self, std::io::Error> {
+        let email_client = configuration.email_client.clone().client();
         let address = format!(
             "{}:{}",
             configuration.application.host, configuration.application.port
         );
+        let email_policy = configuration.email_validation.policy()?;
+        let name_policy = configuration.name_validation.policy();
+        let rate_limits = configuration
+            .application
+            .rate_limits
+            .subscription_rate_limits();
+        let bot_protection = configuration.bot_protection.protection();
+        let pages = configuration
+            .pages
+            .pages()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let redirect_allowlist = configuration.pages.redirect_allowlist();
+        let email_templates = configuration
+            .email_templates
+            .templates()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let domain_checker = if configuration.domain_check.enabled {
+            let resolver = match domain_resolver {
+                Some(resolver) => resolver,
+                None => Box::new(DnsResolver::from_system_conf()?),
+            };
+            Some(DomainChecker::new(
+                resolver,
+                configuration.domain_check.cache_ttl(),
+                configuration.domain_check.cache_capacity,
+            ))
+        } else {
+            None
+        };
+        let personalizer = configuration
+            .merge_tags
+            .personalizer(&configuration.application.base_url);
+        let email_renderer = EmailRenderer::new(&configuration.application.base_url)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let seed_addresses = configuration
+            .issues
+            .seed_addresses()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         let connection_pool = get_connection_pool(&configuration.database);
+        if let Some(initial_admin) = &configuration.initial_admin {
+            create_initial_admin(initial_admin.credentials(), &connection_pool)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        }
+        backfill_canonical_emails(&connection_pool, &email_policy)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
         let server = run(
             listener,
             connection_pool,
             email_client,
+            email_policy,
+            name_policy,
+            domain_checker,
+            rate_limits,
+            bot_protection,
+            pages,
+            redirect_allowlist,
+            email_templates,
+            personalizer,
+            email_renderer,
+            seed_addresses,
+            configuration.issues.archive_page_size,
             &configuration.application.base_url,
+            configuration.application.confirmation_link_ttl(),
+            configuration.application.one_click_confirmation,
+            configuration.cors,
         )?;
         Ok(Self { port, server })
     }
@@ -55,31 +140,197 @@ impl Application {
 
 pub struct ApplicationBaseUrl(pub String);
 
+/// Where test sends of issues go.
+pub struct SeedAddresses(pub Vec<SubscriberEmail>);
+
+/// How many issues a page of the archive lists.
+pub struct ArchivePageSize(pub i64);
+
+/// How long a confirmation link stays valid.
+pub struct ConfirmationLinkTtl(pub chrono::Duration);
+
+pub struct OneClickConfirmation(pub bool);
+
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
     PgPoolOptions::new()
         .acquire_timeout(std::time::Duration::from_secs(2))
         .connect_lazy_with(configuration.with_db())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run(
     listener: TcpListener,
     db_pool: PgPool,
     email_client: EmailClient,
+    email_policy: EmailPolicy,
+    name_policy: NamePolicy,
+    domain_checker: Option<DomainChecker>,
+    rate_limits: SubscriptionRateLimits,
+    bot_protection: BotProtection,
+    pages: Pages,
+    redirect_allowlist: RedirectAllowlist,
+    email_templates: EmailTemplates,
+    personalizer: Personalizer,
+    email_renderer: EmailRenderer,
+    seed_addresses: Vec<SubscriberEmail>,
+    archive_page_size: i64,
     base_url: &str,
+    confirmation_link_ttl: chrono::Duration,
+    one_click_confirmation: bool,
+    cors: CorsSettings,
 ) -> Result<Server, std::io::Error> {
     // wrap the connection in smart pointer
     let db_pool = web::Data::new(db_pool);
     let email_client = web::Data::new(email_client);
+    let email_policy = web::Data::new(email_policy);
+    let name_policy = web::Data::new(name_policy);
+    let domain_checker = web::Data::new(domain_checker);
+    let rate_limits = web::Data::new(rate_limits);
+    let bot_protection = web::Data::new(bot_protection);
+    let pages = web::Data::new(pages);
+    let redirect_allowlist = web::Data::new(redirect_allowlist);
+    let email_templates = web::Data::new(email_templates);
+    let personalizer = web::Data::new(personalizer);
+    let email_renderer = web::Data::new(email_renderer);
+    let seed_addresses = web::Data::new(SeedAddresses(seed_addresses));
+    let archive_page_size = web::Data::new(ArchivePageSize(archive_page_size));
     let base_url = web::Data::new(ApplicationBaseUrl(base_url.to_string()));
+    let confirmation_link_ttl = web::Data::new(ConfirmationLinkTtl(confirmation_link_ttl));
+    let one_click_confirmation = web::Data::new(OneClickConfirmation(one_click_confirmation));
+    let frame_ancestors = web::Data::new(WidgetFrameAncestors(cors.frame_ancestors()));
     let server = HttpServer::new(move || {
         App::new()
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(cors.cors())
+                    .route(web::post().to(subscribe)),
+            )
+            .service(
+                web::resource("/subscriptions/challenge")
+                    .wrap(cors.cors())
+                    .route(web::get().to(subscribe_challenge)),
+            )
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .route(
+                "/subscriptions/confirm",
+                web::post().to(submit_confirmation),
+            )
+            .route("/subscriptions/change_email", web::post().to(change_email))
+            .route(
+                "/subscriptions/change_email/confirm",
+                web::get().to(confirm_email_change),
+            )
+            .route(
+                "/subscriptions/change_email/confirm",
+                web::post().to(submit_email_change_confirmation),
+            )
+            .route(
+                "/subscriptions/change_email/approve",
+                web::get().to(approve_email_change),
+            )
+            .route(
+                "/subscriptions/change_email/approve",
+                web::post().to(submit_email_change_approval),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(unsubscribe_form),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route("/subscriptions/preferences", web::get().to(preferences))
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(save_preferences),
+            )
+            .route("/widget", web::get().to(widget))
+            .route("/widget.js", web::get().to(widget_script))
+            .route("/archive", web::get().to(archive))
+            .route("/archive/{slug}", web::get().to(archived_issue))
+            .route("/feed.rss", web::get().to(rss_feed))
+            .route("/feed.atom", web::get().to(atom_feed))
+            .route("/issues/{issue_id}/open", web::get().to(record_issue_open))
+            .service(
+                web::scope("/admin")
+                    .app_data(basic::Config::default().realm("admin"))
+                    .wrap(HttpAuthentication::basic(admin_basic_auth))
+                    .route("/sequences", web::get().to(list_sequences))
+                    .route("/sequences", web::post().to(create_sequence))
+                    .route("/sequences/{sequence_id}", web::get().to(sequence))
+                    .route("/sequences/{sequence_id}", web::put().to(update_sequence))
+                    .route("/issues", web::get().to(list_issues))
+                    .route("/issues", web::post().to(create_issue))
+                    .route("/issues/preview", web::post().to(preview_markdown))
+                    .route("/issues/{issue_id}", web::get().to(issue))
+                    .route("/issues/{issue_id}", web::put().to(update_issue))
+                    .route("/issues/{issue_id}/preview", web::get().to(preview_issue))
+                    .route("/issues/{issue_id}/test", web::post().to(send_test_issue))
+                    .route(
+                        "/issues/{issue_id}/{action}",
+                        web::post().to(transition_issue),
+                    )
+                    .route("/rss-feeds", web::get().to(list_rss_feeds))
+                    .route("/rss-feeds", web::post().to(create_rss_feed))
+                    .route("/rss-feeds/{feed_id}", web::delete().to(delete_rss_feed))
+                    .route("/email-templates", web::get().to(list_email_templates))
+                    .route("/email-templates/{name}", web::get().to(email_template))
+                    .route(
+                        "/email-templates/{name}",
+                        web::put().to(save_email_template),
+                    )
+                    .route(
+                        "/email-templates/{name}",
+                        web::delete().to(delete_email_template),
+                    )
+                    .route("/segments", web::get().to(list_segments))
+                    .route("/segments", web::post().to(create_segment))
+                    .route("/segments/preview", web::post().to(preview_filter))
+                    .route("/segments/{segment_id}", web::get().to(segment))
+                    .route("/segments/{segment_id}", web::put().to(update_segment))
+                    .route("/segments/{segment_id}", web::delete().to(delete_segment))
+                    .route(
+                        "/segments/{segment_id}/preview",
+                        web::get().to(preview_segment),
+                    )
+                    .route("/subscriber-fields", web::get().to(list_subscriber_fields))
+                    .route("/subscriber-fields", web::post().to(save_subscriber_field))
+                    .route(
+                        "/subscriber-fields/{name}",
+                        web::delete().to(delete_subscriber_field),
+                    )
+                    .route("/subscribers", web::get().to(list_subscribers))
+                    .route(
+                        "/subscribers/{subscriber_id}/attributes",
+                        web::patch().to(update_subscriber_attributes),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/tags/{tag}",
+                        web::put().to(add_subscriber_tag),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/tags/{tag}",
+                        web::delete().to(remove_subscriber_tag),
+                    ),
+            )
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
+            .app_data(email_policy.clone())
+            .app_data(name_policy.clone())
+            .app_data(domain_checker.clone())
+            .app_data(rate_limits.clone())
+            .app_data(bot_protection.clone())
+            .app_data(pages.clone())
+            .app_data(redirect_allowlist.clone())
+            .app_data(email_templates.clone())
+            .app_data(personalizer.clone())
+            .app_data(email_renderer.clone())
+            .app_data(seed_addresses.clone())
+            .app_data(archive_page_size.clone())
             .app_data(base_url.clone())
+            .app_data(confirmation_link_ttl.clone())
+            .app_data(one_click_confirmation.clone())
+            .app_data(frame_ancestors.clone())
     })
     .listen(listener)?
     .run();
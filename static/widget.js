@@ -0,0 +1,25 @@
+// Embeds the newsletter signup form. Add to any page with
+//   <script src="https://<newsletter host>/widget.js" async></script>
+// The form is inserted where the script tag is, or into the element matched
+// by the script's `data-target` selector.
+(function () {
+  var script = document.currentScript;
+  if (!script) {
+    return;
+  }
+  var iframe = document.createElement("iframe");
+  iframe.src = new URL("/widget", script.src).href;
+  iframe.title = script.getAttribute("data-title") || "Newsletter signup";
+  iframe.loading = "lazy";
+  iframe.style.border = "0";
+  iframe.style.width = "100%";
+  iframe.style.height = script.getAttribute("data-height") || "240px";
+
+  var selector = script.getAttribute("data-target");
+  var target = selector && document.querySelector(selector);
+  if (target) {
+    target.appendChild(iframe);
+  } else {
+    script.parentNode.insertBefore(iframe, script.nextSibling);
+  }
+})();
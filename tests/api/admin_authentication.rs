@@ -0,0 +1,86 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use secrecy::Secret;
+use zero2prod::authentication::{create_initial_admin, Credentials};
+use zero2prod::configuration::InitialAdminSettings;
+
+#[tokio::test]
+async fn the_seeded_admin_password_no_longer_works() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/sequences", &app.address))
+        .basic_auth("admin", Some("everythinghastostartsomewhere"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_initial_admin_is_created_from_the_configuration() {
+    let app = spawn_app_with(|c| {
+        c.initial_admin = Some(InitialAdminSettings {
+            username: "ursula".into(),
+            password: Secret::new("a-long-and-unguessable-password".into()),
+        })
+    })
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/sequences", &app.address))
+        .basic_auth("ursula", Some("a-long-and-unguessable-password"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_locked_seeded_admin_gets_the_initial_admin_password() {
+    let app = spawn_app_with(|c| {
+        c.initial_admin = Some(InitialAdminSettings {
+            username: "admin".into(),
+            password: Secret::new("a-long-and-unguessable-password".into()),
+        })
+    })
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/sequences", &app.address))
+        .basic_auth("admin", Some("a-long-and-unguessable-password"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn an_existing_user_is_not_overwritten_by_the_initial_admin() {
+    let app = spawn_app().await;
+
+    create_initial_admin(
+        Credentials {
+            username: app.test_user.username.clone(),
+            password: Secret::new("a-long-and-unguessable-password".into()),
+        },
+        &app.db_pool,
+    )
+    .await
+    .unwrap();
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/sequences", &app.address))
+        .basic_auth(
+            &app.test_user.username,
+            Some("a-long-and-unguessable-password"),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, response.status().as_u16());
+    let response = app.get_admin_sequences().await;
+    assert_eq!(200, response.status().as_u16());
+}
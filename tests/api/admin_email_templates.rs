@@ -0,0 +1,172 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn sent_emails(app: &TestApp) -> Vec<serde_json::Value> {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect()
+}
+
+fn confirmation_template(html_body: &str) -> serde_json::Value {
+    serde_json::json!({
+        "subject": "Hi {{ subscriber.name }}",
+        "html_body": html_body,
+        "text_body": "Confirm at {{ confirmation_link }}"
+    })
+}
+
+#[tokio::test]
+async fn the_default_templates_are_listed_with_their_variables() {
+    let app = spawn_app().await;
+
+    let response = app.get_admin_email_templates().await;
+
+    assert_eq!(200, response.status().as_u16());
+    let templates: Vec<serde_json::Value> = response.json().await.unwrap();
+    let confirmation = templates
+        .iter()
+        .find(|template| template["name"] == "confirmation")
+        .unwrap();
+    assert_eq!(confirmation["subject"], "Welcome!");
+    assert_eq!(
+        confirmation["variables"],
+        serde_json::json!(["confirmation_link"])
+    );
+    assert_eq!(confirmation["customised"], false);
+    let layout = templates
+        .iter()
+        .find(|template| template["name"] == "layout")
+        .unwrap();
+    assert!(layout["subject"].is_null());
+}
+
+#[tokio::test]
+async fn edited_templates_are_used_for_the_next_email() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .put_admin_email_template(
+            "confirmation",
+            &confirmation_template(
+                "{% extends \"layout.html\" %}{% block content %}\
+                 <a href=\"{{ confirmation_link }}\">Yes, sign me up</a>\
+                 {% endblock content %}",
+            ),
+        )
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let email = &sent_emails(&app).await[0];
+    assert_eq!(email["Subject"], "Hi le guin");
+    let html = email["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("Yes, sign me up"), "{}", html);
+    // Still wrapped in the shared layout.
+    assert!(html.contains("1 Newsletter Street"), "{}", html);
+    let confirmation_links =
+        app.get_confirmation_links(&app.email_server.received_requests().await.unwrap()[0]);
+    assert_eq!(confirmation_links.html, confirmation_links.plain_text);
+}
+
+#[tokio::test]
+async fn templates_using_unknown_variables_are_rejected_when_saved() {
+    let app = spawn_app().await;
+
+    for (name, body) in [
+        (
+            "already_subscribed",
+            serde_json::json!({
+                "subject": "Hello",
+                "html_body": "{{ confirmation_link }}",
+                "text_body": "Hello"
+            }),
+        ),
+        (
+            "footer",
+            serde_json::json!({
+                "html_body": "{{ list.address }}",
+                "text_body": "{{ list.physical_address }}"
+            }),
+        ),
+        (
+            "confirmation",
+            confirmation_template("{% include \"missing.html\" %}"),
+        ),
+    ] {
+        let response = app.put_admin_email_template(name, &body).await;
+        assert_eq!(400, response.status().as_u16(), "{}", name);
+    }
+    let stored = sqlx::query!("SELECT count(*) AS \"count!\" FROM email_templates")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(stored.count, 0);
+}
+
+#[tokio::test]
+async fn emails_need_a_subject_and_partials_cannot_have_one() {
+    let app = spawn_app().await;
+
+    let email = app
+        .put_admin_email_template(
+            "confirmation",
+            &serde_json::json!({"html_body": "Hello", "text_body": "Hello"}),
+        )
+        .await;
+    let partial = app
+        .put_admin_email_template(
+            "signature",
+            &serde_json::json!({"subject": "Hello", "html_body": "Hello", "text_body": "Hello"}),
+        )
+        .await;
+
+    assert_eq!(400, email.status().as_u16());
+    assert_eq!(400, partial.status().as_u16());
+}
+
+#[tokio::test]
+async fn partials_cannot_be_removed_while_a_template_includes_them() {
+    let app = spawn_app().await;
+    let signature = serde_json::json!({
+        "html_body": "<p>The {{ list.name }} team</p>",
+        "text_body": "The {{ list.name }} team"
+    });
+    assert_eq!(
+        200,
+        app.put_admin_email_template("signature", &signature)
+            .await
+            .status()
+            .as_u16()
+    );
+    assert_eq!(
+        200,
+        app.put_admin_email_template(
+            "confirmation",
+            &confirmation_template("{{ confirmation_link }}{% include \"signature.html\" %}"),
+        )
+        .await
+        .status()
+        .as_u16()
+    );
+
+    let while_included = app.delete_admin_email_template("signature").await;
+    assert_eq!(400, while_included.status().as_u16());
+
+    let reset = app.delete_admin_email_template("confirmation").await;
+    assert_eq!(204, reset.status().as_u16());
+    let removed = app.delete_admin_email_template("signature").await;
+    assert_eq!(204, removed.status().as_u16());
+    let again = app.delete_admin_email_template("signature").await;
+    assert_eq!(404, again.status().as_u16());
+}
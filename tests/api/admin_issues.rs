@@ -0,0 +1,466 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp, TestUser};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn issue_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Issue #1",
+        "text_content": "Hello",
+        "html_content": "<p>Hello</p>"
+    })
+}
+
+async fn create_issue(app: &TestApp) -> serde_json::Value {
+    let response = app.post_admin_issues(&issue_body()).await;
+    assert_eq!(201, response.status().as_u16());
+    response.json().await.unwrap()
+}
+
+async fn apply(
+    app: &TestApp,
+    user: &TestUser,
+    issue: &serde_json::Value,
+    action: &str,
+) -> reqwest::Response {
+    app.post_admin_issue_action(
+        user,
+        issue["id"].as_str().unwrap(),
+        action,
+        &serde_json::json!({ "version": issue["version"] }),
+    )
+    .await
+}
+
+async fn reviewer(app: &TestApp) -> TestUser {
+    let reviewer = TestUser::generate();
+    reviewer.store(&app.db_pool).await;
+    reviewer
+}
+
+#[tokio::test]
+async fn new_issues_are_drafts_authored_by_the_caller() {
+    let app = spawn_app().await;
+
+    let issue = create_issue(&app).await;
+
+    assert_eq!(issue["status"], "draft");
+    assert_eq!(issue["version"], 1);
+    assert_eq!(issue["author_id"], app.test_user.user_id.to_string());
+}
+
+#[tokio::test]
+async fn edits_made_on_a_stale_version_are_rejected() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+    let id = issue["id"].as_str().unwrap();
+    let mut edit = issue_body();
+    edit["version"] = 1.into();
+    edit["title"] = "Issue #1, revised".into();
+
+    let first = app.put_admin_issue(id, &edit).await;
+    let second = app.put_admin_issue(id, &edit).await;
+
+    assert_eq!(200, first.status().as_u16());
+    let updated: serde_json::Value = first.json().await.unwrap();
+    assert_eq!(updated["version"], 2);
+    assert_eq!(409, second.status().as_u16());
+}
+
+#[tokio::test]
+async fn issues_under_review_cannot_be_edited() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+    let submitted: serde_json::Value = apply(&app, &app.test_user, &issue, "submit")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let mut edit = issue_body();
+    edit["version"] = submitted["version"].clone();
+
+    let response = app
+        .put_admin_issue(issue["id"].as_str().unwrap(), &edit)
+        .await;
+
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn authors_cannot_approve_their_own_issues() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+    let submitted: serde_json::Value = apply(&app, &app.test_user, &issue, "submit")
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    let by_author = apply(&app, &app.test_user, &submitted, "approve").await;
+    assert_eq!(403, by_author.status().as_u16());
+
+    let reviewer = reviewer(&app).await;
+    let by_reviewer = apply(&app, &reviewer, &submitted, "approve").await;
+    assert_eq!(200, by_reviewer.status().as_u16());
+    let approved: serde_json::Value = by_reviewer.json().await.unwrap();
+    assert_eq!(approved["status"], "approved");
+    assert_eq!(approved["approved_by"], reviewer.user_id.to_string());
+}
+
+#[tokio::test]
+async fn editors_cannot_approve_issues_they_changed() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+    let editor = reviewer(&app).await;
+    let mut edit = issue_body();
+    edit["version"] = issue["version"].clone();
+    edit["text_content"] = "Hello, world".into();
+    let edited: serde_json::Value = app
+        .put_admin_issue_as(&editor, issue["id"].as_str().unwrap(), &edit)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let submitted: serde_json::Value = apply(&app, &app.test_user, &edited, "submit")
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    let by_editor = apply(&app, &editor, &submitted, "approve").await;
+    assert_eq!(403, by_editor.status().as_u16());
+
+    let reviewer = reviewer(&app).await;
+    let by_reviewer = apply(&app, &reviewer, &submitted, "approve").await;
+    assert_eq!(200, by_reviewer.status().as_u16());
+}
+
+#[tokio::test]
+async fn steps_of_the_workflow_cannot_be_skipped() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+    let reviewer = reviewer(&app).await;
+
+    for action in ["approve", "schedule", "unschedule"] {
+        let response = apply(&app, &reviewer, &issue, action).await;
+        assert_eq!(409, response.status().as_u16(), "{}", action);
+    }
+    let unknown = apply(&app, &reviewer, &issue, "publish").await;
+    assert_eq!(404, unknown.status().as_u16());
+}
+
+#[tokio::test]
+async fn scheduling_needs_a_publication_time() {
+    let app = spawn_app().await;
+    let reviewer = reviewer(&app).await;
+    let issue = create_issue(&app).await;
+    let issue: serde_json::Value = apply(&app, &app.test_user, &issue, "submit")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let issue: serde_json::Value = apply(&app, &reviewer, &issue, "approve")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = issue["id"].as_str().unwrap();
+
+    let without_time = apply(&app, &app.test_user, &issue, "schedule").await;
+    assert_eq!(400, without_time.status().as_u16());
+
+    let response = app
+        .post_admin_issue_action(
+            &app.test_user,
+            id,
+            "schedule",
+            &serde_json::json!({
+                "version": issue["version"],
+                "publish_at": "2030-01-01T09:00:00Z"
+            }),
+        )
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    let scheduled: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(scheduled["status"], "scheduled");
+    assert_eq!(scheduled["publish_at"], "2030-01-01T09:00:00Z");
+}
+
+#[tokio::test]
+async fn issues_cannot_target_an_unknown_segment() {
+    let app = spawn_app().await;
+    let mut body = issue_body();
+    body["segment_id"] = Uuid::new_v4().to_string().into();
+
+    let response = app.post_admin_issues(&body).await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn issues_written_in_markdown_are_rendered_to_both_bodies() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin_issues(&serde_json::json!({
+            "title": "Issue #1",
+            "markdown_content": "# Hello\n\nRead [the post](https://example.com/post)."
+        }))
+        .await;
+
+    assert_eq!(201, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        issue["html_content"],
+        "<h1>Hello</h1>\n<p>Read <a href=\"https://example.com/post\">the post</a>.</p>\n"
+    );
+    assert_eq!(
+        issue["text_content"],
+        "Hello\n=====\n\nRead the post (https://example.com/post).\n"
+    );
+}
+
+#[tokio::test]
+async fn issues_need_either_markdown_or_both_bodies() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({"title": "Issue #1", "html_content": "<p>Hello</p>"}),
+            "missing text content",
+        ),
+        (
+            serde_json::json!({"title": "Issue #1", "markdown_content": "  "}),
+            "empty markdown content",
+        ),
+        (
+            serde_json::json!({
+                "title": "Issue #1",
+                "markdown_content": "Hello",
+                "text_content": "Hello",
+                "html_content": "<p>Hello</p>"
+            }),
+            "markdown and bodies",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_admin_issues(&body).await;
+        assert_eq!(400, response.status().as_u16(), "{}", description);
+    }
+}
+
+#[tokio::test]
+async fn markdown_can_be_previewed_without_saving_an_issue() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin_issues_preview(&serde_json::json!({
+            "markdown_content": "- one\n- two\n\n<b>raw</b>"
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        preview["html_content"],
+        "<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n<p>&lt;b&gt;raw&lt;/b&gt;</p>\n"
+    );
+    assert_eq!(preview["text_content"], "- one\n- two\n\n<b>raw</b>\n");
+    let saved = sqlx::query!("SELECT count(*) AS \"count!\" FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 0);
+}
+
+#[tokio::test]
+async fn issues_with_invalid_merge_tags_are_rejected() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("Hi {{ subscriber.name", "an unclosed tag"),
+        ("Hi {{ subscriber.age }}", "an unknown tag"),
+        ("Hi {{ subscriber.name | upper }}", "an unknown filter"),
+        (
+            "Hi {{ subscriber.attributes.company }}",
+            "an undefined subscriber field",
+        ),
+    ];
+
+    for (markdown, description) in test_cases {
+        let response = app
+            .post_admin_issues(&serde_json::json!({
+                "title": "Issue #1",
+                "markdown_content": markdown
+            }))
+            .await;
+        assert_eq!(400, response.status().as_u16(), "{}", description);
+    }
+}
+
+#[tokio::test]
+async fn issues_can_be_previewed_as_a_subscriber() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id
+        .to_string();
+    let issue: serde_json::Value = app
+        .post_admin_issues(&serde_json::json!({
+            "title": "Hi {{ subscriber.first_name }}",
+            "markdown_content": "Hello {{ subscriber.name }}, [unsubscribe](<{{ unsubscribe_url }}>)"
+        }))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let issue_id = issue["id"].as_str().unwrap();
+
+    let response = app
+        .get_admin_issue_preview(issue_id, &[("subscriber_id", &subscriber_id)])
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(preview["subject"], "Hi le");
+    let html = preview["html_content"].as_str().unwrap();
+    assert!(html.contains("<p>Hello le guin, <a href=\""), "{}", html);
+    assert!(
+        html.contains("/subscriptions/unsubscribe?token="),
+        "{}",
+        html
+    );
+
+    let sample: serde_json::Value = app
+        .get_admin_issue_preview(issue_id, &[])
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(sample["subject"], "Hi Jane");
+
+    let unknown = Uuid::new_v4().to_string();
+    let response = app
+        .get_admin_issue_preview(issue_id, &[("subscriber_id", &unknown)])
+        .await;
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn previews_warn_about_messages_gmail_would_clip() {
+    let app = spawn_app().await;
+    let issue: serde_json::Value = app
+        .post_admin_issues(&serde_json::json!({
+            "title": "Issue #1",
+            "text_content": "Hello",
+            "html_content": format!("<p>{}</p>", "a".repeat(110 * 1024))
+        }))
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    let preview: serde_json::Value = app
+        .get_admin_issue_preview(issue["id"].as_str().unwrap(), &[])
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    let warnings = preview["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].as_str().unwrap().contains("Gmail"));
+}
+
+#[tokio::test]
+async fn test_sends_go_to_the_seed_addresses_only() {
+    let app = spawn_app_with(|c| {
+        c.issues.seed_addresses = vec!["qa@example.com".into(), "editor@example.com".into()];
+    })
+    .await;
+    app.create_confirmed_subscriber().await;
+    let issue = create_issue(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let response = apply(&app, &app.test_user, &issue, "test").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let requests = app.email_server.received_requests().await.unwrap();
+    let sent: Vec<serde_json::Value> = requests[requests.len() - 2..]
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect();
+    assert_eq!(sent[0]["To"], "qa@example.com");
+    assert_eq!(sent[1]["To"], "editor@example.com");
+    assert!(sent
+        .iter()
+        .all(|email| email["Subject"] == "[TEST] Issue #1"));
+    let queued = sqlx::query!("SELECT count(*) AS \"count!\" FROM delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(queued, 0);
+    let status = sqlx::query!("SELECT status FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status;
+    assert_eq!(status, "draft");
+}
+
+#[tokio::test]
+async fn test_sends_need_seed_addresses() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+
+    let response = apply(&app, &app.test_user, &issue, "test").await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn issues_can_be_previewed_as_html_text_or_eml() {
+    let app = spawn_app().await;
+    let issue = create_issue(&app).await;
+    let issue_id = issue["id"].as_str().unwrap();
+
+    let html = app
+        .get_admin_issue_preview(issue_id, &[("format", "html")])
+        .await;
+    assert_eq!(html.headers()["Content-Type"], "text/html; charset=utf-8");
+    let html = html.text().await.unwrap();
+    assert!(html.contains("<p>Hello</p>"), "{}", html);
+
+    let text = app
+        .get_admin_issue_preview(issue_id, &[("format", "text")])
+        .await;
+    assert_eq!(text.headers()["Content-Type"], "text/plain; charset=utf-8");
+    let text = text.text().await.unwrap();
+    assert!(text.starts_with("Hello\n"), "{}", text);
+
+    let eml = app
+        .get_admin_issue_preview(issue_id, &[("format", "eml")])
+        .await;
+    assert_eq!(eml.headers()["Content-Type"], "message/rfc822");
+    let eml = eml.text().await.unwrap();
+    assert!(eml.contains("\r\nTo: jane.doe@example.com\r\n"), "{}", eml);
+    assert!(eml.contains("\r\nSubject: Issue #1\r\n"), "{}", eml);
+    assert!(
+        eml.contains("Content-Type: multipart/alternative"),
+        "{}",
+        eml
+    );
+
+    let unknown = app
+        .get_admin_issue_preview(issue_id, &[("format", "pdf")])
+        .await;
+    assert_eq!(400, unknown.status().as_u16());
+}
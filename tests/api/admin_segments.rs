@@ -0,0 +1,185 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+
+async fn subscribe(app: &TestApp, name: &str) -> Uuid {
+    app.create_confirmed_subscriber_with(&format!("name={0}&email={0}%40example.com", name))
+        .await;
+    sqlx::query!("SELECT id FROM subscriptions WHERE name = $1", name)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id
+}
+
+/// Publishes `n` issues, one day apart, and returns their ids newest first.
+async fn publish_issues(app: &TestApp, n: i32) -> Vec<Uuid> {
+    let mut ids = Vec::new();
+    for days_ago in 0..n {
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            INSERT INTO newsletter_issues
+                (newsletter_issue_id, title, text_content, html_content, published_at)
+            VALUES ($1, 'Issue', 'Text', '<p>Html</p>', now() - make_interval(days => $2))
+            "#,
+            id,
+            days_ago
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+        ids.push(id);
+    }
+    ids
+}
+
+async fn record_open(app: &TestApp, issue_id: Uuid, subscriber_id: Uuid) {
+    sqlx::query!(
+        "INSERT INTO issue_opens (newsletter_issue_id, subscriber_id) VALUES ($1, $2)",
+        issue_id,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn preview_names(app: &TestApp, filter: &str) -> (i64, Vec<String>) {
+    let response = app.post_admin_segments_preview(filter).await;
+    assert_eq!(200, response.status().as_u16(), "{}", filter);
+    let preview: serde_json::Value = response.json().await.unwrap();
+    let mut names: Vec<String> = preview["sample"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|member| member["name"].as_str().unwrap().to_owned())
+        .collect();
+    names.sort();
+    (preview["count"].as_i64().unwrap(), names)
+}
+
+#[tokio::test]
+async fn the_preview_counts_and_samples_matching_subscribers() {
+    let app = spawn_app().await;
+    let ursula = subscribe(&app, "ursula").await;
+    let octavia = subscribe(&app, "octavia").await;
+    let iain = subscribe(&app, "iain").await;
+    subscribe(&app, "nnedi").await;
+    for id in [ursula, octavia, iain] {
+        app.put_admin_subscriber_tag(&id.to_string(), "beta")
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+    let issues = publish_issues(&app, 6).await;
+    // Octavia only opened an issue that is no longer among the last five.
+    record_open(&app, issues[1], ursula).await;
+    record_open(&app, issues[5], octavia).await;
+    record_open(&app, issues[0], iain).await;
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = now() - interval '120 days' WHERE id = $1",
+        iain
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let (count, names) = preview_names(
+        &app,
+        "confirmed, tag=beta, subscribed in last 90 days, opened at least one of last 5 issues",
+    )
+    .await;
+
+    assert_eq!(count, 1);
+    assert_eq!(names, vec!["ursula"]);
+    assert_eq!(
+        preview_names(&app, "not tag = beta").await,
+        (1, vec!["nnedi".to_string()])
+    );
+    assert_eq!(
+        preview_names(&app, "subscribed more than 90 days ago").await,
+        (1, vec!["iain".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn attribute_comparisons_use_the_field_type() {
+    let app = spawn_app().await;
+    app.post_admin_subscriber_field(&serde_json::json!({"name": "seats", "type": "number"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    for (name, seats) in [("ursula", 5), ("octavia", 50)] {
+        let id = subscribe(&app, name).await;
+        app.patch_admin_subscriber_attributes(
+            &id.to_string(),
+            &serde_json::json!({ "seats": seats }),
+        )
+        .await
+        .error_for_status()
+        .unwrap();
+    }
+    subscribe(&app, "iain").await;
+
+    assert_eq!(
+        preview_names(&app, "attribute.seats >= 10").await,
+        (1, vec!["octavia".to_string()])
+    );
+    assert_eq!(
+        preview_names(&app, "attribute.seats != 5").await,
+        (2, vec!["iain".to_string(), "octavia".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn invalid_filters_are_rejected_with_a_400_naming_the_clause() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin_segments_preview("confirmed, attribute.plan = pro")
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("attribute.plan = pro"));
+}
+
+#[tokio::test]
+async fn saved_segments_can_be_previewed_and_names_are_unique() {
+    let app = spawn_app().await;
+    subscribe(&app, "ursula").await;
+    let body = serde_json::json!({"name": "Everyone", "filter": "confirmed"});
+
+    let response = app.post_admin_segments(&body).await;
+    assert_eq!(201, response.status().as_u16());
+    let created: serde_json::Value = response.json().await.unwrap();
+    let duplicate = app.post_admin_segments(&body).await;
+    assert_eq!(409, duplicate.status().as_u16());
+    let invalid = app
+        .post_admin_segments(&serde_json::json!({"name": "Broken", "filter": "vip"}))
+        .await;
+    assert_eq!(400, invalid.status().as_u16());
+
+    let preview: serde_json::Value = app
+        .get_admin_segment_preview(created["id"].as_str().unwrap())
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(preview["count"], 1);
+    assert_eq!(preview["sample"][0]["email"], "ursula@example.com");
+}
+
+#[tokio::test]
+async fn previewing_an_unknown_segment_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .get_admin_segment_preview(&Uuid::new_v4().to_string())
+        .await;
+
+    assert_eq!(404, response.status().as_u16());
+}
@@ -0,0 +1,149 @@
+use crate::helpers::spawn_app;
+use uuid::Uuid;
+
+fn welcome_sequence() -> serde_json::Value {
+    serde_json::json!({
+        "name": "Welcome",
+        "steps": [
+            {
+                "delay_hours": 0,
+                "subject": "Welcome aboard",
+                "html_content": "<p>Day 0</p>",
+                "text_content": "Day 0"
+            },
+            {
+                "delay_hours": 72,
+                "subject": "Our best issues",
+                "html_content": "<p>Day 3</p>",
+                "text_content": "Day 3"
+            }
+        ]
+    })
+}
+
+#[tokio::test]
+async fn requests_without_valid_credentials_are_rejected() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let anonymous = client
+        .get(format!("{}/admin/sequences", &app.address))
+        .send()
+        .await
+        .unwrap();
+    let wrong_password = client
+        .get(format!("{}/admin/sequences", &app.address))
+        .basic_auth(&app.test_user.username, Some(Uuid::new_v4().to_string()))
+        .send()
+        .await
+        .unwrap();
+    let unknown_user = client
+        .get(format!("{}/admin/sequences", &app.address))
+        .basic_auth(Uuid::new_v4().to_string(), Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+
+    for response in [anonymous, wrong_password, unknown_user] {
+        assert_eq!(401, response.status().as_u16());
+        assert_eq!(
+            r#"Basic realm="admin""#,
+            response.headers()["WWW-Authenticate"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_created_sequence_is_listed_with_its_steps_in_order() {
+    let app = spawn_app().await;
+
+    let response = app.post_admin_sequences(&welcome_sequence()).await;
+    assert_eq!(201, response.status().as_u16());
+    let created: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(created["active"], true);
+
+    let listed: serde_json::Value = app.get_admin_sequences().await.json().await.unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["id"], created["id"]);
+    assert_eq!(listed[0]["steps"][0]["subject"], "Welcome aboard");
+    assert_eq!(listed[0]["steps"][1]["delay_hours"], 72);
+}
+
+#[tokio::test]
+async fn invalid_sequences_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    let step = |delay_hours: i32, subject: &str| {
+        serde_json::json!({
+            "delay_hours": delay_hours,
+            "subject": subject,
+            "html_content": "<p>Hi</p>",
+            "text_content": "Hi"
+        })
+    };
+    let test_cases = vec![
+        (
+            serde_json::json!({"name": " ", "steps": [step(0, "Hi")]}),
+            "empty name",
+        ),
+        (
+            serde_json::json!({"name": "Welcome", "steps": []}),
+            "no steps",
+        ),
+        (
+            serde_json::json!({"name": "Welcome", "steps": [step(72, "Hi"), step(0, "Hi")]}),
+            "steps out of order",
+        ),
+        (
+            serde_json::json!({"name": "Welcome", "steps": [step(0, "")]}),
+            "empty subject",
+        ),
+        (
+            serde_json::json!({"name": "Welcome", "steps": [step(0, "Hi {{ subscriber.name")]}),
+            "an invalid merge tag",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_admin_sequences(&body).await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn updating_a_sequence_replaces_its_steps() {
+    let app = spawn_app().await;
+    let created: serde_json::Value = app
+        .post_admin_sequences(&welcome_sequence())
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    let mut body = welcome_sequence();
+    body["active"] = false.into();
+    body["steps"].as_array_mut().unwrap().truncate(1);
+    let response = app
+        .put_admin_sequence(created["id"].as_str().unwrap(), &body)
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    let updated: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(updated["active"], false);
+    assert_eq!(updated["steps"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn updating_an_unknown_sequence_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .put_admin_sequence(&Uuid::new_v4().to_string(), &welcome_sequence())
+        .await;
+
+    assert_eq!(404, response.status().as_u16());
+}
@@ -0,0 +1,191 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+
+async fn define_fields(app: &TestApp) {
+    for field in [
+        serde_json::json!({
+            "name": "plan",
+            "type": "choice",
+            "choices": ["free", "pro"],
+            "signup": true
+        }),
+        serde_json::json!({"name": "seats", "type": "number", "min": 1}),
+    ] {
+        app.post_admin_subscriber_field(&field)
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+}
+
+async fn subscriber_id(app: &TestApp, email: &str) -> String {
+    sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id
+        .to_string()
+}
+
+async fn listed_emails(app: &TestApp, query: &[(&str, &str)]) -> Vec<String> {
+    let response = app.get_admin_subscribers(query).await;
+    assert_eq!(200, response.status().as_u16());
+    let listed: Vec<serde_json::Value> = response.json().await.unwrap();
+    listed
+        .iter()
+        .map(|s| s["email"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn invalid_field_definitions_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({"name": "Plan", "type": "text"}),
+            "bad name",
+        ),
+        (
+            serde_json::json!({"name": "email", "type": "text"}),
+            "reserved name",
+        ),
+        (
+            serde_json::json!({"name": "plan", "type": "choice", "choices": []}),
+            "no choices",
+        ),
+        (
+            serde_json::json!({"name": "plan", "type": "colour"}),
+            "unknown type",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_admin_subscriber_field(&body).await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn signup_attributes_are_stored_and_listed() {
+    let app = spawn_app().await;
+    define_fields(&app).await;
+
+    app.create_confirmed_subscriber_with(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&plan=pro&seats=5",
+    )
+    .await;
+
+    let response = app.get_admin_subscribers(&[]).await;
+    let listed: serde_json::Value = response.json().await.unwrap();
+    // `seats` is not open to signup, so the form cannot set it.
+    assert_eq!(listed[0]["attributes"], serde_json::json!({"plan": "pro"}));
+    assert_eq!(listed[0]["tags"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn admins_can_update_attributes_and_remove_them_with_null() {
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    app.create_confirmed_subscriber_with(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&plan=pro",
+    )
+    .await;
+    let id = subscriber_id(&app, "ursula_le_guin@gmail.com").await;
+
+    let response = app
+        .patch_admin_subscriber_attributes(&id, &serde_json::json!({"seats": "3", "plan": null}))
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    let attributes: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(attributes, serde_json::json!({"seats": 3}));
+
+    let invalid = app
+        .patch_admin_subscriber_attributes(&id, &serde_json::json!({"seats": 0}))
+        .await;
+    assert_eq!(400, invalid.status().as_u16());
+    let unknown = app
+        .patch_admin_subscriber_attributes(
+            &Uuid::new_v4().to_string(),
+            &serde_json::json!({"seats": 3}),
+        )
+        .await;
+    assert_eq!(404, unknown.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_listing_can_be_filtered_by_status_attribute_and_tag() {
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    app.create_confirmed_subscriber_with("name=ursula&email=ursula%40example.com&plan=pro")
+        .await;
+    app.create_confirmed_subscriber_with("name=octavia&email=octavia%40example.com&plan=free")
+        .await;
+    app.create_confirmed_subscriber_with("name=iain&email=iain%40example.com&plan=pro")
+        .await;
+    for email in ["ursula@example.com", "octavia@example.com"] {
+        let id = subscriber_id(&app, email).await;
+        let response = app.put_admin_subscriber_tag(&id, "Beta").await;
+        assert_eq!(204, response.status().as_u16());
+    }
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed' WHERE name = 'iain'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        listed_emails(&app, &[("attribute.plan", "pro")]).await,
+        vec!["ursula@example.com", "iain@example.com"]
+    );
+    assert_eq!(
+        listed_emails(&app, &[("attribute.plan", "pro"), ("tag", "beta")]).await,
+        vec!["ursula@example.com"]
+    );
+    assert_eq!(
+        listed_emails(
+            &app,
+            &[("status", "confirmed"), ("limit", "1"), ("offset", "1")]
+        )
+        .await,
+        vec!["octavia@example.com"]
+    );
+    let tagged: serde_json::Value = app
+        .get_admin_subscribers(&[("tag", "beta"), ("limit", "1")])
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(tagged[0]["tags"], serde_json::json!(["beta"]));
+}
+
+#[tokio::test]
+async fn invalid_filters_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    define_fields(&app).await;
+
+    for query in [
+        [("attribute.colour", "blue")],
+        [("attribute.seats", "many")],
+        [("tag", "not a tag")],
+        [("limit", "0")],
+        [("sort", "email")],
+    ] {
+        let response = app.get_admin_subscribers(&query).await;
+        assert_eq!(400, response.status().as_u16(), "{:?}", query);
+    }
+}
+
+#[tokio::test]
+async fn tagging_an_unknown_subscriber_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .put_admin_subscriber_tag(&Uuid::new_v4().to_string(), "beta")
+        .await;
+
+    assert_eq!(404, response.status().as_u16());
+}
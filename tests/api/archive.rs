@@ -0,0 +1,165 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+fn issue(title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "text_content": "Hello",
+        "html_content": "<p>Hello</p>"
+    })
+}
+
+async fn get(app: &TestApp, path: &str) -> reqwest::Response {
+    reqwest::get(format!("{}{}", app.address, path))
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn the_archive_lists_published_issues_that_are_not_private() {
+    let app = spawn_app().await;
+    app.publish_issue(&issue("Issue #1")).await;
+    let mut private = issue("Board minutes");
+    private["private"] = true.into();
+    app.publish_issue(&private).await;
+    app.post_admin_issues(&issue("Work in progress")).await;
+
+    let response = get(&app, "/archive").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(&format!(
+        "<a href=\"{}/archive/issue-1\">Issue #1</a>",
+        app.base_url
+    )));
+    assert!(html.contains(&format!(
+        "<link rel=\"canonical\" href=\"{}/archive\">",
+        app.base_url
+    )));
+    assert!(!html.contains("Board minutes"));
+    assert!(!html.contains("Work in progress"));
+}
+
+#[tokio::test]
+async fn archived_issues_leave_out_what_is_meant_for_subscribers() {
+    let app = spawn_app().await;
+    app.publish_issue(&serde_json::json!({
+        "title": "News for {{ subscriber.first_name }}",
+        "text_content": "Hi",
+        "html_content": "<p>Hi {{ subscriber.name | default(value=\"reader\") }}</p>\
+            <a href=\"{{ unsubscribe_url }}\">Unsubscribe</a><script>alert(1)</script>"
+    }))
+    .await;
+
+    let response = get(&app, "/archive/news-for").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<h1>News for there</h1>"));
+    assert!(html.contains("<p>Hi reader</p>"));
+    assert!(!html.contains("token="));
+    assert!(!html.contains("<script>alert"));
+    assert!(html.contains(&format!(
+        "<link rel=\"canonical\" href=\"{}/archive/news-for\">",
+        app.base_url
+    )));
+}
+
+#[tokio::test]
+async fn unknown_and_private_issues_are_not_found() {
+    let app = spawn_app().await;
+    let mut private = issue("Board minutes");
+    private["private"] = true.into();
+    app.publish_issue(&private).await;
+
+    for path in ["/archive/board-minutes", "/archive/nothing-here"] {
+        let response = get(&app, path).await;
+        assert_eq!(404, response.status().as_u16(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn issues_with_the_same_title_get_numbered_slugs() {
+    let app = spawn_app().await;
+    app.publish_issue(&issue("Weekly")).await;
+    app.publish_issue(&issue("Weekly")).await;
+
+    for path in ["/archive/weekly", "/archive/weekly-2"] {
+        let response = get(&app, path).await;
+        assert_eq!(200, response.status().as_u16(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn the_archive_is_paginated() {
+    let app = spawn_app_with(|c| c.issues.archive_page_size = 2).await;
+    for title in ["Issue #1", "Issue #2", "Issue #3"] {
+        app.publish_issue(&issue(title)).await;
+    }
+
+    let first = get(&app, "/archive").await.text().await.unwrap();
+    assert!(first.contains("Issue #3") && first.contains("Issue #2"));
+    assert!(!first.contains("Issue #1"));
+    assert!(first.contains(&format!(
+        "rel=\"next\" href=\"{}/archive?page=2\"",
+        app.base_url
+    )));
+    assert!(!first.contains("rel=\"prev\""));
+
+    let second = get(&app, "/archive?page=2").await.text().await.unwrap();
+    assert!(second.contains("Issue #1"));
+    assert!(second.contains(&format!("rel=\"prev\" href=\"{}/archive\"", app.base_url)));
+    assert!(!second.contains("rel=\"next\""));
+
+    for path in ["/archive?page=3", "/archive?page=0"] {
+        let response = get(&app, path).await;
+        assert_eq!(404, response.status().as_u16(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn unchanged_pages_are_not_sent_again() {
+    let app = spawn_app().await;
+    app.publish_issue(&issue("Issue #1")).await;
+
+    for path in ["/archive", "/archive/issue-1"] {
+        let response = get(&app, path).await;
+        let etag = response.headers()["ETag"].to_str().unwrap().to_owned();
+        let last_modified = response.headers()["Last-Modified"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        for (header, value) in [
+            ("If-None-Match", etag),
+            ("If-Modified-Since", last_modified),
+        ] {
+            let response = reqwest::Client::new()
+                .get(format!("{}{}", app.address, path))
+                .header(header, value)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(304, response.status().as_u16(), "{} {}", path, header);
+            assert!(response.text().await.unwrap().is_empty());
+        }
+    }
+}
+
+#[tokio::test]
+async fn the_archive_changes_when_an_issue_is_published() {
+    let app = spawn_app().await;
+    app.publish_issue(&issue("Issue #1")).await;
+    let response = get(&app, "/archive").await;
+    let etag = response.headers()["ETag"].to_str().unwrap().to_owned();
+
+    app.publish_issue(&issue("Issue #2")).await;
+    let response = reqwest::Client::new()
+        .get(format!("{}/archive", app.address))
+        .header("If-None-Match", etag)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.text().await.unwrap().contains("Issue #2"));
+}
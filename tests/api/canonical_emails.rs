@@ -0,0 +1,76 @@
+use zero2prod::canonical_emails::{backfill_canonical_emails, BackfillReport};
+use zero2prod::domain::EmailPolicy;
+
+use crate::helpers::{spawn_app, TestApp};
+
+/// Stores a subscriber the way IDN addresses were stored before their
+/// domains were converted to punycode.
+async fn store_legacy_subscriber(app: &TestApp, email: &str) -> uuid::Uuid {
+    let id = uuid::Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, canonical_email, name, subscribed_at, status)
+        VALUES ($1, $2, lower($2), 'le guin', now(), 'confirmed')
+        "#,
+        id,
+        email
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    id
+}
+
+async fn canonical_email(app: &TestApp, id: uuid::Uuid) -> String {
+    sqlx::query!(
+        "SELECT canonical_email FROM subscriptions WHERE id = $1",
+        id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .canonical_email
+}
+
+#[tokio::test]
+async fn legacy_canonical_emails_are_converted_to_punycode() {
+    let app = spawn_app().await;
+    let id = store_legacy_subscriber(&app, "ursula@Bücher.de").await;
+
+    let report = backfill_canonical_emails(&app.db_pool, &EmailPolicy::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report,
+        BackfillReport {
+            updated: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(canonical_email(&app, id).await, "ursula@xn--bcher-kva.de");
+}
+
+#[tokio::test]
+async fn subscribers_that_would_collide_are_left_in_place() {
+    let app = spawn_app().await;
+    let legacy = store_legacy_subscriber(&app, "ursula@bücher.de").await;
+    let current = store_legacy_subscriber(&app, "ursula@xn--bcher-kva.de").await;
+
+    let report = backfill_canonical_emails(&app.db_pool, &EmailPolicy::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report,
+        BackfillReport {
+            conflicts: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(canonical_email(&app, legacy).await, "ursula@bücher.de");
+    assert_eq!(
+        canonical_email(&app, current).await,
+        "ursula@xn--bcher-kva.de"
+    );
+}
@@ -0,0 +1,123 @@
+use crate::helpers::{spawn_app, TestApp};
+
+fn issue(title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "text_content": "Hello",
+        "html_content": "<p>Hello {{ subscriber.first_name }}</p><script>alert(1)</script>"
+    })
+}
+
+async fn get(app: &TestApp, path: &str) -> reqwest::Response {
+    reqwest::get(format!("{}{}", app.address, path))
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn the_rss_feed_carries_published_issues_in_full() {
+    let app = spawn_app().await;
+    let id = app.publish_issue(&issue("Issue #1")).await;
+    let mut private = issue("Board minutes");
+    private["private"] = true.into();
+    app.publish_issue(&private).await;
+
+    let response = get(&app, "/feed.rss").await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/rss+xml; charset=utf-8"
+    );
+    let channel: rss::Channel = response.text().await.unwrap().parse().unwrap();
+    assert_eq!(channel.link, format!("{}/archive", app.base_url));
+    assert_eq!(channel.items.len(), 1);
+    let item = &channel.items[0];
+    assert_eq!(item.title.as_deref(), Some("Issue #1"));
+    assert_eq!(
+        item.link.as_deref(),
+        Some(format!("{}/archive/issue-1", app.base_url).as_str())
+    );
+    assert_eq!(item.description.as_deref(), Some("<p>Hello there</p>"));
+    assert_eq!(
+        item.guid.as_ref().unwrap().value,
+        format!("urn:uuid:{}", id)
+    );
+    let published = chrono::DateTime::parse_from_rfc2822(item.pub_date.as_ref().unwrap());
+    assert!(published.is_ok());
+}
+
+#[tokio::test]
+async fn the_atom_feed_carries_published_issues_in_full() {
+    let app = spawn_app().await;
+    let id = app.publish_issue(&issue("Issue #1")).await;
+
+    let response = get(&app, "/feed.atom").await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/atom+xml; charset=utf-8"
+    );
+    let feed: atom_syndication::Feed = response.text().await.unwrap().parse().unwrap();
+    assert_eq!(feed.id, format!("{}/feed.atom", app.base_url));
+    assert_eq!(feed.entries.len(), 1);
+    let entry = &feed.entries[0];
+    assert_eq!(entry.title.value, "Issue #1");
+    assert_eq!(entry.id, format!("urn:uuid:{}", id));
+    assert!(entry.published.is_some());
+    assert_eq!(
+        entry.content.as_ref().unwrap().value.as_deref(),
+        Some("<p>Hello there</p>")
+    );
+}
+
+#[tokio::test]
+async fn unchanged_feeds_are_not_sent_again() {
+    let app = spawn_app().await;
+    app.publish_issue(&issue("Issue #1")).await;
+
+    for path in ["/feed.rss", "/feed.atom"] {
+        let response = get(&app, path).await;
+        let etag = response.headers()["ETag"].to_str().unwrap().to_owned();
+        let last_modified = response.headers()["Last-Modified"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        for (header, value) in [
+            ("If-None-Match", etag),
+            ("If-Modified-Since", last_modified),
+        ] {
+            let response = reqwest::Client::new()
+                .get(format!("{}{}", app.address, path))
+                .header(header, value)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(304, response.status().as_u16(), "{} {}", path, header);
+        }
+    }
+}
+
+#[tokio::test]
+async fn feeds_are_valid_before_anything_is_published() {
+    let app = spawn_app().await;
+
+    let channel: rss::Channel = get(&app, "/feed.rss")
+        .await
+        .text()
+        .await
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(channel.items.is_empty());
+    let feed: atom_syndication::Feed = get(&app, "/feed.atom")
+        .await
+        .text()
+        .await
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(feed.entries.is_empty());
+}
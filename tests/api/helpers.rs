@@ -1,8 +1,27 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, DeliveryQueueSettings, PendingSubscriptionsSettings,
+    RssToEmailSettings, Settings,
+};
+use zero2prod::delivery_queue::{try_execute_task, ExecutionOutcome};
+use zero2prod::domain_checker::DomainResolver;
+use zero2prod::email_client::EmailClient;
+use zero2prod::email_rendering::EmailRenderer;
+use zero2prod::email_templates::EmailTemplates;
+use zero2prod::issue_scheduler::{publish_due_issues, PublishReport};
+use zero2prod::merge_tags::Personalizer;
+use zero2prod::pending_subscriptions_worker::{
+    process_pending_subscriptions, PendingSubscriptionsReport,
+};
+use zero2prod::rate_limit::SubscriptionRateLimits;
+use zero2prod::rss_to_email::{poll_feeds, FeedFetcher, PollReport};
+use zero2prod::sequences::{schedule_due_steps, ScheduleReport};
 use zero2prod::startup::{get_connection_pool, Application};
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
@@ -24,6 +43,55 @@ pub struct TestApp {
     pub port: u16,
     pub db_pool: PgPool,
     pub email_server: MockServer,
+    pub email_client: EmailClient,
+    pub email_templates: EmailTemplates,
+    pub base_url: String,
+    pub pending_subscriptions: PendingSubscriptionsSettings,
+    pub delivery_queue: DeliveryQueueSettings,
+    pub rss_to_email: RssToEmailSettings,
+    pub personalizer: Personalizer,
+    pub email_renderer: EmailRenderer,
+    pub rate_limits: SubscriptionRateLimits,
+    pub test_user: TestUser,
+}
+
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        // Verification reads the parameters from the hash, so a cheap one
+        // keeps the tests fast.
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(1024, 1, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash,
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
 }
 
 pub struct ConfirmationLinks {
@@ -42,6 +110,417 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_subscribe_challenge(&self) -> serde_json::Value {
+        reqwest::Client::new()
+            .get(format!("{}/subscriptions/challenge", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .json()
+            .await
+            .expect("Failed to parse the challenge.")
+    }
+
+    pub async fn post_change_email(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/change_email", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Subscribes `ursula_le_guin@gmail.com` and clicks the confirmation link.
+    pub async fn create_confirmed_subscriber(&self) {
+        self.create_confirmed_subscriber_with("name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .await
+    }
+
+    /// Subscribes with the given form body and clicks the confirmation link.
+    pub async fn create_confirmed_subscriber_with(&self, body: &str) {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .named("Create confirmed subscriber")
+            .expect(1)
+            .mount_as_scoped(&self.email_server)
+            .await;
+        self.post_subscriptions(body.into())
+            .await
+            .error_for_status()
+            .unwrap();
+
+        let email_request = &self
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        let confirmation_links = self.get_confirmation_links(email_request);
+        self.click_confirmation_link(confirmation_links.html)
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+
+    /// Opens the confirmation link and presses the button on the page.
+    pub async fn click_confirmation_link(&self, link: reqwest::Url) -> reqwest::Response {
+        reqwest::get(link.clone())
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        let (_, subscription_token) = link
+            .query_pairs()
+            .find(|(key, _)| key == "subscription_token")
+            .unwrap();
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/confirm", &self.address))
+            .form(&[("subscription_token", subscription_token)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_sequences(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/sequences", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_sequences(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/sequences", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_admin_sequence(
+        &self,
+        sequence_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}/admin/sequences/{}", &self.address, sequence_id))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_issues(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/issues", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_email_templates(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/email-templates", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_admin_email_template(
+        &self,
+        name: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}/admin/email-templates/{}", &self.address, name))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn delete_admin_email_template(&self, name: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!("{}/admin/email-templates/{}", &self.address, name))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_issues_preview(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/issues/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_issue_preview(
+        &self,
+        issue_id: &str,
+        query: &[(&str, &str)],
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/issues/{}/preview",
+                &self.address, issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .query(query)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_admin_issue(
+        &self,
+        issue_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        self.put_admin_issue_as(&self.test_user, issue_id, body)
+            .await
+    }
+
+    /// Edits an issue as `user`.
+    pub async fn put_admin_issue_as(
+        &self,
+        user: &TestUser,
+        issue_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}/admin/issues/{}", &self.address, issue_id))
+            .basic_auth(&user.username, Some(&user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Applies a workflow action to an issue as `user`.
+    pub async fn post_admin_issue_action(
+        &self,
+        user: &TestUser,
+        issue_id: &str,
+        action: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/admin/issues/{}/{}",
+                &self.address, issue_id, action
+            ))
+            .basic_auth(&user.username, Some(&user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_rss_feeds(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/rss-feeds", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_segments(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/segments", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_segments_preview(&self, filter: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/segments/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&serde_json::json!({ "filter": filter }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_segment_preview(&self, segment_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/segments/{}/preview",
+                &self.address, segment_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_subscriber_field(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/subscriber-fields", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_subscribers(&self, query: &[(&str, &str)]) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/subscribers", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .query(query)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn patch_admin_subscriber_attributes(
+        &self,
+        subscriber_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .patch(format!(
+                "{}/admin/subscribers/{}/attributes",
+                &self.address, subscriber_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_admin_subscriber_tag(
+        &self,
+        subscriber_id: &str,
+        tag: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!(
+                "{}/admin/subscribers/{}/tags/{}",
+                &self.address, subscriber_id, tag
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Creates an issue and takes it through review to `scheduled`.
+    pub async fn schedule_issue(
+        &self,
+        body: &serde_json::Value,
+        publish_at: chrono::DateTime<chrono::Utc>,
+    ) -> String {
+        let reviewer = TestUser::generate();
+        reviewer.store(&self.db_pool).await;
+        let issue: serde_json::Value = self.post_admin_issues(body).await.json().await.unwrap();
+        let id = issue["id"].as_str().unwrap().to_owned();
+        for (user, action, version) in [
+            (&self.test_user, "submit", 1),
+            (&reviewer, "approve", 2),
+            (&self.test_user, "schedule", 3),
+        ] {
+            self.post_admin_issue_action(
+                user,
+                &id,
+                action,
+                &serde_json::json!({ "version": version, "publish_at": publish_at }),
+            )
+            .await
+            .error_for_status()
+            .unwrap();
+        }
+        id
+    }
+
+    /// Takes an issue through review and publishes it straight away.
+    pub async fn publish_issue(&self, body: &serde_json::Value) -> String {
+        let id = self.schedule_issue(body, chrono::Utc::now()).await;
+        self.publish_due_issues().await;
+        id
+    }
+
+    /// Runs one pass of the issue scheduler.
+    pub async fn publish_due_issues(&self) -> PublishReport {
+        publish_due_issues(&self.db_pool).await.unwrap()
+    }
+
+    /// Runs one pass over the registered feeds, fetching them with `fetcher`.
+    pub async fn poll_feeds(&self, fetcher: &dyn FeedFetcher) -> PollReport {
+        poll_feeds(&self.db_pool, fetcher, &self.rss_to_email)
+            .await
+            .unwrap()
+    }
+
+    /// Runs one pass of the sequence scheduler.
+    pub async fn schedule_sequence_steps(&self) -> ScheduleReport {
+        schedule_due_steps(&self.db_pool).await.unwrap()
+    }
+
+    /// Runs the delivery queue worker until nothing is due.
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.personalizer,
+                &self.email_renderer,
+                &self.email_templates,
+                &self.delivery_queue,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Runs one pass of the pending subscriptions worker.
+    pub async fn process_pending_subscriptions(&self) -> PendingSubscriptionsReport {
+        process_pending_subscriptions(
+            &self.db_pool,
+            &self.email_client,
+            &self.email_templates,
+            &self.base_url,
+            &self.pending_subscriptions,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// The link the email asks its recipient to follow, leaving out the
+    /// footer's unsubscribe link.
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
@@ -49,6 +528,7 @@ impl TestApp {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
                 .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .filter(|l| !l.as_str().contains("/subscriptions/unsubscribe"))
                 .collect();
             assert_eq!(links.len(), 1);
             let raw_link = links[0].as_str().to_owned();
@@ -65,6 +545,20 @@ impl TestApp {
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawns the app with settings adjusted by `customise` on top of the
+/// test defaults.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn_app_with_domain_resolver(customise, None).await
+}
+
+/// Like `spawn_app_with`, checking email domains with `domain_resolver`.
+pub async fn spawn_app_with_domain_resolver(
+    customise: impl FnOnce(&mut Settings),
+    domain_resolver: Option<Box<dyn DomainResolver>>,
+) -> TestApp {
     // The first time `spawn_app` is invoked the code in `TRACING` executes
     // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
@@ -77,23 +571,42 @@ pub async fn spawn_app() -> TestApp {
         // Use a random OS port
         c.application.port = 0;
         c.email_client.base_url = email_server.uri();
+        customise(&mut c);
         c
     };
 
     configure_database(&configuration.database).await;
 
-    let application = Application::build(configuration.clone())
-        .await
-        .expect("Failed to build application.");
+    let application =
+        Application::build_with_domain_resolver(configuration.clone(), domain_resolver)
+            .await
+            .expect("Failed to build application.");
     let application_port = application.port();
     let _ = tokio::spawn(application.run_until_stopped());
 
-    TestApp {
+    let test_app = TestApp {
         address: format!("http://localhost:{}", application_port),
         port: application_port,
         db_pool: get_connection_pool(&configuration.database),
         email_server,
-    }
+        email_client: configuration.email_client.client(),
+        email_templates: configuration.email_templates.templates().unwrap(),
+        personalizer: configuration
+            .merge_tags
+            .personalizer(&configuration.application.base_url),
+        email_renderer: EmailRenderer::new(&configuration.application.base_url).unwrap(),
+        rate_limits: configuration
+            .application
+            .rate_limits
+            .subscription_rate_limits(),
+        base_url: configuration.application.base_url,
+        pending_subscriptions: configuration.pending_subscriptions,
+        delivery_queue: configuration.delivery_queue,
+        rss_to_email: configuration.rss_to_email,
+        test_user: TestUser::generate(),
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
 }
 
 async fn configure_database(config: &DatabaseSettings) -> PgPool {
@@ -0,0 +1,71 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn recorded_opens(app: &TestApp) -> i64 {
+    sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM issue_opens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+/// Publishes an issue to a confirmed subscriber and returns the address of
+/// the tracking pixel in the email they got.
+async fn deliver_issue(app: &TestApp) -> reqwest::Url {
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.publish_issue(&serde_json::json!({
+        "title": "Issue #1",
+        "text_content": "Hello",
+        "html_content": "<p>Hello</p>"
+    }))
+    .await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    let pixel = html.find("/open?manage_token=").unwrap();
+    let start = html[..pixel].rfind("src=\"").unwrap() + "src=\"".len();
+    let end = start + html[start..].find('"').unwrap();
+    let mut pixel_url = reqwest::Url::parse(&html[start..end]).unwrap();
+    pixel_url.set_port(Some(app.port)).unwrap();
+    pixel_url
+}
+
+#[tokio::test]
+async fn loading_the_pixel_records_the_first_open() {
+    let app = spawn_app().await;
+    let pixel_url = deliver_issue(&app).await;
+
+    for _ in 0..2 {
+        let response = reqwest::get(pixel_url.clone()).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(response.headers()["Content-Type"], "image/gif");
+    }
+
+    assert_eq!(recorded_opens(&app).await, 1);
+}
+
+#[tokio::test]
+async fn a_pixel_with_an_unknown_token_records_nothing() {
+    let app = spawn_app().await;
+    let mut pixel_url = deliver_issue(&app).await;
+    pixel_url.set_query(Some("manage_token=forged"));
+
+    let response = reqwest::get(pixel_url).await.unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(recorded_opens(&app).await, 0);
+}
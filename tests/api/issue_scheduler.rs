@@ -0,0 +1,330 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// Creates an issue and takes it through review to `scheduled`.
+async fn schedule_issue(
+    app: &TestApp,
+    segment_id: Option<&str>,
+    publish_at: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let body = serde_json::json!({
+        "title": "Issue #1",
+        "text_content": "Hello",
+        "html_content": "<p>Hello</p>",
+        "segment_id": segment_id
+    });
+    app.schedule_issue(&body, publish_at).await
+}
+
+async fn status(app: &TestApp, issue_id: &str) -> String {
+    sqlx::query!(
+        "SELECT status FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        uuid::Uuid::parse_str(issue_id).unwrap()
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .status
+}
+
+#[tokio::test]
+async fn due_issues_are_sent_to_confirmed_subscribers() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let issue_id = schedule_issue(&app, None, chrono::Utc::now()).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let report = app.publish_due_issues().await;
+    assert_eq!(report.issues_started, 1);
+    assert_eq!(report.deliveries_queued, 1);
+    assert_eq!(status(&app, &issue_id).await, "sending");
+
+    app.dispatch_all_pending_emails().await;
+    let report = app.publish_due_issues().await;
+    assert_eq!(report.issues_sent, 1);
+    assert_eq!(status(&app, &issue_id).await, "sent");
+}
+
+#[tokio::test]
+async fn issues_are_not_sent_before_their_publication_time() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let issue_id =
+        schedule_issue(&app, None, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+
+    let report = app.publish_due_issues().await;
+
+    assert_eq!(report.issues_started, 0);
+    assert_eq!(status(&app, &issue_id).await, "scheduled");
+}
+
+#[tokio::test]
+async fn issues_addressed_to_a_segment_only_reach_its_members() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber_with("name=ursula&email=ursula%40example.com")
+        .await;
+    app.create_confirmed_subscriber_with("name=octavia&email=octavia%40example.com")
+        .await;
+    let ursula = sqlx::query!("SELECT id FROM subscriptions WHERE name = 'ursula'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    app.put_admin_subscriber_tag(&ursula.to_string(), "beta")
+        .await
+        .error_for_status()
+        .unwrap();
+    let segment: serde_json::Value = app
+        .post_admin_segments(&serde_json::json!({"name": "Beta", "filter": "tag = beta"}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    schedule_issue(&app, segment["id"].as_str(), chrono::Utc::now()).await;
+
+    let report = app.publish_due_issues().await;
+
+    assert_eq!(report.deliveries_queued, 1);
+    let queued = sqlx::query!("SELECT subscriber_id FROM delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.subscriber_id, ursula);
+    // The segment cannot go away while an issue is addressed to it.
+    let response = reqwest::Client::new()
+        .delete(format!(
+            "{}/admin/segments/{}",
+            &app.address,
+            segment["id"].as_str().unwrap()
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn only_one_replica_runs_the_scheduler_at_a_time() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let issue_id = schedule_issue(&app, None, chrono::Utc::now()).await;
+    let mut other_replica = app.db_pool.begin().await.unwrap();
+    sqlx::query!("SELECT pg_advisory_xact_lock(x'697373756573'::bigint)")
+        .execute(&mut other_replica)
+        .await
+        .unwrap();
+
+    let report = app.publish_due_issues().await;
+    assert!(report.lock_held_elsewhere);
+    assert_eq!(status(&app, &issue_id).await, "scheduled");
+
+    other_replica.rollback().await.unwrap();
+    let report = app.publish_due_issues().await;
+    assert_eq!(report.issues_started, 1);
+}
+
+async fn manage_token(app: &TestApp) -> String {
+    sqlx::query!("SELECT manage_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .manage_token
+}
+
+#[tokio::test]
+async fn issues_are_personalised_for_each_subscriber() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber_with("name=Tom%20%26%20Jerry&email=tom%40example.com")
+        .await;
+    app.schedule_issue(
+        &serde_json::json!({
+            "title": "News for {{ subscriber.first_name }}",
+            "text_content": "Hi {{ subscriber.name }}\n{{ unsubscribe_url }}",
+            "html_content": "<p>Hi {{ subscriber.name }}</p><a href=\"{{ unsubscribe_url }}\">Unsubscribe</a>"
+        }),
+        chrono::Utc::now(),
+    )
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let unsubscribe_url = format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.base_url,
+        manage_token(&app).await
+    );
+    assert_eq!(body["Subject"], "News for Tom");
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(
+        html.contains(&format!(
+            "<p>Hi Tom &amp; Jerry</p><a href=\"{}\" rel=\"noopener noreferrer\">Unsubscribe</a>",
+            unsubscribe_url
+        )),
+        "{}",
+        html
+    );
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(
+        text.starts_with(&format!("Hi Tom & Jerry\n{}\n", unsubscribe_url)),
+        "{}",
+        text
+    );
+}
+
+#[tokio::test]
+async fn missing_subscriber_fields_use_the_configured_fallbacks() {
+    let app = spawn_app_with(|c| {
+        c.merge_tags.fallbacks.insert(
+            "subscriber.attributes.company".into(),
+            "your company".into(),
+        );
+    })
+    .await;
+    app.post_admin_subscriber_field(&serde_json::json!({"name": "company", "type": "text"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    app.create_confirmed_subscriber().await;
+    app.schedule_issue(
+        &serde_json::json!({
+            "title": "Issue #1",
+            "text_content": "News for {{ subscriber.attributes.company }}",
+            "html_content": "<p>News for {{ subscriber.attributes.company }}</p>"
+        }),
+        chrono::Utc::now(),
+    )
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("News for your company\n"), "{}", text);
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("<p>News for your company</p>"), "{}", html);
+}
+
+#[tokio::test]
+async fn html_bodies_are_rendered_for_email_clients() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    app.schedule_issue(
+        &serde_json::json!({
+            "title": "Issue #1",
+            "text_content": "Hello",
+            "html_content": "<style>p { color: red }</style>\
+                <p onclick=\"steal()\">Hello</p><script>steal()</script>\
+                <a href=\"/archive\">Archive</a>"
+        }),
+        chrono::Utc::now(),
+    )
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(
+        html.contains(&format!(
+            "<p style=\"color: red\">Hello</p>\
+             <a href=\"{}/archive\" rel=\"noopener noreferrer\">Archive</a>",
+            app.base_url
+        )),
+        "{}",
+        html
+    );
+    assert!(!html.contains("steal()"), "{}", html);
+}
+
+#[tokio::test]
+async fn issues_are_sent_with_the_footer_of_the_email_layout() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    app.schedule_issue(
+        &serde_json::json!({
+            "title": "Issue #1",
+            "text_content": "Hello",
+            "html_content": "<p>Hello</p>"
+        }),
+        chrono::Utc::now(),
+    )
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let unsubscribe_url = format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.base_url,
+        manage_token(&app).await
+    );
+    for content in [&body["HtmlBody"], &body["TextBody"]] {
+        let content = content.as_str().unwrap();
+        assert!(content.contains("1 Newsletter Street"), "{}", content);
+        assert!(content.contains(&unsubscribe_url), "{}", content);
+    }
+}
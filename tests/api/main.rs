@@ -1,4 +1,21 @@
+mod admin_authentication;
+mod admin_email_templates;
+mod admin_issues;
+mod admin_segments;
+mod admin_sequences;
+mod admin_subscribers;
+mod archive;
+mod canonical_emails;
+mod feeds;
 mod health_check;
 mod helpers;
+mod issue_opens;
+mod issue_scheduler;
+mod pending_subscriptions;
+mod rss_to_email;
+mod sequences;
 mod subscriptions;
+mod subscriptions_change_email;
 mod subscriptions_confirm;
+mod subscriptions_manage;
+mod widget;
@@ -0,0 +1,245 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_unconfirmed_subscriber(app: &TestApp) {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+async fn backdate_subscriptions(app: &TestApp, hours: i32) {
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = now() - make_interval(hours => $1)",
+        hours
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn unconfirmed_subscribers_get_one_reminder_after_the_delay() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    backdate_subscriptions(&app, 49).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.reminders_sent, 1);
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.reminders_sent, 0);
+}
+
+#[tokio::test]
+async fn the_link_in_a_reminder_confirms_the_subscription() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    // Older than the confirmation link TTL, so only the reminder's token works.
+    backdate_subscriptions(&app, 80).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '80 hours'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.process_pending_subscriptions().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    app.click_confirmation_link(confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn no_reminder_is_sent_before_the_delay_or_to_confirmed_subscribers() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    app.create_confirmed_subscriber_with("name=tom&email=thomas_mann%40hotmail.com")
+        .await;
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = now() - interval '49 hours' WHERE status = 'confirmed'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.reminders_sent, 0);
+}
+
+#[tokio::test]
+async fn a_failed_reminder_is_not_retried() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    backdate_subscriptions(&app, 49).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.reminders_sent, 0);
+    assert_eq!(report.reminders_failed, 1);
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.reminders_failed, 0);
+}
+
+#[tokio::test]
+async fn unconfirmed_subscribers_are_deleted_after_the_retention_period() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    app.create_confirmed_subscriber_with("name=tom&email=thomas_mann%40hotmail.com")
+        .await;
+    backdate_subscriptions(&app, 15 * 24).await;
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.subscriptions_deleted, 1);
+    assert_eq!(report.tokens_deleted, 1);
+    assert_eq!(report.reminders_sent, 0);
+
+    let remaining = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].status, "confirmed");
+    let tokens = sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+}
+
+#[tokio::test]
+async fn restarted_subscribers_with_enrollments_are_deleted_after_the_retention_period() {
+    let app = spawn_app().await;
+    sqlx::query!(
+        "INSERT INTO sequences (id, name) VALUES ($1, 'Welcome')",
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.create_confirmed_subscriber_with("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    create_unconfirmed_subscriber(&app).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO sequence_enrollments (sequence_id, subscriber_id)
+        SELECT sequences.id, subscriptions.id FROM sequences, subscriptions
+        ON CONFLICT DO NOTHING
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO delivery_queue (id, subscriber_id, subject, html_content, text_content)
+        SELECT $1, id, 'Day 0', '<p>Welcome aboard</p>', 'Welcome aboard' FROM subscriptions
+        "#,
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    backdate_subscriptions(&app, 15 * 24).await;
+
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.subscriptions_deleted, 1);
+
+    let remaining = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT count(*) FROM subscriptions) AS "subscriptions!",
+            (SELECT count(*) FROM sequence_enrollments) AS "enrollments!",
+            (SELECT count(*) FROM delivery_queue) AS "deliveries!"
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(remaining.subscriptions, 0);
+    assert_eq!(remaining.enrollments, 0);
+    assert_eq!(remaining.deliveries, 0);
+}
+
+#[tokio::test]
+async fn the_report_counts_every_subscription_and_token_deleted() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    for body in [
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+        "name=tom&email=thomas_mann%40hotmail.com",
+    ] {
+        app.post_subscriptions(body.into())
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+    backdate_subscriptions(&app, 49).await;
+    let report = app.process_pending_subscriptions().await;
+    assert_eq!(report.reminders_sent, 2);
+    assert_eq!(report.subscriptions_deleted, 0);
+
+    backdate_subscriptions(&app, 15 * 24).await;
+    let report = app.process_pending_subscriptions().await;
+
+    assert_eq!(report.subscriptions_deleted, 2);
+    assert_eq!(report.tokens_deleted, 4);
+}
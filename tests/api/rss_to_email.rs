@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::rss_to_email::{FeedFetcher, PollReport};
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+const FEED_URL: &str = "https://blog.example.com/feed.xml";
+
+/// Serves documents set by the test instead of fetching them.
+#[derive(Default)]
+struct StubFetcher {
+    documents: Mutex<HashMap<String, String>>,
+}
+
+impl StubFetcher {
+    /// Serves an RSS feed of `posts`, newest first, at `FEED_URL`.
+    fn serve(&self, posts: &[u32]) {
+        let items: String = posts
+            .iter()
+            .map(|n| {
+                format!(
+                    "<item><title>Post #{n}</title><link>https://blog.example.com/{n}</link>\
+                     <guid>post-{n}</guid><description>&lt;p&gt;Body {n}&lt;/p&gt;</description>\
+                     </item>"
+                )
+            })
+            .collect();
+        self.documents.lock().unwrap().insert(
+            FEED_URL.into(),
+            format!(
+                "<rss version=\"2.0\"><channel><title>Blog</title>\
+                 <link>https://blog.example.com</link><description>Posts</description>\
+                 {}</channel></rss>",
+                items
+            ),
+        );
+    }
+}
+
+#[async_trait::async_trait]
+impl FeedFetcher for StubFetcher {
+    async fn fetch(&self, url: &str) -> Result<String, anyhow::Error> {
+        self.documents
+            .lock()
+            .unwrap()
+            .get(url)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("404 Not Found"))
+    }
+}
+
+async fn register_feed(app: &TestApp, body: serde_json::Value) -> serde_json::Value {
+    let response = app.post_admin_rss_feeds(&body).await;
+    assert_eq!(201, response.status().as_u16());
+    response.json().await.unwrap()
+}
+
+struct IssueRow {
+    title: String,
+    status: String,
+    markdown_content: Option<String>,
+    html_content: String,
+}
+
+async fn issues(app: &TestApp) -> Vec<IssueRow> {
+    sqlx::query_as!(
+        IssueRow,
+        "SELECT title, status, markdown_content, html_content FROM newsletter_issues \
+        ORDER BY created_at"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn feeds_need_an_http_url_and_templates_that_render() {
+    let app = spawn_app().await;
+    for (body, reason) in [
+        (
+            serde_json::json!({ "url": "ftp://blog.example.com/feed", "mode": "draft" }),
+            "not http",
+        ),
+        (
+            serde_json::json!({ "url": FEED_URL, "mode": "draft", "title_template": "{{ item.title" }),
+            "unclosed tag",
+        ),
+        (
+            serde_json::json!({ "url": FEED_URL, "mode": "draft", "body_template": "{{ item.author }}" }),
+            "unknown variable",
+        ),
+    ] {
+        let response = app.post_admin_rss_feeds(&body).await;
+        assert_eq!(400, response.status().as_u16(), "{}", reason);
+    }
+
+    let feed = register_feed(
+        &app,
+        serde_json::json!({ "url": FEED_URL, "mode": "draft" }),
+    )
+    .await;
+    assert_eq!(feed["title_template"], "{{ item.title }}");
+    assert!(feed["last_checked_at"].is_null());
+
+    let response = app
+        .post_admin_rss_feeds(&serde_json::json!({ "url": FEED_URL, "mode": "send" }))
+        .await;
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn new_items_become_draft_issues_but_existing_ones_do_not() {
+    let app = spawn_app().await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": FEED_URL, "mode": "draft" }),
+    )
+    .await;
+    let fetcher = StubFetcher::default();
+
+    fetcher.serve(&[2, 1]);
+    let report = app.poll_feeds(&fetcher).await;
+    assert_eq!(report.issues_created, 0);
+    assert!(issues(&app).await.is_empty());
+
+    fetcher.serve(&[3, 2, 1]);
+    let report = app.poll_feeds(&fetcher).await;
+    assert_eq!(
+        report,
+        PollReport {
+            feeds_checked: 1,
+            feeds_failed: 0,
+            new_items: 1,
+            issues_created: 1,
+        }
+    );
+    let created = issues(&app).await;
+    assert_eq!(created.len(), 1);
+    assert_eq!(created[0].title, "Post #3");
+    assert_eq!(created[0].status, "draft");
+    assert!(created[0]
+        .markdown_content
+        .as_ref()
+        .unwrap()
+        .starts_with("## [Post #3](https://blog.example.com/3)"));
+    assert!(created[0].html_content.contains("<p>Body 3</p>"));
+
+    // The same items are not picked up twice.
+    let report = app.poll_feeds(&fetcher).await;
+    assert_eq!(report.issues_created, 0);
+}
+
+#[tokio::test]
+async fn issues_of_feeds_in_send_mode_go_out_without_review() {
+    let app = spawn_app().await;
+    register_feed(&app, serde_json::json!({ "url": FEED_URL, "mode": "send" })).await;
+    let fetcher = StubFetcher::default();
+    fetcher.serve(&[1]);
+    app.poll_feeds(&fetcher).await;
+
+    fetcher.serve(&[2, 1]);
+    app.poll_feeds(&fetcher).await;
+
+    assert_eq!(issues(&app).await[0].status, "scheduled");
+    assert_eq!(app.publish_due_issues().await.issues_started, 1);
+}
+
+#[tokio::test]
+async fn digests_bundle_the_items_of_a_week() {
+    let app = spawn_app().await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": FEED_URL, "mode": "draft", "digest": true }),
+    )
+    .await;
+    let fetcher = StubFetcher::default();
+    fetcher.serve(&[1]);
+    app.poll_feeds(&fetcher).await;
+
+    fetcher.serve(&[3, 2, 1]);
+    let report = app.poll_feeds(&fetcher).await;
+    assert_eq!((report.new_items, report.issues_created), (2, 0));
+
+    sqlx::query!("UPDATE rss_feeds SET last_digest_at = now() - interval '8 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let report = app.poll_feeds(&fetcher).await;
+    assert_eq!(report.issues_created, 1);
+    let created = issues(&app).await;
+    assert_eq!(created[0].title, "2 new posts this week");
+    let markdown = created[0].markdown_content.as_ref().unwrap();
+    assert!(markdown.contains("Post #2") && markdown.contains("Post #3"));
+    assert!(!markdown.contains("Post #1"));
+
+    // The next digest is a week away again.
+    fetcher.serve(&[4, 3, 2, 1]);
+    let report = app.poll_feeds(&fetcher).await;
+    assert_eq!((report.new_items, report.issues_created), (1, 0));
+}
+
+#[tokio::test]
+async fn feeds_that_fail_are_reported_without_stopping_the_others() {
+    let app = spawn_app().await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": "https://gone.example.com/feed", "mode": "draft" }),
+    )
+    .await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": FEED_URL, "mode": "draft" }),
+    )
+    .await;
+    let fetcher = StubFetcher::default();
+    fetcher.serve(&[1]);
+
+    let report = app.poll_feeds(&fetcher).await;
+
+    assert_eq!((report.feeds_checked, report.feeds_failed), (2, 1));
+    let feeds: serde_json::Value = reqwest::Client::new()
+        .get(format!("{}/admin/rss-feeds", app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(feeds[0]["last_error"], "404 Not Found");
+    assert!(feeds[1]["last_error"].is_null());
+    assert!(!feeds[1]["last_checked_at"].is_null());
+}
+
+/// Serves `body` as the feed of a blog on the loopback interface, which the
+/// fetcher only connects to when private addresses are allowed.
+async fn serve_feed(body: String, expected_fetches: u64) -> MockServer {
+    let blog = MockServer::start().await;
+    Mock::given(path("/feed.xml"))
+        .and(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(body))
+        .expect(expected_fetches)
+        .mount(&blog)
+        .await;
+    blog
+}
+
+#[tokio::test]
+async fn feeds_are_fetched_over_http() {
+    let app = spawn_app_with(|c| c.rss_to_email.allow_private_addresses = true).await;
+    let blog = serve_feed(
+        "<feed xmlns=\"http://www.w3.org/2005/Atom\"><title>Blog</title><id>urn:blog</id>\
+         <updated>2023-04-16T10:00:00Z</updated></feed>"
+            .into(),
+        1,
+    )
+    .await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": format!("{}/feed.xml", blog.uri()), "mode": "draft" }),
+    )
+    .await;
+
+    let report = app.poll_feeds(&app.rss_to_email.fetcher()).await;
+
+    assert_eq!((report.feeds_checked, report.feeds_failed), (1, 0));
+}
+
+#[tokio::test]
+async fn feeds_on_private_addresses_are_not_fetched() {
+    let app = spawn_app().await;
+    let blog = serve_feed(String::new(), 0).await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": format!("{}/feed.xml", blog.uri()), "mode": "draft" }),
+    )
+    .await;
+
+    let report = app.poll_feeds(&app.rss_to_email.fetcher()).await;
+
+    assert_eq!((report.feeds_checked, report.feeds_failed), (1, 1));
+}
+
+#[tokio::test]
+async fn feeds_over_the_size_limit_are_not_read() {
+    let app = spawn_app_with(|c| {
+        c.rss_to_email.allow_private_addresses = true;
+        c.rss_to_email.max_feed_bytes = 1000;
+    })
+    .await;
+    let blog = serve_feed("x".repeat(1001), 1).await;
+    register_feed(
+        &app,
+        serde_json::json!({ "url": format!("{}/feed.xml", blog.uri()), "mode": "draft" }),
+    )
+    .await;
+
+    let report = app.poll_feeds(&app.rss_to_email.fetcher()).await;
+
+    assert_eq!((report.feeds_checked, report.feeds_failed), (1, 1));
+}
@@ -0,0 +1,206 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_welcome_sequence(app: &TestApp) {
+    let body = serde_json::json!({
+        "name": "Welcome",
+        "steps": [
+            {
+                "delay_hours": 0,
+                "subject": "Day 0",
+                "html_content": "<p>Welcome aboard</p>",
+                "text_content": "Welcome aboard"
+            },
+            {
+                "delay_hours": 72,
+                "subject": "Day 3",
+                "html_content": "<p>Our best issues</p>",
+                "text_content": "Our best issues"
+            },
+            {
+                "delay_hours": 168,
+                "subject": "Day 7",
+                "html_content": "<p>Tell us what you think</p>",
+                "text_content": "Tell us what you think"
+            }
+        ]
+    });
+    app.post_admin_sequences(&body)
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+async fn backdate_enrollments(app: &TestApp, hours: i32) {
+    sqlx::query!(
+        "UPDATE sequence_enrollments SET enrolled_at = now() - make_interval(hours => $1)",
+        hours
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn sent_subjects(app: &TestApp) -> Vec<String> {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            body["Subject"].as_str().unwrap().to_owned()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_get_the_first_step_right_away() {
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    app.create_confirmed_subscriber().await;
+    app.email_server.reset().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let report = app.schedule_sequence_steps().await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(report.steps_queued, 1);
+    assert_eq!(sent_subjects(&app).await, vec!["Day 0"]);
+}
+
+#[tokio::test]
+async fn later_steps_are_sent_once_their_delay_has_passed() {
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    app.create_confirmed_subscriber().await;
+    app.email_server.reset().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.schedule_sequence_steps().await;
+    backdate_enrollments(&app, 73).await;
+    app.schedule_sequence_steps().await;
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(sent_subjects(&app).await, vec!["Day 0", "Day 3"]);
+
+    backdate_enrollments(&app, 169).await;
+    let report = app.schedule_sequence_steps().await;
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(report.enrollments_completed, 1);
+    assert_eq!(sent_subjects(&app).await, vec!["Day 0", "Day 3", "Day 7"]);
+}
+
+#[tokio::test]
+async fn subscribers_who_are_no_longer_confirmed_get_nothing_more() {
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    app.create_confirmed_subscriber().await;
+    app.email_server.reset().await;
+    app.schedule_sequence_steps().await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // The step queued before unsubscribing is dropped, later ones never queued.
+    app.dispatch_all_pending_emails().await;
+    backdate_enrollments(&app, 169).await;
+    let report = app.schedule_sequence_steps().await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(report.enrollments_stopped, 1);
+    assert_eq!(report.steps_queued, 0);
+}
+
+#[tokio::test]
+async fn inactive_sequences_do_not_enroll_new_subscribers() {
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    sqlx::query!("UPDATE sequences SET active = false")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    app.create_confirmed_subscriber().await;
+
+    let enrollments = sqlx::query!("SELECT subscriber_id FROM sequence_enrollments")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(enrollments.is_empty());
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_later() {
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    app.create_confirmed_subscriber().await;
+    app.email_server.reset().await;
+    app.schedule_sequence_steps().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.dispatch_all_pending_emails().await;
+
+    let queued = sqlx::query!("SELECT n_retries, execute_after FROM delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.n_retries, 1);
+    assert!(queued.execute_after > chrono::Utc::now());
+}
+
+#[tokio::test]
+async fn resubscribing_starts_the_sequences_from_the_first_step() {
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    app.create_confirmed_subscriber().await;
+    app.email_server.reset().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.schedule_sequence_steps().await;
+    app.dispatch_all_pending_emails().await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.email_server.reset().await;
+
+    app.create_confirmed_subscriber().await;
+    app.email_server.reset().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let report = app.schedule_sequence_steps().await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(report.steps_queued, 1);
+    assert_eq!(sent_subjects(&app).await, vec!["Day 0"]);
+}
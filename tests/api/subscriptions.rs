@@ -1,6 +1,7 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with, spawn_app_with_domain_resolver};
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
+use zero2prod::domain_checker::DomainResolver;
 
 #[tokio::test]
 async fn subscribe_returns_a_200_for_valid_form_data() {
@@ -84,6 +85,104 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
     }
 }
 
+#[tokio::test]
+async fn subscribe_explains_why_an_email_was_rejected() {
+    let test_app = spawn_app().await;
+    let test_cases = vec![
+        (
+            "name=Ursula&email=ursula%40",
+            "The part of the email address after the @ sign is empty.",
+        ),
+        (
+            "name=Ursula&email=postmaster%40example.com",
+            "Role addresses such as postmaster@ cannot subscribe, please use a personal address.",
+        ),
+        (
+            "name=Ursula&email=ursula%40mailinator.com",
+            "Disposable email addresses from mailinator.com are not accepted.",
+        ),
+    ];
+
+    for (body, message) in test_cases {
+        let response = test_app.post_subscriptions(body.into()).await;
+
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(message, response.text().await.unwrap());
+    }
+}
+
+/// Only `example.com` accepts email.
+struct FakeResolver;
+
+#[async_trait::async_trait]
+impl DomainResolver for FakeResolver {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        Ok(domain == "example.com")
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_domains_that_do_not_accept_email() {
+    let test_app = spawn_app_with_domain_resolver(
+        |c| c.domain_check.enabled = true,
+        Some(Box::new(FakeResolver)),
+    )
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let response = test_app
+        .post_subscriptions("name=Ursula&email=ursula%40gmial.com".into())
+        .await;
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "gmial.com does not accept email. Did you mean gmail.com?",
+        response.text().await.unwrap()
+    );
+
+    let response = test_app
+        .post_subscriptions("name=Ursula&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_persists_a_normalized_name() {
+    let app = spawn_app().await;
+    let body = "name=%20Ursula%20%20K.%E2%80%AE%20Le%20Guin%20&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+
+    let saved = sqlx::query!("select name from subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "Ursula K. Le Guin");
+}
+
+#[tokio::test]
+async fn subscribe_explains_why_a_name_was_rejected() {
+    let test_app = spawn_app().await;
+    let body = "name=P%D0%B0ypal&email=ursula_le_guin%40gmail.com";
+
+    let response = test_app.post_subscriptions(body.into()).await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "The word P\u{0430}ypal mixes letters from different alphabets.",
+        response.text().await.unwrap()
+    );
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     let app = spawn_app().await;
@@ -117,6 +216,25 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
 
+#[tokio::test]
+async fn the_confirmation_email_puts_each_body_in_its_own_field() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body["HtmlBody"].as_str().unwrap().contains("<a href="));
+    assert!(!body["TextBody"].as_str().unwrap().contains('<'));
+}
+
 #[tokio::test]
 async fn subscribing_twice_sends_confirmation_email_twice_with_the_same_token() {
     let app = spawn_app().await;
@@ -142,6 +260,152 @@ async fn subscribing_twice_sends_confirmation_email_twice_with_the_same_token()
     );
 }
 
+#[tokio::test]
+async fn subscribing_with_a_differently_cased_email_reuses_the_pending_subscription() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40Gmail.com".into())
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(
+        app.get_confirmation_links(&email_requests[0]).html,
+        app.get_confirmation_links(&email_requests[1]).html
+    );
+    let saved = sqlx::query!("select email, canonical_email from subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "Ursula_Le_Guin@Gmail.com");
+    assert_eq!(saved[0].canonical_email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribing_when_already_confirmed_sends_an_already_subscribed_email() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "You're already subscribed");
+    let saved = sqlx::query!("SELECT status, manage_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    let unsubscribe_url = format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.base_url, saved.manage_token
+    );
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(text.contains(&unsubscribe_url), "{}", text);
+}
+
+#[tokio::test]
+async fn subscribing_after_unsubscribing_starts_a_fresh_double_opt_in() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.click_confirmation_link(confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_a_suppressed_address_is_refused() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    sqlx::query!("UPDATE subscriptions SET status = 'suppressed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        problem["errors"]["email"],
+        "Emails to this address bounce, please subscribe with another one."
+    );
+}
+
 #[tokio::test]
 async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     let app = spawn_app().await;
@@ -156,3 +420,434 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
 
     assert_eq!(500, response.status().as_u16());
 }
+
+#[tokio::test]
+async fn subscribe_is_rate_limited_per_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    for _ in 0..3 {
+        let response = app.post_subscriptions(body.into()).await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    // The same mailbox, spelled differently.
+    let response = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%2Bnews%40gmail.com".into())
+        .await;
+
+    assert_eq!(429, response.status().as_u16());
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0);
+}
+
+#[tokio::test]
+async fn subscribe_is_rate_limited_per_ip() {
+    let app = spawn_app().await;
+
+    for _ in 0..20 {
+        let response = app
+            .post_subscriptions("name=le%20guin&email=definitely-not-an-email".into())
+            .await;
+        assert_eq!(400, response.status().as_u16());
+    }
+    let response = app
+        .post_subscriptions("name=le%20guin&email=definitely-not-an-email".into())
+        .await;
+
+    assert_eq!(429, response.status().as_u16());
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn the_per_ip_limit_ignores_forwarded_for_from_untrusted_peers() {
+    let app = spawn_app().await;
+
+    for i in 0..21 {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("X-Forwarded-For", format!("198.51.100.{}", i))
+            .form(&[("name", "le guin"), ("email", "definitely-not-an-email")])
+            .send()
+            .await
+            .unwrap();
+        let expected = if i < 20 { 400 } else { 429 };
+        assert_eq!(expected, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn the_per_ip_limit_follows_forwarded_for_from_trusted_proxies() {
+    let app = spawn_app_with(|c| {
+        c.application.rate_limits.trusted_proxies = vec!["127.0.0.1".parse().unwrap()]
+    })
+    .await;
+
+    for i in 0..21 {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("X-Forwarded-For", format!("198.51.100.{}", i))
+            .form(&[("name", "le guin"), ("email", "definitely-not-an-email")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(400, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn idle_rate_limit_buckets_are_deleted() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=definitely-not-an-email".into())
+        .await;
+    sqlx::query!(
+        "INSERT INTO rate_limit_buckets (key, tokens, updated_at)
+        VALUES ('ip:192.0.2.1', 0, now() - interval '2 hours')"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let deleted = app
+        .rate_limits
+        .delete_idle_buckets(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(deleted, 1);
+    let keys: Vec<String> = sqlx::query_scalar!("SELECT key FROM rate_limit_buckets")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(keys, vec!["ip:127.0.0.1".to_string()]);
+}
+
+#[tokio::test]
+async fn subscribe_fakes_success_when_the_honeypot_is_filled_in() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&website=spam.example.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("select email from subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_accepts_a_form_with_a_fresh_nonce() {
+    let app = spawn_app_with(|c| {
+        c.bot_protection.require_form_nonce = true;
+        c.bot_protection.min_fill_seconds = 0;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let challenge = app.get_subscribe_challenge().await;
+    let body = format!(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&form_nonce={}",
+        challenge["nonce"].as_str().unwrap()
+    );
+    let response = app.post_subscriptions(body).await;
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_fakes_success_when_a_nonce_is_replayed() {
+    let app = spawn_app_with(|c| {
+        c.bot_protection.require_form_nonce = true;
+        c.bot_protection.min_fill_seconds = 0;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let challenge = app.get_subscribe_challenge().await;
+    let nonce = challenge["nonce"].as_str().unwrap();
+    // A rejected submission does not spend the nonce.
+    let response = app
+        .post_subscriptions(format!("name=le%20guin&email=ursula&form_nonce={}", nonce))
+        .await;
+    assert_eq!(400, response.status().as_u16());
+    for email in ["ursula_le_guin%40gmail.com", "le_guin%40example.com"] {
+        let body = format!("name=le%20guin&email={}&form_nonce={}", email, nonce);
+        let response = app.post_subscriptions(body).await;
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribe_fakes_success_without_a_nonce_or_when_submitted_too_fast() {
+    let app = spawn_app_with(|c| {
+        c.bot_protection.require_form_nonce = true;
+        c.bot_protection.min_fill_seconds = 60;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let challenge = app.get_subscribe_challenge().await;
+    let test_cases = vec![
+        (
+            "name=le%20guin&email=ursula_le_guin%40gmail.com".to_string(),
+            "no nonce",
+        ),
+        (
+            format!(
+                "name=le%20guin&email=ursula_le_guin%40gmail.com&form_nonce={}",
+                challenge["nonce"].as_str().unwrap()
+            ),
+            "a nonce issued a moment ago",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_subscriptions(body).await;
+        assert_eq!(
+            200,
+            response.status().as_u16(),
+            "The API did not pretend to succeed with {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_accepts_json_bodies() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_returns_field_errors_as_problem_json_for_json_clients() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin"
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["status"], 400);
+    assert_eq!(
+        problem["errors"]["email"],
+        "The email address is missing an @ sign."
+    );
+    assert!(problem["errors"].get("name").is_none());
+}
+
+#[tokio::test]
+async fn subscribe_returns_problem_json_when_a_form_client_accepts_json() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/problem+json")
+        .body("email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(400, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"]["name"], "The name is empty.");
+}
+
+#[tokio::test]
+async fn subscribe_renders_the_check_your_inbox_page_for_browsers() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.text().await.unwrap().contains("Check your inbox"));
+}
+
+#[tokio::test]
+async fn subscribe_redirects_to_an_allowed_origin() {
+    let app = spawn_app_with(|c| {
+        c.pages.allowed_redirect_origins = vec!["https://example.com".into()];
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com&redirect_to=https%3A%2F%2Fexample.com%2Fthanks")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(303, response.status().as_u16());
+    assert_eq!(response.headers()["Location"], "https://example.com/thanks");
+}
+
+#[tokio::test]
+async fn subscribe_rejects_redirects_to_other_origins() {
+    let app = spawn_app_with(|c| {
+        c.pages.allowed_redirect_origins = vec!["https://example.com".into()];
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&redirect_to=https%3A%2F%2Fevil.com%2F"
+                .into(),
+        )
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_reports_invalid_custom_fields_as_field_errors() {
+    let app = spawn_app().await;
+    app.post_admin_subscriber_field(&serde_json::json!({
+        "name": "plan",
+        "type": "choice",
+        "choices": ["free", "pro"],
+        "required": true,
+        "signup": true
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for (body, message) in [
+        ("", "plan is required."),
+        ("&plan=enterprise", "plan must be one of free, pro."),
+    ] {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Accept", "application/problem+json")
+            .body(format!(
+                "name=le%20guin&email=ursula_le_guin%40gmail.com{}",
+                body
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        assert_eq!(400, response.status().as_u16());
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["errors"]["plan"], message);
+    }
+}
+
+#[tokio::test]
+async fn concurrent_subscriptions_to_the_same_address_both_succeed() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let (first, second) = tokio::join!(
+        app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into()),
+        app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+    );
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+}
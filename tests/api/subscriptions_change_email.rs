@@ -0,0 +1,255 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const CHANGE: &str = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+
+/// Asks to move the confirmed subscriber to `ursula@example.com` and returns
+/// the links sent to the new and to the old address.
+async fn request_change(app: &TestApp) -> (reqwest::Url, reqwest::Url) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_change_email(CHANGE.into()).await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let n = email_requests.len();
+    let confirmation_links = app.get_confirmation_links(&email_requests[n - 2]);
+    assert_eq!(confirmation_links.html, confirmation_links.plain_text);
+    let approval_links = app.get_confirmation_links(&email_requests[n - 1]);
+    assert_eq!(approval_links.html, approval_links.plain_text);
+    (confirmation_links.html, approval_links.html)
+}
+
+/// Opens `link`, then presses the button of the page it shows.
+async fn follow(link: reqwest::Url) -> reqwest::Response {
+    let page = reqwest::get(link.clone()).await.unwrap();
+    assert_eq!(200, page.status().as_u16());
+    let mut action = link.clone();
+    action.set_query(None);
+    let form: Vec<_> = link.query_pairs().into_owned().collect();
+    reqwest::Client::new()
+        .post(action)
+        .form(&form)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn saved_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .email
+}
+
+#[tokio::test]
+async fn change_email_sends_a_confirmation_to_the_new_address_and_a_notice_to_the_old_one() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_change_email(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let recipients: Vec<String> = email_requests[1..]
+        .iter()
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            body["To"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(
+        recipients,
+        vec!["ursula@example.com", "ursula_le_guin@gmail.com"]
+    );
+}
+
+#[tokio::test]
+async fn email_is_not_changed_until_the_new_address_is_confirmed() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_change_email(body.into()).await;
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn confirming_and_approving_the_change_swaps_the_email_and_keeps_the_subscriber() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let before = sqlx::query!("SELECT id FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    let (confirmation_link, approval_link) = request_change(&app).await;
+
+    let response = follow(confirmation_link).await;
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.text().await.unwrap().contains("Almost there"));
+    assert_eq!(saved_email(&app).await, "ursula_le_guin@gmail.com");
+    let response = follow(approval_link).await;
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.text().await.unwrap().contains("Email changed"));
+
+    let saved = sqlx::query!("SELECT id, email, status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.id, before.id);
+    assert_eq!(saved.email, "ursula@example.com");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn the_new_address_alone_cannot_move_the_subscription() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let (confirmation_link, approval_link) = request_change(&app).await;
+
+    follow(confirmation_link.clone()).await;
+    follow(confirmation_link.clone()).await;
+    // Nor can it use its token to approve.
+    let mut forged = approval_link.clone();
+    let (_, change_token) = confirmation_link.query_pairs().next().unwrap();
+    forged.set_query(Some(&format!("approval_token={}", change_token)));
+    assert_eq!(401, reqwest::get(forged).await.unwrap().status().as_u16());
+
+    assert_eq!(saved_email(&app).await, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn opening_the_links_does_not_change_anything() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let (confirmation_link, approval_link) = request_change(&app).await;
+
+    for link in [confirmation_link, approval_link] {
+        let response = reqwest::get(link).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("<form method=\"post\""));
+    }
+
+    assert_eq!(saved_email(&app).await, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn expired_change_requests_cannot_be_completed() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let (confirmation_link, approval_link) = request_change(&app).await;
+    follow(confirmation_link).await;
+    sqlx::query!("UPDATE email_change_requests SET requested_at = now() - interval '1 year'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(approval_link).await.unwrap();
+
+    assert_eq!(410, response.status().as_u16());
+    assert_eq!(saved_email(&app).await, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn change_email_is_rate_limited_per_address() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // The subscription already took a token for the old address.
+    for _ in 0..2 {
+        let response = app.post_change_email(CHANGE.into()).await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = app.post_change_email(CHANGE.into()).await;
+
+    assert_eq!(429, response.status().as_u16());
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn change_email_for_an_unknown_subscriber_sends_nothing() {
+    let app = spawn_app().await;
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_change_email(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn change_email_returns_a_400_when_fields_are_invalid() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("email=ursula_le_guin%40gmail.com", "missing the new email"),
+        (
+            "email=ursula_le_guin%40gmail.com&new_email=not-an-email",
+            "invalid new email",
+        ),
+        (
+            "email=ursula_le_guin%40gmail.com&new_email=ursula_le_guin%40gmail.com",
+            "new email same as the current one",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_change_email(body.into()).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload was {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn change_confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/change_email/confirm?change_token={}",
+        &app.address,
+        "a".repeat(25)
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+}
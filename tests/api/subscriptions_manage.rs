@@ -0,0 +1,98 @@
+use crate::helpers::{spawn_app, TestApp};
+
+async fn manage_token(app: &TestApp) -> String {
+    sqlx::query!("SELECT manage_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .manage_token
+}
+
+async fn subscriber(app: &TestApp) -> (String, String) {
+    let row = sqlx::query!("SELECT name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    (row.name, row.status)
+}
+
+#[tokio::test]
+async fn unsubscribe_links_ask_before_unsubscribing() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = manage_token(&app).await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.text().await.unwrap().contains(&token));
+    assert_eq!(subscriber(&app).await.1, "confirmed");
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/unsubscribe", app.address))
+        .form(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(subscriber(&app).await.1, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unknown_manage_tokens_are_rejected() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+
+    for path in ["unsubscribe", "preferences"] {
+        let response = reqwest::get(format!(
+            "{}/subscriptions/{}?token=not-a-token",
+            app.address, path
+        ))
+        .await
+        .unwrap();
+        assert_eq!(401, response.status().as_u16(), "{}", path);
+    }
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/unsubscribe", app.address))
+        .form(&[("token", "not-a-token")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(subscriber(&app).await.1, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribers_can_change_their_name() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let token = manage_token(&app).await;
+    let page = reqwest::get(format!(
+        "{}/subscriptions/preferences?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
+    assert!(page.contains("value=\"le guin\""), "{}", page);
+
+    let save = |name: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/preferences", app.address))
+            .form(&[("token", token.as_str()), ("name", name)])
+            .send()
+    };
+    let response = save("Ursula K. Le Guin").await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(subscriber(&app).await.0, "Ursula K. Le Guin");
+
+    let response = save("  ").await.unwrap();
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(subscriber(&app).await.0, "Ursula K. Le Guin");
+}
@@ -0,0 +1,86 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn the_widget_script_is_served_as_javascript() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/widget.js", &app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/javascript"));
+    assert!(response.text().await.unwrap().contains("/widget"));
+}
+
+#[tokio::test]
+async fn the_widget_can_only_be_framed_by_allowed_origins() {
+    let app = spawn_app_with(|c| {
+        c.cors.allowed_origins = vec!["https://example.com".into()];
+    })
+    .await;
+
+    let response = reqwest::get(format!("{}/widget", &app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Security-Policy"],
+        "frame-ancestors 'self' https://example.com"
+    );
+    assert!(response.text().await.unwrap().contains("<form"));
+}
+
+#[tokio::test]
+async fn subscriptions_allow_cross_origin_requests_from_allowed_origins() {
+    let app = spawn_app_with(|c| {
+        c.cors.allowed_origins = vec!["https://example.com".into()];
+    })
+    .await;
+
+    let response = reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}/subscriptions", &app.address),
+        )
+        .header("Origin", "https://example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Access-Control-Allow-Origin"],
+        "https://example.com"
+    );
+}
+
+#[tokio::test]
+async fn subscriptions_reject_cross_origin_requests_from_other_origins() {
+    let app = spawn_app_with(|c| {
+        c.cors.allowed_origins = vec!["https://example.com".into()];
+    })
+    .await;
+
+    let response = reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}/subscriptions", &app.address),
+        )
+        .header("Origin", "https://evil.com")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response
+        .headers()
+        .get("Access-Control-Allow-Origin")
+        .is_none());
+}